@@ -0,0 +1,1530 @@
+//! Backends for communicating with a REPL process.
+//!
+//! The REPLs are normally run as processes with [DefaultBackend], but a custom [ReplBackend] can
+//! be given to [crate::check_document_with_backend] to e.g. run an embedded interpreter.
+
+use crate::reader::{spawn_reader, Cancel, Encoding, OutputReader, ReadLimits};
+use crate::report::{ResourceUsage, ScreenSnapshot};
+use regex::Regex;
+use serde::Deserialize;
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::iter;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Environment variables which are removed when spawning a REPL with a clean environment, since
+/// they may change the prompt or the colors of the output.
+const UNSET_ENV_VARS: &[&str] = &[
+    "PS1",
+    "PS2",
+    "PS3",
+    "PS4",
+    "PROMPT",
+    "RPROMPT",
+    "PROMPT_COMMAND",
+    "FORCE_COLOR",
+    "CLICOLOR_FORCE",
+];
+
+/// How long to wait for a REPL to exit after `SIGTERM` before it is killed.
+#[cfg(unix)]
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How to communicate with the REPL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplMode {
+    /// Run the REPL in a pseudo terminal.
+    Pty,
+
+    /// Run the REPL with plain pipes as stdin and stdout, for programs which behave badly in a
+    /// terminal.
+    Pipe,
+}
+
+impl std::str::FromStr for ReplMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "pty" => Ok(ReplMode::Pty),
+            "pipe" => Ok(ReplMode::Pipe),
+            _ => Err("Expected pty or pipe".to_string()),
+        }
+    }
+}
+
+/// The prompt of [JupyterBackend] with a counter placeholder, which is the default prompt for
+/// [BackendKind::Jupyter].
+pub const JUPYTER_PROMPT: &str = r"In \[{n}\]: ";
+
+/// What kind of backend runs the REPL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Run the command as a process and communicate with it in the [ReplMode].
+    Process,
+
+    /// Execute every command in a Jupyter kernel, see [JupyterBackend].
+    Jupyter,
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "process" => Ok(BackendKind::Process),
+            "jupyter" => Ok(BackendKind::Jupyter),
+            _ => Err("Expected process or jupyter".to_string()),
+        }
+    }
+}
+
+/// The kinds of output from a Jupyter kernel which are compared with the expected output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JupyterStreams {
+    pub stdout: bool,
+    pub stderr: bool,
+
+    /// The `text/plain` representation of the result of an expression.
+    pub result: bool,
+
+    /// The `text/plain` representation of displayed data.
+    pub display: bool,
+
+    /// The name and value of an exception.
+    pub error: bool,
+}
+
+impl Default for JupyterStreams {
+    fn default() -> Self {
+        Self {
+            stdout: true,
+            stderr: true,
+            result: true,
+            display: true,
+            error: true,
+        }
+    }
+}
+
+impl JupyterStreams {
+    /// Whether output of a kind, as named in the output of the Jupyter helper, is included.
+    fn includes(&self, kind: &str) -> bool {
+        match kind {
+            "stdout" => self.stdout,
+            "stderr" => self.stderr,
+            "result" => self.result,
+            "display" => self.display,
+            "error" => self.error,
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for JupyterStreams {
+    type Err = String;
+
+    /// Parse a comma separated list like `stdout,result`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut streams = Self {
+            stdout: false,
+            stderr: false,
+            result: false,
+            display: false,
+            error: false,
+        };
+        for stream in s.split(',').map(str::trim) {
+            let included = match stream {
+                "stdout" => &mut streams.stdout,
+                "stderr" => &mut streams.stderr,
+                "result" => &mut streams.result,
+                "display" => &mut streams.display,
+                "error" => &mut streams.error,
+                _ => {
+                    return Err(format!(
+                        "Expected a comma separated list of stdout, stderr, result, display and \
+                         error, got {stream}"
+                    ))
+                }
+            };
+            *included = true;
+        }
+        Ok(streams)
+    }
+}
+
+/// Settings for the terminal the REPL is running in.
+#[derive(Debug, Clone, Copy)]
+pub struct TerminalSettings {
+    /// Whether to spawn the REPL with a controlled environment: `TERM=dumb`, `NO_COLOR=1`, fixed
+    /// `COLUMNS` and `LINES` and without variables which may change the prompt.
+    pub clean_env: bool,
+
+    /// The width of the pseudo terminal.
+    pub cols: u16,
+
+    /// The height of the pseudo terminal.
+    pub rows: u16,
+}
+
+/// Everything needed to spawn the REPL of a session.
+#[derive(Debug, Clone)]
+pub struct SpawnOptions<'a> {
+    /// The command used to run the repl from a system shell. For [BackendKind::Jupyter] it is the
+    /// Python interpreter which runs the kernel client.
+    pub shell_cmd: &'a str,
+
+    /// The program and its arguments, which are passed on as they are instead of splitting
+    /// [Self::shell_cmd], so quotes and spaces need no escaping. See [Self::with_args].
+    pub cmd_args: Option<Vec<String>>,
+
+    pub backend: BackendKind,
+
+    /// The name of the Jupyter kernel, like `python3`.
+    pub kernel: &'a str,
+
+    /// The output from the Jupyter kernel which is compared with the expected output.
+    pub jupyter_streams: JupyterStreams,
+
+    pub mode: ReplMode,
+
+    pub terminal: TerminalSettings,
+
+    /// Timeout when waiting for the prompt.
+    pub timeout_ms: u64,
+
+    /// Timeout when waiting for more output before the prompt.
+    pub idle_timeout_ms: Option<u64>,
+
+    /// The most output a command may print before the prompt, to stop runaway commands before
+    /// they use up the memory.
+    pub max_output_bytes: usize,
+
+    /// Run the command in a container with this image.
+    pub container: Option<&'a str>,
+
+    /// The program used to run containers, like `docker` or `podman`.
+    pub container_runtime: &'a str,
+
+    /// A directory which is mounted at the same path and used as working directory in the
+    /// container.
+    pub mount_dir: Option<&'a Path>,
+
+    /// Run the command on a remote host over ssh, like `user@host`.
+    pub ssh: Option<&'a str>,
+
+    /// Environment variables for the REPL.
+    pub env: &'a BTreeMap<String, String>,
+
+    /// A locale like `C.UTF-8` which `LANG` and `LC_ALL` are set to.
+    pub locale: Option<&'a str>,
+
+    /// How the output of the REPL is decoded.
+    pub encoding: Encoding,
+
+    /// A command which makes the REPL exit, which is sent when it is shut down.
+    pub quit: Option<&'a str>,
+
+    /// A command which restores a clean state in the REPL, like `%reset -f` in IPython, so that
+    /// [PooledBackend] can reuse it for another session.
+    pub reset_cmd: Option<&'a str>,
+
+    /// Prefix every line from stderr with [STDERR_PREFIX] instead of merging it with stdout
+    /// as it is. Only for [ReplMode::Pipe].
+    pub separate_stderr: bool,
+
+    /// Cancels reads from the REPL, see [Cancel].
+    pub cancel: Option<&'a Cancel>,
+
+    /// A temporary directory which the REPL runs in, with its path in [SANDBOX_ENV_VAR].
+    pub sandbox: Option<PathBuf>,
+}
+
+impl SpawnOptions<'_> {
+    /// Run `program` with `args`, like [Command::new] and [Command::args], instead of splitting
+    /// [Self::shell_cmd].
+    pub fn with_args(
+        mut self,
+        program: impl Into<String>,
+        args: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        let mut cmd_args = vec![program.into()];
+        cmd_args.extend(args.into_iter().map(Into::into));
+        self.cmd_args = Some(cmd_args);
+        self
+    }
+
+    /// The program and its arguments, from [Self::cmd_args] or [Self::shell_cmd].
+    pub fn args(&self) -> anyhow::Result<Vec<String>> {
+        match &self.cmd_args {
+            Some(args) if args.is_empty() => anyhow::bail!("cmd_args must not be empty."),
+            Some(args) => Ok(args.clone()),
+            None => comma::parse_command(self.shell_cmd)
+                .filter(|x| !x.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("Bad command: `{}`", self.shell_cmd)),
+        }
+    }
+
+    /// Create a [Command] for the shell command with the environment set up.
+    pub fn command(&self) -> anyhow::Result<Command> {
+        let mut args = self.args()?;
+        let TerminalSettings {
+            clean_env,
+            cols,
+            rows,
+        } = self.terminal;
+        let mut env = if clean_env {
+            vec![
+                ("TERM", "dumb".to_string()),
+                ("NO_COLOR", "1".to_string()),
+                ("COLUMNS", cols.to_string()),
+                ("LINES", rows.to_string()),
+            ]
+        } else {
+            Vec::new()
+        };
+        if let Some(locale) = self.locale {
+            env.extend([("LANG", locale.to_string()), ("LC_ALL", locale.to_string())]);
+        }
+        env.extend(self.env.iter().map(|(k, v)| (k.as_str(), v.clone())));
+        if let Some(dir) = &self.sandbox {
+            env.push((SANDBOX_ENV_VAR, dir.display().to_string()));
+        }
+        if let Some(destination) = self.ssh {
+            if self.container.is_some() {
+                anyhow::bail!("container and ssh can not both be set.");
+            }
+            if self.sandbox.is_some() {
+                anyhow::bail!("sandbox and ssh can not both be set.");
+            }
+            let mut command = Command::new("ssh");
+            command.arg(if self.mode == ReplMode::Pty {
+                "-tt"
+            } else {
+                "-T"
+            });
+            let mut remote_cmd = remote_env(&env)?;
+            match &self.cmd_args {
+                Some(_) => {
+                    let quoted: Vec<Cow<str>> =
+                        args.iter().map(String::as_str).map(shell_quote).collect();
+                    remote_cmd += &quoted.join(" ");
+                }
+                None => remote_cmd += self.shell_cmd,
+            }
+            command.arg(destination).arg("--").arg(remote_cmd);
+            return Ok(command);
+        }
+        let Some(image) = self.container else {
+            let mut command = Command::new(args.remove(0));
+            command.args(args).envs(env);
+            if let Some(dir) = &self.sandbox {
+                command.current_dir(dir);
+            }
+            if clean_env {
+                for var in UNSET_ENV_VARS {
+                    command.env_remove(var);
+                }
+            }
+            return Ok(command);
+        };
+        let mut command = Command::new(self.container_runtime);
+        command.args(["run", "--rm", "--init", "--interactive"]);
+        if self.mode == ReplMode::Pty {
+            command.arg("--tty");
+        }
+        // The sandbox is the working directory if there is one, otherwise the document directory.
+        for dir in self.mount_dir.iter().chain(self.sandbox.as_deref()) {
+            command
+                .arg("--volume")
+                .arg(format!("{0}:{0}", dir.display()));
+        }
+        if let Some(dir) = self.sandbox.as_deref().or(self.mount_dir) {
+            command.arg("--workdir").arg(dir);
+        }
+        for (key, value) in env {
+            command.arg("--env").arg(format!("{key}={value}"));
+        }
+        command.arg(image).args(args);
+        Ok(command)
+    }
+}
+
+/// Quote an argument for a POSIX shell.
+fn shell_quote(arg: &str) -> Cow<'_, str> {
+    let safe = |x: char| x.is_ascii_alphanumeric() || "-_./=:,@%+".contains(x);
+    match !arg.is_empty() && arg.chars().all(safe) {
+        true => Cow::Borrowed(arg),
+        false => Cow::Owned(format!("'{}'", arg.replace('\'', r"'\''"))),
+    }
+}
+
+/// The prefix of a command for a remote shell which sets the environment variables with `env`,
+/// or an empty string if there are none. The values are quoted, and names which are not valid
+/// identifiers are rejected since they could not be set anyway.
+fn remote_env(env: &[(&str, String)]) -> anyhow::Result<String> {
+    let is_name = |x: &str| {
+        x.starts_with(|x: char| x.is_ascii_alphabetic() || x == '_')
+            && x.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+    };
+    if env.is_empty() {
+        return Ok(String::new());
+    }
+    let mut prefix = "env".to_string();
+    for (key, value) in env {
+        if !is_name(key) {
+            anyhow::bail!("Bad name of an environment variable for ssh: `{key}`");
+        }
+        prefix += &format!(" {key}={}", shell_quote(value));
+    }
+    Ok(prefix + " ")
+}
+
+/// A way to communicate with a running REPL.
+pub trait ReplBackend {
+    /// Start the REPL for a session.
+    fn spawn(options: &SpawnOptions) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+
+    /// Send a line of input to the REPL.
+    fn send_line(&mut self, line: &str) -> anyhow::Result<()>;
+
+    /// Send input without a newline, like a key for a pager.
+    fn send_keys(&mut self, keys: &str) -> anyhow::Result<()> {
+        let _ = keys;
+        anyhow::bail!("Keys can't be sent with this backend.")
+    }
+
+    /// Read output until the prompt regex matches or end of file is reached.
+    ///
+    /// Returns the output before the prompt together with the prompt, or all remaining output and
+    /// `None` at end of file.
+    fn read_until_prompt(&mut self, prompt: &Regex) -> anyhow::Result<(String, Option<String>)>;
+
+    /// Like [Self::read_until_prompt], but `on_line` is called with every complete line of output
+    /// (without the line ending) as soon as it has been read. If it returns an error, reading
+    /// stops and the error is returned, so a mismatch in a long output can be detected early.
+    ///
+    /// The prompt regex should not match newlines. The default implementation calls `on_line`
+    /// with all lines after all output has been read.
+    fn read_until_prompt_streaming(
+        &mut self,
+        prompt: &Regex,
+        on_line: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        let (output, actual_prompt) = self.read_until_prompt(prompt)?;
+        output.lines().try_for_each(on_line)?;
+        Ok((output, actual_prompt))
+    }
+
+    /// Wait until the REPL has printed nothing for `idle`, and return all output so far without
+    /// consuming it, so that it is returned again by the next read. Used to detect the prompt.
+    fn peek_until_idle(&mut self, idle: Duration) -> anyhow::Result<String> {
+        let _ = idle;
+        anyhow::bail!("The prompt can't be detected with this backend.")
+    }
+
+    /// Terminate the REPL.
+    fn shutdown(&mut self) -> anyhow::Result<()>;
+
+    /// Terminate the REPL and its process group right away, without asking it to quit, after a
+    /// failure when it may hang. Defaults to [Self::shutdown].
+    fn kill(&mut self) -> anyhow::Result<()> {
+        self.shutdown()
+    }
+
+    /// The resources used by the REPL, available after [Self::shutdown].
+    fn resource_usage(&self) -> Option<ResourceUsage> {
+        None
+    }
+
+    /// A snapshot of the terminal screen, if the backend emulates a terminal.
+    fn screen_snapshot(&self) -> Option<ScreenSnapshot> {
+        None
+    }
+
+    /// Attach the terminal of the user to the REPL, so they can use it directly, until they detach
+    /// with [crate::debug::DETACH_KEY] or the REPL exits. Only the backends with a pseudo terminal
+    /// support it.
+    fn attach(&mut self) -> anyhow::Result<()> {
+        anyhow::bail!("Only REPLs in a pseudo terminal can be attached to.")
+    }
+}
+
+/// How long a REPL is given to exit after the quit command before it is killed.
+pub(crate) const QUIT_GRACE: Duration = Duration::from_secs(1);
+
+/// The error when the prompt has not been printed within the timeout. All backends return it, so
+/// that timeouts can be handled alike.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Timed out after {} waiting for the prompt `{expected}`, got: {got}",
+    humantime::format_duration(*timeout)
+)]
+pub struct TimeoutError {
+    /// The prompt regex.
+    pub expected: String,
+
+    /// The output which has been read.
+    pub got: String,
+    pub timeout: Duration,
+}
+
+/// Wait for a child process to exit and return its resource usage, including the usage of its
+/// waited-for descendants.
+///
+/// The process is given `grace` time to exit on its own, then it is sent `SIGHUP` (which makes
+/// interactive shells exit, unlike `SIGTERM`) and `SIGTERM`, and finally `SIGKILL` if it still
+/// hasn't exited after [KILL_TIMEOUT]. Every signal is sent once, to its whole process group, and
+/// the processes which are left in the group when it has exited are killed with
+/// [kill_process_group].
+#[cfg(unix)]
+pub(crate) fn wait_with_usage(pid: libc::pid_t, grace: Duration) -> anyhow::Result<ResourceUsage> {
+    let start = Instant::now();
+    // The signals which have been sent: none, `SIGHUP` and `SIGTERM`, or also `SIGKILL`.
+    let mut signals_sent = 0;
+    let usage = loop {
+        let mut status = 0;
+        // SAFETY: An all-zero `rusage` is valid.
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        // SAFETY: `status` and `usage` are valid for writes.
+        match unsafe { libc::wait4(pid, &mut status, libc::WNOHANG, &mut usage) } {
+            0 => {}
+            x if x == pid => {
+                let time = |x: libc::timeval| {
+                    Duration::from_secs(x.tv_sec as u64) + Duration::from_micros(x.tv_usec as u64)
+                };
+                // The peak RSS is in bytes on macOS, and in kilobytes on Linux and the BSDs.
+                let rss_unit = if cfg!(target_vendor = "apple") {
+                    1
+                } else {
+                    1024
+                };
+                break ResourceUsage {
+                    cpu_time: time(usage.ru_utime) + time(usage.ru_stime),
+                    max_rss: usage.ru_maxrss as u64 * rss_unit,
+                };
+            }
+            _ => anyhow::bail!(
+                "Failed to wait for the REPL: {}",
+                std::io::Error::last_os_error()
+            ),
+        }
+        let elapsed = start.elapsed();
+        if signals_sent == 1 && elapsed >= grace + KILL_TIMEOUT {
+            signal_group(pid, libc::SIGKILL);
+            signals_sent = 2;
+        } else if signals_sent == 0 && elapsed >= grace {
+            signal_group(pid, libc::SIGHUP);
+            signal_group(pid, libc::SIGTERM);
+            signals_sent = 1;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    kill_process_group(pid);
+    Ok(usage)
+}
+
+/// Send a signal to a process and to the process group it leads, if any.
+#[cfg(unix)]
+fn signal_group(pid: libc::pid_t, signal: libc::c_int) {
+    // SAFETY: Sending a signal to a process is safe.
+    unsafe {
+        libc::kill(pid, signal);
+        libc::kill(-pid, signal);
+    }
+}
+
+/// Terminate the processes which are left in the process group of a REPL which has exited, like
+/// programs it started in the background, with `SIGTERM` and then `SIGKILL` after
+/// [KILL_TIMEOUT].
+#[cfg(target_os = "linux")]
+fn kill_process_group(pgid: libc::pid_t) {
+    let start = Instant::now();
+    let mut terminated = Vec::new();
+    loop {
+        let processes = group_processes(pgid);
+        if processes.is_empty() {
+            return;
+        }
+        let kill = start.elapsed() >= KILL_TIMEOUT;
+        for pid in processes {
+            if kill {
+                // SAFETY: Sending a signal to a process is safe.
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+            } else if !terminated.contains(&pid) {
+                // SAFETY: As above.
+                unsafe { libc::kill(pid, libc::SIGTERM) };
+                terminated.push(pid);
+            }
+        }
+        if kill {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// The running processes in the process group `pgid` or in the session with the same id, found in
+/// `/proc`. A REPL in a pseudo terminal leads a session of its own, and shells with job control
+/// put every job in a process group of its own within it. Zombies are skipped as they can't be
+/// killed.
+#[cfg(target_os = "linux")]
+fn group_processes(pgid: libc::pid_t) -> Vec<libc::pid_t> {
+    let Ok(dir) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    dir.filter_map(|entry| {
+        let pid = entry.ok()?.file_name().to_str()?.parse().ok()?;
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        // The fields after the command name, which is in parentheses and may contain spaces, are
+        // the state, the parent, the process group and the session.
+        let mut fields = stat.rsplit_once(") ")?.1.split(' ');
+        let state = fields.next()?;
+        let group = fields.nth(1)?.parse::<libc::pid_t>().ok()?;
+        let session = fields.next()?.parse::<libc::pid_t>().ok()?;
+        (state != "Z" && (group == pgid || session == pgid)).then_some(pid)
+    })
+    .collect()
+}
+
+/// Terminate the processes which are left in the process group of a REPL which has exited, with
+/// `SIGTERM` and then `SIGKILL` after [KILL_TIMEOUT]. Without `/proc`, the processes can't be
+/// listed, so the group is signalled as a whole and the other groups in its session are not found.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn kill_process_group(pgid: libc::pid_t) {
+    let start = Instant::now();
+    // SAFETY: Sending a signal to a process group is safe.
+    unsafe { libc::kill(-pgid, libc::SIGTERM) };
+    // SAFETY: The signal 0 only checks whether there are processes in the group.
+    while unsafe { libc::kill(-pgid, 0) } == 0 {
+        if start.elapsed() >= KILL_TIMEOUT {
+            // SAFETY: As above.
+            unsafe { libc::kill(-pgid, libc::SIGKILL) };
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Wait for a child process to exit, see [wait_with_usage].
+#[cfg(unix)]
+fn wait_child(child: &mut Child, grace: Duration) -> anyhow::Result<ResourceUsage> {
+    wait_with_usage(child.id() as _, grace)
+}
+
+/// Wait for a child process to exit for `grace`, and then kill it. The resource usage is not
+/// measured on Windows, so the default is returned.
+#[cfg(windows)]
+fn wait_child(child: &mut Child, grace: Duration) -> anyhow::Result<ResourceUsage> {
+    let start = Instant::now();
+    while child.try_wait()?.is_none() {
+        if start.elapsed() >= grace {
+            // The process may exit right before it is killed.
+            let _ = child.kill();
+            child.wait()?;
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(ResourceUsage::default())
+}
+
+pub use crate::pty::PtyBackend;
+
+/// The default backend which runs the REPL with the [BackendKind] and [ReplMode] given by the
+/// [SpawnOptions].
+pub enum DefaultBackend {
+    Pty(PtyBackend),
+    Pipe(PipeBackend),
+    Jupyter(JupyterBackend),
+}
+
+impl ReplBackend for DefaultBackend {
+    fn spawn(options: &SpawnOptions) -> anyhow::Result<Self> {
+        Ok(match (options.backend, options.mode) {
+            (BackendKind::Jupyter, _) => DefaultBackend::Jupyter(JupyterBackend::spawn(options)?),
+            (BackendKind::Process, ReplMode::Pty) => {
+                DefaultBackend::Pty(PtyBackend::spawn(options)?)
+            }
+            (BackendKind::Process, ReplMode::Pipe) => {
+                DefaultBackend::Pipe(PipeBackend::spawn(options)?)
+            }
+        })
+    }
+
+    fn send_line(&mut self, line: &str) -> anyhow::Result<()> {
+        match self {
+            DefaultBackend::Pty(x) => x.send_line(line),
+            DefaultBackend::Pipe(x) => x.send_line(line),
+            DefaultBackend::Jupyter(x) => x.send_line(line),
+        }
+    }
+
+    fn send_keys(&mut self, keys: &str) -> anyhow::Result<()> {
+        match self {
+            DefaultBackend::Pty(x) => x.send_keys(keys),
+            DefaultBackend::Pipe(x) => x.send_keys(keys),
+            DefaultBackend::Jupyter(x) => x.send_keys(keys),
+        }
+    }
+
+    fn read_until_prompt(&mut self, prompt: &Regex) -> anyhow::Result<(String, Option<String>)> {
+        match self {
+            DefaultBackend::Pty(x) => x.read_until_prompt(prompt),
+            DefaultBackend::Pipe(x) => x.read_until_prompt(prompt),
+            DefaultBackend::Jupyter(x) => x.read_until_prompt(prompt),
+        }
+    }
+
+    fn read_until_prompt_streaming(
+        &mut self,
+        prompt: &Regex,
+        on_line: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        match self {
+            DefaultBackend::Pty(x) => x.read_until_prompt_streaming(prompt, on_line),
+            DefaultBackend::Pipe(x) => x.read_until_prompt_streaming(prompt, on_line),
+            DefaultBackend::Jupyter(x) => x.read_until_prompt_streaming(prompt, on_line),
+        }
+    }
+
+    fn peek_until_idle(&mut self, idle: Duration) -> anyhow::Result<String> {
+        match self {
+            DefaultBackend::Pty(x) => x.peek_until_idle(idle),
+            DefaultBackend::Pipe(x) => x.peek_until_idle(idle),
+            DefaultBackend::Jupyter(x) => x.peek_until_idle(idle),
+        }
+    }
+
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        match self {
+            DefaultBackend::Pty(x) => x.shutdown(),
+            DefaultBackend::Pipe(x) => x.shutdown(),
+            DefaultBackend::Jupyter(x) => x.shutdown(),
+        }
+    }
+
+    fn kill(&mut self) -> anyhow::Result<()> {
+        match self {
+            DefaultBackend::Pty(x) => x.kill(),
+            DefaultBackend::Pipe(x) => x.kill(),
+            DefaultBackend::Jupyter(x) => x.kill(),
+        }
+    }
+
+    fn resource_usage(&self) -> Option<ResourceUsage> {
+        match self {
+            DefaultBackend::Pty(x) => x.resource_usage(),
+            DefaultBackend::Pipe(x) => x.resource_usage(),
+            DefaultBackend::Jupyter(x) => x.resource_usage(),
+        }
+    }
+
+    fn screen_snapshot(&self) -> Option<ScreenSnapshot> {
+        match self {
+            DefaultBackend::Pty(x) => x.screen_snapshot(),
+            DefaultBackend::Pipe(x) => x.screen_snapshot(),
+            DefaultBackend::Jupyter(x) => x.screen_snapshot(),
+        }
+    }
+
+    fn attach(&mut self) -> anyhow::Result<()> {
+        match self {
+            DefaultBackend::Pty(x) => x.attach(),
+            DefaultBackend::Pipe(x) => x.attach(),
+            DefaultBackend::Jupyter(x) => x.attach(),
+        }
+    }
+}
+
+/// Processes which have been spawned ahead of time or are reused by [PooledBackend], by
+/// [pool_key]. A key with an empty list has been spawned before.
+static POOL: Mutex<BTreeMap<String, Vec<Pooled>>> = Mutex::new(BTreeMap::new());
+
+/// A process in the [POOL], with what it printed before the first command.
+struct Pooled {
+    process: Box<dyn PooledProcess>,
+    startup: Vec<StartupRead>,
+}
+
+/// A read from a REPL before the first command was sent to it, which is replayed when the REPL is
+/// reused by another session, see [PooledBackend].
+#[derive(Debug, Clone)]
+struct StartupRead {
+    /// The regex which was read until.
+    regex: String,
+
+    /// The output before the match.
+    output: String,
+
+    /// The match, or `None` at the end of the output.
+    matched: Option<String>,
+}
+
+/// A type erased process in the [POOL].
+trait PooledProcess: Send {
+    fn shutdown_pooled(&mut self) -> anyhow::Result<()>;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<B: ReplBackend + Send + 'static> PooledProcess for B {
+    fn shutdown_pooled(&mut self) -> anyhow::Result<()> {
+        self.shutdown()
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// The key of processes in the [POOL] which can be used for a session: the command and its
+/// environment, and how it is run. Options which only exist at runtime, like
+/// [SpawnOptions::cancel], are left out.
+fn pool_key(options: &SpawnOptions) -> String {
+    format!(
+        "{:?}",
+        (
+            (options.shell_cmd, &options.cmd_args, options.env),
+            (options.backend, options.kernel, options.jupyter_streams),
+            (options.mode, options.container, options.ssh, options.locale),
+            (options.terminal, options.encoding, options.separate_stderr),
+        )
+    )
+}
+
+/// A backend which keeps a warm pool of REPL processes, for running the same sessions over and
+/// over like in watch mode, or many sessions with the same command.
+///
+/// When a session is spawned with a command which has been spawned before, another process is
+/// spawned and kept in the pool, so the next run of the session gets a process which has already
+/// started. Sessions with a [SpawnOptions::reset_cmd] reuse their process instead: when it is shut
+/// down, the reset command is sent, its output is read until the prompt and the process is put
+/// back into the pool. The next session which gets it reads what the process printed when it
+/// started, like the banner, just like from a new process. Sessions with a
+/// [SpawnOptions::sandbox] don't use the pool, since their processes run in their own
+/// directories. The pooled processes are shut down with [PooledBackend::clear_pool].
+pub struct PooledBackend<B> {
+    /// The process, or `None` if it has been put back into the pool.
+    process: Option<B>,
+
+    /// The key in the [POOL] and the reset command, if the process is reused.
+    reuse: Option<(String, String)>,
+
+    /// What the process printed before the first command, recorded when it is new.
+    startup: Vec<StartupRead>,
+
+    /// The reads of [Self::startup] which have not been replayed yet, if the process is reused.
+    replay: VecDeque<StartupRead>,
+
+    /// Whether a command has been sent, so the reads are not a part of the startup.
+    started: bool,
+
+    /// The regex of the last read, which is the prompt after the last command.
+    last_prompt: Option<Regex>,
+}
+
+impl<B: ReplBackend + Send + 'static> PooledBackend<B> {
+    /// Shut down all processes in the pool.
+    pub fn clear_pool() {
+        let pool = std::mem::take(&mut *POOL.lock().unwrap());
+        for mut pooled in pool.into_values().flatten() {
+            let _ = pooled.process.shutdown_pooled();
+        }
+    }
+
+    /// The process, unless it has been put back into the pool.
+    fn process(&mut self) -> anyhow::Result<&mut B> {
+        self.process
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("The REPL has been put back into the pool."))
+    }
+
+    /// The next read of the startup of a reused process, which must be until the same regex as
+    /// when the process was new.
+    fn replayed(&mut self, prompt: &Regex) -> anyhow::Result<Option<StartupRead>> {
+        let Some(read) = self.replay.pop_front() else {
+            return Ok(None);
+        };
+        if read.regex != prompt.as_str() {
+            anyhow::bail!(
+                "The REPL from the pool was started by a session which read until `{}` instead of \
+                 `{prompt}`, so it can't be reused.",
+                read.regex
+            );
+        }
+        self.last_prompt = Some(prompt.clone());
+        Ok(Some(read))
+    }
+
+    /// Record a read, as a part of the startup if no command has been sent yet.
+    fn record(&mut self, prompt: &Regex, output: &str, matched: &Option<String>) {
+        if !self.started {
+            self.startup.push(StartupRead {
+                regex: prompt.as_str().to_string(),
+                output: output.to_string(),
+                matched: matched.clone(),
+            });
+        }
+        self.last_prompt = Some(prompt.clone());
+    }
+
+    /// Send the reset command and read its output until the prompt, so the process can be
+    /// reused by another session.
+    fn reset(&mut self, reset_cmd: &str) -> anyhow::Result<()> {
+        let prompt = self
+            .last_prompt
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("The prompt of the REPL is not known."))?;
+        let process = self.process()?;
+        process.send_line(reset_cmd)?;
+        match process.read_until_prompt(&prompt)? {
+            (_, Some(_)) => Ok(()),
+            (_, None) => anyhow::bail!("The REPL exited after the reset command."),
+        }
+    }
+}
+
+impl<B: ReplBackend + Send + 'static> ReplBackend for PooledBackend<B> {
+    fn spawn(options: &SpawnOptions) -> anyhow::Result<Self> {
+        let mut backend = PooledBackend {
+            process: None,
+            reuse: None,
+            startup: Vec::new(),
+            replay: VecDeque::new(),
+            started: false,
+            last_prompt: None,
+        };
+        if options.sandbox.is_some() {
+            backend.process = Some(B::spawn(options)?);
+            return Ok(backend);
+        }
+        let key = pool_key(options);
+        let (pooled, spawned_before) = {
+            let mut pool = POOL.lock().unwrap();
+            let spawned_before = pool.contains_key(&key);
+            (pool.entry(key.clone()).or_default().pop(), spawned_before)
+        };
+        match pooled.map(|x| (x.process.into_any().downcast::<B>(), x.startup)) {
+            Some((Ok(process), startup)) => {
+                backend.process = Some(*process);
+                backend.replay = startup.iter().cloned().collect();
+                backend.startup = startup;
+                // A reused process has started already.
+                backend.started = !backend.startup.is_empty();
+            }
+            _ => backend.process = Some(B::spawn(options)?),
+        }
+        // A process which is reused comes back to the pool by itself, and a command which has
+        // not been spawned before is likely not spawned again.
+        if options.reset_cmd.is_none() && spawned_before {
+            // If the next process fails to spawn, the error is reported when it is needed.
+            if let Ok(next) = B::spawn(options) {
+                POOL.lock()
+                    .unwrap()
+                    .entry(key.clone())
+                    .or_default()
+                    .push(Pooled {
+                        process: Box::new(next),
+                        startup: Vec::new(),
+                    });
+            }
+        }
+        backend.reuse = options.reset_cmd.map(|x| (key, x.to_string()));
+        Ok(backend)
+    }
+
+    fn send_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.started = true;
+        self.replay.clear();
+        self.process()?.send_line(line)
+    }
+
+    fn send_keys(&mut self, keys: &str) -> anyhow::Result<()> {
+        self.started = true;
+        self.replay.clear();
+        self.process()?.send_keys(keys)
+    }
+
+    fn read_until_prompt(&mut self, prompt: &Regex) -> anyhow::Result<(String, Option<String>)> {
+        if let Some(read) = self.replayed(prompt)? {
+            return Ok((read.output, read.matched));
+        }
+        let (output, matched) = self.process()?.read_until_prompt(prompt)?;
+        self.record(prompt, &output, &matched);
+        Ok((output, matched))
+    }
+
+    fn read_until_prompt_streaming(
+        &mut self,
+        prompt: &Regex,
+        on_line: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        if let Some(read) = self.replayed(prompt)? {
+            read.output.lines().try_for_each(on_line)?;
+            return Ok((read.output, read.matched));
+        }
+        let (output, matched) = self
+            .process()?
+            .read_until_prompt_streaming(prompt, on_line)?;
+        self.record(prompt, &output, &matched);
+        Ok((output, matched))
+    }
+
+    fn peek_until_idle(&mut self, idle: Duration) -> anyhow::Result<String> {
+        if !self.replay.is_empty() {
+            let replay = self.replay.iter();
+            return Ok(replay
+                .map(|x| x.output.clone() + x.matched.as_deref().unwrap_or_default())
+                .collect());
+        }
+        self.process()?.peek_until_idle(idle)
+    }
+
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        if let Some((key, reset_cmd)) = self.reuse.clone() {
+            // The REPL may have exited, and then it is shut down as usual.
+            if self.reset(&reset_cmd).is_ok() {
+                if let Some(process) = self.process.take() {
+                    POOL.lock().unwrap().entry(key).or_default().push(Pooled {
+                        process: Box::new(process),
+                        startup: std::mem::take(&mut self.startup),
+                    });
+                }
+                return Ok(());
+            }
+        }
+        self.process.as_mut().map_or(Ok(()), B::shutdown)
+    }
+
+    fn kill(&mut self) -> anyhow::Result<()> {
+        self.process.as_mut().map_or(Ok(()), B::kill)
+    }
+
+    /// The resources used by a process which is reused can't be measured, since it hasn't exited.
+    fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.process.as_ref()?.resource_usage()
+    }
+
+    fn screen_snapshot(&self) -> Option<ScreenSnapshot> {
+        self.process.as_ref()?.screen_snapshot()
+    }
+
+    fn attach(&mut self) -> anyhow::Result<()> {
+        self.process()?.attach()
+    }
+}
+
+/// A callback for every line of output, see [ReplBackend::read_until_prompt_streaming].
+pub(crate) type OnLine<'a> = &'a mut dyn FnMut(&str) -> anyhow::Result<()>;
+
+/// The error when a command has printed more than `max` bytes without the prompt, with the
+/// beginning and the end of the output.
+pub(crate) fn output_limit_error(max: usize, output: &str) -> anyhow::Error {
+    // How many bytes to show from the beginning and the end of the output.
+    const CONTEXT: usize = 400;
+    let boundary = |i| (0..=i).rev().find(|&i| output.is_char_boundary(i)).unwrap();
+    let head = &output[..boundary(CONTEXT.min(output.len()))];
+    let tail = &output[boundary(output.len().saturating_sub(CONTEXT).max(head.len()))..];
+    anyhow::anyhow!(
+        "The command printed more than {max} bytes without printing the prompt, see the \
+         max_output_bytes attribute. The output began with:\n{head}\n[...]\nand ended with:\n{tail}"
+    )
+}
+
+/// The error when no output has come for `idle` while waiting for `prompt`.
+pub(crate) fn idle_timeout_error(idle: Duration, prompt: &Regex, got: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "No new output for {} while waiting for the prompt `{prompt}`, got: {got}",
+        humantime::format_duration(idle)
+    )
+}
+
+/// The environment variable with the path of the sandbox of a session, see
+/// [SpawnOptions::sandbox].
+pub const SANDBOX_ENV_VAR: &str = "REPL_CHECK_TMP";
+
+/// The prefix of lines from stderr with [SpawnOptions::separate_stderr].
+pub const STDERR_PREFIX: &str = "stderr: ";
+
+/// How long to wait for more output from stdout or stderr when the prompt has been read with
+/// [SpawnOptions::separate_stderr], since the streams are read independently.
+const STREAM_SETTLE_TIME: Duration = Duration::from_millis(20);
+
+/// A REPL with plain pipes as stdin and stdout. Stderr is merged with stdout, with every line
+/// prefixed with [STDERR_PREFIX] if [SpawnOptions::separate_stderr] is set.
+pub struct PipeBackend {
+    child: Child,
+    stdin: ChildStdin,
+
+    /// Receives chunks of output from stdout and stderr, together with whether they are from
+    /// stderr. An empty chunk means end of file for that stream.
+    output: Receiver<(bool, Vec<u8>)>,
+
+    /// Number of open output streams.
+    open_streams: usize,
+
+    /// Output which is read but not yet returned.
+    buffer: String,
+
+    /// The output from stdout and stderr after their last newlines if
+    /// [SpawnOptions::separate_stderr] is set. Complete lines are moved to `buffer`, those from
+    /// stderr with [STDERR_PREFIX], so that the lines of the two streams are not mixed up. An
+    /// incomplete line may be a prompt, so it is only prefixed when the line ends.
+    partial_lines: Option<[String; 2]>,
+
+    /// Trailing bytes of an incomplete UTF-8 sequence, for stdout and stderr.
+    incomplete: [Vec<u8>; 2],
+
+    encoding: Encoding,
+
+    /// The error if output couldn't be decoded, which is returned by the next read.
+    decode_error: Option<anyhow::Error>,
+
+    limits: ReadLimits,
+
+    /// See [SpawnOptions::quit].
+    quit: Option<String>,
+
+    resource_usage: Option<ResourceUsage>,
+}
+
+impl PipeBackend {
+    /// Append a chunk of bytes from stdout or stderr to the buffer, decoded with
+    /// [SpawnOptions::encoding]. Invalid output is decoded lossily, and the error is kept for the
+    /// next read.
+    fn push_bytes(&mut self, stderr: bool, chunk: &[u8]) {
+        let incomplete = &mut self.incomplete[usize::from(stderr)];
+        incomplete.extend_from_slice(chunk);
+        let end = self.encoding.complete_len(incomplete);
+        let text = match self.encoding.decode(&incomplete[..end]) {
+            Ok(text) => text.into_owned(),
+            Err(e) => {
+                self.decode_error.get_or_insert(e);
+                String::from_utf8_lossy(&incomplete[..end]).into_owned()
+            }
+        };
+        incomplete.drain(..end);
+        match &mut self.partial_lines {
+            Some(lines) => {
+                let line = &mut lines[usize::from(stderr)];
+                line.push_str(&text);
+                while let Some(end) = line.find('\n') {
+                    if stderr {
+                        self.buffer.push_str(STDERR_PREFIX);
+                    }
+                    self.buffer.extend(line.drain(..=end));
+                }
+            }
+            None => self.buffer.push_str(&text),
+        }
+    }
+
+    /// Receive the next chunk of output and add it to the buffer, or return the error if no
+    /// output came within `timeout`.
+    fn receive(&mut self, timeout: Duration) -> Result<(), RecvTimeoutError> {
+        match self.output.recv_timeout(timeout) {
+            Ok((_, chunk)) if chunk.is_empty() => self.open_streams -= 1,
+            Ok((stderr, chunk)) => self.push_bytes(stderr, &chunk),
+            Err(RecvTimeoutError::Timeout) => return Err(RecvTimeoutError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => self.open_streams = 0,
+        }
+        if let Some([stdout, stderr]) = self
+            .partial_lines
+            .as_mut()
+            .filter(|_| self.open_streams == 0)
+        {
+            // Incomplete last lines are lines too.
+            self.buffer.extend(stdout.drain(..));
+            if !stderr.is_empty() {
+                self.buffer.push_str(STDERR_PREFIX);
+                self.buffer.extend(stderr.drain(..));
+            }
+        }
+        Ok(())
+    }
+
+    /// The output which has been read but not returned, including incomplete lines.
+    fn pending(&self) -> Cow<'_, str> {
+        match &self.partial_lines {
+            Some([stdout, stderr]) if !stdout.is_empty() || !stderr.is_empty() => {
+                Cow::Owned(format!("{}{stdout}{stderr}", self.buffer))
+            }
+            _ => Cow::Borrowed(&self.buffer),
+        }
+    }
+
+    /// Remove the first `len` bytes of [Self::pending].
+    fn consume(&mut self, mut len: usize) {
+        let partial_lines = self.partial_lines.iter_mut().flatten();
+        for text in iter::once(&mut self.buffer).chain(partial_lines) {
+            let n = len.min(text.len());
+            text.drain(..n);
+            len -= n;
+        }
+    }
+}
+
+impl ReplBackend for PipeBackend {
+    fn spawn(options: &SpawnOptions) -> anyhow::Result<Self> {
+        let mut command = options.command()?;
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // A process group of its own, so its children are terminated with it.
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let (sender, output) = channel();
+        spawn_reader(child.stdout.take().unwrap(), false, sender.clone());
+        spawn_reader(child.stderr.take().unwrap(), true, sender);
+        Ok(Self {
+            child,
+            stdin,
+            output,
+            open_streams: 2,
+            buffer: String::new(),
+            partial_lines: options.separate_stderr.then(Default::default),
+            incomplete: Default::default(),
+            encoding: options.encoding,
+            decode_error: None,
+            limits: ReadLimits {
+                timeout: Duration::from_millis(options.timeout_ms),
+                idle_timeout: options.idle_timeout_ms.map(Duration::from_millis),
+                max_output_bytes: options.max_output_bytes,
+                cancel: options.cancel.cloned(),
+            },
+            quit: options.quit.map(str::to_string),
+            resource_usage: None,
+        })
+    }
+
+    fn send_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.send_keys(&format!("{line}\n"))
+    }
+
+    fn send_keys(&mut self, keys: &str) -> anyhow::Result<()> {
+        self.stdin.write_all(&self.encoding.encode(keys))?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    fn read_until_prompt(&mut self, prompt: &Regex) -> anyhow::Result<(String, Option<String>)> {
+        self.read_until_prompt_streaming(prompt, &mut |_| Ok(()))
+    }
+
+    fn read_until_prompt_streaming(
+        &mut self,
+        prompt: &Regex,
+        on_line: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        let start = Instant::now();
+        let mut last_output = start;
+        // The length of the complete lines in the buffer which have been given to `on_line`.
+        let mut streamed = 0;
+        loop {
+            if let Some(e) = self.decode_error.take() {
+                return Err(e);
+            }
+            let pending = self.pending();
+            if let Some(m) = prompt.find(&pending) {
+                let (start, end) = (m.start(), m.end());
+                let matched = pending[start..end].to_string();
+                let before_prompt = pending[..start].to_string();
+                // With separate streams, the output from the other stream may still be on its
+                // way, so wait a little for more output before the prompt is taken.
+                if self.partial_lines.is_some()
+                    && self.open_streams > 0
+                    && self.receive(STREAM_SETTLE_TIME).is_ok()
+                {
+                    continue;
+                }
+                self.consume(end);
+                return Ok((before_prompt, Some(matched)));
+            }
+            while let Some(len) = self.buffer[streamed..].find('\n') {
+                let line = &self.buffer[streamed..streamed + len];
+                on_line(line.strip_suffix('\r').unwrap_or(line))?;
+                streamed += len + 1;
+            }
+            if self.open_streams == 0 {
+                self.buffer[streamed..]
+                    .lines()
+                    .try_for_each(&mut *on_line)?;
+                return Ok((std::mem::take(&mut self.buffer), None));
+            }
+            let wait = self
+                .limits
+                .next_wait(start, last_output, prompt, &self.pending())?;
+            if self.receive(wait).is_ok() {
+                last_output = Instant::now();
+            }
+        }
+    }
+
+    fn peek_until_idle(&mut self, idle: Duration) -> anyhow::Result<String> {
+        let start = Instant::now();
+        while self.open_streams > 0 && start.elapsed() < self.limits.timeout {
+            if self.receive(idle).is_err() {
+                break;
+            }
+        }
+        Ok(self.pending().into_owned())
+    }
+
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        if self.resource_usage.is_none() {
+            let grace = match &self.quit {
+                Some(quit) => writeln!(self.stdin, "{quit}")
+                    .and_then(|_| self.stdin.flush())
+                    .map_or(Duration::ZERO, |_| QUIT_GRACE),
+                None => Duration::ZERO,
+            };
+            self.resource_usage = Some(wait_child(&mut self.child, grace)?);
+        }
+        Ok(())
+    }
+
+    fn kill(&mut self) -> anyhow::Result<()> {
+        if self.resource_usage.is_none() {
+            self.resource_usage = Some(wait_child(&mut self.child, Duration::ZERO)?);
+        }
+        Ok(())
+    }
+
+    fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.resource_usage.filter(|_| cfg!(unix))
+    }
+}
+
+impl Drop for PipeBackend {
+    fn drop(&mut self) {
+        let _ = self.kill();
+    }
+}
+
+/// Executes every command in a Jupyter kernel and returns the [JupyterStreams] given by
+/// [SpawnOptions::jupyter_streams] as output.
+///
+/// The kernel is run by a Python helper using the `jupyter_client` package, which talks the
+/// Jupyter messaging protocol over ZeroMQ, so it has to be installed for the Python interpreter
+/// given as [SpawnOptions::shell_cmd]. The helper runs on this machine, so the kernel can't be
+/// run in a container or over ssh. Every line is executed on its own, and the prompt is always
+/// `In [n]: ` where `n` is the next execution count, see [JUPYTER_PROMPT]. The responses of the
+/// helper are read with the [ReadLimits] of the session, like the output of other REPLs.
+pub struct JupyterBackend {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: OutputReader,
+
+    /// Whether code has been sent but the response not yet read.
+    pending: bool,
+
+    /// Whether the kernel has died.
+    exited: bool,
+
+    streams: JupyterStreams,
+
+    execution_count: u64,
+
+    /// How long to wait for the kernel to shut down.
+    timeout: Duration,
+
+    resource_usage: Option<ResourceUsage>,
+}
+
+/// A response from the Jupyter helper script.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct JupyterResponse {
+    ready: bool,
+    exit: bool,
+
+    /// The kind and text of every output in order.
+    output: Vec<(String, String)>,
+
+    execution_count: Option<u64>,
+    error: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    /// The end of a response from the Jupyter helper, which is read until like a prompt.
+    static ref JUPYTER_RESPONSE_END: Regex = Regex::new("\n").unwrap();
+}
+
+impl JupyterBackend {
+    /// Read the next response from the helper.
+    fn read_response(&mut self) -> anyhow::Result<JupyterResponse> {
+        let (line, end) = self
+            .stdout
+            .read_until_prompt(&JUPYTER_RESPONSE_END, None)
+            .map_err(|e| match e.downcast() {
+                // Report the prompt rather than the end of the response.
+                Ok(TimeoutError { got, timeout, .. }) => TimeoutError {
+                    expected: format!("In [{}]: ", self.execution_count + 1),
+                    got,
+                    timeout,
+                }
+                .into(),
+                Err(e) => e,
+            })?;
+        if end.is_none() {
+            anyhow::bail!("The Jupyter helper exited unexpectedly.");
+        }
+        let response: JupyterResponse = serde_json::from_str(&line)?;
+        if let Some(error) = response.error {
+            anyhow::bail!("Jupyter: {error}");
+        }
+        Ok(response)
+    }
+}
+
+impl ReplBackend for JupyterBackend {
+    fn spawn(options: &SpawnOptions) -> anyhow::Result<Self> {
+        let mut args = options.args()?;
+        let mut command = Command::new(args.remove(0));
+        command
+            .args(args)
+            .arg("-c")
+            .arg(include_str!("jupyter_helper.py"))
+            .arg(options.kernel)
+            .arg((options.timeout_ms as f64 / 1000.0).to_string())
+            .envs(
+                options
+                    .locale
+                    .iter()
+                    .flat_map(|x| [("LANG", *x), ("LC_ALL", *x)]),
+            )
+            .envs(options.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        if let Some(dir) = &options.sandbox {
+            command.current_dir(dir).env(SANDBOX_ENV_VAR, dir);
+        }
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command.spawn()?;
+        let limits = ReadLimits {
+            timeout: Duration::from_millis(options.timeout_ms),
+            idle_timeout: options.idle_timeout_ms.map(Duration::from_millis),
+            max_output_bytes: options.max_output_bytes,
+            cancel: options.cancel.cloned(),
+        };
+        let mut backend = Self {
+            stdin: child.stdin.take().unwrap(),
+            stdout: OutputReader::new(child.stdout.take().unwrap(), limits),
+            child,
+            pending: false,
+            exited: false,
+            streams: options.jupyter_streams,
+            execution_count: 0,
+            timeout: Duration::from_millis(options.timeout_ms),
+            resource_usage: None,
+        };
+        if !backend.read_response()?.ready {
+            anyhow::bail!("Failed to start the Jupyter kernel {}.", options.kernel);
+        }
+        Ok(backend)
+    }
+
+    fn send_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let request = serde_json::json!({ "code": line });
+        writeln!(self.stdin, "{request}")?;
+        self.stdin.flush()?;
+        self.pending = true;
+        Ok(())
+    }
+
+    fn read_until_prompt(&mut self, _prompt: &Regex) -> anyhow::Result<(String, Option<String>)> {
+        if self.exited {
+            return Ok((String::new(), None));
+        }
+        let mut output = String::new();
+        if self.pending {
+            self.pending = false;
+            let response = self.read_response()?;
+            output = response
+                .output
+                .into_iter()
+                .filter(|(kind, _)| self.streams.includes(kind))
+                .map(|(_, text)| text)
+                .collect();
+            if response.exit {
+                self.exited = true;
+                return Ok((output, None));
+            }
+            self.execution_count = response.execution_count.unwrap_or(self.execution_count + 1);
+        }
+        Ok((output, Some(format!("In [{}]: ", self.execution_count + 1))))
+    }
+
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        if self.resource_usage.is_none() {
+            let _ = writeln!(self.stdin, "{}", serde_json::json!({ "shutdown": true }));
+            let _ = self.stdin.flush();
+            self.resource_usage = Some(wait_child(&mut self.child, self.timeout)?);
+        }
+        Ok(())
+    }
+
+    fn kill(&mut self) -> anyhow::Result<()> {
+        if self.resource_usage.is_none() {
+            self.resource_usage = Some(wait_child(&mut self.child, Duration::ZERO)?);
+        }
+        Ok(())
+    }
+
+    fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.resource_usage.filter(|_| cfg!(unix))
+    }
+}
+
+impl Drop for JupyterBackend {
+    fn drop(&mut self) {
+        let _ = self.kill();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_env_quotes_values() {
+        let env = [("A", "1".to_string()), ("B_2", "x; rm -rf ~".to_string())];
+        assert_eq!(remote_env(&env).unwrap(), "env A=1 B_2='x; rm -rf ~' ");
+        assert_eq!(remote_env(&[]).unwrap(), "");
+    }
+
+    #[test]
+    fn remote_env_rejects_bad_names() {
+        for name in ["", "1A", "A B", "A;B", "A=B"] {
+            assert!(remote_env(&[(name, String::new())]).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn shell_quote_quotes_only_when_needed() {
+        assert!(matches!(
+            shell_quote("a-b_c/d.e=f:1,2@3%+"),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$HOME*"), "'$HOME*'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("ä"), "'ä'");
+    }
+}
@@ -0,0 +1,54 @@
+//! A cache of the sessions which have passed, so that unchanged sessions can be skipped.
+//!
+//! A session is identified by a hash of everything which affects its result: the version of
+//! repl-check, the command and how it is spawned, and the code and attributes of all its blocks
+//! in order. The version of the REPL itself is not known before it is started, so the cache is
+//! only used when it is asked for, like with `repl-check check --cache`. The cache directory
+//! contains an empty file for every session which passed, named after the hash, and the
+//! modification time of the file is when the session last passed.
+
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// The default cache directory, relative to the current directory.
+pub const DEFAULT_CACHE_DIR: &str = "target/repl-check-cache";
+
+/// A directory with cached session results.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// A cache in a directory, which is created when something is cached.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Cache { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    /// Whether a session with this key has passed, within `max_age` if it is given.
+    pub fn has_passed(&self, key: &str, max_age: Option<Duration>) -> bool {
+        let Ok(time) = std::fs::metadata(self.path(key)).and_then(|x| x.modified()) else {
+            return false;
+        };
+        max_age.is_none_or(|max_age| {
+            SystemTime::now()
+                .duration_since(time)
+                .is_ok_and(|x| x <= max_age)
+        })
+    }
+
+    /// Record that a session with this key has passed now.
+    pub fn record_passed(&self, key: &str) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {e}", self.dir.display()))?;
+        let path = self.path(key);
+        // Recreate the file so its modification time is updated.
+        let _ = std::fs::remove_file(&path);
+        std::fs::write(&path, "")
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {e}", path.display()))
+    }
+}
@@ -1,46 +1,232 @@
-/// A Vec of strings, (usually lines), which is either borrowed (`Vec<&str>`) or owned
-/// (`Vec<String>`).
+use std::ops::Range;
+
+/// What an edit of the lines of a block comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditOrigin {
+    /// A `???` hole, in the output or as a prompt, is filled in.
+    Hole,
+
+    /// A prompt is rewritten or renumbered to the actual prompt.
+    Prompt,
+
+    /// Mismatching output is replaced with the actual output.
+    Output,
+
+    /// The output of a command without expected output is recorded.
+    Record,
+
+    /// A suggested fix is applied to mismatching output.
+    Suggestion,
+}
+
+/// A replacement of some of the original lines of a block, see [LineEditor].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineEdit {
+    /// The indices of the replaced lines in the original lines.
+    pub original: Range<usize>,
+
+    /// The new lines.
+    pub lines: Vec<String>,
+
+    pub origin: EditOrigin,
+}
+
+/// Edits the lines of a block in order, from the first line to the last, by keeping or replacing
+/// the next original lines.
 ///
-/// The idea is that we want to push strings, usually borrowed strings, but sometimes some line
-/// mighg be updated, and then we want to handle the logic of changing every line from `&str` to
-/// `String`.
+/// Only the replaced regions are stored, as [LineEdit]s, and the kept lines are borrowed from the
+/// original lines, so replacing a line doesn't copy the lines before it. The edits can be applied
+/// to get the whole edited block with [Self::edited_text], or be used to patch the block in place.
 #[derive(Debug)]
-pub enum LinesCow<'a> {
-    Borrowed(Vec<&'a str>),
-    Owned(Vec<String>),
+pub struct LineEditor<'a> {
+    original: &'a [&'a str],
+
+    /// The number of original lines which have been kept or replaced.
+    position: usize,
+    edits: Vec<LineEdit>,
 }
 
-impl<'a> LinesCow<'a> {
-    /// Create a new, empty list of lines.
-    pub fn new() -> Self {
-        LinesCow::Borrowed(Vec::new())
+impl<'a> LineEditor<'a> {
+    /// Start editing `original`.
+    pub fn new(original: &'a [&'a str]) -> Self {
+        Self {
+            original,
+            position: 0,
+            edits: Vec::new(),
+        }
     }
 
-    /// Push a slice of borrowed lines. If `self` is owned, the pushed lines will be cloned.
-    pub fn push_borrowed(&mut self, lines: &[&'a str]) {
-        match self {
-            LinesCow::Borrowed(x) => x.extend_from_slice(lines),
-            LinesCow::Owned(x) => x.extend(lines.into_iter().map(|x| x.to_string())),
-        }
+    /// Keep the next `count` original lines, which must not be more than the lines which are left.
+    pub fn keep(&mut self, count: usize) {
+        debug_assert!(
+            self.position + count <= self.original.len(),
+            "Kept {count} lines after line {} of {}",
+            self.position,
+            self.original.len()
+        );
+        self.position = (self.position + count).min(self.original.len());
     }
 
-    /// Push a slice of owned strings. If `self` is borrowed, all lines in `self` will be cloned.
-    pub fn push_owned(&mut self, lines: &[&str]) {
-        match self {
-            LinesCow::Borrowed(x) => {
-                let new_lines = x.into_iter().map(|x| x.to_string()).collect();
-                *self = LinesCow::Owned(new_lines);
-                self.push_owned(lines);
+    /// Replace the next `count` original lines with `lines`. Nothing is edited if the lines are
+    /// the same, and edits with the same origin which follow each other are merged.
+    pub fn replace(&mut self, count: usize, lines: &[&str], origin: EditOrigin) {
+        let start = self.position;
+        self.keep(count);
+        if self.original[start..self.position] == *lines {
+            return;
+        }
+        let lines = lines.iter().map(|x| x.to_string());
+        match self.edits.last_mut() {
+            Some(last) if last.original.end == start && last.origin == origin => {
+                last.original.end = self.position;
+                last.lines.extend(lines);
             }
-            LinesCow::Owned(x) => x.extend(lines.into_iter().map(|x| x.to_string())),
+            _ => self.edits.push(LineEdit {
+                original: start..self.position,
+                lines: lines.collect(),
+                origin,
+            }),
+        }
+    }
+
+    /// Drop the edits whose origins don't satisfy `keep`, so the original lines are kept there.
+    pub fn retain(&mut self, keep: impl Fn(EditOrigin) -> bool) {
+        self.edits.retain(|x| keep(x.origin));
+    }
+
+    /// The edits in order, which don't overlap.
+    pub fn edits(&self) -> &[LineEdit] {
+        &self.edits
+    }
+
+    /// The edited lines joined with newlines, or `None` if nothing has been edited. The original
+    /// lines which have not been kept or replaced yet are kept.
+    pub fn edited_text(&self) -> Option<String> {
+        if self.edits.is_empty() {
+            return None;
+        }
+        let mut lines: Vec<&str> = Vec::with_capacity(self.original.len());
+        let mut kept = 0;
+        for edit in &self.edits {
+            lines.extend_from_slice(&self.original[kept..edit.original.start]);
+            lines.extend(edit.lines.iter().map(String::as_str));
+            kept = edit.original.end;
         }
+        lines.extend_from_slice(&self.original[kept..]);
+        Some(lines.join("\n"))
     }
+}
 
-    /// Convert this to `Some(lines)` if this is owned or `None` otherwise.
-    pub fn maybe_owned(self) -> Option<Vec<String>> {
-        match self {
-            LinesCow::Owned(x) => Some(x),
-            LinesCow::Borrowed(_) => None,
+/// The number of characters which must be inserted, removed or replaced to turn `a` into `b`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // The distances from the prefix of `a` so far to every prefix of `b`.
+    let mut distances: Vec<usize> = (0..=b.len()).collect();
+    for (i, x) in a.chars().enumerate() {
+        let mut previous = distances[0];
+        distances[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let replaced = previous + usize::from(x != *y);
+            previous = distances[j + 1];
+            distances[j + 1] = replaced.min(distances[j] + 1).min(previous + 1);
         }
     }
+    distances[b.len()]
+}
+
+/// The name in `names` which is closest to `name`, if it is close enough to be a typo.
+pub fn closest_name<'a>(name: &str, names: &[&'a str]) -> Option<&'a str> {
+    names
+        .iter()
+        .map(|x| (edit_distance(name, x), *x))
+        .filter(|(distance, _)| *distance <= name.chars().count().div_ceil(3).max(1))
+        .min()
+        .map(|(_, x)| x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjacent_replaces_with_the_same_origin_are_merged() {
+        let original = ["a", "b", "c", "d"];
+        let mut editor = LineEditor::new(&original);
+        editor.replace(1, &["x"], EditOrigin::Hole);
+        editor.replace(1, &["y", "z"], EditOrigin::Hole);
+        editor.replace(1, &["w"], EditOrigin::Prompt);
+        assert_eq!(
+            editor.edits(),
+            [
+                LineEdit {
+                    original: 0..2,
+                    lines: vec!["x".to_string(), "y".to_string(), "z".to_string()],
+                    origin: EditOrigin::Hole,
+                },
+                LineEdit {
+                    original: 2..3,
+                    lines: vec!["w".to_string()],
+                    origin: EditOrigin::Prompt,
+                },
+            ]
+        );
+        // The lines which have not been visited are kept.
+        assert_eq!(editor.edited_text().unwrap(), "x\ny\nz\nw\nd");
+    }
+
+    #[test]
+    fn kept_lines_separate_the_edits() {
+        let original = ["a", "b", "c"];
+        let mut editor = LineEditor::new(&original);
+        editor.replace(1, &["x"], EditOrigin::Output);
+        editor.keep(1);
+        editor.replace(1, &[], EditOrigin::Output);
+        assert_eq!(editor.edits().len(), 2);
+        assert_eq!(editor.edited_text().unwrap(), "x\nb");
+    }
+
+    #[test]
+    fn replacing_lines_with_themselves_is_no_edit() {
+        let original = ["a", "b"];
+        let mut editor = LineEditor::new(&original);
+        editor.replace(2, &["a", "b"], EditOrigin::Hole);
+        assert!(editor.edits().is_empty());
+        assert_eq!(editor.edited_text(), None);
+    }
+
+    #[test]
+    fn dropped_edits_keep_the_original_lines() {
+        let original = ["a", "b", "c"];
+        let mut editor = LineEditor::new(&original);
+        editor.replace(1, &["x"], EditOrigin::Prompt);
+        editor.replace(1, &["y"], EditOrigin::Hole);
+        editor.retain(|x| x != EditOrigin::Prompt);
+        assert_eq!(editor.edited_text().unwrap(), "a\ny\nc");
+        editor.retain(|_| false);
+        assert_eq!(editor.edited_text(), None);
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("flaw", "lawn"), 2);
+        // Characters are counted, not bytes.
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn closest_names() {
+        let names = ["prompt", "prompt_char", "cmd", "cwd", "timeout"];
+        assert_eq!(closest_name("promt", &names), Some("prompt"));
+        assert_eq!(closest_name("prompt", &names), Some("prompt"));
+        assert_eq!(closest_name("timeuot", &names), Some("timeout"));
+        // Both are one edit away, and the first in order is taken.
+        assert_eq!(closest_name("cxd", &names), Some("cmd"));
+        // Short names must be closer.
+        assert_eq!(closest_name("xyz", &names), None);
+        assert_eq!(closest_name("colour", &names), None);
+    }
 }
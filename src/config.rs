@@ -0,0 +1,278 @@
+//! The project configuration file `repl-check.toml`.
+
+use crate::interactive::InteractivePrompt;
+use crate::pattern::{deserialize_tokens, Tokens};
+use crate::preset::Preset;
+use crate::substitute::{deserialize_substitutions, Substitution};
+use crate::Options;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The name of the configuration file which is looked up in the current directory.
+pub const CONFIG_FILE_NAME: &str = "repl-check.toml";
+
+/// Project wide configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Enabled features. Blocks with an `if_feature` attribute are only run if that feature is
+    /// enabled.
+    pub features: Vec<String>,
+
+    /// Documents to check when no files are given on the command line, as a map from glob
+    /// patterns to settings for the matching documents.
+    pub inputs: BTreeMap<String, InputSettings>,
+
+    /// Glob patterns for more documents to check when no files are given on the command line.
+    pub include: Vec<String>,
+
+    /// Glob patterns for documents which are excluded from `[inputs]` and `include`.
+    pub exclude: Vec<String>,
+
+    /// The timeout when waiting for a prompt, like `10s`.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub timeout: Option<Duration>,
+
+    /// The longest time without new output when waiting for a prompt, like `30s`.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub idle_timeout: Option<Duration>,
+
+    /// Default prompt regexes for blocks with a class, like `python = ">>> "`.
+    pub prompts: HashMap<String, String>,
+
+    /// Environment variables for all REPLs.
+    pub env: BTreeMap<String, String>,
+
+    /// Fail on blocks which have not passed within this time, like `30d`.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub max_age: Option<Duration>,
+
+    /// The number of documents which are checked in parallel.
+    pub concurrency: Option<usize>,
+
+    /// Placeholders in the documents mapped to the real values used when running the REPLs,
+    /// like `"<API-KEY>" = "abc123"`.
+    pub placeholders: BTreeMap<String, String>,
+
+    /// Output filters which can be enabled on blocks with the `filter` attribute, as a map from
+    /// names to shell commands. See the [crate::plugin] module.
+    pub filters: BTreeMap<String, String>,
+
+    /// Custom matchers which can be enabled on blocks with the `matcher` attribute, as a map from
+    /// names to shell commands. See the [crate::plugin] module.
+    pub matchers: BTreeMap<String, String>,
+
+    /// Regexes for volatile lines, like timestamps or PIDs, which are dropped from the actual and
+    /// expected output of every session before they are matched.
+    #[serde(deserialize_with = "deserialize_regexes")]
+    pub ignore_lines: Vec<Regex>,
+
+    /// Sed-style substitutions which rewrite the actual output of every session before it is
+    /// matched, like `["s/0x[0-9a-f]+/0xADDR/g"]`. See the [crate::substitute] module.
+    #[serde(deserialize_with = "deserialize_substitutions")]
+    pub substitute: Vec<Substitution>,
+
+    /// Tokens for the expected output as a map from names to regexes, like
+    /// `BUILD_ID = "[0-9a-f]{7}"` in `[tokens]` for `{BUILD_ID}`. They may also override the
+    /// built-in `DATE`, `TIME`, `DATETIME` and `DURATION`.
+    #[serde(deserialize_with = "deserialize_tokens")]
+    pub tokens: Tokens,
+
+    /// Presets for the `preset` attribute, in addition to the built-in ones, like
+    /// `[presets.lua]` with `cmd`, `prompt`, `continuation_prompt` and `quit`.
+    pub presets: BTreeMap<String, Preset>,
+
+    /// A shell command which is run before every session without a `setup_cmd` attribute, like
+    /// `cp -r fixtures tmp/`.
+    pub setup_cmd: Option<String>,
+
+    /// A shell command which is run after every session without a `teardown_cmd` attribute.
+    pub teardown_cmd: Option<String>,
+
+    /// Prompts of pagers and confirmations, in addition to the built-in ones, as
+    /// `[[interactive_prompts]]` with a `regex` and the keys to `respond` with. See the
+    /// [crate::interactive] module.
+    pub interactive_prompts: Vec<InteractivePrompt>,
+}
+
+/// Deserialize an optional duration like `10s` with humantime.
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Deserialize a list of regexes.
+fn deserialize_regexes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|x| Regex::new(x).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Settings for all documents matching a glob pattern in the `[inputs]` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputSettings {
+    /// The pandoc format of the documents. Deduced from the file extension if not set.
+    pub format: Option<String>,
+
+    /// Default attributes for all REPL blocks in the documents.
+    pub attributes: HashMap<String, String>,
+}
+
+impl Config {
+    /// Load the configuration from a file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {e}", path.display()))?;
+        toml::from_str(&content).map_err(|e| anyhow::anyhow!("In {}: {e}", path.display()))
+    }
+
+    /// Load [CONFIG_FILE_NAME] from the current directory, or the default configuration if there
+    /// is no such file.
+    pub fn load_default() -> anyhow::Result<Self> {
+        let path = Path::new(CONFIG_FILE_NAME);
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Expand all glob patterns in the `[inputs]` section and `include` to a sorted list of
+    /// files, without the files matching `exclude`.
+    pub fn input_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let exclude = self
+            .exclude
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern)
+                    .map_err(|e| anyhow::anyhow!("Bad glob pattern in exclude: {pattern}: {e}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut files = Vec::new();
+        for pattern in self.inputs.keys().chain(&self.include) {
+            for path in glob::glob(pattern)
+                .map_err(|e| anyhow::anyhow!("Bad glob pattern in inputs: {pattern}: {e}"))?
+            {
+                let path = path?;
+                if !exclude.iter().any(|x| x.matches_path(&path)) {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    /// The options for checking documents with this configuration.
+    pub fn options(&self) -> Options {
+        Options {
+            features: self.features.iter().cloned().collect(),
+            timeout: self.timeout,
+            idle_timeout: self.idle_timeout,
+            default_prompts: self.prompts.clone(),
+            env: self.env.clone(),
+            placeholders: self.placeholders.clone(),
+            filters: self.filters.clone(),
+            matchers: self.matchers.clone(),
+            max_age: self.max_age,
+            ignore_lines: self.ignore_lines.clone(),
+            substitutions: self.substitute.clone(),
+            tokens: self.tokens.clone(),
+            presets: self.presets.clone(),
+            interactive_prompts: self.interactive_prompts.clone(),
+            setup_cmd: self.setup_cmd.clone(),
+            teardown_cmd: self.teardown_cmd.clone(),
+            ..Options::default()
+        }
+    }
+
+    /// The options for checking a document: `options` with the default attributes from the
+    /// settings of the document in the `[inputs]` section, and the directory of the document.
+    pub fn document_options(&self, path: &Path, options: &Options) -> anyhow::Result<Options> {
+        let mut options = options.clone();
+        if let Some(settings) = self.input_settings(path) {
+            options.default_attrs.clone_from(&settings.attributes);
+        }
+        options.document_dir = path
+            .parent()
+            .map(|x| {
+                if x.as_os_str().is_empty() {
+                    ".".as_ref()
+                } else {
+                    x
+                }
+            })
+            .map(std::fs::canonicalize)
+            .transpose()?;
+        Ok(options)
+    }
+
+    /// Get the settings of the first pattern in the `[inputs]` section which matches a path.
+    pub fn input_settings(&self, path: &Path) -> Option<&InputSettings> {
+        self.inputs
+            .iter()
+            .find(|(pattern, _)| {
+                glob::Pattern::new(pattern).is_ok_and(|pattern| pattern.matches_path(path))
+            })
+            .map(|(_, settings)| settings)
+    }
+}
+
+/// File extensions of documents which are checked when a directory is given as input.
+pub const DOCUMENT_EXTENSIONS: &[&str] = &[
+    "md", "markdown", "rst", "org", "tex", "html", "ipynb", "typ", "dj",
+];
+
+/// Expand directories and glob patterns in the input files.
+///
+/// Directories are searched recursively for files with [DOCUMENT_EXTENSIONS], and glob patterns
+/// like `docs/**/*.md` are expanded unless a file with that name exists.
+pub fn expand_inputs(inputs: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        let mut entries = std::fs::read_dir(dir)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {e}", dir.display()))?
+            .map(|x| x.map(|x| x.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                walk(&path, files)?;
+            } else if path
+                .extension()
+                .and_then(|x| x.to_str())
+                .is_some_and(|x| DOCUMENT_EXTENSIONS.contains(&x))
+            {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+    let mut files = Vec::new();
+    for input in inputs {
+        if input.is_dir() {
+            walk(input, &mut files)?;
+        } else if input.exists() {
+            files.push(input.clone());
+        } else {
+            let pattern = input.to_string_lossy();
+            let matches = glob::glob(&pattern)
+                .map_err(|e| anyhow::anyhow!("Bad glob pattern: {pattern}: {e}"))?
+                .collect::<Result<Vec<_>, _>>()?;
+            if matches.is_empty() {
+                anyhow::bail!("No such file: {pattern}");
+            }
+            files.extend(matches.into_iter().filter(|x| !x.is_dir()));
+        }
+    }
+    Ok(files)
+}
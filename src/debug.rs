@@ -0,0 +1,119 @@
+//! An interactive debug shell which attaches the terminal of the user to the REPL of a session
+//! after a block has failed, with [crate::OnFailure::Debug], so the state of the session can be
+//! inspected by hand.
+//!
+//! While attached, the terminal is in raw mode and everything typed is sent to the REPL as it is,
+//! including Ctrl-C, and the output of the REPL is written to the terminal as it comes. Pressing
+//! [DETACH_KEY] detaches the terminal, and the user is asked whether the run should continue.
+
+use crate::backend::ReplBackend;
+use crate::Options;
+use std::io::BufRead;
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(unix)]
+use {crate::reader::OutputReader, std::io::Write};
+
+/// The key which detaches the terminal from the REPL, Ctrl-].
+pub const DETACH_KEY: u8 = 0x1d;
+
+/// How long to wait for output from the REPL before checking for input from the user.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Held while the terminal is attached to a REPL, since sessions may fail at the same time.
+static TERMINAL: Mutex<()> = Mutex::new(());
+
+/// Show the error of a failed block in session `session_name` and attach the terminal to its REPL.
+/// If the user doesn't want to continue afterwards, the run is aborted with [Options::cancel].
+pub(crate) fn debug_shell(
+    session_name: &str,
+    error: &anyhow::Error,
+    process: &mut impl ReplBackend,
+    options: &Options,
+) {
+    let _terminal = TERMINAL.lock().unwrap_or_else(|e| e.into_inner());
+    eprintln!("\nA block in session {session_name} failed:\n{error}\n");
+    eprintln!("Attached to the REPL, press Ctrl-] to detach. The prompt has already been read.");
+    if let Err(e) = process.attach() {
+        eprintln!("Could not attach to the REPL: {e}");
+    }
+    eprint!("\nContinue the run? [Y/n] ");
+    let mut answer = String::new();
+    let _ = std::io::stdin().lock().read_line(&mut answer);
+    if matches!(answer.trim(), "n" | "N" | "no") {
+        eprintln!("Aborting the run.");
+        if let Some(cancel) = &options.cancel {
+            cancel.cancel();
+        }
+    }
+}
+
+/// Pass the input from the terminal to the REPL with `send`, and the output from `reader` to the
+/// terminal, until the user presses [DETACH_KEY] or the REPL exits.
+#[cfg(unix)]
+pub(crate) fn attach(
+    mut send: impl FnMut(&[u8]) -> anyhow::Result<()>,
+    reader: &mut OutputReader,
+) -> anyhow::Result<()> {
+    let _raw_mode = RawMode::enable()?;
+    let mut stdout = std::io::stdout();
+    let mut buf = [0u8; 1024];
+    while !reader.is_eof() {
+        stdout.write_all(&reader.pass_through(POLL_INTERVAL))?;
+        stdout.flush()?;
+        let mut stdin = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `stdin` is a valid pollfd.
+        if unsafe { libc::poll(&mut stdin, 1, 0) } <= 0 {
+            continue;
+        }
+        // Stdin is read directly, since the buffered reader of std could read ahead.
+        // SAFETY: `buf` is valid for its length.
+        let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+        if n <= 0 {
+            break;
+        }
+        let input = &buf[..n as usize];
+        match input.iter().position(|x| *x == DETACH_KEY) {
+            Some(i) => return send(&input[..i]),
+            None => send(input)?,
+        }
+    }
+    Ok(())
+}
+
+/// The terminal in raw mode, which is restored when this is dropped.
+#[cfg(unix)]
+struct RawMode(libc::termios);
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> anyhow::Result<Self> {
+        // SAFETY: The termios struct is initialized by tcgetattr before it is used.
+        unsafe {
+            let mut original = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                anyhow::bail!("Stdin is not a terminal.");
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                anyhow::bail!("Failed to put the terminal in raw mode.");
+            }
+            Ok(Self(original))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: The termios struct was filled in by tcgetattr.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Line based diffs between the code of a block and its update, for reviewing updates.
+
+use crate::pattern::printable;
+
+/// A line in a diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    /// A line which is in both texts.
+    Same(&'a str),
+
+    /// A line which is only in the old text.
+    Removed(&'a str),
+
+    /// A line which is only in the new text.
+    Added(&'a str),
+}
+
+/// The maximum number of cells in the table of the longest common subsequence. If the changed
+/// part of the texts is larger, all old lines are shown as removed and all new lines as added.
+const MAX_TABLE_SIZE: usize = 1 << 22;
+
+/// Compute a diff from `old` to `new` with as few changed lines as possible.
+pub fn diff<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(x, y)| x == y).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (old_middle, new_middle) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    let mut result: Vec<_> = old[..prefix].iter().map(|x| DiffLine::Same(x)).collect();
+    if (old_middle.len() + 1) * (new_middle.len() + 1) > MAX_TABLE_SIZE {
+        result.extend(old_middle.iter().map(|x| DiffLine::Removed(x)));
+        result.extend(new_middle.iter().map(|x| DiffLine::Added(x)));
+    } else {
+        // lcs[i][j] is the length of the longest common subsequence of old_middle[i..] and
+        // new_middle[j..].
+        let mut lcs = vec![vec![0usize; new_middle.len() + 1]; old_middle.len() + 1];
+        for i in (0..old_middle.len()).rev() {
+            for j in (0..new_middle.len()).rev() {
+                lcs[i][j] = match old_middle[i] == new_middle[j] {
+                    true => lcs[i + 1][j + 1] + 1,
+                    false => lcs[i + 1][j].max(lcs[i][j + 1]),
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old_middle.len() || j < new_middle.len() {
+            if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+                result.push(DiffLine::Same(old_middle[i]));
+                (i, j) = (i + 1, j + 1);
+            } else if j == new_middle.len()
+                || (i < old_middle.len() && lcs[i + 1][j] >= lcs[i][j + 1])
+            {
+                result.push(DiffLine::Removed(old_middle[i]));
+                i += 1;
+            } else {
+                result.push(DiffLine::Added(new_middle[j]));
+                j += 1;
+            }
+        }
+    }
+    result.extend(old[old.len() - suffix..].iter().map(|x| DiffLine::Same(x)));
+    result
+}
+
+/// Format a diff from `old` to `new` with `-` and `+` before removed and added lines, colored red
+/// and green with ANSI escape codes if `color` is set. Control characters in the lines are
+/// escaped.
+pub fn format_diff(old: &str, new: &str, color: bool) -> String {
+    let mut result = String::new();
+    for line in diff(old, new) {
+        let (prefix, line, escape) = match line {
+            DiffLine::Same(x) => (' ', x, None),
+            DiffLine::Removed(x) => ('-', x, Some("\x1b[31m")),
+            DiffLine::Added(x) => ('+', x, Some("\x1b[32m")),
+        };
+        let line = printable(line);
+        match escape.filter(|_| color) {
+            Some(escape) => result += &format!("{escape}{prefix}{line}\x1b[0m\n"),
+            None => result += &format!("{prefix}{line}\n"),
+        }
+    }
+    result
+}
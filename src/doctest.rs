@@ -0,0 +1,287 @@
+//! Conversion between doctests and REPL blocks.
+//!
+//! [import] turns the examples of Python or Elixir doctests into Markdown with REPL blocks, and
+//! [export] turns the commands and expected output of the REPL blocks in the documents, as listed
+//! by [crate::list_sessions], back into doctests.
+
+use crate::pattern::{self, LineAnnotation, LineAnnotations};
+use crate::BlockInfo;
+
+/// The format of doctests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoctestFormat {
+    /// Python doctests with `>>> ` and `... ` prompts.
+    Python,
+
+    /// Elixir doctests with `iex> ` and `...> ` prompts.
+    Elixir,
+}
+
+impl std::str::FromStr for DoctestFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "python" => Ok(Self::Python),
+            "elixir" => Ok(Self::Elixir),
+            _ => Err("Expected python or elixir".to_string()),
+        }
+    }
+}
+
+/// A blank line in the expected output of a Python doctest, since a blank line ends the output.
+const BLANKLINE: &str = "<BLANKLINE>";
+
+impl DoctestFormat {
+    /// The prompt and the continuation prompt of the examples.
+    fn prompts(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Python => (">>> ", "... "),
+            Self::Elixir => ("iex> ", "...> "),
+        }
+    }
+
+    /// The session attributes of the imported REPL blocks. The prompts of IEx are numbered, but
+    /// not in the doctests.
+    fn session_attrs(self) -> &'static str {
+        match self {
+            Self::Python => "preset=python",
+            Self::Elixir => {
+                r#"cmd="iex" prompt="iex(?:[(][0-9]+[)])?> " continuation_prompt="[.][.][.](?:[(][0-9]+[)])?> ""#
+            }
+        }
+    }
+
+    /// The command in a line, without its indentation and `prompt`, if it starts with the prompt.
+    /// A prompt may be the last thing on a line without its trailing space.
+    fn strip_prompt(line: &str, prompt: &str) -> Option<String> {
+        let line = &line[indentation(line)..];
+        match line.strip_prefix(prompt) {
+            Some(cmd) => Some(cmd.to_string()),
+            None => (line == prompt.trim_end()).then(String::new),
+        }
+    }
+}
+
+/// An example in a doctest: a command and its expected output.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Example {
+    /// The lines of the command, without the prompts.
+    command: Vec<String>,
+
+    /// The lines of the expected output, without their indentation.
+    output: Vec<String>,
+}
+
+/// The length of the indentation of a line, in bytes. Only spaces and tabs are indentation, so a
+/// line can be sliced after the indentation of another line which is at most as long.
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches([' ', '\t']).len()
+}
+
+/// Find the doctests in `source`, like the docstrings of a Python module. Examples which follow
+/// each other with only output or blank lines between them are a group.
+fn parse(source: &str, format: DoctestFormat) -> Vec<Vec<Example>> {
+    let (prompt, continuation) = format.prompts();
+    let mut groups: Vec<Vec<Example>> = Vec::new();
+    // The indentation of the prompt of the last example, while its output is read.
+    let mut indent: Option<usize> = None;
+    // Whether the last line was blank, which ends the output and, before other text, the group.
+    let mut blank = true;
+    let mut lines = source.lines().peekable();
+    while let Some(line) = lines.next() {
+        let line_indent = indentation(line);
+        if let Some(cmd) = DoctestFormat::strip_prompt(line, prompt) {
+            let mut command = vec![cmd];
+            while let Some(cmd) = lines
+                .peek()
+                .and_then(|x| DoctestFormat::strip_prompt(x, continuation))
+            {
+                command.push(cmd);
+                lines.next();
+            }
+            let example = Example {
+                command,
+                output: Vec::new(),
+            };
+            match groups.last_mut() {
+                Some(group) if indent.is_some() || blank => group.push(example),
+                _ => groups.push(vec![example]),
+            }
+            indent = Some(line_indent);
+            blank = false;
+        } else if line.trim().is_empty() {
+            indent = None;
+            blank = true;
+        } else if let Some(indent) = indent.filter(|x| line_indent >= *x && !ends_docstring(line)) {
+            let output = match &line[indent..] {
+                BLANKLINE if format == DoctestFormat::Python => "",
+                output => output,
+            };
+            let example = groups.last_mut().and_then(|x| x.last_mut()).unwrap();
+            example.output.push(output.to_string());
+        } else {
+            // Other text ends the group.
+            if !groups.last().is_some_and(Vec::is_empty) {
+                groups.push(Vec::new());
+            }
+            indent = None;
+            blank = false;
+        }
+    }
+    groups.retain(|x| !x.is_empty());
+    groups
+}
+
+/// Whether a line ends a Python docstring or an Elixir heredoc, and with it the output.
+fn ends_docstring(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("\"\"\"") || line.starts_with("'''")
+}
+
+/// Turn the doctests in `source` into Markdown with a REPL block in the session `session` for
+/// every group of examples.
+pub fn import(source: &str, format: DoctestFormat, session: &str) -> String {
+    let prompts = format.prompts();
+    let mut markdown = String::new();
+    for (i, group) in parse(source, format).iter().enumerate() {
+        if i == 0 {
+            markdown += &format!("```{{.repl-{session} {}}}\n", format.session_attrs());
+        } else {
+            markdown += &format!("\n```{{.repl-{session}}}\n");
+        }
+        for example in group {
+            markdown += &render_example(example, prompts, |x| x);
+        }
+        markdown += "```\n";
+    }
+    markdown
+}
+
+/// Turn the REPL blocks of the sessions in `blocks` into doctests, with a blank line after the
+/// examples of every block.
+///
+/// A `...` hole in the output of a Python example becomes a `...` which matches anything with
+/// the `ELLIPSIS` option, which is enabled for the example. Expected lines which a doctest can't
+/// match in the same way, like other holes, placeholders and lines with annotations, are an
+/// error.
+pub fn export(blocks: &[BlockInfo], format: DoctestFormat) -> anyhow::Result<String> {
+    let prompts = format.prompts();
+    let mut doctest = String::new();
+    for block in blocks {
+        for (command, output) in block.commands.iter().zip(&block.outputs) {
+            let mut example = Example {
+                command: command.lines().map(str::to_string).collect(),
+                output: Vec::new(),
+            };
+            let mut ellipsis = false;
+            for (i, line) in output.iter().enumerate() {
+                let (exported, hole) = export_line(line, format, i == 0).map_err(|e| {
+                    anyhow::anyhow!(
+                        "In code block {}: `{line}` can't be exported, since {e}.",
+                        block.number
+                    )
+                })?;
+                example.output.push(exported);
+                ellipsis |= hole;
+            }
+            if let Some(first) = example.command.first_mut().filter(|_| ellipsis) {
+                *first += "  # doctest: +ELLIPSIS";
+            }
+            doctest += &render_example(&example, prompts, |x| match x {
+                "" if format == DoctestFormat::Python => BLANKLINE,
+                x => x,
+            });
+        }
+        doctest += "\n";
+    }
+    Ok(doctest)
+}
+
+/// An expected line as a line of output in a doctest, and whether it is a `...` hole. `first` is
+/// set for the first line of output, which can't be a hole since it would be read as a
+/// continuation line. Returns why the line can't be exported otherwise, to follow "since".
+fn export_line(line: &str, format: DoctestFormat, first: bool) -> Result<(String, bool), String> {
+    let annotated = !matches!(
+        pattern::parse_annotations(line),
+        Ok((_, annotations)) if annotations == LineAnnotations::default()
+    );
+    match pattern::annotate_line(line) {
+        LineAnnotation::Text if annotated => Err("doctests have no line annotations".to_string()),
+        LineAnnotation::Text => Ok((line.to_string(), false)),
+        LineAnnotation::Escaped => {
+            let escaped = line.trim_start();
+            let text = escaped.strip_prefix('\\').unwrap_or(escaped);
+            match text.starts_with("...") && format == DoctestFormat::Python {
+                true => Err("it would be read as a hole or a continuation line".to_string()),
+                false => Ok((
+                    format!("{}{text}", &line[..line.len() - escaped.len()]),
+                    false,
+                )),
+            }
+        }
+        LineAnnotation::Hole { min: 0, max: None } if format == DoctestFormat::Python => {
+            match first {
+                true => Err("it would be read as a continuation line".to_string()),
+                false => Ok(("...".to_string(), true)),
+            }
+        }
+        LineAnnotation::Hole { .. } | LineAnnotation::UpdateHole { .. } => {
+            Err(format!("{format:?} doctests have no holes like it"))
+        }
+        LineAnnotation::Placeholders { .. } => Err("doctests have no placeholders".to_string()),
+        LineAnnotation::UnorderedStart | LineAnnotation::UnorderedEnd => {
+            Err("doctests have no groups of lines in any order".to_string())
+        }
+        LineAnnotation::Binary { .. } => Err("doctests can't match binary output".to_string()),
+    }
+}
+
+/// The lines of an example with the prompts before the lines of the command, and every line of
+/// output passed through `output_line`.
+fn render_example(
+    example: &Example,
+    (prompt, continuation): (&str, &str),
+    output_line: impl Fn(&str) -> &str,
+) -> String {
+    let mut text = String::new();
+    for (i, line) in example.command.iter().enumerate() {
+        let prompt = if i == 0 { prompt } else { continuation };
+        text += &format!("{prompt}{line}\n");
+    }
+    for line in &example.output {
+        text += output_line(line);
+        text += "\n";
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unicode_indentation_is_not_sliced() {
+        let source = "    >>> print('a')\n\u{3000}\u{3000}a\n";
+        let groups = parse(source, DoctestFormat::Python);
+        assert_eq!(groups[0][0].command, ["print('a')"]);
+        assert!(groups[0][0].output.is_empty());
+    }
+
+    #[test]
+    fn holes_are_exported_with_ellipsis() {
+        assert_eq!(
+            export_line("...", DoctestFormat::Python, false),
+            Ok(("...".to_string(), true))
+        );
+        assert!(export_line("...", DoctestFormat::Python, true).is_err());
+        assert!(export_line("...", DoctestFormat::Elixir, false).is_err());
+        for line in ["???", "...{2}", "{float}", "1  #repl: optional"] {
+            assert!(export_line(line, DoctestFormat::Python, false).is_err());
+        }
+        assert_eq!(
+            export_line("\\{unordered}", DoctestFormat::Python, false),
+            Ok(("{unordered}".to_string(), false))
+        );
+    }
+}
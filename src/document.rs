@@ -0,0 +1,405 @@
+//! Reading and writing documents by converting them to and from pandoc's JSON format, and
+//! editing the source text of documents in place.
+
+use pandoc_ast::{Block, Pandoc};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The pandoc executable.
+const PANDOC: &str = "pandoc";
+
+/// Read a document with pandoc. If `format` is `None`, it is deduced from the file extension.
+pub fn read_document(path: &Path, format: Option<&str>) -> anyhow::Result<Pandoc> {
+    let output = Command::new(PANDOC)
+        .arg(path)
+        .args(format.map(|x| ["--from", x]).into_iter().flatten())
+        .args(["--to", "json"])
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run {PANDOC}: {e}"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{PANDOC} failed to read {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(Pandoc::from_json(std::str::from_utf8(&output.stdout)?))
+}
+
+/// Write a document with pandoc. If `format` is `None`, it is deduced from the file extension.
+pub fn write_document(path: &Path, format: Option<&str>, document: &Pandoc) -> anyhow::Result<()> {
+    let mut child = Command::new(PANDOC)
+        .args(format.map(|x| ["--to", x]).into_iter().flatten())
+        .args(["--from", "json", "--standalone", "--output"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to run {PANDOC}: {e}"))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(document.to_json().as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{PANDOC} failed to write {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// The new contents of a document which is written with [write_documents].
+#[derive(Debug)]
+pub enum NewDocument<'a> {
+    /// Source text which is written as it is.
+    Source(String),
+
+    /// A document which is written with pandoc. If the format is `None`, it is deduced from the
+    /// file extension.
+    Pandoc(Option<&'a str>, Pandoc),
+}
+
+/// Write several documents, so that either all of them are updated or none.
+///
+/// Every document is first written to a temporary file next to it, so nothing is changed if
+/// pandoc fails on any of them. The temporary files then replace the documents one by one, and if
+/// that fails the documents which were already replaced are restored from backups.
+pub fn write_documents(documents: &[(PathBuf, NewDocument)]) -> anyhow::Result<()> {
+    let sibling = |path: &Path, prefix: &str| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        // The file name keeps its extension, from which pandoc deduces the format.
+        path.with_file_name(format!(".{prefix}.{name}"))
+    };
+    let temp_paths: Vec<_> = documents
+        .iter()
+        .map(|(path, ..)| sibling(path, "repl-check-new"))
+        .collect();
+    let backup_paths: Vec<_> = documents
+        .iter()
+        .map(|(path, ..)| sibling(path, "repl-check-backup"))
+        .collect();
+    let remove_all = |paths: &[PathBuf]| {
+        for path in paths {
+            let _ = std::fs::remove_file(path);
+        }
+    };
+    for ((path, document), temp_path) in documents.iter().zip(&temp_paths) {
+        let result = match document {
+            NewDocument::Source(source) => std::fs::write(temp_path, source).map_err(Into::into),
+            NewDocument::Pandoc(format, document) => write_document(temp_path, *format, document),
+        };
+        let result = result.and_then(|()| {
+            if let Ok(metadata) = std::fs::metadata(path) {
+                std::fs::set_permissions(temp_path, metadata.permissions())?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            remove_all(&temp_paths);
+            let e = e.to_string();
+            anyhow::bail!(
+                "In {}: {}\nNo documents were changed.",
+                path.display(),
+                e.trim_end()
+            );
+        }
+    }
+    for (idx, (path, ..)) in documents.iter().enumerate() {
+        if path.exists() {
+            if let Err(e) = std::fs::copy(path, &backup_paths[idx]) {
+                remove_all(&temp_paths);
+                remove_all(&backup_paths[..idx]);
+                anyhow::bail!(
+                    "Failed to back up {}: {e}\nNo documents were changed.",
+                    path.display()
+                );
+            }
+        }
+    }
+    for (idx, (path, ..)) in documents.iter().enumerate() {
+        let Err(e) = std::fs::rename(&temp_paths[idx], path) else {
+            continue;
+        };
+        let mut message = format!("Failed to replace {}: {e}", path.display());
+        let mut restored = true;
+        for ((path, ..), backup_path) in documents[..idx].iter().zip(&backup_paths) {
+            let result = match backup_path.exists() {
+                true => std::fs::rename(backup_path, path),
+                // The document didn't exist before.
+                false => std::fs::remove_file(path),
+            };
+            if let Err(e) = result {
+                message += &format!(
+                    "\nFailed to restore {} from {}: {e}",
+                    path.display(),
+                    backup_path.display()
+                );
+                restored = false;
+            }
+        }
+        remove_all(&temp_paths[idx..]);
+        remove_all(&backup_paths[idx..]);
+        if restored {
+            message += "\nNo documents were changed.";
+        }
+        anyhow::bail!(message);
+    }
+    remove_all(&backup_paths);
+    Ok(())
+}
+
+/// The directory where the updated documents are written with `--pending`, to be reviewed and
+/// accepted later.
+pub const PENDING_DIR: &str = ".repl-check/pending";
+
+/// Where the pending update of a document is written, at the same path in [PENDING_DIR], so it
+/// keeps its extension from which pandoc deduces the format. Only the normal components of the
+/// path are kept, so an absolute path or `..` doesn't lead out of the directory.
+pub fn pending_path(path: &Path) -> PathBuf {
+    let relative: PathBuf = path
+        .components()
+        .filter(|x| matches!(x, Component::Normal(_)))
+        .collect();
+    Path::new(PENDING_DIR).join(relative)
+}
+
+/// Replace documents with their pending updates in [PENDING_DIR], if they have any. Returns the
+/// documents which were replaced.
+pub fn accept_pending<'a>(paths: &'a [PathBuf]) -> anyhow::Result<Vec<&'a Path>> {
+    let mut accepted = Vec::new();
+    for path in paths {
+        let pending = pending_path(path);
+        if !pending.exists() {
+            continue;
+        }
+        std::fs::rename(&pending, path).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to replace {} with {}: {e}",
+                path.display(),
+                pending.display()
+            )
+        })?;
+        accepted.push(path.as_path());
+    }
+    Ok(accepted)
+}
+
+/// An edit replacing a byte range in the source text of a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentEdit {
+    /// The byte range in the source which is replaced.
+    pub range: Range<usize>,
+
+    /// The new text for the range.
+    pub replacement: String,
+}
+
+/// Apply a set of non-overlapping edits to the source text of a document.
+pub fn apply_edits(source: &str, edits: &[DocumentEdit]) -> anyhow::Result<String> {
+    let mut edits: Vec<&DocumentEdit> = edits.iter().collect();
+    edits.sort_by_key(|x| (x.range.start, x.range.end));
+    let mut result = String::with_capacity(source.len());
+    let mut pos = 0;
+    for edit in edits {
+        let Range { start, end } = edit.range;
+        if start < pos {
+            anyhow::bail!("Overlapping edits at byte {start}.");
+        }
+        if end > source.len() || !source.is_char_boundary(start) || !source.is_char_boundary(end) {
+            anyhow::bail!("Bad range for edit: {start}..{end}");
+        }
+        result.push_str(&source[pos..start]);
+        result.push_str(&edit.replacement);
+        pos = end;
+    }
+    result.push_str(&source[pos..]);
+    Ok(result)
+}
+
+/// The location of the content of a code block in the source text of a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlockLocation {
+    /// The index of the code block in [Pandoc::blocks].
+    pub block_idx: usize,
+
+    /// The byte range of the code in the source, not including the fences.
+    pub range: Range<usize>,
+}
+
+/// Find the code of all code blocks in the source text of a document, see [code_block_ranges].
+pub fn locate_code_blocks(
+    source: &str,
+    document: &Pandoc,
+) -> anyhow::Result<Vec<CodeBlockLocation>> {
+    let blocks: Vec<(usize, &str)> = document
+        .blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(block_idx, block)| match block {
+            Block::CodeBlock(_, code) => Some((block_idx, code.as_str())),
+            _ => None,
+        })
+        .collect();
+    let codes: Vec<&str> = blocks.iter().map(|(_, code)| *code).collect();
+    blocks
+        .iter()
+        .zip(code_block_ranges(source, &codes))
+        .map(|(&(block_idx, code), range)| {
+            let range = range.ok_or_else(|| {
+                anyhow::anyhow!("Could not find code block {block_idx} in the source:\n{code}")
+            })?;
+            Ok(CodeBlockLocation { block_idx, range })
+        })
+        .collect()
+}
+
+/// Find the byte range of the code of each block in the source text of a document.
+///
+/// The code is searched for in document order, and it is only found between an opening fence
+/// line and a closing fence line, as fenced code blocks in Markdown are written. Blocks which are
+/// not found, for example because they are indented in a list or in a block quote, get `None`.
+pub fn code_block_ranges(source: &str, codes: &[&str]) -> Vec<Option<Range<usize>>> {
+    let mut pos = 0;
+    codes
+        .iter()
+        .map(|code| {
+            let (range, next) = find_fenced_code(source, pos, code)?;
+            pos = next;
+            Some(range)
+        })
+        .collect()
+}
+
+/// Find the line (1-based) where the code of each block starts in the source text of a document,
+/// for showing where blocks are, see [code_block_ranges].
+pub fn code_block_lines(source: &str, codes: &[&str]) -> Vec<Option<usize>> {
+    code_block_ranges(source, codes)
+        .into_iter()
+        .map(|range| Some(source[..range?.start].matches('\n').count() + 1))
+        .collect()
+}
+
+/// The kind of fence, "```" or "~~~", if the text starts with a code fence.
+fn fence(text: &str) -> Option<&'static str> {
+    let text = text.trim_start_matches(' ');
+    ["```", "~~~"].into_iter().find(|x| text.starts_with(x))
+}
+
+/// Find `code` as the content of a fenced code block in `source[pos..]`, where `pos` is at the
+/// beginning of a line. That is the code must follow an opening fence line and be followed by a
+/// closing fence of the same kind on the next line.
+///
+/// Returns the byte range of the code and the position of the line after the closing fence.
+fn find_fenced_code(source: &str, pos: usize, code: &str) -> Option<(Range<usize>, usize)> {
+    let line_end = |start: usize| source[start..].find('\n').map(|x| start + x);
+    let mut line_start = pos;
+    while line_start < source.len() {
+        let end_of_line = line_end(line_start);
+        let line = &source[line_start..end_of_line.unwrap_or(source.len())];
+        if let (Some(kind), Some(end_of_line)) = (fence(line), end_of_line) {
+            let start = end_of_line + 1;
+            let end = start + code.len();
+            // The closing fence, an empty code block has no line of its own.
+            let closing = match code.is_empty() {
+                true => Some(start),
+                false => source[start..]
+                    .starts_with(code)
+                    .then_some(end + 1)
+                    .filter(|_| source[end..].starts_with('\n')),
+            };
+            if let Some(closing) = closing.filter(|x| fence(&source[*x..]) == Some(kind)) {
+                let next = line_end(closing).map_or(source.len(), |x| x + 1);
+                return Some((start..end, next));
+            }
+        }
+        line_start = end_of_line.map_or(source.len(), |x| x + 1);
+    }
+    None
+}
+
+/// Compute the edits which turn the code blocks of `original` into those of `updated`, given the
+/// source text of `original`. The documents must have the same block structure.
+pub fn code_block_edits(
+    source: &str,
+    original: &Pandoc,
+    updated: &Pandoc,
+) -> anyhow::Result<Vec<DocumentEdit>> {
+    let mut edits = Vec::new();
+    for CodeBlockLocation { block_idx, range } in locate_code_blocks(source, original)? {
+        let (Block::CodeBlock(_, old_code), Some(Block::CodeBlock(_, new_code))) =
+            (&original.blocks[block_idx], updated.blocks.get(block_idx))
+        else {
+            anyhow::bail!("Block {block_idx} is not a code block in the updated document.");
+        };
+        if old_code == new_code {
+            continue;
+        }
+        edits.push(code_edit(source, range, old_code, new_code));
+    }
+    Ok(edits)
+}
+
+/// The edit which replaces the code `old_code` of a block at `range` in the source with
+/// `new_code`.
+pub fn code_edit(
+    source: &str,
+    range: Range<usize>,
+    old_code: &str,
+    new_code: &str,
+) -> DocumentEdit {
+    // An empty code block has no line of its own in the source.
+    match (old_code.is_empty(), new_code.is_empty()) {
+        (true, false) => DocumentEdit {
+            range,
+            replacement: format!("{new_code}\n"),
+        },
+        (false, true) if source[range.end..].starts_with('\n') => DocumentEdit {
+            range: range.start..range.end + 1,
+            replacement: String::new(),
+        },
+        _ => DocumentEdit {
+            range,
+            replacement: new_code.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_is_only_found_between_fences() {
+        let source = "ls is a command.\n\n```{.repl-sh}\nls\n```\n\n```\necho\n```\n";
+        let ranges = code_block_ranges(source, &["ls", "echo"]);
+        assert_eq!(ranges, [Some(32..34), Some(44..48)]);
+    }
+
+    #[test]
+    fn code_must_be_followed_by_the_closing_fence() {
+        let source = "```\nls -l\n```\n~~~\nls\n~~~\n";
+        assert_eq!(code_block_ranges(source, &["ls"]), [Some(18..20)]);
+        assert_eq!(code_block_ranges("```\nls\n~~~\n", &["ls"]), [None]);
+    }
+
+    #[test]
+    fn empty_code_blocks() {
+        let source = "```\n```\n\n```\nx\n```\n";
+        assert_eq!(
+            code_block_ranges(source, &["", "x"]),
+            [Some(4..4), Some(13..14)]
+        );
+    }
+
+    #[test]
+    fn missing_blocks_dont_move_the_search() {
+        let source = "- ```\n  a\n  ```\n\n```\nb\n```\n";
+        assert_eq!(code_block_ranges(source, &["a", "b"]), [None, Some(21..22)]);
+    }
+}
@@ -0,0 +1,125 @@
+//! Running the REPL blocks in documents as tests with `cargo test`, see [harness].
+
+use crate::config::{expand_inputs, Config};
+use crate::document::read_document;
+use crate::report::BlockStatus;
+use crate::{check_document, iter_code_blocks, session_defaults, validate_document};
+use crate::{CheckResult, Options};
+use libtest_mimic::{Arguments, Failed, Trial};
+use pandoc_ast::Pandoc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+/// A document which is checked once, by the first test of one of its blocks which is run.
+struct DocumentCheck {
+    document: Pandoc,
+    options: Options,
+    result: OnceLock<Result<CheckResult, String>>,
+}
+
+impl DocumentCheck {
+    /// The outcome of the test for the block with this number.
+    fn block_outcome(&self, number: usize) -> Result<(), Failed> {
+        let result = self.result.get_or_init(|| {
+            check_document(&self.document, &self.options).map_err(|e| e.to_string())
+        });
+        let result = result.as_ref().map_err(Failed::from)?;
+        let status = result
+            .blocks
+            .iter()
+            .find(|x| x.number == number)
+            .map(|x| x.status);
+        match status {
+            Some(BlockStatus::Passed | BlockStatus::Cached) => Ok(()),
+            Some(BlockStatus::Failed) => {
+                let failure = result.failures.iter().find(|x| x.block == number);
+                Err(failure
+                    .map_or("Failed".into(), |x| x.error.to_string())
+                    .into())
+            }
+            _ => Err("Not run, since an earlier block in the session failed.".into()),
+        }
+    }
+}
+
+/// The tests for a document, or a single failing test if it can't be read.
+fn document_trials(path: &Path, config: &Config) -> Vec<Trial> {
+    let name = path.display().to_string();
+    let check = (|| {
+        let options = config.document_options(path, &config.options())?;
+        let format = config
+            .input_settings(path)
+            .and_then(|x| x.format.as_deref());
+        let document = read_document(path, format)?;
+        validate_document(&document, &options)?;
+        anyhow::Ok(DocumentCheck {
+            document,
+            options,
+            result: OnceLock::new(),
+        })
+    })();
+    let check = match check {
+        Ok(check) => Arc::new(check),
+        Err(e) => {
+            let message = e.to_string();
+            return vec![Trial::test(name, move || Err(message.into()))];
+        }
+    };
+    // There are no errors in the metadata after the validation.
+    let session_defaults = session_defaults(&check.document).unwrap();
+    // Alternatives have no tests of their own, since they pass with the blocks they belong to.
+    iter_code_blocks(&check.document, &session_defaults)
+        .filter(|block| block.attr("alt_of").is_none())
+        .map(|block| {
+            let number = block.idx + 1;
+            let enabled = block.is_enabled(&check.options);
+            let check = check.clone();
+            Trial::test(
+                format!("{name}::{}::block_{number}", block.session_name),
+                move || match enabled {
+                    true => check.block_outcome(number),
+                    false => Ok(()),
+                },
+            )
+            .with_ignored_flag(!enabled)
+        })
+        .collect()
+}
+
+/// Run the REPL blocks in documents as tests and exit, for a test target with `harness = false`.
+///
+/// Every block is a test named `<document>::<session>::block_<number>`, so `cargo test` reports
+/// the result of every block and the usual arguments like a name filter and `--test-threads`
+/// work. A document is checked by the first of its tests which is run, and blocks which are
+/// disabled by `if_feature` are ignored. The paths are documents, directories or glob patterns,
+/// and default to the `[inputs]` in the configuration file.
+///
+/// The `main` function of a test target like `tests/docs.rs` is just:
+///
+/// ```no_run
+/// repl_check::harness(&["docs"]);
+/// ```
+pub fn harness(paths: &[impl AsRef<Path>]) -> ! {
+    let args = Arguments::from_args();
+    let trials = (|| {
+        let config = Config::load_default()?;
+        let paths: Vec<PathBuf> = paths.iter().map(|x| x.as_ref().to_path_buf()).collect();
+        let files = match paths.is_empty() {
+            true => config.input_files()?,
+            false => expand_inputs(&paths)?,
+        };
+        anyhow::Ok(
+            files
+                .iter()
+                .flat_map(|path| document_trials(path, &config))
+                .collect(),
+        )
+    })();
+    match trials {
+        Ok(trials) => libtest_mimic::run(&args, trials).exit(),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(101);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! A record of when every REPL block last passed, to find blocks which have not been executed
+//! for a long time, like blocks behind a feature which is rarely enabled.
+//!
+//! Blocks are identified by the path of the document and a hash of their code, so a block which
+//! is changed counts as a new block which has never passed.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// The file where the history is stored, relative to the current directory.
+pub const HISTORY_FILE_NAME: &str = ".repl-check/history.json";
+
+/// When every block last passed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct History {
+    /// For every document, a map from the hash of the code of a block to the time it last passed
+    /// in seconds since the Unix epoch.
+    documents: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+/// A hash of the code of a block which is stable across versions (64 bit FNV-1a).
+pub fn hash_code(code: &str) -> String {
+    let hash = code.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+impl History {
+    /// Load the history from a file, or an empty history if the file doesn't exist.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {e}", path.display()))?;
+        serde_json::from_str(&content).map_err(|e| anyhow::anyhow!("In {}: {e}", path.display()))
+    }
+
+    /// Save the history to a file, creating its directory if needed.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {e}", path.display()))
+    }
+
+    /// Record that a block in a document passed at a point in time.
+    pub fn record_passed(&mut self, document: &Path, code: &str, time: SystemTime) {
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.documents
+            .entry(document.to_string_lossy().into_owned())
+            .or_default()
+            .insert(hash_code(code), secs);
+    }
+
+    /// When a block in a document last passed, or `None` if it never has.
+    pub fn last_passed(&self, document: &Path, code: &str) -> Option<SystemTime> {
+        self.documents
+            .get(document.to_string_lossy().as_ref())?
+            .get(&hash_code(code))
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(*secs))
+    }
+}
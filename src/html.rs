@@ -0,0 +1,304 @@
+//! A static HTML report of a run, which can be published from CI.
+//!
+//! The report is a single `index.html` with a section for every document, which shows whether it
+//! passed and can be expanded to its failures, its blocks with diffs of the output of those which
+//! didn't match, and the transcripts of its sessions. Next to it, `badge.svg` shows how many of
+//! the blocks are verified, like `docs: 98% verified`, to put in a README.
+
+use crate::report::{BlockStatus, DocumentReport, Report};
+use std::fmt::Write;
+use std::path::Path;
+
+/// The file name of the HTML report in the report directory.
+pub const INDEX_FILE_NAME: &str = "index.html";
+
+/// The file name of the badge in the report directory.
+pub const BADGE_FILE_NAME: &str = "badge.svg";
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #24292f; }
+pre { background: #f6f8fa; padding: 0.5em; overflow-x: auto; }
+summary { cursor: pointer; padding: 0.2em 0; }
+.document { border-left: 4px solid #4c1; padding-left: 0.8em; margin: 0.8em 0; }
+.document.failed { border-left-color: #e05d44; }
+.passed, .cached { color: #2c7a00; }
+.failed { color: #c0392b; }
+.not-run { color: #6a737d; }
+.block, .diff, .failure, .transcript { margin-left: 1em; }
+.added { color: #2c7a00; }
+.removed { color: #c0392b; }
+";
+
+/// Escape text for HTML and SVG.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The percentage of the blocks which passed or are cached, rounded down so a run with failures
+/// never shows 100%. A document which failed before its blocks were known counts as one block
+/// which is not verified. It is 100 if there are no blocks.
+pub fn verified_percent(report: &Report) -> u64 {
+    let stats = report.stats();
+    let unknown = report
+        .documents
+        .iter()
+        .filter(|x| x.failed() && x.blocks.is_empty())
+        .count();
+    match stats.blocks + unknown {
+        0 => 100,
+        n => ((stats.blocks_passed + stats.blocks_cached) * 100 / n) as u64,
+    }
+}
+
+/// An SVG badge like `docs: 98% verified`, green if all blocks are verified, yellow above 80%
+/// and red otherwise.
+pub fn badge(report: &Report) -> String {
+    let percent = verified_percent(report);
+    let color = match percent {
+        100 => "#4c1",
+        80.. => "#dfb317",
+        _ => "#e05d44",
+    };
+    let label = "docs";
+    let message = format!("{percent}% verified");
+    // The text is measured roughly, with a width of 7 pixels per character and a margin of 5
+    // pixels on both sides.
+    let width = |text: &str| text.chars().count() * 7 + 10;
+    let (label_width, message_width) = (width(label), width(&message));
+    let total = label_width + message_width;
+    let (label_x, message_x) = (label_width / 2, label_width + message_width / 2);
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{total}\" height=\"20\" role=\"img\" \
+         aria-label=\"{label}: {message}\">\n\
+         <title>{label}: {message}</title>\n\
+         <rect width=\"{label_width}\" height=\"20\" fill=\"#555\"/>\n\
+         <rect x=\"{label_width}\" width=\"{message_width}\" height=\"20\" fill=\"{color}\"/>\n\
+         <g fill=\"#fff\" text-anchor=\"middle\" font-family=\"Verdana,DejaVu Sans,sans-serif\" \
+         font-size=\"11\">\n\
+         <text x=\"{label_x}\" y=\"14\">{label}</text>\n\
+         <text x=\"{message_x}\" y=\"14\">{message}</text>\n\
+         </g>\n\
+         </svg>\n"
+    )
+}
+
+/// A text in a `<pre>` element.
+fn pre(html: &mut String, text: &str) {
+    let _ = writeln!(html, "<pre>{}</pre>", escape(text.trim_end_matches('\n')));
+}
+
+/// A diff from [crate::diff::format_diff] in a `<pre>` element, with the removed and added lines
+/// colored.
+fn pre_diff(html: &mut String, diff: &str) {
+    html.push_str("<pre>");
+    for line in diff.lines() {
+        match line.chars().next() {
+            Some('-') => {
+                let _ = writeln!(html, "<span class=\"removed\">{}</span>", escape(line));
+            }
+            Some('+') => {
+                let _ = writeln!(html, "<span class=\"added\">{}</span>", escape(line));
+            }
+            _ => {
+                let _ = writeln!(html, "{}", escape(line));
+            }
+        }
+    }
+    html.push_str("</pre>\n");
+}
+
+/// The CSS class and the text of the status of a block.
+fn block_status(status: BlockStatus) -> (&'static str, &'static str) {
+    match status {
+        BlockStatus::Passed => ("passed", "passed"),
+        BlockStatus::Failed => ("failed", "failed"),
+        BlockStatus::NotRun => ("not-run", "not run"),
+        BlockStatus::Cached => ("cached", "cached"),
+    }
+}
+
+/// Render the section of a document, which is expanded if the document failed.
+fn render_document(html: &mut String, document: &DocumentReport) {
+    let failed = document.failed();
+    let (class, open) = match failed {
+        true => ("failed", " open"),
+        false => ("passed", ""),
+    };
+    let blocks = document.blocks.len();
+    let failed_blocks = document
+        .blocks
+        .iter()
+        .filter(|x| x.status == BlockStatus::Failed)
+        .count();
+    let _ = writeln!(
+        html,
+        "<details class=\"document {class}\"{open}>\n<summary><strong class=\"{class}\">{class}\
+         </strong> {} &mdash; {blocks} blocks, {failed_blocks} failed</summary>",
+        escape(&document.path.display().to_string())
+    );
+    if let Some(error) = &document.error {
+        html.push_str("<h3>Error</h3>\n");
+        pre(html, error);
+    }
+    if !document.failures.is_empty() {
+        html.push_str("<h3>Failures</h3>\n");
+        for failure in &document.failures {
+            let (first, rest) = failure.split_once('\n').unwrap_or((failure, ""));
+            let _ = writeln!(
+                html,
+                "<details class=\"failure\" open>\n<summary>{}</summary>",
+                escape(first)
+            );
+            if !rest.is_empty() {
+                pre(html, rest);
+            }
+            html.push_str("</details>\n");
+        }
+    }
+    if !document.stale.is_empty() {
+        html.push_str("<h3>Stale blocks</h3>\n<ul>\n");
+        for stale in &document.stale {
+            let _ = writeln!(html, "<li>{}</li>", escape(stale));
+        }
+        html.push_str("</ul>\n");
+    }
+    if !document.sessions.is_empty() {
+        html.push_str("<h3>Sessions</h3>\n<ul>\n");
+        for session in &document.sessions {
+            let class = session.status.to_string().replace(' ', "-");
+            let _ = write!(
+                html,
+                "<li>{}: <span class=\"{class}\">{}</span>",
+                escape(&session.name),
+                session.status
+            );
+            match session.retries {
+                0 => {}
+                1 => html.push_str(" after 1 retry"),
+                n => {
+                    let _ = write!(html, " after {n} retries");
+                }
+            }
+            if let Some(error) = &session.hook_error {
+                pre(html, error);
+            }
+            html.push_str("</li>\n");
+        }
+        html.push_str("</ul>\n");
+    }
+    if !document.blocks.is_empty() {
+        html.push_str("<h3>Blocks</h3>\n");
+        for block in &document.blocks {
+            let (class, status) = block_status(block.status);
+            // Blocks with a diff are expanded to show it.
+            let open = if block.diff.is_some() { " open" } else { "" };
+            let _ = write!(
+                html,
+                "<details class=\"block\"{open}>\n<summary>Code block {} in session {}: \
+                 <span class=\"{class}\">{status}</span>",
+                block.number,
+                escape(&block.session)
+            );
+            if let Some(alternative) = block.alternative {
+                let _ = write!(html, " with the alternative in code block {alternative}");
+            }
+            if let Some(duration) = block.duration {
+                let _ = write!(html, " ({:.3} s)", duration.as_secs_f64());
+            }
+            html.push_str("</summary>\n");
+            pre(html, &block.code);
+            if let Some(diff) = &block.diff {
+                html.push_str(
+                    "<details class=\"diff\" open>\n<summary>Expected and actual output</summary>\n",
+                );
+                pre_diff(html, diff);
+                html.push_str("</details>\n");
+            }
+            html.push_str("</details>\n");
+        }
+    }
+    let transcripts: Vec<_> = document
+        .sessions
+        .iter()
+        .filter_map(|x| Some((&x.name, x.transcript.as_ref()?)))
+        .collect();
+    if !transcripts.is_empty() {
+        html.push_str("<h3>Transcripts</h3>\n");
+        for (name, transcript) in transcripts {
+            let _ = writeln!(
+                html,
+                "<details class=\"transcript\">\n<summary>Session {}</summary>",
+                escape(name)
+            );
+            pre(html, transcript);
+            html.push_str("</details>\n");
+        }
+    }
+    html.push_str("</details>\n");
+}
+
+/// Render the report as an HTML page, which shows [BADGE_FILE_NAME] at the top.
+pub fn render(report: &Report) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>repl-check report</title>\n<style>\n{STYLE}</style>\n</head>\n<body>\n\
+         <h1>repl-check report <img src=\"{BADGE_FILE_NAME}\" alt=\"docs: {}% verified\"></h1>\n\
+         <p>{}</p>\n",
+        verified_percent(report),
+        escape(&report.summary_line())
+    );
+    for document in &report.documents {
+        render_document(&mut html, document);
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Write [INDEX_FILE_NAME] and [BADGE_FILE_NAME] to a directory, which is created if it doesn't
+/// exist.
+pub fn write_report(report: &Report, dir: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)
+        .map_err(|e| anyhow::anyhow!("Failed to create {}: {e}", dir.display()))?;
+    for (name, content) in [
+        (INDEX_FILE_NAME, render(report)),
+        (BADGE_FILE_NAME, badge(report)),
+    ] {
+        let path = dir.join(name);
+        std::fs::write(&path, content)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {e}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_which_failed_are_not_verified() {
+        let report = Report {
+            documents: vec![DocumentReport {
+                error: Some("The REPL could not be started.".to_string()),
+                ..DocumentReport::default()
+            }],
+            ..Report::default()
+        };
+        assert_eq!(verified_percent(&report), 0);
+        assert!(badge(&report).contains("docs: 0% verified"));
+        assert_eq!(verified_percent(&Report::default()), 100);
+    }
+
+    #[test]
+    fn diffs_are_escaped_and_colored() {
+        let mut html = String::new();
+        pre_diff(&mut html, " a\n-<b>\n+c\n");
+        assert_eq!(
+            html,
+            "<pre> a\n<span class=\"removed\">-&lt;b&gt;</span>\n\
+             <span class=\"added\">+c</span>\n</pre>\n"
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Prompts of pagers and confirmations, like `--More--` or `[y/N]`, which make a command in a
+//! session wait for input that never comes. They are looked for together with the prompt of the
+//! REPL, and are either answered with a key, like `q` to quit a pager, or reported right away
+//! instead of waiting for the timeout. More can be defined with `[[interactive_prompts]]` in the
+//! configuration file.
+
+use regex::Regex;
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// A prompt of a program which waits for input while a command runs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InteractivePrompt {
+    /// A regex for the prompt, which is only recognized as the last thing printed, apart from
+    /// spaces and terminal escape codes.
+    pub regex: Cow<'static, str>,
+
+    /// The keys which are sent to answer the prompt, without a newline. If not set, the block
+    /// fails when the prompt is printed.
+    #[serde(default)]
+    pub respond: Option<Cow<'static, str>>,
+}
+
+const fn prompt(regex: &'static str, respond: Option<&'static str>) -> InteractivePrompt {
+    InteractivePrompt {
+        regex: Cow::Borrowed(regex),
+        respond: match respond {
+            Some(x) => Some(Cow::Borrowed(x)),
+            None => None,
+        },
+    }
+}
+
+/// The built-in interactive prompts. Pagers are quit, and confirmations fail since the right
+/// answer is not known.
+static BUILTIN_PROMPTS: &[InteractivePrompt] = &[
+    prompt(r"--More--(?:\(\d+%\))?", Some("q")),
+    prompt(r"\(END\)", Some("q")),
+    prompt(r"\[[yY]/[nN]\]|\([yY]/[nN]\)", None),
+];
+
+/// Spaces and terminal escape codes, which may come after an interactive prompt.
+const TRAILER: &str = r"(?:\x1b\[[0-9;?]*[A-Za-z]|[ \t\r])*\z";
+
+/// The interactive prompts in `configured` followed by the built-in ones.
+pub fn all_prompts(configured: &[InteractivePrompt]) -> impl Iterator<Item = &InteractivePrompt> {
+    configured.iter().chain(BUILTIN_PROMPTS)
+}
+
+/// A regex which matches either `prompt` or one of `prompts` at the end of the output.
+pub fn combined_regex<'a>(
+    prompt: &Regex,
+    prompts: impl IntoIterator<Item = &'a InteractivePrompt>,
+) -> anyhow::Result<Regex> {
+    let mut regex = format!("(?:{prompt})");
+    for x in prompts {
+        Regex::new(&x.regex)
+            .map_err(|e| anyhow::anyhow!("Bad regex in interactive_prompts: {}: {e}", x.regex))?;
+        regex += &format!("|(?:{}){TRAILER}", x.regex);
+    }
+    Ok(Regex::new(&regex)?)
+}
+
+/// The interactive prompt in `prompts` which the text matched by a [combined_regex] is, or
+/// `None` if it is the prompt of the REPL.
+pub fn find<'a>(
+    prompt: &Regex,
+    prompts: impl IntoIterator<Item = &'a InteractivePrompt>,
+    text: &str,
+) -> Option<&'a InteractivePrompt> {
+    if prompt.is_match(text) {
+        return None;
+    }
+    prompts
+        .into_iter()
+        .find(|x| Regex::new(&format!("^(?:{}){TRAILER}", x.regex)).is_ok_and(|x| x.is_match(text)))
+}
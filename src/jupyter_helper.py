@@ -0,0 +1,83 @@
+# Runs a Jupyter kernel for repl-check and executes code sent as JSON lines on stdin.
+#
+# Usage: python3 -c <this script> KERNEL_NAME TIMEOUT_SECONDS
+#
+# Every request is a JSON object on one line, either {"code": "..."} or {"shutdown": true}.
+# Every response is a JSON object on one line:
+# - {"ready": true} when the kernel has started,
+# - {"output": [[kind, text], ...], "execution_count": n} after executing code, where kind is
+#   stdout, stderr, result, display or error,
+# - {"exit": true} if the kernel is dead,
+# - {"error": "..."} on failure.
+
+import json
+import queue
+import sys
+
+
+def respond(**response):
+    sys.stdout.write(json.dumps(response) + "\n")
+    sys.stdout.flush()
+
+
+def execute(client, code, timeout):
+    msg_id = client.execute(code, store_history=True, allow_stdin=False)
+    output = []
+    execution_count = None
+    while True:
+        msg = client.get_iopub_msg(timeout=timeout)
+        if msg["parent_header"].get("msg_id") != msg_id:
+            continue
+        kind, content = msg["msg_type"], msg["content"]
+        if kind == "stream":
+            output.append([content["name"], content["text"]])
+        elif kind in ("execute_result", "display_data"):
+            text = content["data"].get("text/plain")
+            if text is not None:
+                output.append(["result" if kind == "execute_result" else "display", text + "\n"])
+            execution_count = content.get("execution_count", execution_count)
+        elif kind == "error":
+            output.append(["error", "%s: %s\n" % (content["ename"], content["evalue"])])
+        elif kind == "execute_input":
+            execution_count = content.get("execution_count")
+        elif kind == "status" and content["execution_state"] == "idle":
+            break
+    return output, execution_count
+
+
+def main():
+    kernel_name, timeout = sys.argv[1], float(sys.argv[2])
+    try:
+        import jupyter_client.manager
+    except ImportError as e:
+        respond(error="The jupyter_client package is required: %s" % e)
+        return
+    try:
+        manager, client = jupyter_client.manager.start_new_kernel(kernel_name=kernel_name)
+    except Exception as e:
+        respond(error="Failed to start the kernel %s: %s" % (kernel_name, e))
+        return
+    respond(ready=True)
+    try:
+        for line in sys.stdin:
+            request = json.loads(line)
+            if request.get("shutdown"):
+                break
+            if not manager.is_alive():
+                respond(exit=True)
+                continue
+            try:
+                output, execution_count = execute(client, request["code"], timeout)
+            except queue.Empty:
+                respond(error="Timeout after %s seconds" % timeout)
+                continue
+            if not manager.is_alive():
+                respond(exit=True, output=output)
+            else:
+                respond(output=output, execution_count=execution_count)
+    finally:
+        client.stop_channels()
+        manager.shutdown_kernel(now=True)
+
+
+main()
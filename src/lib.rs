@@ -1,251 +1,4989 @@
 #![allow(unused)]
 
+pub mod backend;
+pub mod cache;
 mod common;
+pub mod config;
+mod debug;
+pub mod diff;
+pub mod doctest;
+pub mod document;
+#[cfg(feature = "harness")]
+mod harness;
+pub mod history;
+pub mod html;
+pub mod interactive;
+pub mod lsp;
+mod machine;
 mod pattern;
-use common::LinesCow;
-use pandoc_ast::{Block, Pandoc};
+pub mod plugin;
+pub mod preset;
+pub mod progress;
+mod pty;
+pub mod reader;
+pub mod report;
+#[cfg(feature = "async")]
+pub mod runtime;
+#[cfg(feature = "vt100")]
+mod screen;
+pub mod substitute;
+mod suggest;
+pub mod testing;
+mod transcript;
+pub mod watch;
+#[cfg(feature = "harness")]
+pub use harness::harness;
+pub use pattern::Tokens;
+
+use backend::{
+    BackendKind, DefaultBackend, OnLine, ReplBackend, ReplMode, SpawnOptions, TerminalSettings,
+    TimeoutError,
+};
+use cache::Cache;
+use common::{closest_name, EditOrigin, LineEditor};
+use interactive::InteractivePrompt;
+use machine::MachineValues;
+use pandoc_ast::{Block, Inline, Pandoc};
+use pattern::{Comparison, LineAnnotation, MatchedSegment};
+use preset::{builtin_preset, builtin_preset_names, Preset};
+use progress::{NoProgress, Progress, SessionProgress};
+use reader::Cancel;
 use regex::Regex;
+use report::{
+    BlockFailure, BlockReport, BlockStatus, BlockUpdate, CommandTiming, FailureKind, ResourceUsage,
+    ScreenError, SessionReport, SessionStatus,
+};
+use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::hash_map::HashMap;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::iter;
+use std::ops::Range;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use substitute::{parse_substitutions, Substitution};
+use transcript::TranscriptBackend;
 
 const TIMEOUT_MS: u64 = 10000;
+const MAX_OUTPUT_BYTES: usize = 64 << 20;
 const DEFAULT_PROMPT_CHAR: &str = ":";
+const DEFAULT_PTY_COLS: u16 = 80;
+const DEFAULT_PTY_ROWS: u16 = 24;
+const DEFAULT_CONTAINER_RUNTIME: &str = "docker";
+const DEFAULT_JUPYTER_KERNEL: &str = "python3";
+const DEFAULT_JUPYTER_PYTHON: &str = "python3";
+const DEFAULT_PROMPT_IDLE_MS: u64 = 500;
+
+/// Attributes which can only be set on the first block of a session.
+const SESSION_ATTRS: &[&str] = &[
+    "cmd",
+    "cmd_args",
+    "cmd_windows",
+    "mode",
+    "clean_env",
+    "pty_cols",
+    "pty_rows",
+    "container",
+    "container_runtime",
+    "ssh",
+    "initial_skip",
+    "skip_banner",
+    "require_version",
+    "version_cmd",
+    "backend",
+    "kernel",
+    "jupyter_streams",
+    "scope",
+    "echo",
+    "prompt_idle_ms",
+    "preset",
+    "continuation_prompt",
+    "quit",
+    "reset_cmd",
+    "normalize_prompt",
+    "ignore_lines",
+    "substitute",
+    "whitespace",
+    "case",
+    "unicode_normalize",
+    "line_annotations",
+    "separate_stderr",
+    "max_output_bytes",
+    "encoding",
+    "locale",
+    "send_delay_ms",
+    "bracketed_paste",
+    "setup_cmd",
+    "teardown_cmd",
+    "sandbox",
+];
+
+/// Attributes which can be set on any block of a session.
+const BLOCK_ATTRS: &[&str] = &[
+    "prompt",
+    "prompt_char",
+    "if_feature",
+    "filter",
+    "matcher",
+    "match",
+    "on_mismatch",
+    "output",
+    "expect_eof",
+    "max_duration",
+    "retries",
+    "after",
+    "needs",
+    "alt_of",
+    "assert_prompt",
+];
+
+/// Options for checking a document.
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Enabled features. Blocks with an `if_feature` attribute are skipped unless that feature is
+    /// enabled.
+    pub features: HashSet<String>,
+
+    /// Default values for attributes which are not set on a REPL block. Session attributes like
+    /// `cmd` and `prompt` are only taken from here at the beginning of a session.
+    pub default_attrs: HashMap<String, String>,
+
+    /// No new sessions are started after this point in time, they are reported as
+    /// [SessionStatus::NotRun] instead.
+    pub deadline: Option<Instant>,
+
+    /// The directory of the document, which is mounted in containers.
+    pub document_dir: Option<PathBuf>,
+
+    /// Instead of failing on mismatching output, apply the first suggested fix to the block.
+    pub fix_suggestions: bool,
+
+    /// The timeout when waiting for a prompt. Defaults to 10 seconds.
+    pub timeout: Option<Duration>,
+
+    /// The longest time without any new output when waiting for a prompt, for commands which
+    /// run for long but keep printing, with a large [Options::timeout].
+    pub idle_timeout: Option<Duration>,
+
+    /// Default prompt regexes for blocks with a class, used if the block has no `prompt`
+    /// attribute at the beginning of a session.
+    pub default_prompts: HashMap<String, String>,
+
+    /// Environment variables for all REPLs.
+    pub env: BTreeMap<String, String>,
+
+    /// The default of the `setup_cmd` attribute, a shell command which is run before a session.
+    pub setup_cmd: Option<String>,
+
+    /// The default of the `teardown_cmd` attribute, a shell command which is run after a session.
+    pub teardown_cmd: Option<String>,
+
+    /// Placeholders in the documents, like `<API-KEY>`, mapped to the real values which are sent
+    /// to the REPL. The values are replaced with the placeholders in the output, so documents
+    /// only ever contain the placeholders.
+    pub placeholders: BTreeMap<String, String>,
+
+    /// Make `scope=global` the default, so sessions with the same name in different documents
+    /// are the same session. See [check_documents].
+    pub shared_sessions: bool,
+
+    /// Output filters by name, used by blocks with the `filter` attribute. See [plugin].
+    pub filters: BTreeMap<String, String>,
+
+    /// Custom matchers by name, used by blocks with the `matcher` attribute. See [plugin].
+    pub matchers: BTreeMap<String, String>,
+
+    /// Stop at the first failing block and return it as an error, instead of running the other
+    /// sessions and returning the failures in [CheckResult::failures].
+    pub fail_fast: bool,
+
+    /// Skip sessions which have passed before without any changes, and report them as
+    /// [SessionStatus::Cached].
+    pub cache: Option<Cache>,
+
+    /// Run sessions which have not passed within this time, even if they are cached.
+    pub max_age: Option<Duration>,
+
+    /// Fill in the actual output of commands which have no expected output in the document.
+    pub record: bool,
+
+    /// Only run the sessions with these names, or all sessions if it is empty.
+    pub sessions: Vec<String>,
+
+    /// Don't run the sessions with these names.
+    pub skip_sessions: Vec<String>,
+
+    /// Only run the blocks whose code matches this regex, and the blocks before them in their
+    /// sessions which set up the state. Sessions without a matching block are not run.
+    pub block_filter: Option<Regex>,
+
+    /// With [Options::block_filter], don't run the earlier blocks of a session either.
+    pub skip_earlier_blocks: bool,
+
+    /// What may be changed in the documents when they are updated, or `None` if they are only
+    /// checked. Then prompts which differ from the actual prompts of the REPLs are not rewritten
+    /// unless the session has `normalize_prompt`, and only their counters are updated.
+    pub update: Option<UpdatePolicy>,
+
+    /// Regexes for volatile lines, like timestamps, which are dropped from both the actual and the
+    /// expected output of every session before they are matched.
+    pub ignore_lines: Vec<Regex>,
+
+    /// Substitutions which rewrite every line of the actual output of every session, before it is
+    /// matched and written to the documents. See [substitute].
+    pub substitutions: Vec<Substitution>,
+
+    /// Custom tokens like `{BUILD_ID}` for the expected output, in addition to the built-in
+    /// `{DATE}`, `{TIME}`, `{DATETIME}` and `{DURATION}`.
+    pub tokens: Tokens,
+
+    /// Collect notes about how the output of every block was matched, like the number of lines
+    /// matched by every hole, in [CheckResult::notes].
+    pub verbose: bool,
+
+    /// Presets for the `preset` attribute by name, in addition to the built-in ones.
+    pub presets: BTreeMap<String, Preset>,
+
+    /// Don't fail on unknown attributes of REPL blocks. They can be found with
+    /// [unknown_attributes] instead, to warn about them.
+    pub lenient: bool,
+
+    /// How many times a session is run again from the start when a block fails, for examples
+    /// which fail now and then, like those which use the network. Blocks can override it with
+    /// the `retries` attribute.
+    pub retries: usize,
+
+    /// Prompts of pagers and confirmations which commands may stop at, in addition to the
+    /// built-in ones. See [interactive].
+    pub interactive_prompts: Vec<InteractivePrompt>,
+
+    /// Keep the transcript of everything sent to and read from the REPL of every session in
+    /// [SessionReport::transcript].
+    pub keep_transcripts: bool,
+
+    /// Don't delete the temporary directories of sessions with `sandbox=true` when they are
+    /// done, see [backend::SpawnOptions::sandbox].
+    pub keep_sandboxes: bool,
+
+    /// Where the progress of the sessions is reported while they run.
+    pub progress: Option<Arc<dyn Progress>>,
+
+    /// Cancels the running sessions, like when another document has failed with
+    /// [Options::fail_fast]. They fail with [reader::CancelledError].
+    pub cancel: Option<Cancel>,
+
+    /// What to do when a block fails, besides reporting it.
+    pub on_failure: OnFailure,
+
+    /// Whether the blocks are run session by session or in document order. With
+    /// [ExecutionOrder::Document] in the options of any of the documents, all blocks of the
+    /// documents are run in document order. Then blocks with retries, from [Options::retries] or
+    /// the `retries` attribute, are an error, and so are blocks whose `after` attribute names a
+    /// session with blocks after them. A block which `needs` a block after it fails.
+    pub order: ExecutionOrder,
+}
+
+impl Options {
+    /// Get the default value for an attribute.
+    fn default_attr(&self, key: &str) -> Option<&str> {
+        self.default_attrs.get(key).map(String::as_str)
+    }
+
+    /// Get a preset from [Options::presets] or a built-in one.
+    pub fn preset(&self, name: &str) -> Option<&Preset> {
+        self.presets.get(name).or_else(|| builtin_preset(name))
+    }
+
+    /// Replace the placeholders in a command with their values.
+    fn fill_placeholders(&self, text: &str) -> String {
+        self.placeholders
+            .iter()
+            .fold(text.to_string(), |text, (placeholder, value)| {
+                text.replace(placeholder, value)
+            })
+    }
+
+    /// Replace the values of the placeholders in output with the placeholders, the longest values
+    /// first.
+    fn restore_placeholders(&self, text: String) -> String {
+        let mut placeholders: Vec<_> = self
+            .placeholders
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        placeholders.sort_by_key(|(_, value)| std::cmp::Reverse(value.len()));
+        placeholders
+            .into_iter()
+            .fold(text, |text, (placeholder, value)| {
+                text.replace(value, placeholder)
+            })
+    }
+}
+
+/// Default attributes for every session, from the `repl-check.sessions` field in the metadata of
+/// a document.
+type SessionDefaults = HashMap<String, HashMap<String, String>>;
+
+/// Read the [SessionDefaults] from the metadata of a document, like:
+///
+/// ```yaml
+/// repl-check:
+///   sessions:
+///     py:
+///       cmd: python3 -q
+///       prompt: "`>>> `"
+/// ```
+///
+/// Values are parsed as markdown by pandoc, so regexes with backslashes or significant trailing
+/// whitespace should be written as inline code.
+fn session_defaults(document: &Pandoc) -> anyhow::Result<SessionDefaults> {
+    use pandoc_ast::MetaValue;
+    let mut defaults = HashMap::new();
+    let Some(sessions) = document
+        .meta
+        .get("repl-check")
+        .and_then(|x| match x {
+            MetaValue::MetaMap(x) => Some(x),
+            _ => None,
+        })
+        .and_then(|x| x.get("sessions"))
+    else {
+        return Ok(defaults);
+    };
+    let MetaValue::MetaMap(sessions) = sessions.as_ref() else {
+        anyhow::bail!("repl-check.sessions in the metadata should be a map of sessions.");
+    };
+    for (session_name, attrs) in sessions {
+        let MetaValue::MetaMap(attrs) = attrs.as_ref() else {
+            anyhow::bail!(
+                "repl-check.sessions.{session_name} in the metadata should be a map of attributes."
+            );
+        };
+        let attrs = attrs
+            .iter()
+            .map(|(key, value)| {
+                meta_to_string(value)
+                    .map(|value| (key.clone(), value))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "repl-check.sessions.{session_name}.{key} in the metadata should be a \
+                             string."
+                        )
+                    })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        defaults.insert(session_name.clone(), attrs);
+    }
+    Ok(defaults)
+}
+
+/// Convert a metadata value to plain text, or `None` if it is a list or a map.
+fn meta_to_string(value: &pandoc_ast::MetaValue) -> Option<String> {
+    use pandoc_ast::{Inline, MetaValue};
+    fn push_inlines(inlines: &[Inline], text: &mut String) {
+        for inline in inlines {
+            match inline {
+                Inline::Str(x) | Inline::Code(_, x) | Inline::Math(_, x) => text.push_str(x),
+                Inline::RawInline(_, x) => text.push_str(x),
+                Inline::Space | Inline::SoftBreak => text.push(' '),
+                Inline::LineBreak => text.push('\n'),
+                Inline::Quoted(quote_type, x) => {
+                    let quote = match quote_type {
+                        pandoc_ast::QuoteType::SingleQuote => '\'',
+                        pandoc_ast::QuoteType::DoubleQuote => '"',
+                    };
+                    text.push(quote);
+                    push_inlines(x, text);
+                    text.push(quote);
+                }
+                Inline::Emph(x)
+                | Inline::Underline(x)
+                | Inline::Strong(x)
+                | Inline::Strikeout(x)
+                | Inline::Superscript(x)
+                | Inline::Subscript(x)
+                | Inline::SmallCaps(x)
+                | Inline::Cite(_, x)
+                | Inline::Link(_, x, _)
+                | Inline::Span(_, x) => push_inlines(x, text),
+                Inline::Image(..) | Inline::Note(_) => {}
+            }
+        }
+    }
+    let mut text = String::new();
+    match value {
+        MetaValue::MetaString(x) => text.push_str(x),
+        MetaValue::MetaBool(x) => text.push_str(&x.to_string()),
+        MetaValue::MetaInlines(x) => push_inlines(x, &mut text),
+        MetaValue::MetaBlocks(blocks) => {
+            for block in blocks {
+                if let Block::Plain(x) | Block::Para(x) = block {
+                    if !text.is_empty() {
+                        text.push('\n');
+                    }
+                    push_inlines(x, &mut text);
+                }
+            }
+        }
+        MetaValue::MetaMap(_) | MetaValue::MetaList(_) => return None,
+    }
+    Some(text)
+}
+
+/// The attribute of a REPL inline code span with its expected output.
+const INLINE_EXPECT_ATTR: &str = "expect";
+
+/// The class of Divs whose attributes are defaults for all REPL blocks inside them.
+const DEFAULTS_DIV_CLASS: &str = "repl-defaults";
 
 #[derive(Debug)]
 struct PandocBlock<'a> {
+    /// The index of the block among all code blocks and REPL inline code spans in the document,
+    /// in the order of [nested_blocks] and [block_inlines].
+    idx: usize,
     session_name: &'a str,
     classes: &'a Vec<String>,
     attrs: &'a Vec<(String, String)>,
     code: &'a String,
+
+    /// Whether this is an inline code span like `` `1+1`{.repl-py expect="2"} ``, whose code is
+    /// a single command without a prompt and whose expected output is the `expect` attribute.
+    inline: bool,
+
+    /// The attributes of all [DEFAULTS_DIV_CLASS] Divs around the block, the innermost first.
+    div_defaults: Vec<&'a Vec<(String, String)>>,
+
+    /// Default attributes for the session from the metadata of the document.
+    session_defaults: Option<&'a HashMap<String, String>>,
 }
 
-fn iter_code_blocks<'a>(pandoc: &'a Pandoc) -> impl Iterator<Item = PandocBlock<'a>> + 'a {
-    pandoc.blocks.iter().filter_map(|block| {
-        if let pandoc_ast::Block::CodeBlock((_, classes, attrs), code) = block {
-            if let Some(session_name) = classes
-                .iter()
-                .filter(|x| x.starts_with("repl-"))
-                .map(|x| &x[5..])
-                .next()
-            {
-                Some(PandocBlock {
-                    session_name,
-                    classes,
-                    attrs,
-                    code,
-                })
-            } else {
-                None
+/// All lists of blocks nested directly in a block, like the items of a list or the cells of a
+/// table.
+fn nested_blocks(block: &Block) -> Vec<&Vec<Block>> {
+    match block {
+        Block::Div(_, blocks) | Block::BlockQuote(blocks) => vec![blocks],
+        Block::OrderedList(_, items) | Block::BulletList(items) => items.iter().collect(),
+        Block::DefinitionList(items) => items
+            .iter()
+            .flat_map(|(_, definitions)| definitions)
+            .collect(),
+        Block::Table(_, (_, caption), _, (_, head), bodies, (_, foot)) => iter::once(caption)
+            .chain(
+                head.iter()
+                    .chain(
+                        bodies
+                            .iter()
+                            .flat_map(|(_, _, head, rows)| head.iter().chain(rows)),
+                    )
+                    .chain(foot)
+                    .flat_map(|(_, cells)| cells)
+                    .map(|(_, _, _, _, blocks)| blocks),
+            )
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// All lists of inlines and blocks directly in a block, in the same order as [block_inlines] and
+/// [nested_blocks]. Both are returned together since a definition list has both.
+fn block_contents_mut(block: &mut Block) -> (Vec<&mut Vec<Inline>>, Vec<&mut Vec<Block>>) {
+    match block {
+        Block::Plain(inlines) | Block::Para(inlines) | Block::Header(_, _, inlines) => {
+            (vec![inlines], Vec::new())
+        }
+        Block::LineBlock(lines) => (lines.iter_mut().collect(), Vec::new()),
+        Block::Div(_, blocks) | Block::BlockQuote(blocks) => (Vec::new(), vec![blocks]),
+        Block::OrderedList(_, items) | Block::BulletList(items) => {
+            (Vec::new(), items.iter_mut().collect())
+        }
+        Block::DefinitionList(items) => {
+            let mut terms = Vec::new();
+            let mut blocks = Vec::new();
+            for (term, definitions) in items {
+                terms.push(term);
+                blocks.extend(definitions);
             }
-        } else {
-            None
+            (terms, blocks)
         }
-    })
+        Block::Table(_, (_, caption), _, (_, head), bodies, (_, foot)) => (
+            Vec::new(),
+            iter::once(caption)
+                .chain(
+                    head.iter_mut()
+                        .chain(
+                            bodies
+                                .iter_mut()
+                                .flat_map(|(_, _, head, rows)| head.iter_mut().chain(rows)),
+                        )
+                        .chain(foot)
+                        .flat_map(|(_, cells)| cells)
+                        .map(|(_, _, _, _, blocks)| blocks),
+                )
+                .collect(),
+        ),
+        _ => (Vec::new(), Vec::new()),
+    }
 }
 
-/// A parsed code block which should be verified in a REPL.
-#[derive(Debug)]
-struct ReplBlock<'a> {
-    /// A regex matching the prompt. Both in the expected an dactual output.
-    prompt: Rc<Regex>,
+/// All lists of inlines directly in a block, like the text of a paragraph. The inlines in the
+/// blocks of [nested_blocks] are not included.
+fn block_inlines(block: &Block) -> Vec<&Vec<Inline>> {
+    match block {
+        Block::Plain(inlines) | Block::Para(inlines) | Block::Header(_, _, inlines) => {
+            vec![inlines]
+        }
+        Block::LineBlock(lines) => lines.iter().collect(),
+        Block::DefinitionList(items) => items.iter().map(|(term, _)| term).collect(),
+        _ => Vec::new(),
+    }
+}
 
-    /// TODO: Is this needed?
-    prompt_char: &'a str,
+/// The inlines nested directly in an inline, like the text of a link. Footnotes are not
+/// included.
+fn nested_inlines(inline: &Inline) -> Option<&Vec<Inline>> {
+    match inline {
+        Inline::Emph(inlines)
+        | Inline::Underline(inlines)
+        | Inline::Strong(inlines)
+        | Inline::Strikeout(inlines)
+        | Inline::Superscript(inlines)
+        | Inline::Subscript(inlines)
+        | Inline::SmallCaps(inlines)
+        | Inline::Quoted(_, inlines)
+        | Inline::Cite(_, inlines)
+        | Inline::Link(_, inlines, _)
+        | Inline::Image(_, inlines, _)
+        | Inline::Span(_, inlines) => Some(inlines),
+        _ => None,
+    }
+}
 
-    /// A list of the expected lines (including prompt-lines).
-    expected: Vec<&'a str>,
+/// The inlines nested directly in an inline, like [nested_inlines].
+fn nested_inlines_mut(inline: &mut Inline) -> Option<&mut Vec<Inline>> {
+    match inline {
+        Inline::Emph(inlines)
+        | Inline::Underline(inlines)
+        | Inline::Strong(inlines)
+        | Inline::Strikeout(inlines)
+        | Inline::Superscript(inlines)
+        | Inline::Subscript(inlines)
+        | Inline::SmallCaps(inlines)
+        | Inline::Quoted(_, inlines)
+        | Inline::Cite(_, inlines)
+        | Inline::Link(_, inlines, _)
+        | Inline::Image(_, inlines, _)
+        | Inline::Span(_, inlines) => Some(inlines),
+        _ => None,
+    }
 }
 
-/// All [ReplBlock]s belonging to the same invocation of the REPL program.
-#[derive(Debug)]
-struct Session<'a> {
-    /// The command used to run the repl from a system shell.
-    shell_cmd: &'a str,
+/// The session name of a code block or inline code span with a `repl-<session>` class.
+fn repl_session_name(classes: &[String]) -> Option<&str> {
+    classes
+        .iter()
+        .filter(|x| x.starts_with("repl-"))
+        .map(|x| &x[5..])
+        .next()
+}
 
-    /// An oredered list of all [ReplBlock]s.
-    blocks: Vec<ReplBlock<'a>>,
+/// Collect all REPL blocks in a document, including those nested in other blocks and REPL inline
+/// code spans.
+fn iter_code_blocks<'a>(
+    pandoc: &'a Pandoc,
+    session_defaults: &'a SessionDefaults,
+) -> impl Iterator<Item = PandocBlock<'a>> + 'a {
+    fn collect<'a>(
+        blocks: &'a [Block],
+        session_defaults: &'a SessionDefaults,
+        div_defaults: &mut Vec<&'a Vec<(String, String)>>,
+        idx: &mut usize,
+        result: &mut Vec<PandocBlock<'a>>,
+    ) {
+        for block in blocks {
+            if let Block::CodeBlock((_, classes, attrs), code) = block {
+                if let Some(session_name) = repl_session_name(classes) {
+                    result.push(PandocBlock {
+                        idx: *idx,
+                        session_name,
+                        classes,
+                        attrs,
+                        code,
+                        inline: false,
+                        div_defaults: div_defaults.iter().rev().copied().collect(),
+                        session_defaults: session_defaults.get(session_name),
+                    });
+                }
+                *idx += 1;
+            }
+            for inlines in block_inlines(block) {
+                collect_inlines(inlines, session_defaults, div_defaults, idx, result);
+            }
+            let defaults = match block {
+                Block::Div((_, classes, attrs), _)
+                    if classes.iter().any(|x| x == DEFAULTS_DIV_CLASS) =>
+                {
+                    Some(attrs)
+                }
+                _ => None,
+            };
+            div_defaults.extend(defaults);
+            for nested in nested_blocks(block) {
+                collect(nested, session_defaults, div_defaults, idx, result);
+            }
+            if defaults.is_some() {
+                div_defaults.pop();
+            }
+        }
+    }
+    fn collect_inlines<'a>(
+        inlines: &'a [Inline],
+        session_defaults: &'a SessionDefaults,
+        div_defaults: &[&'a Vec<(String, String)>],
+        idx: &mut usize,
+        result: &mut Vec<PandocBlock<'a>>,
+    ) {
+        for inline in inlines {
+            if let Inline::Code((_, classes, attrs), code) = inline {
+                // Only REPL inline code spans are counted, so that the numbers of the code
+                // blocks don't depend on other inline code.
+                if let Some(session_name) = repl_session_name(classes) {
+                    result.push(PandocBlock {
+                        idx: *idx,
+                        session_name,
+                        classes,
+                        attrs,
+                        code,
+                        inline: true,
+                        div_defaults: div_defaults.iter().rev().copied().collect(),
+                        session_defaults: session_defaults.get(session_name),
+                    });
+                    *idx += 1;
+                }
+            }
+            if let Some(nested) = nested_inlines(inline) {
+                collect_inlines(nested, session_defaults, div_defaults, idx, result);
+            }
+        }
+    }
+    let mut result = Vec::new();
+    collect(
+        &pandoc.blocks,
+        session_defaults,
+        &mut Vec::new(),
+        &mut 0,
+        &mut result,
+    );
+    result.into_iter()
 }
 
-/// Given a pandoc document, collect all REPL sessions with their names.
-fn get_sessions<'a>(document: &'a Pandoc) -> anyhow::Result<HashMap<&'a str, Session<'a>>> {
-    let mut sessions = HashMap::new();
-    for PandocBlock {
-        session_name,
-        classes,
-        attrs,
-        code,
-    } in iter_code_blocks(document)
-    {
-        let shell_cmd = attrs
+/// A mutable reference to a code block or a REPL inline code span in a document.
+enum CodeMut<'a> {
+    /// The code of a code block.
+    Block(&'a mut String),
+
+    /// The attributes of an inline code span, where the expected output is the `expect`
+    /// attribute.
+    Inline(&'a mut Vec<(String, String)>),
+}
+
+impl CodeMut<'_> {
+    /// Replace the code with the lines of `code`. For an inline code span, the first line is the
+    /// command, which isn't changed, and the other lines are the expected output.
+    fn set(&mut self, code: &str) {
+        match self {
+            CodeMut::Block(x) => code.clone_into(x),
+            CodeMut::Inline(attrs) => {
+                let expect = code.split_once('\n').map_or("", |(_, x)| x).to_string();
+                match attrs.iter_mut().find(|(key, _)| key == INLINE_EXPECT_ATTR) {
+                    Some((_, value)) => *value = expect,
+                    None => attrs.push((INLINE_EXPECT_ATTR.to_string(), expect)),
+                }
+            }
+        }
+    }
+}
+
+/// Get mutable references to all code blocks and REPL inline code spans in a document, indexed
+/// like [PandocBlock::idx].
+fn code_blocks_mut(pandoc: &mut Pandoc) -> Vec<CodeMut<'_>> {
+    fn collect<'a>(blocks: &'a mut [Block], result: &mut Vec<CodeMut<'a>>) {
+        for block in blocks {
+            if let Block::CodeBlock(_, code) = block {
+                result.push(CodeMut::Block(code));
+                continue;
+            }
+            let (inlines, nested) = block_contents_mut(block);
+            for inlines in inlines {
+                collect_inlines(inlines, result);
+            }
+            for nested in nested {
+                collect(nested, result);
+            }
+        }
+    }
+    fn collect_inlines<'a>(inlines: &'a mut [Inline], result: &mut Vec<CodeMut<'a>>) {
+        for inline in inlines {
+            match inline {
+                Inline::Code((_, classes, attrs), _) => {
+                    if repl_session_name(classes).is_some() {
+                        result.push(CodeMut::Inline(attrs));
+                    }
+                }
+                inline => {
+                    if let Some(nested) = nested_inlines_mut(inline) {
+                        collect_inlines(nested, result);
+                    }
+                }
+            }
+        }
+    }
+    let mut result = Vec::new();
+    collect(&mut pandoc.blocks, &mut result);
+    result
+}
+
+impl<'a> PandocBlock<'a> {
+    /// The expected lines of the block. For an inline code span, this is the command followed by
+    /// the lines of the `expect` attribute.
+    fn lines(&self) -> Vec<&'a str> {
+        let mut lines: Vec<&'a str> = self.code.lines().collect();
+        if self.inline {
+            lines.extend(
+                self.attr(INLINE_EXPECT_ATTR)
+                    .into_iter()
+                    .flat_map(str::lines),
+            );
+        }
+        lines
+    }
+
+    /// The code of the block as in [BlockReport::code], which for an inline code span is
+    /// [Self::lines] joined by newlines.
+    fn text(&self) -> String {
+        match self.inline {
+            true => self.lines().join("\n"),
+            false => self.code.clone(),
+        }
+    }
+
+    /// Get the value of an attribute.
+    fn attr(&self, key: &str) -> Option<&'a str> {
+        self.attrs
             .iter()
-            .filter(|(x, _)| x == "cmd")
+            .filter(|(x, _)| x == key)
             .map(|(_, y)| y.as_str())
-            .next();
-        let prompt = attrs
+            .next()
+    }
+
+    /// Get the default value for an attribute, from the Divs around the block, the metadata of
+    /// the document or from `options`.
+    fn default_attr(&self, key: &str, options: &'a Options) -> Option<&'a str> {
+        self.div_defaults
             .iter()
-            .filter(|(x, _)| x == "prompt")
-            .map(|(_, y)| y)
+            .flat_map(|x| x.iter())
+            .find(|(x, _)| x == key)
+            .map(|(_, y)| y.as_str())
+            .or_else(|| {
+                self.session_defaults
+                    .and_then(|x| x.get(key))
+                    .map(String::as_str)
+            })
+            .or_else(|| options.default_attr(key))
+    }
+
+    /// Get the value of an attribute, or the default value if it is not set.
+    fn attr_or_default(&self, key: &str, options: &'a Options) -> Option<&'a str> {
+        self.attr(key).or_else(|| self.default_attr(key, options))
+    }
+
+    /// Parse the value of an attribute, or the default value if it is not set.
+    fn parse_attr_or_default<T: std::str::FromStr>(
+        &self,
+        key: &str,
+        options: &'a Options,
+    ) -> anyhow::Result<Option<T>>
+    where
+        T::Err: std::fmt::Display,
+    {
+        self.attr_or_default(key, options)
             .map(|x| {
-                Regex::new(x).map(Rc::new).map_err(|e| {
+                x.parse().map_err(|e| {
                     anyhow::anyhow!(
-                        "In session {session_name}: Bad regular expression for prompt: {x}: {e}"
+                        "In session {}: Bad value for {key}: {x}: {e}",
+                        self.session_name
                     )
                 })
             })
-            .next()
-            .transpose()?;
-        let prompt_char = attrs
+            .transpose()
+    }
+
+    /// An error for every attribute of the block which is not known, with the closest known
+    /// attribute if it looks like a typo.
+    fn unknown_attrs(&self) -> Vec<String> {
+        let known: Vec<&str> = SESSION_ATTRS.iter().chain(BLOCK_ATTRS).copied().collect();
+        self.attrs
             .iter()
-            .filter(|(x, _)| x == "prompt_char")
-            .map(|(_, y)| y.as_str())
-            .next();
-        let expected = code.lines().collect();
-
-        use std::collections::hash_map::Entry::*;
-        match sessions.entry(session_name) {
-            Vacant(entry) => {
-                let Some(shell_cmd) = shell_cmd else {
-                    anyhow::bail!("No command provided at beginning of session {session_name}.");
-                };
-                let Some(prompt) = prompt else {
-                    anyhow::bail!("ExpectedPrompt must be specified for the session {session_name}.");
-                };
-                let prompt_char = prompt_char.unwrap_or(DEFAULT_PROMPT_CHAR);
-                entry.insert(Session {
-                    shell_cmd,
-                    blocks: vec![ReplBlock {
-                        prompt,
-                        prompt_char,
-                        expected,
-                    }],
-                });
-            }
-            Occupied(mut entry) => {
-                if let Some(shell_cmd) = shell_cmd {
-                    anyhow::bail!("cmd is specified a second time for session {session_name} as `{shell_cmd}`.");
-                }
-                let last_block = entry.get().blocks.last().unwrap();
-                let prompt = prompt.unwrap_or_else(|| last_block.prompt.clone());
-                let prompt_char = prompt_char.unwrap_or(last_block.prompt_char);
-                entry.get_mut().blocks.push(ReplBlock {
-                    prompt,
-                    prompt_char,
-                    expected,
-                });
-            }
-        }
+            .filter(|(key, _)| !known.contains(&key.as_str()))
+            .filter(|(key, _)| !(self.inline && key == INLINE_EXPECT_ATTR))
+            .map(|(key, _)| match closest_name(key, &known) {
+                Some(name) => format!("Unknown attribute {key}, did you mean {name}?"),
+                None => format!("Unknown attribute {key}."),
+            })
+            .collect()
+    }
+
+    /// Whether this block should be run given the enabled features.
+    fn is_enabled(&self, options: &'a Options) -> bool {
+        self.attr_or_default("if_feature", options)
+            .is_none_or(|feature| options.features.contains(feature))
     }
-    Ok(sessions)
 }
 
-/// The kind of prompt that is expected.
-#[derive(Debug)]
-enum ExpectedPrompt<'a> {
-    /// The prompt should match exactly this string.
-    Fixed(&'a str),
+/// The placeholder for a counter in a prompt, like `In [{n}]: `.
+const COUNTER_PLACEHOLDER: &str = "{n}";
 
-    /// The prompt should match the provided prompt regex.
-    Flexible,
+/// Compile a prompt regex which only matches at the beginning of a line and at the end of the
+/// output which has been read, since a REPL waits for input after printing the prompt. Text in
+/// the output of a command which looks like the prompt is then not taken as the prompt, which
+/// would cut the output short and mix up the output of the following commands.
+fn anchor_prompt(regex: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!(r"(?m:^)(?:{regex})\z"))
+}
 
-    /// The prompt should match the provided prompt regex and the promptstring in the document
-    /// should be updated with the actual prompt.
-    Updatable,
+/// Explains why text which looks like the prompt may not be taken as the prompt.
+const PROMPT_POSITION_NOTE: &str = "The prompt is only recognized at the beginning of a line, as \
+                                    the last thing the REPL prints before it waits for input.";
+
+/// A note for an error if the output contains text which looks like the prompt, but isn't taken
+/// as the prompt since it is not at the beginning of a line at the end of the output.
+fn prompt_in_output_note(prompt: &Prompt, output: &str) -> Option<String> {
+    let (i, line) = output.lines().enumerate().find(|(_, line)| {
+        prompt
+            .unanchored_regex
+            .find(line)
+            .is_some_and(|m| !m.is_empty())
+    })?;
+    Some(format!(
+        "Note: Line {} of the output looks like the prompt: {line}\n{PROMPT_POSITION_NOTE}",
+        i + 1
+    ))
 }
 
-/// Information about invoking a command in a REPL.
+/// A prompt regex.
+///
+/// The regex may contain the [COUNTER_PLACEHOLDER] for tools like `ipython` which number their
+/// prompts. It matches a number in the REPL, and a number or the placeholder itself in the
+/// document. Prompts with the wrong number in the document are updated with the actual number.
 #[derive(Debug)]
-struct CmdInvokation<'a> {
-    prompt: ExpectedPrompt<'a>,
+struct Prompt {
+    /// Matches the prompt in the output of the REPL, see [anchor_prompt].
+    regex: Regex,
 
-    /// The command to run.
-    cmd: &'a str,
+    /// Matches the prompt anywhere in the output, for finding text which looks like the prompt
+    /// but isn't taken as one.
+    unanchored_regex: Regex,
 
-    /// The prompt and the command together as it appeared in the document.
-    entire_prompt_line: &'a str,
+    /// Matches the prompt in the document.
+    document_regex: Regex,
 
-    /// Lines of expected output.
-    expected_output: &'a [&'a str],
+    /// Whether the regex contains the [COUNTER_PLACEHOLDER].
+    has_counter: bool,
+
+    /// Whether this is a placeholder for a prompt which is detected when the REPL is started,
+    /// see [AUTO_PROMPT].
+    detect: bool,
 }
 
-/// A list of command invokations.
+/// The value of the `prompt` attribute for detecting the prompt: After the REPL is started, its
+/// output is read until it stops printing for `prompt_idle_ms` milliseconds, and the last line
+/// is taken as the prompt, with numbers in it matching any number.
+const AUTO_PROMPT: &str = "auto";
+
+impl Prompt {
+    fn new(regex: &str) -> Result<Self, regex::Error> {
+        let has_counter = regex.contains(COUNTER_PLACEHOLDER);
+        let regex_in_repl = regex.replace(COUNTER_PLACEHOLDER, r"(?P<n>\d+)");
+        Ok(Self {
+            regex: anchor_prompt(&regex_in_repl)?,
+            unanchored_regex: Regex::new(&regex_in_repl)?,
+            document_regex: Regex::new(&regex.replace(COUNTER_PLACEHOLDER, r"(?P<n>\d+|\{n\})"))?,
+            has_counter,
+            detect: false,
+        })
+    }
+
+    /// A placeholder for a prompt which is detected later, which matches nothing.
+    fn auto() -> Self {
+        let nothing = r"\b\B";
+        Self {
+            regex: anchor_prompt(nothing).unwrap(),
+            unanchored_regex: Regex::new(nothing).unwrap(),
+            document_regex: Regex::new(nothing).unwrap(),
+            has_counter: false,
+            detect: true,
+        }
+    }
+
+    /// Update the counter in the prompt lines in the document with the number in the actual
+    /// prompt, or return `None` if they are the same.
+    fn renumber(&self, prompt_lines: &str, actual_prompt: &str) -> Option<String> {
+        if !self.has_counter {
+            return None;
+        }
+        let actual = self.regex.captures(actual_prompt)?.name("n")?.as_str();
+        let expected = self.document_regex.captures(prompt_lines)?.name("n")?;
+        (expected.as_str() != actual).then(|| {
+            format!(
+                "{}{actual}{}",
+                &prompt_lines[..expected.start()],
+                &prompt_lines[expected.end()..]
+            )
+        })
+    }
+}
+
+/// A parsed code block which should be verified in a REPL.
 #[derive(Debug)]
-struct CmdInvokations<'a> {
-    /// The expected output (list of lines) before the first command.
-    initial_output: &'a [&'a str],
-    cmd_invocations: Vec<CmdInvokation<'a>>,
+struct ReplBlock<'a> {
+    /// The prompt. Both in the expected and actual output.
+    ///
+    /// The prompt may span multiple lines if the regex contains a newline. Note that lines in the
+    /// actual output usually end with `\r\n` so such a regex should match `\r?\n`.
+    prompt: Rc<Prompt>,
+
+    /// TODO: Is this needed?
+    prompt_char: &'a str,
+
+    /// The prompt when the REPL waits for more lines of a command. Lines after a command which
+    /// start with it are sent as part of the command.
+    continuation: Option<Rc<Prompt>>,
+
+    /// A list of the expected lines (including prompt-lines).
+    expected: Vec<&'a str>,
+
+    /// Whether the block is an inline code span. Then the first line of [Self::expected] is a
+    /// command without a prompt and the other lines are its expected output.
+    inline: bool,
+
+    /// The names of the output filters in [Options::filters] which the output of every command
+    /// is passed through, in order.
+    filters: Vec<&'a str>,
+
+    /// The name of a custom matcher in [Options::matchers] which is used instead of the built-in
+    /// patterns.
+    matcher: Option<&'a str>,
+
+    /// Whether the expected output must match all of the output of a command or only a prefix.
+    match_mode: MatchMode,
+
+    /// What to do when the output of a command doesn't match.
+    on_mismatch: OnMismatch,
+
+    /// Whether the output of the commands is matched at all.
+    output: OutputMode,
+
+    /// Whether the REPL should exit after the last command of the block, set with the
+    /// `expect_eof` attribute. The output up to the end is matched instead of the output up to
+    /// the next prompt, and the REPL is started again for the next block.
+    expect_eof: bool,
+
+    /// The longest time a command in the block may take until its output has been read, set
+    /// with the `max_duration` attribute like `2s`.
+    max_duration: Option<Duration>,
+
+    /// How many times the session is run again from the start if this block fails, see
+    /// [Options::retries].
+    retries: usize,
+
+    /// Sessions which must have been run before the session of this block, set with the `after`
+    /// attribute as a comma separated list.
+    after: Vec<&'a str>,
+
+    /// Blocks of other sessions which must have passed before the session of this block is run,
+    /// set with the `needs` attribute like `setup:2`, as the name of the session and the number
+    /// of the block among the blocks of that session.
+    needs: Vec<(&'a str, usize)>,
+
+    /// The index of the document of the block.
+    document: usize,
+
+    /// The number of the block among the blocks of its session, starting at 1, which stays the
+    /// same when blocks are skipped.
+    number: usize,
+
+    /// The text which named groups in the prompt regex must have captured in the prompt before
+    /// the first command of the block, set with the `assert_prompt` attribute like `db=mydb`,
+    /// to catch a REPL whose state like the database or directory has changed unexpectedly.
+    assert_prompt: Vec<(&'a str, &'a str)>,
+
+    /// Other expected output for the same commands, from the blocks whose `alt_of` attribute
+    /// names this block. The block passes if its own or any alternative expected output matches.
+    alternatives: Vec<Alternative<'a>>,
 }
 
-fn repl_block_to_cmd_invocations<'a>(repl_block: &ReplBlock<'a>) -> CmdInvokations<'a> {
-    unimplemented!()
+/// A block with the `alt_of` attribute, whose expected output is an alternative to that of
+/// another block, like for output which differs between operating systems.
+#[derive(Debug)]
+struct Alternative<'a> {
+    /// The lines of the block, which must have the same commands as the other block.
+    expected: Vec<&'a str>,
+
+    /// Whether the block is an inline code span, like [ReplBlock::inline].
+    inline: bool,
+
+    /// The number of the code block in the document, starting at 1.
+    number: usize,
 }
 
-/// Run a set of [Session]s.
-///
-/// Returns for every session a [Vec] with one element for each [ReplBlock] in that session. An
-/// element in the vector is [Some] iff that block should be updated.
-fn run_sessions<'a>(
-    sessions: HashMap<&'a str, Session<'a>>,
-) -> anyhow::Result<HashMap<String, Vec<Option<String>>>> {
-    let mut updated_blocks = HashMap::new();
-    for (session_name, session) in sessions.into_iter() {
-        let mut process = rexpect::spawn(session.shell_cmd, Some(TIMEOUT_MS))?;
-
-        // A list of all updated blocks in this session.
-        let mut updated_repl_blocks = Vec::new();
-        for repl_block in session.blocks {
-            // All the lines in this block, perhaps updated.
-            let mut updated_repl_block = LinesCow::new();
+/// What may be changed in the documents when they are updated, see [Options::update].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum UpdatePolicy {
+    /// Only fill in `???` holes, in the output and as prompts.
+    Holes,
 
-            let CmdInvokations {
-                mut initial_output,
-                cmd_invocations,
-            } = repl_block_to_cmd_invocations(&repl_block);
-            // Loop through all [CmdInvokation]s. [initial_output] will be updated with the
-            // expected output before the prompt.
-            for CmdInvokation {
-                prompt,
-                cmd,
-                entire_prompt_line,
-                expected_output,
-            } in cmd_invocations
-            {
-                // A regex for matching the prompt in the REPL.
-                let prompt_regex = match prompt {
-                    ExpectedPrompt::Fixed(x) => Regex::new(&regex::escape(x)).unwrap(),
-                    ExpectedPrompt::Flexible | ExpectedPrompt::Updatable => {
-                        repl_block.prompt.as_ref().clone()
-                    }
+    /// Also rewrite prompts to the actual prompts of the REPLs.
+    #[default]
+    Prompts,
+
+    /// Also replace any mismatching output with the actual output, like a snapshot.
+    All,
+}
+
+impl std::str::FromStr for UpdatePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "holes" => Ok(Self::Holes),
+            "prompts" => Ok(Self::Prompts),
+            "all" => Ok(Self::All),
+            _ => Err("Expected holes, prompts or all".to_string()),
+        }
+    }
+}
+
+impl UpdatePolicy {
+    /// Whether edits with this origin may be written to the documents. Recorded output and
+    /// suggested fixes have flags of their own.
+    fn allows(self, origin: EditOrigin) -> bool {
+        match origin {
+            EditOrigin::Hole | EditOrigin::Record | EditOrigin::Suggestion => true,
+            EditOrigin::Prompt => self >= Self::Prompts,
+            EditOrigin::Output => self == Self::All,
+        }
+    }
+}
+
+/// What to do when a block fails, see [Options::on_failure].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnFailure {
+    /// Only report the failure.
+    #[default]
+    Report,
+
+    /// Attach the terminal to the REPL of the session with a [debug] shell, and abort the run with
+    /// [Options::cancel] if the user doesn't want to continue.
+    Debug,
+}
+
+impl std::str::FromStr for OnFailure {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(Self::Report),
+            "debug" => Ok(Self::Debug),
+            _ => Err("Expected report or debug".to_string()),
+        }
+    }
+}
+
+/// The order in which the blocks of different sessions are run, see [Options::order].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecutionOrder {
+    /// Run all blocks of a session before the next session, in the order the sessions start and
+    /// after the sessions they depend on with the `after` and `needs` attributes.
+    #[default]
+    Session,
+
+    /// Run the blocks in the order they appear in the documents, switching between the REPLs of
+    /// the sessions, for sessions which interact through files or other shared state. A session
+    /// is started at its first block and shut down after its last one.
+    Document,
+}
+
+impl std::str::FromStr for ExecutionOrder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "session" => Ok(Self::Session),
+            "document" => Ok(Self::Document),
+            _ => Err("Expected document or session".to_string()),
+        }
+    }
+}
+
+/// What to do when the output of a command doesn't match, set with the `on_mismatch` attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OnMismatch {
+    /// Fail the block and stop the session.
+    Stop,
+
+    /// Record the mismatch and continue with the next command, so that later commands in the
+    /// session are checked too. The block fails in the end.
+    Continue,
+}
+
+impl std::str::FromStr for OnMismatch {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(Self::Stop),
+            "continue" => Ok(Self::Continue),
+            _ => Err("Expected stop or continue".to_string()),
+        }
+    }
+}
+
+/// How much of the output of a command must be matched, set with the `match` attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchMode {
+    /// All of the output must be matched.
+    Exact,
+
+    /// The expected lines must match a prefix of the output, and any lines after it are dropped,
+    /// like version banners or telemetry notices. They are still added to the block with
+    /// [UpdatePolicy::All].
+    Prefix,
+}
+
+impl std::str::FromStr for MatchMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(Self::Exact),
+            "prefix" => Ok(Self::Prefix),
+            _ => Err("Expected exact or prefix".to_string()),
+        }
+    }
+}
+
+/// Whether the output of the commands in a block is matched, set with the `output` attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputMode {
+    /// Match the output against the expected output.
+    Match,
+
+    /// Only check that the commands run and the prompt comes back, for commands with noisy
+    /// output like installation steps. The output is read up to every prompt but not matched.
+    Ignore,
+}
+
+impl std::str::FromStr for OutputMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "match" => Ok(Self::Match),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err("Expected match or ignore".to_string()),
+        }
+    }
+}
+
+/// Whether the REPL echoes commands back, set with the `echo` session attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Echo {
+    /// The output is matched as it is, so an echoed command must be in the expected output.
+    Keep,
+
+    /// If the first line of output after a command is the command itself, it is removed before
+    /// the output is matched.
+    Strip,
+}
+
+impl std::str::FromStr for Echo {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "strip" => Ok(Self::Strip),
+            _ => Err("Expected keep or strip".to_string()),
+        }
+    }
+}
+
+/// Whether a line of output is the echo of a command.
+fn is_echo(cmd: &str, line: &str) -> bool {
+    line.trim_end() == cmd.trim_end()
+}
+
+/// The mismatches in a block with `on_mismatch=continue`. The session continues after this
+/// error.
+#[derive(Debug, thiserror::Error)]
+#[error("{}", .0.join("\n"))]
+struct Mismatches(Vec<String>);
+
+/// An error when the output of a command doesn't match, the REPL doesn't exit as expected or a
+/// command takes longer than its `max_duration`, rather than when the REPL can't be run. See
+/// [failure_kind].
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+struct Mismatch {
+    message: String,
+
+    /// A diff from the expected to the actual output, if the output of a command didn't match.
+    diff: Option<String>,
+}
+
+impl Mismatch {
+    fn new(message: String) -> Self {
+        Mismatch {
+            message,
+            diff: None,
+        }
+    }
+}
+
+/// Add a note to the message of an error, keeping the diff of a [Mismatch].
+fn add_note(error: anyhow::Error, note: &str) -> anyhow::Error {
+    match error.downcast::<Mismatch>() {
+        Ok(Mismatch { message, diff }) => Mismatch {
+            message: format!("{message}\n{note}"),
+            diff,
+        }
+        .into(),
+        Err(error) => anyhow::anyhow!("{error}\n{note}"),
+    }
+}
+
+/// The diff from the expected to the actual output, if a block failed with `error` because the
+/// output of a command didn't match.
+pub fn mismatch_diff(error: &anyhow::Error) -> Option<&str> {
+    if let Some(x) = error.downcast_ref::<TranscriptError>() {
+        return mismatch_diff(&x.error);
+    }
+    if let Some(x) = error.downcast_ref::<ScreenError>() {
+        return mismatch_diff(&x.error);
+    }
+    error.downcast_ref::<Mismatch>()?.diff.as_deref()
+}
+
+/// Whether a block failed with `error` because of a mismatch or because its REPL could not be
+/// run as expected.
+pub fn failure_kind(error: &anyhow::Error) -> FailureKind {
+    if let Some(x) = error.downcast_ref::<TranscriptError>() {
+        return failure_kind(&x.error);
+    }
+    if let Some(x) = error.downcast_ref::<ScreenError>() {
+        return failure_kind(&x.error);
+    }
+    match error.is::<Mismatch>() || error.is::<Mismatches>() {
+        true => FailureKind::Mismatch,
+        false => FailureKind::Error,
+    }
+}
+
+/// An error in a block together with an excerpt of the transcript of the REPL since the block
+/// started.
+#[derive(Debug, thiserror::Error)]
+#[error("{error}\nTranscript:\n{transcript}")]
+struct TranscriptError {
+    error: anyhow::Error,
+    transcript: String,
+}
+
+impl ReplBlock<'_> {
+    /// Whether the prompt regex may match multiple lines.
+    fn is_multiline_prompt(&self) -> bool {
+        let prompt = self.prompt.regex.as_str();
+        prompt.contains('\n') || prompt.contains("\\n")
+    }
+
+    /// The parts of the block which affect the result of its session, for [Session::cache_key].
+    /// Where the block is, its document and number, is left out so that adding or moving
+    /// documents doesn't change the key.
+    fn cache_key(&self) -> String {
+        let ReplBlock {
+            prompt,
+            prompt_char,
+            continuation,
+            expected,
+            inline,
+            filters,
+            matcher,
+            match_mode,
+            on_mismatch,
+            output,
+            expect_eof,
+            max_duration,
+            retries,
+            after,
+            needs,
+            document: _,
+            number: _,
+            assert_prompt,
+            alternatives,
+        } = self;
+        let alternatives: Vec<_> = alternatives
+            .iter()
+            .map(|x| (&x.expected, x.inline))
+            .collect();
+        format!(
+            "{:?}",
+            (
+                (prompt, prompt_char, continuation, expected, inline),
+                (filters, matcher, match_mode, on_mismatch),
+                (output, expect_eof, max_duration, retries),
+                (after, needs, assert_prompt, alternatives),
+            )
+        )
+    }
+}
+
+/// All [ReplBlock]s belonging to the same invocation of the REPL program.
+#[derive(Debug)]
+struct Session<'a> {
+    /// How to spawn the REPL.
+    spawn_options: SpawnOptions<'a>,
+
+    /// An oredered list of all [ReplBlock]s.
+    blocks: Vec<ReplBlock<'a>>,
+
+    /// The number of lines to skip at the beginning of the output every time the REPL is started,
+    /// like a login banner.
+    initial_skip: usize,
+
+    /// Whether all output before the first prompt is discarded every time the REPL is started,
+    /// instead of matching it, set with the `skip_banner` attribute.
+    skip_banner: bool,
+
+    /// A regex which the banner of the REPL, or the output of [Self::version_cmd], must match
+    /// every time the REPL is started, set with the `require_version` attribute.
+    require_version: Option<Regex>,
+
+    /// A command which prints the version of the REPL, sent at the first prompt to check
+    /// [Self::require_version] instead of the banner.
+    version_cmd: Option<&'a str>,
+
+    /// Whether echoed commands are removed from the output.
+    echo: Echo,
+
+    /// How the lines of the output are compared, from the `whitespace`, `case` and
+    /// `unicode_normalize` attributes, and the tokens from [Options::tokens].
+    comparison: Comparison<'a>,
+
+    /// With `prompt=auto`, how long the REPL must be silent before the last line is taken as
+    /// the prompt.
+    prompt_idle: Duration,
+
+    /// How long to wait before sending every line of a command after the first one, for REPLs
+    /// which mishandle input which comes in one burst, set with the `send_delay_ms` attribute.
+    send_delay: Duration,
+
+    /// Whether a command with continuation lines is sent at once, wrapped in the escape codes of
+    /// bracketed paste, instead of line by line at the continuation prompts. Set with the
+    /// `bracketed_paste` attribute.
+    bracketed_paste: bool,
+
+    /// A shell command which is run outside the REPL before the session starts, set with the
+    /// `setup_cmd` attribute or [Options::setup_cmd]. The session is not run if it fails.
+    setup_cmd: Option<&'a str>,
+
+    /// A shell command which is run outside the REPL after the session, also if it failed, set
+    /// with the `teardown_cmd` attribute or [Options::teardown_cmd].
+    teardown_cmd: Option<&'a str>,
+
+    /// Whether the session runs in a fresh temporary directory, which is deleted afterwards
+    /// unless [Options::keep_sandboxes] is set. Set with the `sandbox` attribute.
+    sandbox: bool,
+
+    /// A canonical text, like `In [{n}]: `, which all prompts in the document are rewritten to
+    /// instead of the actual prompts, so that they don't change when blocks are added.
+    normalize_prompt: Option<&'a str>,
+
+    /// Lines matching this regex are dropped from the actual and expected output, from
+    /// [Options::ignore_lines] and the `ignore_lines` attribute.
+    ignore_lines: Option<Regex>,
+
+    /// [Options::substitutions] followed by the ones in the `substitute` attribute.
+    substitutions: Vec<Substitution>,
+
+    /// The index of the document where the session starts, which gets the report of the session.
+    document: usize,
+
+    /// The number of blocks which are not run because of [Options::sessions],
+    /// [Options::skip_sessions] or [Options::block_filter].
+    skipped_blocks: usize,
+
+    /// The options of the document where the session starts.
+    options: &'a Options,
+}
+
+impl Session<'_> {
+    /// A hash of everything which affects the result of the session, for the [Cache].
+    fn cache_key(&self) -> String {
+        let Options {
+            placeholders,
+            filters,
+            matchers,
+            ..
+        } = self.options;
+        // Only the options which affect how the REPL runs, not those which only exist at runtime
+        // like `cancel` or the path of the sandbox.
+        let SpawnOptions {
+            shell_cmd,
+            cmd_args,
+            backend,
+            kernel,
+            jupyter_streams,
+            mode,
+            terminal,
+            timeout_ms,
+            idle_timeout_ms,
+            max_output_bytes,
+            container,
+            container_runtime,
+            mount_dir,
+            ssh,
+            env,
+            locale,
+            encoding,
+            quit,
+            reset_cmd,
+            separate_stderr,
+            cancel: _,
+            sandbox: _,
+        } = &self.spawn_options;
+        let spawn_options = format!(
+            "{:?}",
+            (
+                (shell_cmd, cmd_args, backend, kernel, jupyter_streams, mode),
+                (terminal, timeout_ms, idle_timeout_ms, max_output_bytes),
+                (container, container_runtime, mount_dir, ssh, env, locale),
+                (encoding, quit, reset_cmd, separate_stderr),
+            )
+        );
+        history::hash_code(&format!(
+            "{}\n{spawn_options}\n{}\n{}\n{:?}\n{:?}\n{:?}\n{:?}\n{}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{placeholders:?}\n{filters:?}\n{matchers:?}",
+            env!("CARGO_PKG_VERSION"),
+            self.initial_skip,
+            self.skip_banner,
+            self.require_version.as_ref().map(Regex::as_str),
+            self.version_cmd,
+            self.setup_cmd,
+            self.teardown_cmd,
+            self.sandbox,
+            self.echo,
+            self.comparison,
+            self.normalize_prompt,
+            self.ignore_lines.as_ref().map(Regex::as_str),
+            self.substitutions
+                .iter()
+                .map(Substitution::as_str)
+                .collect::<Vec<_>>(),
+            self.blocks
+                .iter()
+                .map(ReplBlock::cache_key)
+                .collect::<Vec<_>>(),
+        ))
+    }
+}
+
+/// Whether a session is limited to one document or may continue in the following documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionScope {
+    Document,
+    Global,
+}
+
+impl std::str::FromStr for SessionScope {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "document" => Ok(Self::Document),
+            "global" => Ok(Self::Global),
+            _ => Err("Expected document or global".to_string()),
+        }
+    }
+}
+
+/// Identifies a session in a group of documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct SessionKey<'a> {
+    /// The index of the document, or [None] for a global session.
+    document: Option<usize>,
+    name: &'a str,
+}
+
+/// All sessions in a group of documents.
+#[derive(Debug, Default)]
+struct Sessions<'a> {
+    sessions: HashMap<SessionKey<'a>, Session<'a>>,
+
+    /// The document, the index and the session of every enabled code block, in order.
+    blocks: Vec<(usize, usize, SessionKey<'a>)>,
+
+    /// The order in which the sessions are run, see [order_sessions].
+    order: Vec<SessionKey<'a>>,
+}
+
+/// The session which a block in document `document` refers to by `name` in the `after` or
+/// `needs` attributes: the session in the same document, or a global session.
+fn dependency_key<'a>(
+    exists: impl Fn(&SessionKey<'a>) -> bool,
+    document: usize,
+    name: &'a str,
+) -> Option<SessionKey<'a>> {
+    [Some(document), None]
+        .into_iter()
+        .map(|document| SessionKey { document, name })
+        .find(exists)
+}
+
+/// Order the sessions so that every session is run after the sessions in the `after` and `needs`
+/// attributes of its blocks, and otherwise in the order they start. Errors about unknown
+/// sessions or blocks and cycles are returned with the index of their document.
+fn order_sessions<'a>(
+    sessions: &Sessions<'a>,
+) -> Result<Vec<SessionKey<'a>>, Vec<(usize, String)>> {
+    let mut errors = Vec::new();
+    // The sessions in the order they start.
+    let mut started = Vec::new();
+    // The sessions which every session must be run after.
+    let mut dependencies: HashMap<SessionKey, Vec<SessionKey>> = HashMap::new();
+    // The number of blocks of every session so far.
+    let mut block_counts: HashMap<SessionKey, usize> = HashMap::new();
+    for (document, idx, key) in &sessions.blocks {
+        let count = block_counts.entry(*key).or_insert_with(|| {
+            started.push(*key);
+            0
+        });
+        // The blocks of a session are in the same order as in `sessions.blocks`.
+        let block = &sessions.sessions[key].blocks[*count];
+        *count += 1;
+        let after = block.after.iter().map(|x| (*x, None));
+        let needs = block.needs.iter().map(|(x, number)| (*x, Some(*number)));
+        for (name, number) in after.chain(needs) {
+            let error = |e| {
+                let e = format!("Code block {}: In session {}: {e}", idx + 1, key.name);
+                (*document, e)
+            };
+            let Some(dependency) =
+                dependency_key(|x| sessions.sessions.contains_key(x), *document, name)
+            else {
+                errors.push(error(format!("Unknown session {name}.")));
+                continue;
+            };
+            let blocks = sessions.sessions[&dependency].blocks.len();
+            if let Some(number) = number.filter(|x| *x > blocks) {
+                errors.push(error(format!(
+                    "Block {number} of session {name} is needed, but it only has {blocks} blocks."
+                )));
+                continue;
+            }
+            dependencies.entry(*key).or_default().push(dependency);
+        }
+    }
+    let mut order = Vec::new();
+    for key in started {
+        if let Err(cycle) = visit_session(key, &dependencies, &mut Vec::new(), &mut order) {
+            let names: Vec<&str> = cycle.iter().map(|x| x.name).collect();
+            errors.push((
+                sessions.sessions[&cycle[0]].document,
+                format!(
+                    "The sessions must be run after each other in a cycle: {}.",
+                    names.join(" -> ")
+                ),
+            ));
+            break;
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(order),
+        false => Err(errors),
+    }
+}
+
+/// Add `key` to `order` after the sessions it must be run after, with a depth first search.
+/// `path` holds the sessions which are being visited, and a cycle is returned if `key` is among
+/// them.
+fn visit_session<'a>(
+    key: SessionKey<'a>,
+    dependencies: &HashMap<SessionKey<'a>, Vec<SessionKey<'a>>>,
+    path: &mut Vec<SessionKey<'a>>,
+    order: &mut Vec<SessionKey<'a>>,
+) -> Result<(), Vec<SessionKey<'a>>> {
+    if order.contains(&key) {
+        return Ok(());
+    }
+    if let Some(i) = path.iter().position(|x| *x == key) {
+        let mut cycle = path[i..].to_vec();
+        cycle.push(key);
+        return Err(cycle);
+    }
+    path.push(key);
+    for dependency in dependencies.get(&key).into_iter().flatten() {
+        visit_session(*dependency, dependencies, path, order)?;
+    }
+    path.pop();
+    order.push(key);
+    Ok(())
+}
+
+/// Given a group of pandoc documents, collect all REPL sessions.
+///
+/// A session with `scope=global` continues in the following documents: blocks with the same name
+/// belong to it instead of starting new sessions. All errors are collected and returned for
+/// every document, with the number of the code block where they occurred.
+fn get_sessions<'a>(
+    documents: &[(&'a Pandoc, &'a Options)],
+    session_defaults: &'a [anyhow::Result<SessionDefaults>],
+) -> (Sessions<'a>, Vec<Vec<String>>) {
+    let mut sessions = Sessions::default();
+    let mut errors = vec![Vec::new(); documents.len()];
+    // Sessions whose first block has errors. Their other blocks are skipped to not report errors
+    // which follow from the first one.
+    let mut failed_sessions = HashSet::new();
+    for (document_idx, ((document, options), session_defaults)) in
+        documents.iter().zip(session_defaults).enumerate()
+    {
+        let session_defaults = match session_defaults {
+            Ok(x) => x,
+            Err(e) => {
+                errors[document_idx].push(e.to_string());
+                continue;
+            }
+        };
+        for block in iter_code_blocks(document, session_defaults) {
+            let (idx, session_name) = (block.idx, block.session_name);
+            if !options.lenient {
+                for e in block.unknown_attrs() {
+                    errors[document_idx].push(format!("Code block {}: {e}", idx + 1));
+                }
+            }
+            if !block.is_enabled(options) {
+                continue;
+            }
+            let global = SessionKey {
+                document: None,
+                name: session_name,
+            };
+            let local = SessionKey {
+                document: Some(document_idx),
+                name: session_name,
+            };
+            let exists = |key| sessions.sessions.contains_key(key) || failed_sessions.contains(key);
+            let key = if exists(&global) {
+                global
+            } else if exists(&local) {
+                local
+            } else {
+                let default_scope = if options.shared_sessions {
+                    SessionScope::Global
+                } else {
+                    SessionScope::Document
                 };
-                let (before_prompt, actual_prompt) = process
-                    .reader
-                    .read_until(&rexpect::ReadUntil::Regex(prompt_regex))?;
-                let read_lines: Vec<&str> = before_prompt.lines().collect();
-                if let Some(updated) = pattern::matchit(initial_output, &read_lines)
-                    .map_err(|e| anyhow::anyhow!("Pattern mismatch: {e}"))?
+                match block.parse_attr_or_default("scope", options) {
+                    Ok(scope) if scope.unwrap_or(default_scope) == SessionScope::Global => global,
+                    Ok(_) => local,
+                    Err(e) => {
+                        errors[document_idx].push(format!("Code block {}: {e}", idx + 1));
+                        failed_sessions.insert(local);
+                        continue;
+                    }
+                }
+            };
+            if failed_sessions.contains(&key) {
+                continue;
+            }
+            if let Some(alt_of) = block.attr("alt_of") {
+                if let Err(e) =
+                    add_alternative(&mut sessions.sessions, key, document_idx, &block, alt_of)
                 {
-                    updated_repl_block.push_owned(updated.as_slice());
-                } else {
-                    updated_repl_block.push_borrowed(initial_output);
+                    errors[document_idx].push(format!("Code block {}: {e}", idx + 1));
                 }
-
-                match prompt {
-                    ExpectedPrompt::Updatable => {
-                        updated_repl_block.push_owned(&[&format!("{}{}", actual_prompt, cmd)])
+                continue;
+            }
+            let is_first = !sessions.sessions.contains_key(&key);
+            match add_block_to_session(&mut sessions.sessions, key, document_idx, block, options) {
+                Ok(()) => sessions.blocks.push((document_idx, idx, key)),
+                Err(e) => {
+                    errors[document_idx].push(format!("Code block {}: {e}", idx + 1));
+                    if is_first {
+                        failed_sessions.insert(key);
                     }
-                    ExpectedPrompt::Flexible | ExpectedPrompt::Fixed(_) => {
-                        updated_repl_block.push_borrowed(&[entire_prompt_line])
+                }
+            }
+        }
+    }
+    // Unknown sessions may follow from other errors.
+    if errors.iter().all(Vec::is_empty) {
+        match order_sessions(&sessions) {
+            Ok(order) => sessions.order = order,
+            Err(order_errors) => {
+                for (document, e) in order_errors {
+                    errors[document].push(e);
+                }
+            }
+        }
+    }
+    (sessions, errors)
+}
+
+/// Add a block to the session `key` in `sessions`, starting the session if it is the first block.
+/// `document` is the index of the document of the block.
+fn add_block_to_session<'a>(
+    sessions: &mut HashMap<SessionKey<'a>, Session<'a>>,
+    key: SessionKey<'a>,
+    document: usize,
+    block: PandocBlock<'a>,
+    options: &'a Options,
+) -> anyhow::Result<()> {
+    let session_name = block.session_name;
+    let parse_prompt = |x: &str| {
+        if x == AUTO_PROMPT {
+            return Ok(Rc::new(Prompt::auto()));
+        }
+        Prompt::new(x).map(Rc::new).map_err(|e| {
+            anyhow::anyhow!(
+                "In session {session_name}: Bad regular expression for prompt: {x}: {e}"
+            )
+        })
+    };
+    let shell_cmd = block.attr("cmd");
+    let prompt = block.attr("prompt").map(parse_prompt).transpose()?;
+    let prompt_char = block.attr("prompt_char");
+    let expected = block.lines();
+    if block.inline && expected.is_empty() {
+        anyhow::bail!("In session {session_name}: An inline code span must contain a command.");
+    }
+    let filters = block
+        .attr_or_default("filter", options)
+        .map_or(Vec::new(), |x| x.split(',').map(str::trim).collect());
+    if let Some(filter) = filters.iter().find(|x| !options.filters.contains_key(**x)) {
+        anyhow::bail!("In session {session_name}: Unknown filter: {filter}");
+    }
+    let matcher = block.attr_or_default("matcher", options);
+    if let Some(matcher) = matcher.filter(|x| !options.matchers.contains_key(*x)) {
+        anyhow::bail!("In session {session_name}: Unknown matcher: {matcher}");
+    }
+    let match_mode = block
+        .parse_attr_or_default("match", options)?
+        .unwrap_or(MatchMode::Exact);
+    let on_mismatch = block
+        .parse_attr_or_default("on_mismatch", options)?
+        .unwrap_or(OnMismatch::Stop);
+    let output = block
+        .parse_attr_or_default("output", options)?
+        .unwrap_or(OutputMode::Match);
+    let expect_eof = block
+        .parse_attr_or_default("expect_eof", options)?
+        .unwrap_or(false);
+    let max_duration = block
+        .parse_attr_or_default::<humantime::Duration>("max_duration", options)?
+        .map(Into::into);
+    let retries = block
+        .parse_attr_or_default("retries", options)?
+        .unwrap_or(options.retries);
+    let after = block
+        .attr("after")
+        .map_or(Vec::new(), |x| x.split(',').map(str::trim).collect());
+    let needs = block
+        .attr("needs")
+        .map_or(Vec::new(), |x| {
+            x.split(',').map(str::trim).collect::<Vec<_>>()
+        })
+        .into_iter()
+        .map(|need| {
+            need.rsplit_once(':')
+                .and_then(|(name, number)| Some((name, number.parse().ok().filter(|x| *x > 0)?)))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "In session {session_name}: Expected a session and the number of a \
+                         block like setup:2 in needs, not `{need}`."
+                    )
+                })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let assert_prompt = block
+        .attr_or_default("assert_prompt", options)
+        .map_or(Vec::new(), |x| x.split(',').map(str::trim).collect())
+        .into_iter()
+        .map(|capture| {
+            capture.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!(
+                    "In session {session_name}: Expected a group of the prompt regex and its text \
+                     like db=mydb in assert_prompt, not `{capture}`."
+                )
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Annotations like `#repl: optional` are checked when the block is read, since an unknown one
+    // would otherwise be matched as text.
+    let annotations = match sessions.get(&key) {
+        Some(session) => session.comparison.annotations,
+        None => block
+            .parse_attr_or_default("line_annotations", options)?
+            .unwrap_or(false),
+    };
+    if annotations {
+        for line in &expected {
+            pattern::parse_annotations(line)
+                .map_err(|e| anyhow::anyhow!("In session {session_name}: {e}: {line}"))?;
+        }
+    }
+
+    use std::collections::hash_map::Entry::*;
+    match sessions.entry(key) {
+        Vacant(entry) => {
+            let preset = match block.attr_or_default("preset", options) {
+                Some(name) => Some(options.preset(name).ok_or_else(|| {
+                    let mut names: Vec<&str> = builtin_preset_names().collect();
+                    names.extend(options.presets.keys().map(String::as_str));
+                    match closest_name(name, &names) {
+                        Some(x) => anyhow::anyhow!(
+                            "In session {session_name}: Unknown preset {name}, did you mean {x}?"
+                        ),
+                        None => {
+                            anyhow::anyhow!("In session {session_name}: Unknown preset {name}.")
+                        }
                     }
+                })?),
+                None => None,
+            };
+            // Attributes on the block override the preset, which overrides the defaults.
+            let preset_attr = |key| preset.and_then(|x| x.attr(key));
+            let backend = block
+                .parse_attr_or_default("backend", options)?
+                .unwrap_or(BackendKind::Process);
+            // `cmd_args` is a JSON array of the program and its arguments, which are not split
+            // like `cmd`. It is kept as the command in the reports.
+            if shell_cmd.is_some() && block.attr("cmd_args").is_some() {
+                anyhow::bail!("In session {session_name}: cmd and cmd_args can not both be set.");
+            }
+            let cmd_args_text = match shell_cmd.or_else(|| preset_attr("cmd")) {
+                Some(_) => block.attr("cmd_args"),
+                None => block.attr_or_default("cmd_args", options),
+            };
+            let cmd_args = cmd_args_text
+                .map(serde_json::from_str::<Vec<String>>)
+                .transpose()
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "In session {session_name}: cmd_args must be a JSON array of strings: {e}"
+                    )
+                })?;
+            let shell_cmd = cmd_args_text.or(shell_cmd);
+            let shell_cmd = shell_cmd
+                .or_else(|| preset_attr("cmd"))
+                .or_else(|| block.default_attr("cmd", options));
+            // On Windows `cmd_windows` overrides `cmd`, so a session can be run on both.
+            let shell_cmd = block
+                .attr("cmd_windows")
+                .or_else(|| block.default_attr("cmd_windows", options))
+                .filter(|_| cfg!(windows))
+                .or(shell_cmd);
+            let shell_cmd = match backend {
+                BackendKind::Jupyter => shell_cmd.or(Some(DEFAULT_JUPYTER_PYTHON)),
+                BackendKind::Process => shell_cmd,
+            };
+            let Some(shell_cmd) = shell_cmd else {
+                anyhow::bail!("No command provided at beginning of session {session_name}.");
+            };
+            let prompt = match prompt {
+                Some(prompt) => Some(prompt),
+                None => preset_attr("prompt")
+                    .or_else(|| {
+                        block
+                            .classes
+                            .iter()
+                            .find_map(|x| options.default_prompts.get(x))
+                            .map(String::as_str)
+                    })
+                    .or_else(|| block.default_attr("prompt", options))
+                    .or_else(|| {
+                        (backend == BackendKind::Jupyter).then_some(backend::JUPYTER_PROMPT)
+                    })
+                    .map(parse_prompt)
+                    .transpose()?,
+            };
+            let Some(prompt) = prompt else {
+                anyhow::bail!("ExpectedPrompt must be specified for the session {session_name}.");
+            };
+            let prompt_char = prompt_char
+                .or_else(|| block.default_attr("prompt_char", options))
+                .unwrap_or(DEFAULT_PROMPT_CHAR);
+            let normalize_prompt = block.attr_or_default("normalize_prompt", options);
+            if let Some(text) = normalize_prompt.filter(|_| !prompt.detect) {
+                if prompt
+                    .document_regex
+                    .find(text)
+                    .is_none_or(|m| m.start() != 0)
+                {
+                    anyhow::bail!(
+                        "In session {session_name}: normalize_prompt `{text}` doesn't match the \
+                         prompt regex, so the normalized prompts couldn't be read."
+                    );
                 }
             }
-            // TODO: Match the rest of the output.
-            updated_repl_blocks.push(
-                updated_repl_block
-                    .maybe_owned()
-                    .map(|x| x.into_iter().reduce(|x, y| x + "\n" + &y).unwrap()),
-            );
+            let ignore_lines = options
+                .ignore_lines
+                .iter()
+                .map(Regex::as_str)
+                .chain(block.attr_or_default("ignore_lines", options))
+                .map(|x| format!("(?:{x})"))
+                .reduce(|x, y| format!("{x}|{y}"))
+                .map(|x| Regex::new(&x))
+                .transpose()
+                .map_err(|e| {
+                    anyhow::anyhow!("In session {session_name}: Bad regex for ignore_lines: {e}")
+                })?;
+            let require_version = block
+                .attr_or_default("require_version", options)
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| {
+                    anyhow::anyhow!("In session {session_name}: Bad regex for require_version: {e}")
+                })?;
+            let mut substitutions = options.substitutions.clone();
+            if let Some(script) = block.attr_or_default("substitute", options) {
+                substitutions.extend(
+                    parse_substitutions(script)
+                        .map_err(|e| anyhow::anyhow!("In session {session_name}: {e:#}"))?,
+                );
+            }
+            let continuation = block
+                .attr("continuation_prompt")
+                .or_else(|| preset_attr("continuation_prompt"))
+                .or_else(|| block.default_attr("continuation_prompt", options))
+                .map(parse_prompt)
+                .transpose()?;
+            let mode = block
+                .parse_attr_or_default("mode", options)?
+                .unwrap_or(ReplMode::Pty);
+            let separate_stderr = block
+                .parse_attr_or_default("separate_stderr", options)?
+                .unwrap_or(false);
+            if separate_stderr && (mode != ReplMode::Pipe || backend != BackendKind::Process) {
+                anyhow::bail!(
+                    "In session {session_name}: separate_stderr can only be used with mode=pipe, \
+                     since stderr is not kept apart from stdout in a terminal."
+                );
+            }
+            let container = block.attr_or_default("container", options);
+            let ssh = block.attr_or_default("ssh", options);
+            if backend == BackendKind::Jupyter && (container.is_some() || ssh.is_some()) {
+                anyhow::bail!(
+                    "In session {session_name}: backend=jupyter can't be used with container or \
+                     ssh, since the kernel is run by a helper on this machine."
+                );
+            }
+            let terminal = TerminalSettings {
+                clean_env: block
+                    .parse_attr_or_default("clean_env", options)?
+                    .unwrap_or(true),
+                cols: block
+                    .parse_attr_or_default("pty_cols", options)?
+                    .unwrap_or(DEFAULT_PTY_COLS),
+                rows: block
+                    .parse_attr_or_default("pty_rows", options)?
+                    .unwrap_or(DEFAULT_PTY_ROWS),
+            };
+            entry.insert(Session {
+                spawn_options: SpawnOptions {
+                    shell_cmd,
+                    cmd_args,
+                    backend,
+                    kernel: block
+                        .attr_or_default("kernel", options)
+                        .unwrap_or(DEFAULT_JUPYTER_KERNEL),
+                    jupyter_streams: block
+                        .parse_attr_or_default("jupyter_streams", options)?
+                        .unwrap_or_default(),
+                    mode,
+                    terminal,
+                    timeout_ms: options.timeout.map_or(TIMEOUT_MS, |x| x.as_millis() as u64),
+                    idle_timeout_ms: options.idle_timeout.map(|x| x.as_millis() as u64),
+                    max_output_bytes: block
+                        .parse_attr_or_default("max_output_bytes", options)?
+                        .unwrap_or(MAX_OUTPUT_BYTES),
+                    env: &options.env,
+                    locale: block.attr_or_default("locale", options),
+                    encoding: block
+                        .parse_attr_or_default("encoding", options)?
+                        .unwrap_or_default(),
+                    container,
+                    container_runtime: block
+                        .attr_or_default("container_runtime", options)
+                        .unwrap_or(DEFAULT_CONTAINER_RUNTIME),
+                    mount_dir: options.document_dir.as_deref(),
+                    ssh,
+                    quit: block
+                        .attr("quit")
+                        .or_else(|| preset_attr("quit"))
+                        .or_else(|| block.default_attr("quit", options)),
+                    reset_cmd: block
+                        .attr("reset_cmd")
+                        .or_else(|| preset_attr("reset_cmd"))
+                        .or_else(|| block.default_attr("reset_cmd", options)),
+                    separate_stderr,
+                    cancel: options.cancel.as_ref(),
+                    // The directory is created when the session is run.
+                    sandbox: None,
+                },
+                blocks: vec![ReplBlock {
+                    prompt,
+                    prompt_char,
+                    continuation,
+                    expected,
+                    inline: block.inline,
+                    filters,
+                    matcher,
+                    match_mode,
+                    on_mismatch,
+                    output,
+                    expect_eof,
+                    max_duration,
+                    retries,
+                    after,
+                    needs,
+                    document,
+                    number: 1,
+                    assert_prompt,
+                    alternatives: Vec::new(),
+                }],
+                initial_skip: block
+                    .parse_attr_or_default("initial_skip", options)?
+                    .unwrap_or(0),
+                skip_banner: block
+                    .parse_attr_or_default("skip_banner", options)?
+                    .unwrap_or(false),
+                require_version,
+                version_cmd: block.attr_or_default("version_cmd", options),
+                echo: block
+                    .parse_attr_or_default("echo", options)?
+                    .unwrap_or(Echo::Keep),
+                comparison: Comparison {
+                    whitespace: block
+                        .parse_attr_or_default("whitespace", options)?
+                        .unwrap_or_default(),
+                    case: block
+                        .parse_attr_or_default("case", options)?
+                        .unwrap_or_default(),
+                    unicode_form: block.parse_attr_or_default("unicode_normalize", options)?,
+                    tokens: &options.tokens,
+                    annotations,
+                },
+                normalize_prompt,
+                ignore_lines,
+                substitutions,
+                send_delay: Duration::from_millis(
+                    block
+                        .parse_attr_or_default("send_delay_ms", options)?
+                        .unwrap_or(0),
+                ),
+                bracketed_paste: block
+                    .parse_attr_or_default("bracketed_paste", options)?
+                    .unwrap_or(false),
+                setup_cmd: block
+                    .attr_or_default("setup_cmd", options)
+                    .or(options.setup_cmd.as_deref()),
+                teardown_cmd: block
+                    .attr_or_default("teardown_cmd", options)
+                    .or(options.teardown_cmd.as_deref()),
+                sandbox: block
+                    .parse_attr_or_default("sandbox", options)?
+                    .unwrap_or(false),
+                prompt_idle: Duration::from_millis(
+                    block
+                        .parse_attr_or_default("prompt_idle_ms", options)?
+                        .unwrap_or(DEFAULT_PROMPT_IDLE_MS),
+                ),
+                document,
+                skipped_blocks: 0,
+                options,
+            });
+        }
+        Occupied(mut entry) => {
+            if let Some(shell_cmd) = shell_cmd {
+                anyhow::bail!(
+                    "cmd is specified a second time for session {session_name} as `{shell_cmd}`."
+                );
+            }
+            if let Some(key) = SESSION_ATTRS.iter().find(|x| block.attr(x).is_some()) {
+                anyhow::bail!("In session {session_name}: {key} can only be set on the first block of the session.");
+            }
+            let last_block = entry.get().blocks.last().unwrap();
+            let prompt = prompt.unwrap_or_else(|| last_block.prompt.clone());
+            let prompt_char = prompt_char.unwrap_or(last_block.prompt_char);
+            let continuation = last_block.continuation.clone();
+            let number = entry.get().blocks.len() + 1;
+            entry.get_mut().blocks.push(ReplBlock {
+                prompt,
+                prompt_char,
+                continuation,
+                expected,
+                inline: block.inline,
+                filters,
+                matcher,
+                match_mode,
+                on_mismatch,
+                output,
+                expect_eof,
+                max_duration,
+                retries,
+                after,
+                needs,
+                document,
+                number,
+                assert_prompt,
+                alternatives: Vec::new(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Add a block with the `alt_of` attribute, like `py:3`, to the alternatives of the block it
+/// names in the session `key`. That block must be an earlier block of the same session in the
+/// same document, whose index is `document`.
+fn add_alternative<'a>(
+    sessions: &mut HashMap<SessionKey<'a>, Session<'a>>,
+    key: SessionKey<'a>,
+    document: usize,
+    block: &PandocBlock<'a>,
+    alt_of: &str,
+) -> anyhow::Result<()> {
+    let session_name = block.session_name;
+    let number = alt_of
+        .rsplit_once(':')
+        .filter(|(name, _)| *name == session_name)
+        .and_then(|(_, number)| number.parse::<usize>().ok().filter(|x| *x > 0))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "In session {session_name}: Expected the session and the number of a block like \
+                 {session_name}:2 in alt_of, not `{alt_of}`."
+            )
+        })?;
+    let expected = block.lines();
+    if block.inline && expected.is_empty() {
+        anyhow::bail!("In session {session_name}: An inline code span must contain a command.");
+    }
+    let Some(repl_block) = sessions
+        .get_mut(&key)
+        .and_then(|x| x.blocks.get_mut(number - 1))
+        .filter(|x| x.document == document)
+    else {
+        anyhow::bail!(
+            "In session {session_name}: alt_of must name an earlier block of the session in the \
+             same document, but there is no block {number} before it."
+        );
+    };
+    repl_block.alternatives.push(Alternative {
+        expected,
+        inline: block.inline,
+        number: block.idx + 1,
+    });
+    Ok(())
+}
+
+/// The kind of prompt that is expected.
+#[derive(Debug)]
+enum ExpectedPrompt<'a> {
+    /// The prompt should match exactly this string.
+    Fixed(&'a str),
+
+    /// The prompt should match the provided prompt regex.
+    Flexible,
+
+    /// The prompt should match the provided prompt regex and the promptstring in the document
+    /// should be updated with the actual prompt.
+    Updatable,
+
+    /// The command is an inline code span, which has no prompt in the document. The prompt
+    /// should match the provided prompt regex.
+    Inline,
+}
+
+/// A line starting with this string followed by a command is a prompt line where the prompt should
+/// be updated with the actual prompt.
+const UPDATABLE_PROMPT: &str = "???";
+
+/// A line only consisting of this directive kills the REPL and starts it again.
+const RESTART_DIRECTIVE: &str = "{restart}";
+
+/// The escape codes which start and end a paste with bracketed paste, see
+/// [Session::bracketed_paste].
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
+/// The lines which start and end a region of input after a command, like the lines of a heredoc,
+/// which are sent to the REPL as they are.
+const INPUT_START: &str = "{input}";
+const INPUT_END: &str = "{/input}";
+
+/// Information about invoking a command in a REPL.
+#[derive(Debug)]
+struct CmdInvokation<'a> {
+    /// The index of the first line of the invocation in the lines of the block.
+    start: usize,
+
+    prompt: ExpectedPrompt<'a>,
+
+    /// The command to run.
+    cmd: &'a str,
+
+    /// More lines of the command, which are sent when the REPL shows the continuation prompt.
+    continuation_lines: Vec<&'a str>,
+
+    /// The prompt and the command together as it appeared in the document. This is usually one
+    /// line but may be more if the prompt spans multiple lines or there are continuation lines.
+    entire_prompt_lines: &'a [&'a str],
+
+    /// Lines of input in an `{input}` region after the command, which are sent right after it
+    /// without waiting for any prompt.
+    input_lines: &'a [&'a str],
+
+    /// The number of lines of the `{input}` region in the document, with the lines which start
+    /// and end it, or 0 if there is none.
+    input_region: usize,
+
+    /// Lines of expected output.
+    expected_output: &'a [&'a str],
+}
+
+/// Something which happens in a [ReplBlock] after the initial output.
+#[derive(Debug)]
+enum BlockItem<'a> {
+    /// Run a command in the REPL.
+    Cmd(CmdInvokation<'a>),
+
+    /// Kill the REPL and spawn it again with the same shell command.
+    Restart {
+        /// The index of the directive in the lines of the block.
+        start: usize,
+
+        /// The directive as it appeared in the document.
+        directive_line: &'a str,
+
+        /// Lines of expected output from the new REPL before its first prompt.
+        expected_output: &'a [&'a str],
+    },
+}
+
+/// A list of command invokations.
+#[derive(Debug)]
+struct CmdInvokations<'a> {
+    /// The expected output (list of lines) before the first command.
+    initial_output: &'a [&'a str],
+    items: Vec<BlockItem<'a>>,
+}
+
+impl<'a> BlockItem<'a> {
+    /// Get a mutable reference to the expected output after this item.
+    fn expected_output_mut(&mut self) -> &mut &'a [&'a str] {
+        match self {
+            BlockItem::Cmd(x) => &mut x.expected_output,
+            BlockItem::Restart {
+                expected_output, ..
+            } => expected_output,
         }
-        updated_blocks.insert(session_name.to_string(), updated_repl_blocks);
     }
-    Ok(updated_blocks)
+
+    /// The index of the first line of the expected output after this item in the lines of the
+    /// block.
+    fn output_start(&self) -> usize {
+        match self {
+            BlockItem::Cmd(x) => x.start + x.entire_prompt_lines.len() + x.input_region,
+            BlockItem::Restart { start, .. } => start + 1,
+        }
+    }
+}
+
+/// Parse the lines of a [ReplBlock] starting at line `start` as a [BlockItem] with empty
+/// expected output, or return `None` if the line is an output line.
+///
+/// Returns the item together with the number of lines it spans.
+fn parse_block_item<'a>(
+    repl_block: &ReplBlock<'a>,
+    lines: &'a [&'a str],
+    start: usize,
+) -> Option<(BlockItem<'a>, usize)> {
+    let lines = &lines[start..];
+    let line = lines[0];
+    if line.trim() == RESTART_DIRECTIVE {
+        return Some((
+            BlockItem::Restart {
+                start,
+                directive_line: line,
+                expected_output: &[],
+            },
+            1,
+        ));
+    }
+    let (prompt, cmd, line_count) = if let Some(cmd) = line
+        .strip_prefix(UPDATABLE_PROMPT)
+        .filter(|cmd| !cmd.trim().is_empty())
+    {
+        (ExpectedPrompt::Updatable, cmd, 1)
+    } else if repl_block.is_multiline_prompt() {
+        // Match the prompt against all remaining lines to let it span multiple lines.
+        let text = lines.join("\n");
+        let prompt = repl_block
+            .prompt
+            .document_regex
+            .find(&text)
+            .filter(|m| m.start() == 0)?;
+        let line_count = prompt.as_str().matches('\n').count() + 1;
+        let last_line = lines[line_count - 1];
+        // The prompt ends this many bytes before the end of the last line.
+        let rest = text[prompt.end()..].split('\n').next().unwrap().len();
+        (
+            ExpectedPrompt::Flexible,
+            &last_line[last_line.len() - rest..],
+            line_count,
+        )
+    } else {
+        let prompt = repl_block
+            .prompt
+            .document_regex
+            .find(line)
+            .filter(|m| m.start() == 0)?;
+        (ExpectedPrompt::Flexible, &line[prompt.end()..], 1)
+    };
+    // The following lines which start with the continuation prompt, but not with the prompt.
+    let mut continuation_lines = Vec::new();
+    if let Some(continuation) = &repl_block.continuation {
+        for line in &lines[line_count..] {
+            let is_prompt = |x: &Regex| x.find(line).is_some_and(|m| m.start() == 0);
+            match continuation.document_regex.find(line) {
+                Some(m) if m.start() == 0 && !is_prompt(&repl_block.prompt.document_regex) => {
+                    continuation_lines.push(&line[m.end()..])
+                }
+                _ => break,
+            }
+        }
+    }
+    let line_count = line_count + continuation_lines.len();
+    // The region ends at the end of the block if there is no `{/input}` line.
+    let (input_lines, input_region) = match lines.get(line_count) {
+        Some(line) if line.trim() == INPUT_START => {
+            let rest = &lines[line_count + 1..];
+            match rest.iter().position(|x| x.trim() == INPUT_END) {
+                Some(end) => (&rest[..end], end + 2),
+                None => (rest, rest.len() + 1),
+            }
+        }
+        _ => (&lines[..0], 0),
+    };
+    Some((
+        BlockItem::Cmd(CmdInvokation {
+            start,
+            prompt,
+            cmd,
+            continuation_lines,
+            entire_prompt_lines: &lines[..line_count],
+            input_lines,
+            input_region,
+            expected_output: &[],
+        }),
+        line_count + input_region,
+    ))
+}
+
+/// Split the lines of a [ReplBlock] into the initial output and a list of [BlockItem]s.
+fn repl_block_to_cmd_invocations<'a>(repl_block: &'a ReplBlock<'a>) -> CmdInvokations<'a> {
+    cmd_invocations(repl_block, &repl_block.expected, repl_block.inline)
+}
+
+/// Split `lines` into the initial output and a list of [BlockItem]s with the prompts of
+/// `repl_block`, like [repl_block_to_cmd_invocations] but for other lines, like those of an
+/// [Alternative]. `inline` is set if the lines are from an inline code span.
+fn cmd_invocations<'a>(
+    repl_block: &ReplBlock<'a>,
+    lines: &'a [&'a str],
+    inline: bool,
+) -> CmdInvokations<'a> {
+    if inline {
+        return CmdInvokations {
+            initial_output: &lines[..0],
+            items: vec![BlockItem::Cmd(CmdInvokation {
+                start: 0,
+                prompt: ExpectedPrompt::Inline,
+                cmd: lines[0],
+                continuation_lines: Vec::new(),
+                entire_prompt_lines: &lines[..1],
+                input_lines: &[],
+                input_region: 0,
+                expected_output: &lines[1..],
+            })],
+        };
+    }
+    let mut initial_output = lines;
+    let mut items: Vec<BlockItem> = Vec::new();
+    // The index of the first line after the last item.
+    let mut output_start = 0;
+    let mut i = 0;
+    while i < lines.len() {
+        let Some((item, line_count)) = parse_block_item(repl_block, lines, i) else {
+            i += 1;
+            continue;
+        };
+        let output = &lines[output_start..i];
+        match items.last_mut() {
+            Some(last_item) => *last_item.expected_output_mut() = output,
+            None => initial_output = output,
+        }
+        items.push(item);
+        i += line_count;
+        output_start = i;
+    }
+    if let Some(last_item) = items.last_mut() {
+        *last_item.expected_output_mut() = &lines[output_start..];
+    }
+    CmdInvokations {
+        initial_output,
+        items,
+    }
+}
+
+/// Whether two lists of [BlockItem]s send the same commands, so that the output of one can be
+/// matched against the expected output of the other.
+fn same_commands(x: &[BlockItem], y: &[BlockItem]) -> bool {
+    x.len() == y.len()
+        && x.iter().zip(y).all(|items| match items {
+            (BlockItem::Cmd(x), BlockItem::Cmd(y)) => {
+                x.cmd == y.cmd
+                    && x.continuation_lines == y.continuation_lines
+                    && x.input_lines == y.input_lines
+            }
+            (BlockItem::Restart { .. }, BlockItem::Restart { .. }) => true,
+            _ => false,
+        })
+}
+
+/// The [Alternative]s of a block while it is run, and which of them have matched all output
+/// so far.
+#[derive(Debug)]
+struct Alternatives<'a> {
+    /// The number of the code block of every alternative.
+    numbers: Vec<usize>,
+
+    /// The expected output of every alternative before the first prompt and after every item.
+    outputs: Vec<Vec<&'a [&'a str]>>,
+
+    /// The number of outputs which have been read.
+    read: usize,
+
+    /// The indices of the alternatives which have matched all output so far.
+    matching: Vec<usize>,
+
+    /// Whether some output didn't match the expected output of the block itself, so it only
+    /// passes if an alternative matches.
+    block_mismatched: bool,
+}
+
+impl<'a> Alternatives<'a> {
+    /// The alternatives of `repl_block`, whose own lines are split into `invocations`. Fails if
+    /// an alternative doesn't have the same commands.
+    fn new(
+        repl_block: &'a ReplBlock<'a>,
+        invocations: &CmdInvokations<'a>,
+        session_name: &str,
+    ) -> anyhow::Result<Self> {
+        let mut outputs = Vec::new();
+        for alternative in &repl_block.alternatives {
+            let CmdInvokations {
+                initial_output,
+                mut items,
+            } = cmd_invocations(repl_block, &alternative.expected, alternative.inline);
+            if !same_commands(&invocations.items, &items) {
+                anyhow::bail!(
+                    "In session {session_name}: The alternative in code block {} doesn't have the \
+                     same commands as the block.",
+                    alternative.number
+                );
+            }
+            let item_outputs = items.iter_mut().map(|x| *x.expected_output_mut());
+            outputs.push(iter::once(initial_output).chain(item_outputs).collect());
+        }
+        Ok(Self {
+            numbers: repl_block.alternatives.iter().map(|x| x.number).collect(),
+            matching: (0..outputs.len()).collect(),
+            outputs,
+            read: 0,
+            block_mismatched: false,
+        })
+    }
+
+    /// Skip an output which is not matched.
+    fn skip(&mut self) {
+        self.read += 1;
+    }
+
+    /// Match the next output with `match_output`, which is given the expected lines and an editor
+    /// for them. The block's own expected output `expected` is matched and updated in `updated`
+    /// as long as it has matched all output. The alternatives which still match are matched too,
+    /// but they are never updated. The output passes if the block or any alternative matches.
+    fn match_output(
+        &mut self,
+        expected: &'a [&'a str],
+        updated: &mut LineEditor<'_>,
+        mut match_output: impl FnMut(&'a [&'a str], &mut LineEditor<'_>) -> anyhow::Result<Vec<String>>,
+    ) -> anyhow::Result<Vec<String>> {
+        let index = self.read;
+        self.read += 1;
+        let mut first_mismatch = None;
+        let mut matching = Vec::new();
+        for i in std::mem::take(&mut self.matching) {
+            let expected = self.outputs[i][index];
+            match match_output(expected, &mut LineEditor::new(expected)) {
+                Ok(_) => matching.push(i),
+                Err(e) if e.is::<Mismatch>() => {
+                    first_mismatch.get_or_insert((i, e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.matching = matching;
+        if !self.block_mismatched {
+            match match_output(expected, updated) {
+                Err(e) if e.is::<Mismatch>() && !self.matching.is_empty() => {
+                    self.block_mismatched = true
+                }
+                Err(e) if e.is::<Mismatch>() && !self.outputs.is_empty() => {
+                    return Err(add_note(e, "None of the alternatives matched either."));
+                }
+                result => return result,
+            }
+        }
+        match first_mismatch {
+            // The block and all alternatives which had matched so far have mismatched.
+            Some((i, e)) if self.matching.is_empty() => Err(Mismatch::new(format!(
+                "In the alternative in code block {}: {e}",
+                self.numbers[i]
+            ))
+            .into()),
+            _ => {
+                updated.keep(expected.len());
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// The number of the code block of the alternative which matched, if the block itself didn't.
+    fn used(&self) -> Option<usize> {
+        self.matching
+            .first()
+            .filter(|_| self.block_mismatched)
+            .map(|i| self.numbers[*i])
+    }
+}
+
+/// Match `actual` output lines against `expected`, which starts at line `first_line` (1-based) in
+/// the block.
+///
+/// The expected lines are kept in `updated`, or replaced with the updated lines if they should be
+/// updated.
+/// On mismatch, fixes are suggested in the error or the first fix is applied if
+/// [Options::fix_suggestions] is set. If `matcher` is set, that custom matcher in
+/// [Options::matchers] is used instead of the patterns. If [Options::record] is set and nothing
+/// is expected, the actual output is recorded as if `expected` was a `???` hole.
+///
+/// The expected lines matching [Session::ignore_lines] are not matched, but they are kept, also
+/// when `???` holes are filled in. The lines are compared according to [Session::comparison].
+///
+/// Returns notes about the match if [Options::verbose] is set, like the number of lines matched
+/// by every hole.
+fn match_output<'a>(
+    all_expected: &'a [&'a str],
+    first_line: usize,
+    actual: &[&str],
+    matcher: Option<&str>,
+    match_mode: MatchMode,
+    session: &Session,
+    updated: &mut LineEditor,
+    options: &Options,
+) -> anyhow::Result<Vec<String>> {
+    let ignore_lines = session.ignore_lines.as_ref();
+    // The indices of the expected lines which are not ignored.
+    let kept: Vec<usize> = (0..all_expected.len())
+        .filter(|i| !ignore_lines.is_some_and(|regex| regex.is_match(all_expected[*i])))
+        .collect();
+    // The built-in placeholders are expanded in the expected lines, and the lines which are
+    // written to the document get the placeholders back instead of the values.
+    let machine = MachineValues::new(session.spawn_options.sandbox.as_deref());
+    let expected: Vec<Cow<str>> = kept
+        .iter()
+        .map(|i| machine.expand(all_expected[*i]))
+        .collect();
+    let expected: Vec<&str> = expected.iter().map(AsRef::as_ref).collect();
+    let expected = expected.as_slice();
+    let recorded: Vec<String> = actual.iter().map(|x| machine.restore(x)).collect();
+    let recorded: Vec<&str> = recorded.iter().map(String::as_str).collect();
+    if let Some(matcher) = matcher {
+        if let Err(message) = plugin::matches(&options.matchers[matcher], expected, actual)? {
+            return Err(Mismatch::new(format!(
+                "Mismatch reported by the matcher {matcher}: {message}"
+            ))
+            .into());
+        }
+        updated.keep(all_expected.len());
+        return Ok(Vec::new());
+    }
+    if options.record && all_expected.is_empty() && !actual.is_empty() {
+        updated.replace(0, &recorded, EditOrigin::Record);
+        return Ok(Vec::new());
+    }
+    tracing::trace!(?expected, ?actual, "matching output");
+    let matched = match match_mode {
+        MatchMode::Exact => pattern::matchit(expected, actual, session.comparison).map(|x| (x, 0)),
+        MatchMode::Prefix => pattern::matchit_prefix(expected, actual, session.comparison),
+    };
+    match matched {
+        Ok((matched, dropped)) => {
+            tracing::debug!(lines = actual.len(), "output matched");
+            // Only the `???` holes are replaced, the other expected lines are kept.
+            let mut kept_until = 0;
+            for segment in &matched {
+                if let MatchedSegment::Updated {
+                    expected: holes,
+                    actual: lines,
+                } = segment
+                {
+                    let start = kept[holes.start];
+                    updated.keep(start - kept_until);
+                    updated.replace(holes.len(), &recorded[lines.clone()], EditOrigin::Hole);
+                    kept_until = start + holes.len();
+                }
+            }
+            updated.keep(all_expected.len() - kept_until);
+            // The lines after a prefix match are dropped, unless all output should be recorded.
+            if options.update == Some(UpdatePolicy::All) {
+                updated.replace(0, &recorded[actual.len() - dropped..], EditOrigin::Output);
+            }
+            if options.verbose {
+                let notes = matched
+                    .iter()
+                    .filter_map(MatchedSegment::hole)
+                    .map(|(i, lines)| {
+                        let count = lines.len();
+                        format!(
+                            "At line {} of the block, `{}` matched {count} line{}.",
+                            first_line + kept[i],
+                            expected[i].trim(),
+                            if count == 1 { "" } else { "s" }
+                        )
+                    });
+                return Ok(notes.collect());
+            }
+        }
+        Err(e) if options.update == Some(UpdatePolicy::All) => {
+            tracing::debug!(error = %e, "output mismatched, replacing it");
+            updated.replace(all_expected.len(), &recorded, EditOrigin::Output);
+        }
+        Err(e) => {
+            tracing::debug!(error = %e, "output mismatched");
+            let suggestions = suggest::suggest(expected, actual, first_line, session.comparison);
+            match suggestions.first() {
+                Some(suggestion) if options.fix_suggestions => {
+                    // The fix is of the lines which are not ignored, so the ignored lines are kept
+                    // around it.
+                    let mut kept_until = 0;
+                    for (start, count, lines) in fix_edits(&kept, expected, &suggestion.fixed) {
+                        let lines: Vec<String> = lines.iter().map(|x| machine.restore(x)).collect();
+                        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+                        updated.keep(start - kept_until);
+                        updated.replace(count, &lines, EditOrigin::Suggestion);
+                        kept_until = start + count;
+                    }
+                    updated.keep(all_expected.len() - kept_until);
+                }
+                _ => {
+                    let mut message = format!("Pattern mismatch: {e}");
+                    for suggestion in suggestions {
+                        message += &format!("\nSuggestion: {suggestion}");
+                    }
+                    return Err(Mismatch {
+                        message,
+                        diff: Some(diff::format_diff(
+                            &expected.join("\n"),
+                            &actual.join("\n"),
+                            false,
+                        )),
+                    }
+                    .into());
+                }
+            }
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// The edits of all expected lines which turn the lines which are not ignored, `expected`, into
+/// `fixed`. `kept` are the indices of `expected` among all expected lines.
+///
+/// Returns the index of the first line, the number of lines and the lines to replace them with
+/// for every edit, in order. Only the lines which differ are replaced, so the ignored lines
+/// between them are kept. Lines which are inserted go right before the next line which is not
+/// ignored, or right after the last one.
+fn fix_edits<'b>(
+    kept: &[usize],
+    expected: &[&str],
+    fixed: &'b [String],
+) -> Vec<(usize, usize, &'b [String])> {
+    let prefix = iter::zip(expected, fixed)
+        .take_while(|(x, y)| **x == y.as_str())
+        .count();
+    let suffix = iter::zip(
+        expected[prefix..].iter().rev(),
+        fixed[prefix..].iter().rev(),
+    )
+    .take_while(|(x, y)| **x == y.as_str())
+    .count();
+    let (old, new) = (
+        prefix..expected.len() - suffix,
+        prefix..fixed.len() - suffix,
+    );
+    if old.len() == new.len() {
+        return iter::zip(old, new)
+            .map(|(i, j)| (kept[i], 1, &fixed[j..j + 1]))
+            .collect();
+    }
+    let Some(first) = old.clone().next() else {
+        let end = kept.last().map_or(0, |x| x + 1);
+        let start = kept.get(prefix).copied().unwrap_or(end);
+        return vec![(start, 0, &fixed[new])];
+    };
+    // The new lines replace the first line, and the other lines are removed.
+    iter::once((kept[first], 1, &fixed[new]))
+        .chain(old.skip(1).map(|i| (kept[i], 1, &fixed[..0])))
+        .collect()
+}
+
+/// The most times the interactive prompts are answered while waiting for the prompt after one
+/// command, in case a pager doesn't quit.
+const MAX_INTERACTIVE_RESPONSES: usize = 100;
+
+/// Read until the prompt like [ReplBackend::read_until_prompt], or like
+/// [ReplBackend::read_until_prompt_streaming] if `on_line` is given, but answer the
+/// [interactive] prompts of pagers and confirmations on the way, or fail on them.
+fn read_until_prompt_interactive(
+    process: &mut impl ReplBackend,
+    prompt_regex: &Regex,
+    mut on_line: Option<OnLine>,
+    options: &Options,
+) -> anyhow::Result<(String, Option<String>)> {
+    let prompts = || interactive::all_prompts(&options.interactive_prompts);
+    let regex = interactive::combined_regex(prompt_regex, prompts())?;
+    let mut output = String::new();
+    // After an interactive prompt, the lines are given to `on_line` when all output has been read,
+    // since the line with the prompt goes on after the answer. This is where they begin.
+    let mut deferred_lines = None;
+    for _ in 0..=MAX_INTERACTIVE_RESPONSES {
+        let (more, actual_prompt) = match on_line.as_mut().filter(|_| deferred_lines.is_none()) {
+            Some(on_line) => process.read_until_prompt_streaming(&regex, &mut **on_line),
+            None => process.read_until_prompt(&regex),
+        }
+        .map_err(|e| match e.downcast() {
+            // Report the prompt of the REPL rather than the combined regex.
+            Ok(TimeoutError { got, timeout, .. }) => TimeoutError {
+                expected: prompt_regex.to_string(),
+                got,
+                timeout,
+            }
+            .into(),
+            Err(e) => e,
+        })?;
+        tracing::debug!(bytes = more.len(), prompt = ?actual_prompt, "read output");
+        output += &more;
+        let interactive = actual_prompt
+            .as_deref()
+            .and_then(|x| interactive::find(prompt_regex, prompts(), x));
+        let Some(interactive) = interactive else {
+            if let (Some(start), Some(on_line)) = (deferred_lines, on_line) {
+                output[start..].lines().try_for_each(on_line)?;
+            }
+            return Ok((output, actual_prompt));
+        };
+        tracing::debug!(regex = %interactive.regex, "stopped at an interactive prompt");
+        deferred_lines.get_or_insert(output.rfind('\n').map_or(0, |x| x + 1));
+        match &interactive.respond {
+            Some(keys) => process.send_keys(keys)?,
+            None => anyhow::bail!(
+                "The command waits for input at `{}`, which looks like a pager or a \
+                 confirmation. Make the command non-interactive, like with `--yes` or \
+                 `PAGER=cat`, or add a response for `{}` to interactive_prompts in {}.",
+                actual_prompt.unwrap_or_default().trim_end(),
+                interactive.regex,
+                config::CONFIG_FILE_NAME
+            ),
+        }
+    }
+    anyhow::bail!(
+        "Gave up after answering interactive prompts {MAX_INTERACTIVE_RESPONSES} times for one \
+         command."
+    )
+}
+
+/// Read output from the REPL until the prompt or end of file, and match it against `expected`,
+/// the lines of `repl_block` from the line at index `expected_start`.
+///
+/// If `consumed_prompt` is `Some`, the prompt has already been read so it is taken and `expected`
+/// is matched against no output at all.
+///
+/// If the block has `on_mismatch=continue`, a mismatch is pushed to `mismatches` and the expected
+/// lines are kept, instead of returning an error. Notes about the match from [match_output] are
+/// pushed to `notes`.
+///
+/// `echo` is the command which was sent before with `echo=strip`, which is removed if it is the
+/// first line of the output.
+///
+/// `banner` is set if the REPL has just been started, so the output is not matched if the session
+/// has [Session::skip_banner].
+///
+/// The actual output is rewritten with [Session::substitutions], and then the lines matching
+/// [Session::ignore_lines] are dropped from both the actual and the expected output.
+///
+/// Returns the actual prompt, or `None` if the REPL reached end of file.
+#[allow(clippy::too_many_arguments)]
+fn read_and_match<'a>(
+    process: &mut impl ReplBackend,
+    consumed_prompt: &mut Option<String>,
+    prompt_regex: Regex,
+    repl_block: &ReplBlock,
+    expected_start: usize,
+    expected: &'a [&'a str],
+    alternatives: &mut Alternatives<'a>,
+    updated: &mut LineEditor,
+    mismatches: &mut Vec<String>,
+    notes: &mut Vec<String>,
+    echo: Option<&str>,
+    banner: bool,
+    session: &Session,
+    options: &Options,
+) -> anyhow::Result<Option<String>> {
+    let ignore_output = repl_block.output == OutputMode::Ignore || (banner && session.skip_banner);
+    let ignore_lines = session.ignore_lines.as_ref();
+    let substitute = |line: String| Substitution::apply_all(&session.substitutions, line);
+    let ignored = |line: &str| ignore_lines.is_some_and(|x| x.is_match(line));
+    let first_line = expected_start + 1;
+    let mut match_output = |actual: &[&str]| {
+        if ignore_output {
+            alternatives.skip();
+            updated.keep(expected.len());
+            return Ok(());
+        }
+        let result = alternatives.match_output(expected, updated, |expected, updated| {
+            match_output(
+                expected,
+                first_line,
+                actual,
+                repl_block.matcher,
+                repl_block.match_mode,
+                session,
+                updated,
+                options,
+            )
+        });
+        match result {
+            Ok(match_notes) => {
+                notes.extend(match_notes);
+                Ok(())
+            }
+            Err(e) if repl_block.on_mismatch == OnMismatch::Continue => {
+                mismatches.push(format!("At line {first_line} of the block: {e}"));
+                updated.keep(expected.len());
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    };
+    if let Some(prompt) = consumed_prompt.take() {
+        match_output(&[])?;
+        return Ok(Some(prompt));
+    }
+    // The output is compared line by line while it is read, to fail early on a mismatch in a
+    // long output. This is not possible if the output is transformed or reordered before it is
+    // matched, or if a fix should be suggested from the whole output, or if the session should
+    // continue after a mismatch since then the output must be read until the prompt anyway, or
+    // if mismatching output should be replaced, or if it may also match an alternative or skip
+    // optional lines.
+    let stream = repl_block.on_mismatch == OnMismatch::Stop
+        && !ignore_output
+        && options.update != Some(UpdatePolicy::All)
+        && !session.spawn_options.separate_stderr
+        && repl_block.filters.is_empty()
+        && repl_block.matcher.is_none()
+        && repl_block.alternatives.is_empty()
+        && !session.comparison.annotations
+        && !repl_block.is_multiline_prompt()
+        && !options.fix_suggestions;
+    let (output, actual_prompt) = if stream {
+        let prefix = pattern::literal_prefix(expected);
+        let mut lines = prefix.iter().enumerate().filter(|(_, x)| !ignored(x));
+        let machine = MachineValues::new(session.spawn_options.sandbox.as_deref());
+        let mut echo = echo;
+        // The expected and actual lines which have been compared, for the suggestions and the
+        // diff on a mismatch.
+        let (mut compared_expected, mut compared_actual) = (Vec::new(), Vec::new());
+        let on_line: OnLine = &mut |line| {
+            if echo.take().is_some_and(|cmd| is_echo(cmd, line)) {
+                return Ok(());
+            }
+            let line = substitute(options.restore_placeholders(line.to_string()));
+            if ignored(&line) {
+                return Ok(());
+            }
+            let line = pattern::escape_line(&line).into_owned();
+            let Some((i, expected_line)) = lines.next() else {
+                return Ok(());
+            };
+            let expected_line = machine.expand(expected_line).into_owned();
+            let matched = pattern::lines_match(&expected_line, &line, session.comparison);
+            compared_expected.push(expected_line);
+            compared_actual.push(line);
+            if !matched {
+                let (expected_line, line) = (
+                    compared_expected.last().unwrap(),
+                    compared_actual.last().unwrap(),
+                );
+                let mut message = format!(
+                    "Pattern mismatch at line {} of the block: Expected: {expected_line}\nGot: {}",
+                    first_line + i,
+                    pattern::printable(line)
+                );
+                // The fixes are suggested from the output which has been read so far.
+                let compared_expected: Vec<&str> =
+                    compared_expected.iter().map(String::as_str).collect();
+                let compared_actual: Vec<&str> =
+                    compared_actual.iter().map(String::as_str).collect();
+                let suggestions = suggest::suggest(
+                    &compared_expected,
+                    &compared_actual,
+                    first_line,
+                    session.comparison,
+                );
+                for suggestion in suggestions {
+                    message += &format!("\nSuggestion: {suggestion}");
+                }
+                if let Some(note) = prompt_in_output_note(&repl_block.prompt, line) {
+                    message += &format!("\n{note}");
+                }
+                return Err(Mismatch {
+                    message,
+                    diff: Some(diff::format_diff(
+                        &compared_expected.join("\n"),
+                        &compared_actual.join("\n"),
+                        false,
+                    )),
+                }
+                .into());
+            }
+            Ok(())
+        };
+        read_until_prompt_interactive(process, &prompt_regex, Some(on_line), options)
+    } else {
+        read_until_prompt_interactive(process, &prompt_regex, None, options)
+    }
+    .map_err(|e| match e.downcast_ref() {
+        Some(TimeoutError { .. }) => anyhow::anyhow!("{e}\n{PROMPT_POSITION_NOTE}"),
+        _ => e,
+    })?;
+    let actual_prompt = match banner {
+        true => check_version(
+            process,
+            &repl_block.prompt.regex,
+            &output,
+            actual_prompt,
+            session,
+        )?,
+        false => actual_prompt,
+    };
+    let output = match (echo, output.split_once('\n')) {
+        (Some(cmd), Some((first, rest))) if is_echo(cmd, first) => rest.to_string(),
+        _ => output,
+    };
+    let mut output = options.restore_placeholders(output);
+    for filter in &repl_block.filters {
+        output = plugin::filter(&options.filters[*filter], &output)?;
+    }
+    let actual_prompt = actual_prompt.map(|x| options.restore_placeholders(x));
+    let output = match session.substitutions.is_empty() {
+        true => output,
+        false => output
+            .lines()
+            .map(|x| substitute(x.to_string()) + "\n")
+            .collect(),
+    };
+    let mut read_lines: Vec<Cow<str>> = output
+        .lines()
+        .filter(|x| !ignored(x))
+        .map(pattern::escape_line)
+        .collect();
+    read_lines = pattern::collapse_binary(read_lines);
+    if session.spawn_options.separate_stderr {
+        let tag = pattern::stderr_tag(&repl_block.expected);
+        read_lines = pattern::interleave_streams(expected, read_lines, tag, session.comparison);
+    }
+    let read_lines: Vec<&str> = read_lines.iter().map(AsRef::as_ref).collect();
+    match_output(&read_lines).map_err(|e| {
+        match prompt_in_output_note(&repl_block.prompt, &output) {
+            Some(note) => add_note(e, &note),
+            None => e,
+        }
+    })?;
+    Ok(actual_prompt)
+}
+
+/// Check that a REPL which has just been started has the version in [Session::require_version],
+/// by matching its `banner` or the output of [Session::version_cmd]. `prompt` is the first prompt,
+/// and the prompt after the version command is returned.
+fn check_version(
+    process: &mut impl ReplBackend,
+    prompt_regex: &Regex,
+    banner: &str,
+    prompt: Option<String>,
+    session: &Session,
+) -> anyhow::Result<Option<String>> {
+    let Some(required) = &session.require_version else {
+        return Ok(prompt);
+    };
+    let (output, prompt) = match (session.version_cmd, prompt) {
+        (Some(cmd), Some(_)) => {
+            tracing::debug!(cmd, "checking the version");
+            process.send_line(cmd)?;
+            process.read_until_prompt(prompt_regex)?
+        }
+        (_, prompt) => (banner.to_string(), prompt),
+    };
+    if !required.is_match(&output) {
+        let got = output.lines().find(|x| !x.trim().is_empty());
+        anyhow::bail!(
+            "The version of the REPL doesn't match require_version `{}`, it printed: {}\nThe \
+             documents may have been written for another version.",
+            required.as_str(),
+            got.unwrap_or("nothing")
+        );
+    }
+    Ok(prompt)
+}
+
+/// Spawn the REPL of a session and skip the first [Session::initial_skip] lines of output.
+fn spawn_session<B: ReplBackend>(session: &Session) -> anyhow::Result<B> {
+    tracing::debug!(options = ?session.spawn_options, "spawning the REPL");
+    let mut process = B::spawn(&session.spawn_options)?;
+    if session.initial_skip > 0 {
+        let lines = Regex::new(&format!(r"(?:.*\n){{{}}}", session.initial_skip)).unwrap();
+        if process.read_until_prompt(&lines)?.1.is_none() {
+            anyhow::bail!(
+                "The REPL exited before {} lines could be skipped.",
+                session.initial_skip
+            );
+        }
+    }
+    Ok(process)
+}
+
+/// Detect the prompt of a session with `prompt=auto` in a REPL which has just been started, and
+/// use it for all blocks which don't have a prompt of their own.
+fn detect_prompt(session: &mut Session, process: &mut impl ReplBackend) -> anyhow::Result<()> {
+    if !session.blocks.iter().any(|x| x.prompt.detect) {
+        return Ok(());
+    }
+    let output = process.peek_until_idle(session.prompt_idle)?;
+    let Some(line) = output
+        .rsplit('\n')
+        .map(|x| x.strip_suffix('\r').unwrap_or(x))
+        .find(|x| !x.trim().is_empty())
+    else {
+        anyhow::bail!("Could not detect the prompt since the REPL printed nothing.");
+    };
+    // Numbers like in `irb(main):001:0> ` usually change from one prompt to the next.
+    let regex = Regex::new(r"\d+")
+        .unwrap()
+        .replace_all(&regex::escape(line), regex::NoExpand(r"\d+"))
+        .into_owned();
+    tracing::info!(prompt = %regex, "detected the prompt");
+    let prompt = Rc::new(Prompt::new(&regex)?);
+    for block in &mut session.blocks {
+        if block.prompt.detect {
+            block.prompt = prompt.clone();
+        }
+    }
+    Ok(())
+}
+
+/// The status of every session together with the result of every block which has been run and
+/// what happened in it, see [run_sessions].
+type BlockResults<'a> = HashMap<
+    SessionKey<'a>,
+    (
+        SessionStatus,
+        VecDeque<anyhow::Result<BlockOutput>>,
+        VecDeque<BlockRun>,
+    ),
+>;
+
+/// The result of a block which passed.
+#[derive(Debug, Default)]
+struct BlockOutput {
+    /// The updated code of the block, if it should be updated.
+    updated_code: Option<String>,
+
+    /// Notes about how the output was matched, if [Options::verbose] is set.
+    notes: Vec<String>,
+
+    /// The number of the code block of the [Alternative] whose expected output matched, if the
+    /// block's own didn't.
+    alternative: Option<usize>,
+}
+
+/// What happened in a block which has been run, whether it passed or failed.
+#[derive(Debug, Default)]
+struct BlockRun {
+    /// How long it took to run the block.
+    duration: Duration,
+
+    /// Every command which was run in the block and how long it took until its output was read.
+    timings: Vec<(String, Duration)>,
+}
+
+/// Run a block of a session in a spawned REPL.
+///
+/// Returns the updated code if the block should be updated, or a [Mismatches] error if the block
+/// has `on_mismatch=continue` and some output didn't match. `consumed_prompt` is the prompt if it
+/// has already been read at the end of the last block. The usage of the processes which are shut
+/// down on restarts is added to `resource_usage`, and every command which is run to `timings`
+/// together with how long it took.
+#[allow(clippy::too_many_arguments)]
+fn run_block<B: ReplBackend>(
+    session_name: &str,
+    session: &Session,
+    repl_block: &ReplBlock,
+    process: &mut TranscriptBackend<B>,
+    consumed_prompt: &mut Option<String>,
+    resource_usage: &mut Option<ResourceUsage>,
+    timings: &mut Vec<(String, Duration)>,
+    progress: &dyn SessionProgress,
+    options: &Options,
+) -> anyhow::Result<BlockOutput> {
+    // All the lines in this block, perhaps updated.
+    let mut updated_repl_block = LineEditor::new(&repl_block.expected);
+    // Mismatches which have been recorded with `on_mismatch=continue`.
+    let mut mismatches = Vec::new();
+    // Notes about how the output was matched, with `Options::verbose`.
+    let mut notes = Vec::new();
+    // The last command which has been sent, if its echo should be removed from the output.
+    let mut echo: Option<String> = None;
+    // The last command which has been sent and when, until its output has been read.
+    let mut running: Option<(&str, Instant)> = None;
+    // Whether the REPL has just been started, so the output before the next prompt is its banner.
+    let mut banner = consumed_prompt.is_none();
+    // Whether the prompt before the next command should be checked against `assert_prompt`.
+    let mut assert_prompt = !repl_block.assert_prompt.is_empty();
+
+    let invocations = repl_block_to_cmd_invocations(repl_block);
+    let mut alternatives = Alternatives::new(repl_block, &invocations, session_name)?;
+    let CmdInvokations {
+        initial_output,
+        items,
+    } = invocations;
+    // The expected output before the next prompt and where it starts.
+    let mut expected_output = initial_output;
+    let mut expected_start = 0;
+    for item in items {
+        let output_start = item.output_start();
+        match item {
+            BlockItem::Cmd(CmdInvokation {
+                start: _,
+                prompt,
+                cmd,
+                continuation_lines,
+                entire_prompt_lines,
+                input_lines,
+                input_region,
+                expected_output: next_expected_output,
+            }) => {
+                // A regex for matching the prompt in the REPL.
+                let prompt_regex = match prompt {
+                    ExpectedPrompt::Fixed(x) => anchor_prompt(&regex::escape(x)).unwrap(),
+                    ExpectedPrompt::Flexible
+                    | ExpectedPrompt::Updatable
+                    | ExpectedPrompt::Inline => repl_block.prompt.regex.clone(),
+                };
+                let Some(actual_prompt) = read_and_match(
+                    process,
+                    consumed_prompt,
+                    prompt_regex,
+                    repl_block,
+                    expected_start,
+                    expected_output,
+                    &mut alternatives,
+                    &mut updated_repl_block,
+                    &mut mismatches,
+                    &mut notes,
+                    echo.take().as_deref(),
+                    std::mem::take(&mut banner),
+                    session,
+                    options,
+                )?
+                else {
+                    anyhow::bail!(
+                        "In session {session_name}: The REPL exited before the command `{cmd}`."
+                    );
+                };
+                record_duration(running.take(), repl_block, session_name, timings)?;
+                if std::mem::take(&mut assert_prompt) {
+                    check_prompt_captures(repl_block, &actual_prompt, session_name)?;
+                }
+                let new_prompt = session.normalize_prompt.unwrap_or(&actual_prompt);
+
+                match prompt {
+                    ExpectedPrompt::Updatable => {
+                        updated_repl_block.replace(
+                            1,
+                            &format!("{new_prompt}{cmd}").lines().collect::<Vec<_>>(),
+                            EditOrigin::Hole,
+                        );
+                        // The `???` line is followed by the continuation lines.
+                        updated_repl_block.keep(entire_prompt_lines.len() - 1);
+                    }
+                    ExpectedPrompt::Flexible
+                        if session.normalize_prompt.is_some() || options.update.is_some() =>
+                    {
+                        let (prompt_lines, continuation) = entire_prompt_lines
+                            .split_at(entire_prompt_lines.len() - continuation_lines.len());
+                        let new_prompt = format!("{new_prompt}{cmd}");
+                        let new_prompt_lines: Vec<&str> = new_prompt.lines().collect();
+                        updated_repl_block.replace(
+                            prompt_lines.len(),
+                            &new_prompt_lines,
+                            EditOrigin::Prompt,
+                        );
+                        updated_repl_block.keep(continuation.len());
+                    }
+                    ExpectedPrompt::Flexible => match repl_block
+                        .prompt
+                        .renumber(&entire_prompt_lines.join("\n"), &actual_prompt)
+                    {
+                        Some(renumbered) => updated_repl_block.replace(
+                            entire_prompt_lines.len(),
+                            &renumbered.lines().collect::<Vec<_>>(),
+                            EditOrigin::Prompt,
+                        ),
+                        None => updated_repl_block.keep(entire_prompt_lines.len()),
+                    },
+                    ExpectedPrompt::Fixed(_) | ExpectedPrompt::Inline => {
+                        updated_repl_block.keep(entire_prompt_lines.len())
+                    }
+                }
+                running = Some((cmd, Instant::now()));
+                let cmd = options.fill_placeholders(cmd);
+                tracing::debug!(prompt = %actual_prompt, cmd = %cmd, "sending command");
+                progress.command(&cmd);
+                if session.bracketed_paste && !continuation_lines.is_empty() {
+                    let mut text = cmd.clone();
+                    for line in continuation_lines {
+                        text += "\n";
+                        text += &options.fill_placeholders(line);
+                    }
+                    process.send_keys(&format!("{PASTE_START}{text}{PASTE_END}"))?;
+                    process.send_line("")?;
+                } else {
+                    process.send_line(&cmd)?;
+                    for line in continuation_lines {
+                        let continuation = repl_block.continuation.as_ref().unwrap();
+                        if process.read_until_prompt(&continuation.regex)?.1.is_none() {
+                            anyhow::bail!("The REPL exited while a command was sent.");
+                        }
+                        thread::sleep(session.send_delay);
+                        process.send_line(&options.fill_placeholders(line))?;
+                    }
+                }
+                for line in input_lines {
+                    thread::sleep(session.send_delay);
+                    process.send_line(&options.fill_placeholders(line))?;
+                }
+                updated_repl_block.keep(input_region);
+                echo = (session.echo == Echo::Strip).then_some(cmd);
+                expected_output = next_expected_output;
+            }
+            BlockItem::Restart {
+                start: _,
+                directive_line,
+                expected_output: next_expected_output,
+            } => {
+                read_and_match(
+                    process,
+                    consumed_prompt,
+                    repl_block.prompt.regex.clone(),
+                    repl_block,
+                    expected_start,
+                    expected_output,
+                    &mut alternatives,
+                    &mut updated_repl_block,
+                    &mut mismatches,
+                    &mut notes,
+                    echo.take().as_deref(),
+                    std::mem::take(&mut banner),
+                    session,
+                    options,
+                )?;
+                record_duration(running.take(), repl_block, session_name, timings)?;
+                restart_session(session, process, resource_usage)?;
+                banner = true;
+                updated_repl_block.keep(1);
+                expected_output = next_expected_output;
+            }
+        }
+        expected_start = output_start;
+    }
+    // Match the output of the last command up to the next prompt, or up to the end with
+    // `expect_eof`.
+    *consumed_prompt = read_and_match(
+        process,
+        consumed_prompt,
+        repl_block.prompt.regex.clone(),
+        repl_block,
+        expected_start,
+        expected_output,
+        &mut alternatives,
+        &mut updated_repl_block,
+        &mut mismatches,
+        &mut notes,
+        echo.take().as_deref(),
+        banner,
+        session,
+        options,
+    )?;
+    record_duration(running.take(), repl_block, session_name, timings)?;
+    if repl_block.expect_eof {
+        if let Some(prompt) = consumed_prompt.take() {
+            return Err(Mismatch::new(format!(
+                "In session {session_name}: The REPL should exit after the block, but it printed \
+                 the prompt `{}`.",
+                prompt.trim_end()
+            ))
+            .into());
+        }
+    }
+    if !mismatches.is_empty() {
+        return Err(Mismatches(mismatches).into());
+    }
+    if let Some(policy) = options.update {
+        updated_repl_block.retain(|x| policy.allows(x));
+    }
+    let alternative = alternatives.used();
+    if let Some(number) = alternative.filter(|_| options.verbose) {
+        notes.push(format!(
+            "The output matched the alternative in code block {number}."
+        ));
+    }
+    Ok(BlockOutput {
+        updated_code: updated_repl_block.edited_text(),
+        notes,
+        alternative,
+    })
+}
+
+/// Check that the named groups of the prompt regex have captured the text in
+/// [ReplBlock::assert_prompt] in the actual prompt before the first command of a block.
+fn check_prompt_captures(
+    repl_block: &ReplBlock,
+    actual_prompt: &str,
+    session_name: &str,
+) -> anyhow::Result<()> {
+    let captures = repl_block.prompt.regex.captures(actual_prompt);
+    let prompt = actual_prompt.trim();
+    for (name, expected) in &repl_block.assert_prompt {
+        match captures.as_ref().and_then(|x| x.name(name)) {
+            Some(actual) if actual.as_str() == *expected => {}
+            Some(actual) => {
+                return Err(Mismatch::new(format!(
+                    "In session {session_name}: Expected {name}={expected} in the prompt \
+                     `{prompt}` before the block, but it is {name}={}.",
+                    actual.as_str()
+                ))
+                .into())
+            }
+            None => anyhow::bail!(
+                "In session {session_name}: The group {name} of the prompt regex in assert_prompt \
+                 didn't capture anything in the prompt `{prompt}`."
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Record the duration of a command whose output has been read, if any, and fail if it took
+/// longer than [ReplBlock::max_duration].
+fn record_duration(
+    running: Option<(&str, Instant)>,
+    repl_block: &ReplBlock,
+    session_name: &str,
+    timings: &mut Vec<(String, Duration)>,
+) -> anyhow::Result<()> {
+    let Some((cmd, start)) = running else {
+        return Ok(());
+    };
+    let duration = start.elapsed();
+    timings.push((cmd.to_string(), duration));
+    if let Some(max_duration) = repl_block.max_duration.filter(|x| duration > *x) {
+        return Err(Mismatch::new(format!(
+            "In session {session_name}: The command `{cmd}` took {:.2} s, longer than the \
+             max_duration of {}.",
+            duration.as_secs_f64(),
+            humantime::format_duration(max_duration)
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Run the `setup_cmd` or `teardown_cmd`, named by `hook`, of the session `session_name` with
+/// `sh -c`, in its sandbox if it has one. Returns an error with the output of the command if it
+/// fails.
+fn run_hook(hook: &str, cmd: &str, session_name: &str, session: &Session) -> Result<(), String> {
+    tracing::debug!(hook, cmd, "running hook");
+    let mut command = std::process::Command::new("sh");
+    command.args(["-c", cmd]).envs(&session.options.env);
+    if let Some(dir) = &session.spawn_options.sandbox {
+        command.current_dir(dir).env(backend::SANDBOX_ENV_VAR, dir);
+    }
+    let output = command
+        .output()
+        .map_err(|e| format!("The {hook} `{cmd}` of session {session_name} failed: {e}"))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let mut error = format!(
+        "The {hook} `{cmd}` of session {session_name} failed with {}.",
+        output.status
+    );
+    for stream in [&output.stdout, &output.stderr] {
+        let text = String::from_utf8_lossy(stream);
+        if !text.trim().is_empty() {
+            error += "\n";
+            error += text.trim_end();
+        }
+    }
+    Err(error)
+}
+
+/// How many random names are tried for a sandbox before giving up.
+const SANDBOX_ATTEMPTS: usize = 16;
+
+/// Create a fresh temporary directory for a session with `sandbox=true`, which the REPL and the
+/// hooks are run in.
+fn enter_sandbox(session: &mut Session, session_name: &str) -> anyhow::Result<()> {
+    if !session.sandbox {
+        return Ok(());
+    }
+    // Session names are made of word characters, but they may come from other documents.
+    let name: String = session_name
+        .chars()
+        .map(|x| if x.is_alphanumeric() { x } else { '_' })
+        .collect();
+    // The directory must be new, so a directory which someone else has created at the same path
+    // is never used. It is only accessible by the user on Unix.
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    for _ in 0..SANDBOX_ATTEMPTS {
+        let dir =
+            std::env::temp_dir().join(format!("repl-check-{name}-{:016x}", rand::random::<u64>()));
+        match builder.create(&dir) {
+            Ok(()) => {
+                tracing::debug!(dir = %dir.display(), "created the sandbox");
+                session.spawn_options.sandbox = Some(dir);
+                return Ok(());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => anyhow::bail!(
+                "In session {session_name}: Failed to create the sandbox {}: {e}",
+                dir.display()
+            ),
+        }
+    }
+    anyhow::bail!("In session {session_name}: Failed to create a new directory for the sandbox.")
+}
+
+/// Delete the sandbox of a session after it has run, unless [Options::keep_sandboxes] is set.
+/// Returns the sandbox if it is kept.
+fn leave_sandbox(session: &mut Session) -> Option<PathBuf> {
+    let dir = session.spawn_options.sandbox.take()?;
+    if session.options.keep_sandboxes {
+        tracing::info!(dir = %dir.display(), "kept the sandbox");
+        return Some(dir);
+    }
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        tracing::warn!(dir = %dir.display(), error = %e, "failed to delete the sandbox");
+    }
+    None
+}
+
+/// Prepare a session which has failed to be run again from a clean state: run its
+/// `teardown_cmd`, give it a new sandbox and run its `setup_cmd` again. A kept sandbox is added
+/// to `kept_sandboxes`.
+fn reset_session(
+    session: &mut Session,
+    session_name: &str,
+    kept_sandboxes: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let run = |hook, cmd: Option<&str>, session: &Session| match cmd {
+        Some(cmd) => run_hook(hook, cmd, session_name, session).map_err(anyhow::Error::msg),
+        None => Ok(()),
+    };
+    run("teardown_cmd", session.teardown_cmd, session)?;
+    if !session.sandbox {
+        return run("setup_cmd", session.setup_cmd, session);
+    }
+    kept_sandboxes.extend(leave_sandbox(session));
+    enter_sandbox(session, session_name)?;
+    run("setup_cmd", session.setup_cmd, session)
+}
+
+/// Shut down the REPL of a session and spawn it again. The usage of the old process is added to
+/// `resource_usage`.
+fn restart_session<B: ReplBackend>(
+    session: &Session,
+    process: &mut TranscriptBackend<B>,
+    resource_usage: &mut Option<ResourceUsage>,
+) -> anyhow::Result<()> {
+    tracing::debug!("restarting the REPL");
+    process.shutdown()?;
+    *resource_usage = resource_usage
+        .zip(process.resource_usage())
+        .map(|(x, y)| x.combine(y));
+    process.restart(spawn_session(session)?);
+    Ok(())
+}
+
+/// The state of a session between its blocks, see [run_session_block].
+#[derive(Debug, Default)]
+struct BlockState {
+    /// The prompt if it has already been read at the end of the last block.
+    consumed_prompt: Option<String>,
+
+    /// Whether the REPL has exited after a block with `expect_eof`.
+    exited: bool,
+}
+
+/// Run block `i` of a session in a spawned REPL, see [run_block]. The REPL is restarted first if
+/// it has exited after the last block, and the usage of the old process is added to
+/// `resource_usage`. What happened in the block is added to `runs`.
+#[allow(clippy::too_many_arguments)]
+fn run_session_block<B: ReplBackend>(
+    session_name: &str,
+    session: &Session,
+    i: usize,
+    process: &mut TranscriptBackend<B>,
+    state: &mut BlockState,
+    resource_usage: &mut Option<ResourceUsage>,
+    runs: &mut Vec<BlockRun>,
+    progress: &dyn SessionProgress,
+) -> anyhow::Result<BlockOutput> {
+    let (options, repl_block) = (session.options, &session.blocks[i]);
+    let _span = tracing::info_span!("block", number = i + 1).entered();
+    if state.exited {
+        restart_session(session, process, resource_usage)?;
+    }
+    let transcript_start = process.transcript().len();
+    let start = Instant::now();
+    let mut timings = Vec::new();
+    let result = run_block(
+        session_name,
+        session,
+        repl_block,
+        process,
+        &mut state.consumed_prompt,
+        resource_usage,
+        &mut timings,
+        progress,
+        options,
+    )
+    .map_err(|error| {
+        let transcript = process.transcript().excerpt(transcript_start);
+        match error.downcast::<Mismatches>() {
+            Ok(Mismatches(mut mismatches)) => {
+                mismatches.push(format!("Transcript:\n{transcript}"));
+                Mismatches(mismatches).into()
+            }
+            Err(error) => anyhow::Error::from(TranscriptError { error, transcript }),
+        }
+    });
+    match &result {
+        Ok(_) => tracing::info!("block passed"),
+        Err(e) => tracing::info!(error = %e, "block failed"),
+    }
+    if let (Err(e), OnFailure::Debug) = (&result, options.on_failure) {
+        debug::debug_shell(session_name, e, process, options);
+    }
+    progress.block_done(result.is_ok());
+    runs.push(BlockRun {
+        duration: start.elapsed(),
+        timings,
+    });
+    state.exited = repl_block.expect_eof;
+    result
+}
+
+/// Whether a session must stop after a block with `result`. Only [Mismatches] let it continue.
+fn stops_session(result: &anyhow::Result<BlockOutput>) -> bool {
+    result.as_ref().is_err_and(|e| !e.is::<Mismatches>())
+}
+
+/// Run all blocks of a session in a spawned REPL.
+///
+/// Returns a [Result] for every [ReplBlock] up to the first one that fails, which is [Some] iff
+/// that block should be updated. The session continues after blocks which fail with
+/// [Mismatches]. The usage of the processes which are shut down on restarts is
+/// added to `resource_usage`, and what happened in every block which is run to `runs`.
+fn run_session<B: ReplBackend>(
+    session_name: &str,
+    session: &Session,
+    process: &mut TranscriptBackend<B>,
+    resource_usage: &mut Option<ResourceUsage>,
+    runs: &mut Vec<BlockRun>,
+    progress: &dyn SessionProgress,
+) -> Vec<anyhow::Result<BlockOutput>> {
+    let mut state = BlockState::default();
+    let mut results = Vec::new();
+    for i in 0..session.blocks.len() {
+        let result = run_session_block(
+            session_name,
+            session,
+            i,
+            process,
+            &mut state,
+            resource_usage,
+            runs,
+            progress,
+        );
+        let stop = stops_session(&result);
+        results.push(result);
+        if stop {
+            break;
+        }
+    }
+    results
+}
+
+/// Remove the blocks which are not selected by [Options::sessions], [Options::skip_sessions] and
+/// [Options::block_filter] from the sessions, and return their documents and indices.
+///
+/// The blocks before a selected block in its session are kept, since they may set up the state
+/// the selected block depends on, unless [Options::skip_earlier_blocks] is set.
+fn select_blocks(
+    sessions: &mut HashMap<SessionKey, Session>,
+    blocks: &[(usize, usize, SessionKey)],
+) -> HashSet<(usize, usize)> {
+    let mut skipped = HashSet::new();
+    for (key, session) in sessions.iter_mut() {
+        let options = session.options;
+        let selected = (options.sessions.is_empty()
+            || options.sessions.iter().any(|x| x == key.name))
+            && !options.skip_sessions.iter().any(|x| x == key.name);
+        let keep: Vec<bool> = match (selected, &options.block_filter) {
+            (false, _) => vec![false; session.blocks.len()],
+            (true, None) => continue,
+            (true, Some(regex)) => {
+                let matches: Vec<bool> = session
+                    .blocks
+                    .iter()
+                    .map(|x| regex.is_match(&x.expected.join("\n")))
+                    .collect();
+                let last_match = matches.iter().rposition(|x| *x);
+                match options.skip_earlier_blocks {
+                    true => matches,
+                    false => (0..matches.len())
+                        .map(|i| last_match.is_some_and(|x| i <= x))
+                        .collect(),
+                }
+            }
+        };
+        // The blocks of a session are in the same order as in `blocks`.
+        let session_blocks = blocks.iter().filter(|(_, _, x)| x == key);
+        for ((document, idx, _), keep) in session_blocks.zip(&keep) {
+            if !keep {
+                skipped.insert((*document, *idx));
+            }
+        }
+        session.skipped_blocks = keep.iter().filter(|x| !**x).count();
+        let mut keep = keep.into_iter();
+        session.blocks.retain(|_| keep.next().unwrap());
+    }
+    skipped
+}
+
+/// A session which is about to be run or has been run, see [SessionResults::start].
+struct SessionRun<'a> {
+    session: Session<'a>,
+
+    /// The hash of the session before it is run, see [Session::cache_key].
+    cache_key: String,
+
+    /// The result of every block which has been run.
+    results: Vec<anyhow::Result<BlockOutput>>,
+
+    /// What happened in the blocks the last time the session was run.
+    runs: Vec<BlockRun>,
+
+    /// The resources used by the processes which have been shut down, or `None` if it can't
+    /// be measured.
+    resource_usage: Option<ResourceUsage>,
+
+    /// How many times the session has been run again.
+    retries: usize,
+
+    /// The transcript of the session, with [Options::keep_transcripts].
+    transcript: String,
+
+    /// The sandboxes of earlier runs which were kept, with [Options::keep_sandboxes].
+    kept_sandboxes: Vec<PathBuf>,
+}
+
+/// The results of the sessions which have been run so far, see [run_sessions].
+struct SessionResults<'a> {
+    block_results: BlockResults<'a>,
+    reports: Vec<(usize, SessionReport)>,
+
+    /// Set after a failure with [Options::fail_fast], and then no more sessions are started.
+    failed: bool,
+
+    /// All sessions, for the `needs` attribute.
+    keys: HashSet<SessionKey<'a>>,
+
+    /// The sessions and numbers of the blocks which have passed, for the `needs` attribute.
+    passed: HashSet<(SessionKey<'a>, usize)>,
+}
+
+impl<'a> SessionResults<'a> {
+    /// An error if a block of the session `session_name` needs a block which has not passed.
+    fn unmet_need(&self, session_name: &str, block: &ReplBlock<'a>) -> Option<anyhow::Error> {
+        let (name, number) = block.needs.iter().find(|(name, number)| {
+            let dependency = dependency_key(|x| self.keys.contains(x), block.document, *name);
+            !dependency.is_some_and(|x| self.passed.contains(&(x, *number)))
+        })?;
+        Some(anyhow::anyhow!(
+            "In session {session_name}: Block {} needs block {number} of session {name}, which \
+             has not passed.",
+            block.number
+        ))
+    }
+
+    /// Get a session ready to be run: create its sandbox and run its `setup_cmd`. Returns `None`
+    /// if the session is not run, after it has been reported: because of a deadline, an earlier
+    /// failure, the cache or a failure of the setup, or, if `check_needs` is set, because blocks
+    /// which its blocks need have not passed.
+    fn start(
+        &mut self,
+        key: SessionKey<'a>,
+        mut session: Session<'a>,
+        check_needs: bool,
+    ) -> Option<SessionRun<'a>> {
+        // The error of the setup command, which is reported apart from the blocks.
+        let mut hook_error = None;
+        let (session_name, options) = (key.name, session.options);
+        let cache_key = session.cache_key();
+        let unmet_need = match check_needs {
+            true => session
+                .blocks
+                .iter()
+                .find_map(|x| self.unmet_need(session_name, x)),
+            false => None,
+        };
+        let (status, results) = if self.failed
+            || session.blocks.is_empty()
+            || options.deadline.is_some_and(|x| Instant::now() >= x)
+        {
+            (SessionStatus::NotRun, VecDeque::new())
+        } else if let Some(error) = unmet_need {
+            (SessionStatus::Failed, VecDeque::from([Err(error)]))
+        } else if options
+            .cache
+            .as_ref()
+            .is_some_and(|x| x.has_passed(&cache_key, options.max_age))
+        {
+            let results = session
+                .blocks
+                .iter()
+                .map(|_| Ok(BlockOutput::default()))
+                .collect();
+            (SessionStatus::Cached, results)
+        } else if let Err(error) = enter_sandbox(&mut session, session_name) {
+            (SessionStatus::Failed, VecDeque::from([Err(error)]))
+        } else if let Some(error) = session
+            .setup_cmd
+            .and_then(|cmd| run_hook("setup_cmd", cmd, session_name, &session).err())
+        {
+            hook_error = Some(error);
+            (SessionStatus::HookFailed, VecDeque::new())
+        } else {
+            return Some(SessionRun {
+                session,
+                cache_key,
+                results: Vec::new(),
+                runs: Vec::new(),
+                resource_usage: Some(ResourceUsage::default()),
+                retries: 0,
+                transcript: String::new(),
+                kept_sandboxes: Vec::new(),
+            });
+        };
+        self.failed |= matches!(status, SessionStatus::Failed | SessionStatus::HookFailed)
+            && options.fail_fast;
+        if status == SessionStatus::Cached {
+            self.passed
+                .extend(session.blocks.iter().map(|x| (key, x.number)));
+        }
+        let kept_sandboxes = leave_sandbox(&mut session).into_iter().collect();
+        self.block_results
+            .insert(key, (status, results, VecDeque::new()));
+        self.reports.push((
+            session.document,
+            SessionReport {
+                name: session_name.to_string(),
+                status,
+                resource_usage: None,
+                blocks: session.blocks.len() + session.skipped_blocks,
+                retries: 0,
+                transcript: None,
+                hook_error,
+                kept_sandboxes,
+            },
+        ));
+        None
+    }
+
+    /// Report a session which has been run, after running its `teardown_cmd` and deleting its
+    /// sandbox.
+    fn finish(&mut self, key: SessionKey<'a>, run: SessionRun<'a>) {
+        let SessionRun {
+            mut session,
+            cache_key,
+            results,
+            runs,
+            resource_usage,
+            retries,
+            transcript,
+            mut kept_sandboxes,
+        } = run;
+        let (session_name, options) = (key.name, session.options);
+        // The error of the teardown command, which is reported apart from the blocks.
+        let hook_error = session
+            .teardown_cmd
+            .and_then(|cmd| run_hook("teardown_cmd", cmd, session_name, &session).err());
+        kept_sandboxes.extend(leave_sandbox(&mut session));
+        let status = match (&hook_error, results.iter().any(Result::is_err)) {
+            (Some(_), _) => SessionStatus::HookFailed,
+            (None, true) => SessionStatus::Failed,
+            (None, false) => SessionStatus::Passed,
+        };
+        self.failed |= matches!(status, SessionStatus::Failed | SessionStatus::HookFailed)
+            && options.fail_fast;
+        for (block, result) in session.blocks.iter().zip(&results) {
+            if result.is_ok() {
+                self.passed.insert((key, block.number));
+            }
+        }
+        if let (SessionStatus::Passed, Some(cache)) = (status, &options.cache) {
+            // A session which updates a block must be run again, or the update would be lost.
+            if results
+                .iter()
+                .all(|x| x.as_ref().is_ok_and(|x| x.updated_code.is_none()))
+            {
+                // A cache which can't be written only makes later runs slower.
+                let _ = cache.record_passed(&cache_key);
+            }
+        }
+        self.block_results
+            .insert(key, (status, results.into(), runs.into()));
+        self.reports.push((
+            session.document,
+            SessionReport {
+                name: session_name.to_string(),
+                status,
+                resource_usage,
+                blocks: session.blocks.len() + session.skipped_blocks,
+                retries,
+                transcript: options.keep_transcripts.then_some(transcript),
+                hook_error,
+                kept_sandboxes,
+            },
+        ));
+    }
+}
+
+/// Run a set of [Session]s.
+///
+/// Returns for every session a [Result] for each [ReplBlock] which has been run, which is [Some]
+/// iff that block should be updated. A session stops at the first block which fails, unless it
+/// fails with [Mismatches], and the following blocks are not run. If [Options::fail_fast] is set,
+/// no more sessions are run after a failure. Also returns a report for every session together
+/// with the index of the document where it starts.
+///
+/// The sessions are run in `order`. A session whose blocks need blocks of other sessions which
+/// have not passed fails without being run. If `schedule` is given, the blocks are run in that
+/// order instead, see [run_blocks_in_document_order].
+fn run_sessions<'a, B: ReplBackend>(
+    mut sessions: HashMap<SessionKey<'a>, Session<'a>>,
+    order: &[SessionKey<'a>],
+    schedule: Option<&[SessionKey<'a>]>,
+) -> (BlockResults<'a>, Vec<(usize, SessionReport)>) {
+    let mut results = SessionResults {
+        block_results: HashMap::new(),
+        reports: Vec::new(),
+        failed: false,
+        keys: sessions.keys().copied().collect(),
+        passed: HashSet::new(),
+    };
+    if let Some(schedule) = schedule {
+        run_blocks_in_document_order::<B>(&mut sessions, schedule, &mut results);
+    }
+    // After a schedule, only sessions which are not run are left.
+    for key in order {
+        let Some((key, session)) = sessions.remove_entry(key) else {
+            continue;
+        };
+        let Some(mut run) = results.start(key, session, true) else {
+            continue;
+        };
+        run.results = loop {
+            if run.retries > 0 {
+                tracing::info!(
+                    session = key.name,
+                    retries = run.retries,
+                    "running the session again"
+                );
+                run.transcript += &format!("retry {}\n", run.retries);
+            }
+            run.runs.clear();
+            let block_results = spawn_and_run_session::<B>(
+                key.name,
+                &mut run.session,
+                &mut run.resource_usage,
+                &mut run.runs,
+                &mut run.transcript,
+            );
+            // The session is run again if the block which failed allows more retries.
+            match block_results.iter().position(Result::is_err) {
+                Some(i) if run.retries < run.session.blocks[i].retries => {}
+                _ => break block_results,
+            }
+            if let Err(e) = reset_session(&mut run.session, key.name, &mut run.kept_sandboxes) {
+                tracing::warn!(session = key.name, error = %e, "failed to reset the session");
+                break block_results;
+            }
+            run.retries += 1;
+        };
+        results.finish(key, run);
+    }
+    (results.block_results, results.reports)
+}
+
+/// A session whose REPL keeps running between the blocks of other sessions, see
+/// [run_blocks_in_document_order].
+struct RunningSession<'a, B: ReplBackend> {
+    run: SessionRun<'a>,
+    process: TranscriptBackend<B>,
+    progress: Box<dyn SessionProgress>,
+    state: BlockState,
+}
+
+/// Run the blocks of `sessions` in the order of `schedule`, which has the session of every block
+/// which is run. A session is started at its first block and stopped after its last one, or
+/// after a block which stops it, and its later blocks are not run. The `needs` of a block are
+/// checked right before it is run. The sessions are reported to `results`, and they are removed
+/// from `sessions` when they are started.
+fn run_blocks_in_document_order<'a, B: ReplBackend>(
+    sessions: &mut HashMap<SessionKey<'a>, Session<'a>>,
+    schedule: &[SessionKey<'a>],
+    results: &mut SessionResults<'a>,
+) {
+    // The sessions with blocks left, in the order they started.
+    let mut running: Vec<(SessionKey, RunningSession<B>)> = Vec::new();
+    for key in schedule {
+        if results.failed {
+            break;
+        }
+        let _span = tracing::info_span!("session", name = key.name).entered();
+        if let Some((key, session)) = sessions.remove_entry(key) {
+            let Some(mut run) = results.start(key, session, false) else {
+                continue;
+            };
+            match start_session::<B>(key.name, &mut run.session) {
+                Ok((process, progress)) => running.push((
+                    key,
+                    RunningSession {
+                        run,
+                        process,
+                        progress,
+                        state: BlockState::default(),
+                    },
+                )),
+                Err(e) => {
+                    run.results.push(Err(e));
+                    results.finish(key, run);
+                    continue;
+                }
+            }
+        }
+        // The session has not been run, or it has stopped.
+        let Some(idx) = running.iter().position(|(x, _)| x == key) else {
+            continue;
+        };
+        let RunningSession {
+            run,
+            process,
+            progress,
+            state,
+        } = &mut running[idx].1;
+        let i = run.results.len();
+        let block = &run.session.blocks[i];
+        let result = match results.unmet_need(key.name, block) {
+            Some(error) => Err(error),
+            None => run_session_block(
+                key.name,
+                &run.session,
+                i,
+                process,
+                state,
+                &mut run.resource_usage,
+                &mut run.runs,
+                &**progress,
+            ),
+        };
+        if result.is_ok() {
+            results.passed.insert((*key, block.number));
+        }
+        results.failed |= result.is_err() && run.session.options.fail_fast;
+        let stop = stops_session(&result) || i + 1 == run.session.blocks.len();
+        run.results.push(result);
+        if stop {
+            let (key, session) = running.remove(idx);
+            stop_running_session(key, session, results);
+        }
+    }
+    // The sessions which have blocks left after a failure with [Options::fail_fast].
+    for (key, session) in running {
+        let _span = tracing::info_span!("session", name = key.name).entered();
+        stop_running_session(key, session, results);
+    }
+}
+
+/// Shut down the REPL of a session which has been run in document order, and report it to
+/// `results`.
+fn stop_running_session<'a, B: ReplBackend>(
+    key: SessionKey<'a>,
+    session: RunningSession<'a, B>,
+    results: &mut SessionResults<'a>,
+) {
+    let RunningSession {
+        mut run,
+        process,
+        progress,
+        ..
+    } = session;
+    stop_session(
+        key.name,
+        &run.session,
+        process,
+        progress,
+        &mut run.results,
+        &mut run.resource_usage,
+        &mut run.transcript,
+    );
+    results.finish(key, run);
+}
+
+/// Spawn the REPL of a session and run all its blocks, see [run_session]. The usage of the
+/// processes is added to `resource_usage`, what happened in the blocks to `runs`, and the
+/// transcript to `transcript` with [Options::keep_transcripts].
+fn spawn_and_run_session<B: ReplBackend>(
+    session_name: &str,
+    session: &mut Session,
+    resource_usage: &mut Option<ResourceUsage>,
+    runs: &mut Vec<BlockRun>,
+    transcript: &mut String,
+) -> Vec<anyhow::Result<BlockOutput>> {
+    let _span = tracing::info_span!("session", name = session_name).entered();
+    let (mut process, progress) = match start_session::<B>(session_name, session) {
+        Ok(x) => x,
+        Err(e) => return vec![Err(e)],
+    };
+    let mut results = run_session(
+        session_name,
+        session,
+        &mut process,
+        resource_usage,
+        runs,
+        &*progress,
+    );
+    stop_session(
+        session_name,
+        session,
+        process,
+        progress,
+        &mut results,
+        resource_usage,
+        transcript,
+    );
+    results
+}
+
+/// Spawn the REPL of a session and detect its prompt, and start reporting its progress to
+/// [Options::progress].
+fn start_session<B: ReplBackend>(
+    session_name: &str,
+    session: &mut Session,
+) -> anyhow::Result<(TranscriptBackend<B>, Box<dyn SessionProgress>)> {
+    tracing::info!("starting the session");
+    let progress: Box<dyn SessionProgress> = match &session.options.progress {
+        Some(x) => x.start_session(session_name, session.blocks.len()),
+        None => Box::new(NoProgress),
+    };
+    let process = spawn_session::<TranscriptBackend<B>>(session).and_then(|mut process| {
+        detect_prompt(session, &mut process)?;
+        Ok(process)
+    });
+    match process {
+        Ok(process) => Ok((process, progress)),
+        Err(e) => {
+            progress.finish(false);
+            Err(e)
+        }
+    }
+}
+
+/// Shut down the REPL of a session after the blocks with `results` have been run. The usage of
+/// the process is added to `resource_usage`, and the transcript to `transcript` with
+/// [Options::keep_transcripts].
+fn stop_session<B: ReplBackend>(
+    session_name: &str,
+    session: &Session,
+    mut process: TranscriptBackend<B>,
+    progress: Box<dyn SessionProgress>,
+    results: &mut Vec<anyhow::Result<BlockOutput>>,
+    resource_usage: &mut Option<ResourceUsage>,
+    transcript: &mut String,
+) {
+    // Whether the session stopped because of a failure.
+    let failed = results.last().is_some_and(stops_session);
+    if let (true, Some(screen)) = (failed, process.screen_snapshot()) {
+        let Some(Err(error)) = results.pop() else {
+            unreachable!()
+        };
+        results.push(Err(ScreenError {
+            session: session_name.to_string(),
+            error,
+            screen,
+        }
+        .into()));
+    }
+    // After a failure the REPL may hang, so it is killed instead of asked to quit. A failure to
+    // shut down is reported on the last block.
+    let shutdown = match failed {
+        true => process.kill(),
+        false => process.shutdown(),
+    };
+    if let (Err(e), false) = (shutdown, failed) {
+        *results.last_mut().unwrap() = Err(e);
+    }
+    *resource_usage = resource_usage
+        .zip(process.resource_usage())
+        .map(|(x, y)| x.combine(y));
+    if session.options.keep_transcripts {
+        *transcript += &process.transcript().render(0);
+    }
+    progress.finish(results.iter().all(Result::is_ok));
+}
+
+/// The result of checking a document.
+#[derive(Debug)]
+pub struct CheckResult {
+    /// A report for every session in the document.
+    pub sessions: Vec<SessionReport>,
+
+    /// The blocks which should be updated, see [apply_updates].
+    pub updates: Vec<BlockUpdate>,
+
+    /// The blocks which failed. The following blocks in their sessions were not run.
+    pub failures: Vec<BlockFailure>,
+
+    /// A report for every REPL block in the document, including the disabled ones but not the
+    /// alternatives of other blocks.
+    pub blocks: Vec<BlockReport>,
+
+    /// Notes about how the output of the blocks which passed was matched, if [Options::verbose]
+    /// is set.
+    pub notes: Vec<String>,
+
+    /// How long every command in the blocks which passed took.
+    pub timings: Vec<CommandTiming>,
+}
+
+/// Check that all attributes and prompt regexes in a document are valid, without running any
+/// REPLs. All errors are reported together.
+pub fn validate_document(document: &Pandoc, options: &Options) -> anyhow::Result<()> {
+    validate_documents(&[(document, options)]).remove(0)
+}
+
+/// Like [validate_document] but for a group of documents which are checked together with
+/// [check_documents]. Returns the result for every document.
+pub fn validate_documents(documents: &[(&Pandoc, &Options)]) -> Vec<anyhow::Result<()>> {
+    let session_defaults: Vec<_> = documents
+        .iter()
+        .map(|(document, _)| session_defaults(document))
+        .collect();
+    let (_, errors) = get_sessions(documents, &session_defaults);
+    errors
+        .into_iter()
+        .map(|errors| match errors.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!(errors.join("\n"))),
+        })
+        .collect()
+}
+
+/// Find the unknown attributes of all REPL blocks in a document, which are errors unless
+/// [Options::lenient] is set. Returns a message for every attribute with the number of its block.
+pub fn unknown_attributes(document: &Pandoc) -> Vec<String> {
+    let Ok(session_defaults) = session_defaults(document) else {
+        return Vec::new();
+    };
+    iter_code_blocks(document, &session_defaults)
+        .flat_map(|block| {
+            block
+                .unknown_attrs()
+                .into_iter()
+                .map(move |e| format!("Code block {}: {e}", block.idx + 1))
+        })
+        .collect()
+}
+
+/// Whether any session in a document has `scope=global` and may continue in other documents. Such
+/// documents should be checked together with [check_documents].
+pub fn has_global_sessions(document: &Pandoc, options: &Options) -> bool {
+    let Ok(session_defaults) = session_defaults(document) else {
+        return false;
+    };
+    let default_scope = match options.shared_sessions {
+        true => SessionScope::Global,
+        false => SessionScope::Document,
+    };
+    let has_global_sessions = iter_code_blocks(document, &session_defaults)
+        .filter(|x| x.is_enabled(options))
+        .any(|x| {
+            x.parse_attr_or_default("scope", options)
+                .ok()
+                .flatten()
+                .unwrap_or(default_scope)
+                == SessionScope::Global
+        });
+    has_global_sessions
+}
+
+/// Check all REPL sessions in a pandoc document.
+///
+/// Blocks which fail are returned in [CheckResult::failures], while errors in the document itself
+/// are returned as an error. With [Options::fail_fast] a failing block is returned as an error too.
+pub fn check_document(document: &Pandoc, options: &Options) -> anyhow::Result<CheckResult> {
+    check_document_with_backend::<DefaultBackend>(document, options)
+}
+
+/// Check all REPL sessions in a pandoc document, communicating with the REPLs through a custom
+/// [ReplBackend].
+pub fn check_document_with_backend<B: ReplBackend>(
+    document: &Pandoc,
+    options: &Options,
+) -> anyhow::Result<CheckResult> {
+    Ok(check_documents_with_backend::<B>(&[(document, options)])?.remove(0))
+}
+
+/// Check all REPL sessions in a group of documents, in order.
+///
+/// Sessions with `scope=global` (the default if [Options::shared_sessions] is set) continue in
+/// the following documents, so blocks in later documents with the same session name are run in
+/// the same REPL process. The report of such a session belongs to the document where it starts.
+/// Returns a result for every document.
+pub fn check_documents(documents: &[(&Pandoc, &Options)]) -> anyhow::Result<Vec<CheckResult>> {
+    check_documents_with_backend::<DefaultBackend>(documents)
+}
+
+/// Like [check_documents] but with a custom [ReplBackend].
+pub fn check_documents_with_backend<B: ReplBackend>(
+    documents: &[(&Pandoc, &Options)],
+) -> anyhow::Result<Vec<CheckResult>> {
+    let session_defaults: Vec<_> = documents
+        .iter()
+        .map(|(document, _)| session_defaults(document))
+        .collect();
+    let (
+        Sessions {
+            mut sessions,
+            blocks,
+            order,
+        },
+        errors,
+    ) = get_sessions(documents, &session_defaults);
+    if errors.iter().any(|x| !x.is_empty()) {
+        let errors: Vec<String> = match documents.len() {
+            1 => errors.concat(),
+            _ => errors
+                .into_iter()
+                .enumerate()
+                .flat_map(|(i, errors)| {
+                    errors
+                        .into_iter()
+                        .map(move |e| format!("Document {}: {e}", i + 1))
+                })
+                .collect(),
+        };
+        anyhow::bail!(errors.join("\n"));
+    }
+    let skipped_blocks = select_blocks(&mut sessions, &blocks);
+    // With `ExecutionOrder::Document`, the session of every block which is run, in order.
+    let schedule = documents
+        .iter()
+        .any(|(_, x)| x.order == ExecutionOrder::Document)
+        .then(|| {
+            blocks
+                .iter()
+                .filter(|(document, idx, _)| !skipped_blocks.contains(&(*document, *idx)))
+                .map(|(_, _, key)| *key)
+                .collect::<Vec<_>>()
+        });
+    // A session can't be run again from the start while the blocks of other sessions which have
+    // run in between depend on it.
+    let retried = |key: &&SessionKey| {
+        sessions
+            .get(*key)
+            .is_some_and(|x| x.blocks.iter().any(|x| x.retries > 0))
+    };
+    if let Some(key) = order.iter().find(retried).filter(|_| schedule.is_some()) {
+        anyhow::bail!(
+            "In session {}: retries can't be used when the blocks are run in document order.",
+            key.name
+        );
+    }
+    // Neither can a block be run after a session whose blocks come later.
+    if let Some(schedule) = &schedule {
+        let mut counts: HashMap<SessionKey, usize> = HashMap::new();
+        for (i, key) in schedule.iter().enumerate() {
+            let count = counts.entry(*key).or_default();
+            let block = &sessions[key].blocks[*count];
+            *count += 1;
+            let later = block.after.iter().copied().find(|name| {
+                dependency_key(|x| sessions.contains_key(x), block.document, name)
+                    .is_some_and(|x| schedule[i + 1..].contains(&x))
+            });
+            if let Some(name) = later {
+                anyhow::bail!(
+                    "In session {}: Block {} must be run after session {name}, which has blocks \
+                     after it in document order.",
+                    key.name,
+                    block.number
+                );
+            }
+        }
+    }
+    let (mut block_results, session_reports) =
+        run_sessions::<B>(sessions, &order, schedule.as_deref());
+    let mut results: Vec<CheckResult> = documents
+        .iter()
+        .map(|_| CheckResult {
+            sessions: Vec::new(),
+            updates: Vec::new(),
+            failures: Vec::new(),
+            blocks: Vec::new(),
+            notes: Vec::new(),
+            timings: Vec::new(),
+        })
+        .collect();
+    for (document, report) in session_reports {
+        results[document].sessions.push(report);
+    }
+    // The updated code of blocks by the document and index.
+    let mut updated_codes = HashMap::new();
+    // The number of the alternative which matched, for blocks whose own output didn't.
+    let mut used_alternatives = HashMap::new();
+    // The diff of the output of blocks which failed since it didn't match.
+    let mut diffs = HashMap::new();
+    // The status of every enabled block, and what happened in it if it was run, by the document
+    // and its index.
+    let mut statuses = HashMap::new();
+    for (document, idx, key) in blocks {
+        if skipped_blocks.contains(&(document, idx)) {
+            statuses.insert((document, idx), (BlockStatus::NotRun, None));
+            continue;
+        }
+        let (session_status, session_results, session_runs) = block_results.get_mut(&key).unwrap();
+        let run = session_runs.pop_front();
+        let status = match session_results.pop_front() {
+            None => BlockStatus::NotRun,
+            Some(Ok(_)) if *session_status == SessionStatus::Cached => BlockStatus::Cached,
+            Some(Ok(output)) => {
+                if let Some(updated_code) = output.updated_code {
+                    updated_codes.insert((document, idx), updated_code);
+                }
+                if let Some(number) = output.alternative {
+                    used_alternatives.insert((document, idx), number);
+                }
+                results[document].notes.extend(
+                    output.notes.into_iter().map(|note| {
+                        format!("Code block {} in session {}: {note}", idx + 1, key.name)
+                    }),
+                );
+                let timings = run.iter().flat_map(|x| &x.timings);
+                results[document]
+                    .timings
+                    .extend(timings.map(|(cmd, duration)| CommandTiming {
+                        block: idx + 1,
+                        session: key.name.to_string(),
+                        cmd: cmd.clone(),
+                        duration: *duration,
+                    }));
+                BlockStatus::Passed
+            }
+            Some(Err(error)) if documents[document].1.fail_fast => return Err(error),
+            Some(Err(error)) => {
+                if let Some(diff) = mismatch_diff(&error) {
+                    diffs.insert((document, idx), diff.to_string());
+                }
+                results[document].failures.push(BlockFailure {
+                    session: key.name.to_string(),
+                    block: idx + 1,
+                    kind: failure_kind(&error),
+                    error,
+                });
+                BlockStatus::Failed
+            }
+        };
+        statuses.insert((document, idx), (status, run));
+    }
+    for (i, (document, session_defaults)) in documents
+        .iter()
+        .map(|(document, _)| document)
+        .zip(&session_defaults)
+        .enumerate()
+    {
+        // There are no errors in the metadata at this point.
+        let session_defaults = session_defaults.as_ref().unwrap();
+        for block in iter_code_blocks(document, session_defaults) {
+            if let Some(updated_code) = updated_codes.remove(&(i, block.idx)) {
+                results[i].updates.push(BlockUpdate {
+                    number: block.idx + 1,
+                    session: block.session_name.to_string(),
+                    code: block.text(),
+                    updated_code,
+                });
+            }
+            // Alternatives are reported as part of the blocks they are alternatives of.
+            if block.attr("alt_of").is_some() {
+                continue;
+            }
+            let (status, run) = statuses
+                .remove(&(i, block.idx))
+                .unwrap_or((BlockStatus::NotRun, None));
+            results[i].blocks.push(BlockReport {
+                number: block.idx + 1,
+                session: block.session_name.to_string(),
+                code: block.text(),
+                status,
+                duration: run.as_ref().map(|x| x.duration),
+                commands: run.map_or(0, |x| x.timings.len()),
+                alternative: used_alternatives.remove(&(i, block.idx)),
+                diff: diffs.remove(&(i, block.idx)),
+            });
+        }
+    }
+    Ok(results)
+}
+
+/// A REPL session in a group of documents, see [list_sessions].
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// The name of the session.
+    pub name: String,
+
+    /// The index of the document where the session starts.
+    pub document: usize,
+
+    /// The shell command which starts the REPL.
+    pub command: String,
+
+    /// The blocks of the session, in the order they are run.
+    pub blocks: Vec<BlockInfo>,
+}
+
+/// A REPL block in a session, see [list_sessions].
+#[derive(Debug, Clone)]
+pub struct BlockInfo {
+    /// The index of the document with the block, which may be a later document than where the
+    /// session starts for sessions with `scope=global`.
+    pub document: usize,
+
+    /// The number of the block among all code blocks in the document, starting at 1.
+    pub number: usize,
+
+    /// The code of the block.
+    pub code: String,
+
+    /// The commands which are run in the REPL, without the prompts.
+    pub commands: Vec<String>,
+
+    /// The expected output lines of every command in [Self::commands].
+    pub outputs: Vec<Vec<String>>,
+
+    /// The regex which matches the prompt in the output of the REPL.
+    pub prompt: String,
+
+    /// The regex which matches the continuation prompt, if there is one.
+    pub continuation_prompt: Option<String>,
+
+    /// The expected output before the first command.
+    pub initial_output: Vec<ExpectedLine>,
+
+    /// The commands and restarts of the block, in order.
+    pub invocations: Vec<InvocationInfo>,
+}
+
+/// A command or a restart of the REPL in a block, see [BlockInfo::invocations].
+#[derive(Debug, Clone, Serialize)]
+pub struct InvocationInfo {
+    /// The lines of the invocation in the code of the block, starting at 0, from the prompt line
+    /// to the end of the expected output.
+    pub lines: Range<usize>,
+
+    /// The prompt as it is written in the document, if it must match exactly. Otherwise the prompt
+    /// regex is used.
+    pub prompt: Option<String>,
+
+    /// Whether the prompt is written `???` in the document and is updated with the actual prompt.
+    pub update_prompt: bool,
+
+    /// Whether this restarts the REPL rather than running a command. The command is then the
+    /// directive.
+    pub restart: bool,
+
+    /// The command with its continuation lines, without the prompts.
+    pub command: String,
+
+    /// The lines of the `{input}` region after the command.
+    pub input: Vec<String>,
+
+    /// The expected output after the command.
+    pub expected_output: Vec<ExpectedLine>,
+}
+
+/// An expected line of output and what it means to the matching.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpectedLine {
+    /// The line as it is written in the document.
+    pub text: String,
+
+    /// What the line means, serialized as a `kind` field and the fields of the annotation.
+    #[serde(flatten)]
+    pub annotation: LineAnnotation,
+}
+
+impl ExpectedLine {
+    fn annotate(lines: &[&str]) -> Vec<Self> {
+        lines
+            .iter()
+            .map(|x| Self {
+                text: x.to_string(),
+                annotation: pattern::annotate_line(x),
+            })
+            .collect()
+    }
+}
+
+/// Describe the items of a block for [BlockInfo::invocations].
+fn invocation_info(item: &BlockItem) -> InvocationInfo {
+    match item {
+        BlockItem::Cmd(x) => {
+            let (prompt, update_prompt) = match x.prompt {
+                ExpectedPrompt::Fixed(prompt) => (Some(prompt.to_string()), false),
+                ExpectedPrompt::Updatable => (None, true),
+                ExpectedPrompt::Flexible | ExpectedPrompt::Inline => (None, false),
+            };
+            InvocationInfo {
+                lines: x.start
+                    ..x.start
+                        + x.entire_prompt_lines.len()
+                        + x.input_region
+                        + x.expected_output.len(),
+                prompt,
+                update_prompt,
+                restart: false,
+                command: iter::once(x.cmd)
+                    .chain(x.continuation_lines.iter().copied())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                input: x.input_lines.iter().map(|x| x.to_string()).collect(),
+                expected_output: ExpectedLine::annotate(x.expected_output),
+            }
+        }
+        BlockItem::Restart {
+            start,
+            directive_line,
+            expected_output,
+        } => InvocationInfo {
+            lines: *start..start + 1 + expected_output.len(),
+            prompt: None,
+            update_prompt: false,
+            restart: true,
+            command: directive_line.to_string(),
+            input: Vec::new(),
+            expected_output: ExpectedLine::annotate(expected_output),
+        },
+    }
+}
+
+/// List the REPL sessions in a group of documents, in the order they start, without running
+/// anything. Disabled blocks are not included. Errors in the documents are returned like in
+/// [check_documents].
+pub fn list_sessions(documents: &[(&Pandoc, &Options)]) -> anyhow::Result<Vec<SessionInfo>> {
+    let session_defaults: Vec<_> = documents
+        .iter()
+        .map(|(document, _)| session_defaults(document))
+        .collect();
+    let (
+        Sessions {
+            sessions, blocks, ..
+        },
+        errors,
+    ) = get_sessions(documents, &session_defaults);
+    if errors.iter().any(|x| !x.is_empty()) {
+        anyhow::bail!(errors.concat().join("\n"));
+    }
+    let mut result: Vec<SessionInfo> = Vec::new();
+    // The index in `result` of every session, and the number of its blocks listed so far.
+    let mut listed: HashMap<SessionKey, (usize, usize)> = HashMap::new();
+    for (document, idx, key) in blocks {
+        let session = &sessions[&key];
+        let (session_idx, block_idx) = *listed.entry(key).or_insert_with(|| {
+            result.push(SessionInfo {
+                name: key.name.to_string(),
+                document: session.document,
+                command: session.spawn_options.shell_cmd.to_string(),
+                blocks: Vec::new(),
+            });
+            (result.len() - 1, 0)
+        });
+        listed.get_mut(&key).unwrap().1 += 1;
+        let repl_block = &session.blocks[block_idx];
+        let invocations = repl_block_to_cmd_invocations(repl_block);
+        let (commands, outputs) = invocations
+            .items
+            .iter()
+            .filter_map(|x| match x {
+                BlockItem::Cmd(x) => Some((
+                    iter::once(x.cmd)
+                        .chain(x.continuation_lines.iter().copied())
+                        .chain(x.input_lines.iter().copied())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    x.expected_output.iter().map(|x| x.to_string()).collect(),
+                )),
+                BlockItem::Restart { .. } => None,
+            })
+            .unzip();
+        result[session_idx].blocks.push(BlockInfo {
+            document,
+            number: idx + 1,
+            code: repl_block.expected.join("\n"),
+            commands,
+            outputs,
+            prompt: repl_block.prompt.unanchored_regex.as_str().to_string(),
+            continuation_prompt: repl_block
+                .continuation
+                .as_ref()
+                .map(|x| x.unanchored_regex.as_str().to_string()),
+            initial_output: ExpectedLine::annotate(invocations.initial_output),
+            invocations: invocations.items.iter().map(invocation_info).collect(),
+        });
+    }
+    Ok(result)
+}
+
+/// The code blocks of a document, including nested ones, and which of them are checked, see
+/// [code_block_coverage].
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// The number of code blocks.
+    pub blocks: usize,
+
+    /// The code blocks without a `repl-<session>` class, together with their first class which
+    /// is usually the language, in the order they appear.
+    pub unchecked: Vec<(Option<String>, String)>,
+}
+
+impl Coverage {
+    /// The percentage of the code blocks which are checked, 100 if there are none.
+    pub fn percentage(&self) -> f64 {
+        match self.blocks {
+            0 => 100.0,
+            blocks => 100.0 * (blocks - self.unchecked.len()) as f64 / blocks as f64,
+        }
+    }
+}
+
+/// Find the code blocks in a document which are not checked since they have no `repl-<session>`
+/// class, like examples which are not run. Inline code spans are not counted.
+pub fn code_block_coverage(document: &Pandoc) -> Coverage {
+    fn collect(blocks: &[Block], coverage: &mut Coverage) {
+        for block in blocks {
+            if let Block::CodeBlock((_, classes, _), code) = block {
+                coverage.blocks += 1;
+                if repl_session_name(classes).is_none() {
+                    coverage
+                        .unchecked
+                        .push((classes.first().cloned(), code.clone()));
+                }
+            }
+            for nested in nested_blocks(block) {
+                collect(nested, coverage);
+            }
+        }
+    }
+    let mut coverage = Coverage::default();
+    collect(&document.blocks, &mut coverage);
+    coverage
+}
+
+/// Apply updates from [CheckResult::updates] to a copy of the document.
+pub fn apply_updates(document: &Pandoc, updates: &[BlockUpdate]) -> Pandoc {
+    let mut updated_document = document.clone();
+    let mut code_blocks = code_blocks_mut(&mut updated_document);
+    for update in updates {
+        code_blocks[update.number - 1].set(&update.updated_code);
+    }
+    updated_document
+}
+
+/// Apply updates from [CheckResult::updates] to the source text of the document, by replacing
+/// the code of the updated blocks, so the rest of the source is kept byte for byte instead of
+/// being reformatted by pandoc.
+///
+/// Returns `None` if an updated block can't be found verbatim in the source, for example because
+/// it is indented in a list, or if an inline code span is updated. Then the document must be
+/// written with [apply_updates] instead.
+pub fn patch_source(source: &str, document: &Pandoc, updates: &[BlockUpdate]) -> Option<String> {
+    let mut copy = document.clone();
+    // The code of every code block, or `None` for an inline code span.
+    let codes: Vec<Option<String>> = code_blocks_mut(&mut copy)
+        .into_iter()
+        .map(|x| match x {
+            CodeMut::Block(code) => Some(code.clone()),
+            CodeMut::Inline(_) => None,
+        })
+        .collect();
+    // All code blocks are searched for in order, so that the code of another block is not
+    // mistaken for an updated block.
+    let block_codes: Vec<&str> = codes.iter().flatten().map(String::as_str).collect();
+    let mut block_ranges = document::code_block_ranges(source, &block_codes).into_iter();
+    let ranges: Vec<Option<Range<usize>>> = codes
+        .iter()
+        .map(|code| code.as_ref().and_then(|_| block_ranges.next().flatten()))
+        .collect();
+    let edits = updates
+        .iter()
+        .map(|update| {
+            let idx = update.number - 1;
+            let (range, code) = (ranges[idx].clone()?, codes[idx].as_deref()?);
+            Some(document::code_edit(
+                source,
+                range,
+                code,
+                &update.updated_code,
+            ))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    document::apply_edits(source, &edits).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixes_keep_the_ignored_lines() {
+        let fixed = |lines: &[&str]| lines.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        // The expected lines are `a`, `#ignored`, `b`, `#ignored` and `c`.
+        let kept = [0, 2, 4];
+        let expected = ["a", "b", "c"];
+        let inserted = fixed(&["a", "...", "b", "c"]);
+        assert_eq!(
+            fix_edits(&kept, &expected, &inserted),
+            [(2, 0, &inserted[1..2])]
+        );
+        let appended = fixed(&["a", "b", "c", "..."]);
+        assert_eq!(
+            fix_edits(&kept, &expected, &appended),
+            [(5, 0, &appended[3..])]
+        );
+        let removed = fixed(&["a"]);
+        assert_eq!(
+            fix_edits(&kept, &expected, &removed),
+            [(2, 1, &removed[..0]), (4, 1, &removed[..0])]
+        );
+        let replaced = fixed(&["a ", "b", "c "]);
+        assert_eq!(
+            fix_edits(&kept, &expected, &replaced),
+            [
+                (0, 1, &replaced[..1]),
+                (2, 1, &replaced[1..2]),
+                (4, 1, &replaced[2..])
+            ]
+        );
+        assert!(fix_edits(&kept, &expected, &fixed(&expected)).is_empty());
+    }
+
+    #[test]
+    fn update_policies_keep_the_allowed_edits() {
+        let original = ["% echo a", "???", "b", "# ok"];
+        let edited = |policy: UpdatePolicy| {
+            let mut editor = LineEditor::new(&original);
+            editor.replace(1, &["$ echo a"], EditOrigin::Prompt);
+            editor.replace(1, &["a"], EditOrigin::Hole);
+            editor.replace(1, &["c"], EditOrigin::Output);
+            editor.replace(1, &["# fixed"], EditOrigin::Suggestion);
+            editor.retain(|x| policy.allows(x));
+            editor.edited_text().unwrap()
+        };
+        assert_eq!(edited(UpdatePolicy::Holes), "% echo a\na\nb\n# fixed");
+        assert_eq!(edited(UpdatePolicy::Prompts), "$ echo a\na\nb\n# fixed");
+        assert_eq!(edited(UpdatePolicy::All), "$ echo a\na\nc\n# fixed");
+    }
+
+    /// A document with a block of a session for every session, attribute and code, where the
+    /// attribute is left out if its name is empty. The sessions run a shell with the prompt `$ `.
+    fn shell_document(blocks: &[(&str, &str, &str, &str)]) -> Pandoc {
+        let blocks: Vec<_> = blocks
+            .iter()
+            .map(|(session, key, value, code)| {
+                let mut attrs = vec![["cmd", "env PS1='$ ' sh"], ["prompt", "[$] "]];
+                if !key.is_empty() {
+                    attrs.push([*key, *value]);
+                }
+                serde_json::json!({
+                    "t": "CodeBlock",
+                    "c": [["", [format!("repl-{session}")], attrs], code],
+                })
+            })
+            .collect();
+        Pandoc::from_json(
+            &serde_json::json!({
+                "pandoc-api-version": [1, 23, 1],
+                "meta": {},
+                "blocks": blocks,
+            })
+            .to_string(),
+        )
+    }
+
+    /// The errors of a document with a block in every session and an attribute on it, or the
+    /// order of the sessions if there are no errors.
+    fn session_errors(blocks: &[(&str, &str, &str)]) -> Vec<String> {
+        let blocks: Vec<_> = blocks
+            .iter()
+            .map(|(session, key, value)| (*session, *key, *value, "$ true"))
+            .collect();
+        let document = shell_document(&blocks);
+        let options = Options::default();
+        let defaults = [session_defaults(&document)];
+        let (sessions, mut errors) = get_sessions(&[(&document, &options)], &defaults);
+        let names: Vec<&str> = sessions.order.iter().map(|x| x.name).collect();
+        match errors[0].is_empty() {
+            true => vec![names.join(" ")],
+            false => errors.remove(0),
+        }
+    }
+
+    #[test]
+    fn sessions_are_ordered_after_their_dependencies() {
+        assert_eq!(
+            session_errors(&[("a", "after", "b"), ("b", "", "")]),
+            ["b a"]
+        );
+        assert_eq!(
+            session_errors(&[("a", "needs", "c:1"), ("b", "", ""), ("c", "after", "b")]),
+            ["b c a"]
+        );
+        assert_eq!(
+            session_errors(&[("a", "needs", "b:2"), ("b", "", "")]),
+            ["Code block 1: In session a: Block 2 of session b is needed, but it only has 1 blocks."]
+        );
+    }
+
+    #[test]
+    fn cycles_of_sessions_are_errors() {
+        let cycle =
+            |names: &str| format!("The sessions must be run after each other in a cycle: {names}.");
+        assert_eq!(session_errors(&[("a", "after", "a")]), [cycle("a -> a")]);
+        assert_eq!(
+            session_errors(&[("a", "after", "b"), ("b", "after", "a")]),
+            [cycle("a -> b -> a")]
+        );
+        assert_eq!(
+            session_errors(&[
+                ("a", "needs", "b:1"),
+                ("b", "needs", "c:1"),
+                ("c", "after", "b")
+            ]),
+            [cycle("b -> c -> b")]
+        );
+    }
+
+    #[test]
+    fn blocks_run_in_document_order() {
+        let path = std::env::temp_dir().join(format!("repl-check-order-{}", std::process::id()));
+        let (write, append, read) = (
+            format!("$ echo a > '{}'", path.display()),
+            format!("$ echo b >> '{}'", path.display()),
+            format!("$ cat '{}'\na\nb", path.display()),
+        );
+        // The first session writes the file, the second one appends to it and then the first one
+        // reads it, which only works if the blocks of the sessions are interleaved.
+        let document = shell_document(&[
+            ("a", "", "", write.as_str()),
+            ("b", "", "", append.as_str()),
+            ("a", "", "", read.as_str()),
+        ]);
+        let options = Options {
+            order: ExecutionOrder::Document,
+            ..Options::default()
+        };
+        let result = check_document(&document, &options);
+        let _ = std::fs::remove_file(&path);
+        assert!(result.unwrap().failures.is_empty());
+    }
+
+    #[test]
+    fn dependencies_against_document_order_are_reported() {
+        let options = Options {
+            order: ExecutionOrder::Document,
+            ..Options::default()
+        };
+        let check = |blocks: &[_]| check_document(&shell_document(blocks), &options);
+        let result = check(&[("a", "needs", "b:1", "$ true"), ("b", "", "", "$ true")]).unwrap();
+        assert_eq!(result.failures.len(), 1);
+        assert_eq!(
+            result.failures[0].error.to_string(),
+            "In session a: Block 1 needs block 1 of session b, which has not passed."
+        );
+        let e = check(&[("a", "after", "b", "$ true"), ("b", "", "", "$ true")]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "In session a: Block 1 must be run after session b, which has blocks after it in \
+             document order."
+        );
+        let result = check(&[("b", "", "", "$ true"), ("a", "after", "b", "$ true")]).unwrap();
+        assert!(result.failures.is_empty());
+    }
 }
@@ -0,0 +1,194 @@
+//! A minimal language server for editors, which checks a document when it is opened or saved and
+//! publishes the failing REPL blocks as diagnostics, so they are shown inline.
+//!
+//! Only the parts of the Language Server Protocol which are needed for that are implemented:
+//! messages are read from stdin and written to stdout with `Content-Length` headers, the server
+//! is notified when documents are opened, saved and closed, and every other request is answered
+//! with an error. Documents are checked as they are saved on disk, not as they are edited.
+
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// The error code of the protocol for requests of methods which are not implemented.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// A problem in a document, like a block whose output doesn't match.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    /// The line of the problem, starting at 1.
+    pub line: usize,
+
+    pub message: String,
+}
+
+/// Read a message, or `None` at the end of the input.
+fn read_message(reader: &mut impl BufRead) -> anyhow::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+    let Some(length) = length else {
+        anyhow::bail!("A message has no Content-Length header.");
+    };
+    let mut content = vec![0; length];
+    reader.read_exact(&mut content)?;
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+/// Write a message with its header.
+fn write_message(writer: &mut impl Write, message: &Value) -> anyhow::Result<()> {
+    let content = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{content}", content.len())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// The path of a `file://` URI, with percent-encoded bytes decoded.
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let hex = tail
+            .get(..2)
+            .filter(|x| x.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|x| std::str::from_utf8(x).ok());
+        match hex.and_then(|x| u8::from_str_radix(x, 16).ok()) {
+            Some(decoded) if byte == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    // Windows paths look like `/C:/dir/file.md` in URIs.
+    let path = match path.as_bytes() {
+        [b'/', _, b':', ..] if cfg!(windows) => &path[1..],
+        _ => &path,
+    };
+    Some(PathBuf::from(path))
+}
+
+/// The `textDocument/publishDiagnostics` notification for a document.
+fn publish(uri: &str, diagnostics: &[Diagnostic]) -> Value {
+    let diagnostics: Vec<Value> = diagnostics
+        .iter()
+        .map(|x| {
+            let position = json!({"line": x.line.saturating_sub(1), "character": 0});
+            json!({
+                "range": {"start": position, "end": position},
+                "severity": 1,
+                "source": "repl-check",
+                "message": x.message,
+            })
+        })
+        .collect();
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+/// Serve on stdin and stdout until the client sends `exit` or closes stdin. `check` is called
+/// with the path of a document whenever it is opened or saved, and returns its diagnostics.
+/// An error from it is published as a diagnostic at the first line.
+pub fn serve(
+    mut check: impl FnMut(&Path) -> anyhow::Result<Vec<Diagnostic>>,
+) -> anyhow::Result<()> {
+    let mut reader = std::io::stdin().lock();
+    let mut writer = std::io::stdout().lock();
+    while let Some(message) = read_message(&mut reader)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let id = message.get("id").cloned();
+        let uri = message["params"]["textDocument"]["uri"].as_str();
+        tracing::debug!(method, "received a message");
+        let response = match (method, uri) {
+            ("initialize", _) => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": {"openClose": true, "change": 0, "save": true},
+                },
+                "serverInfo": {"name": "repl-check", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            ("shutdown", _) => Some(Value::Null),
+            ("exit", _) => return Ok(()),
+            ("textDocument/didOpen" | "textDocument/didSave", Some(uri)) => {
+                let Some(path) = uri_to_path(uri) else {
+                    continue;
+                };
+                let diagnostics = check(&path).unwrap_or_else(|e| {
+                    vec![Diagnostic {
+                        line: 1,
+                        message: e.to_string(),
+                    }]
+                });
+                write_message(&mut writer, &publish(uri, &diagnostics))?;
+                None
+            }
+            ("textDocument/didClose", Some(uri)) => {
+                write_message(&mut writer, &publish(uri, &[]))?;
+                None
+            }
+            _ => None,
+        };
+        // Notifications have no id and are not answered.
+        let Some(id) = id else {
+            continue;
+        };
+        let response = match response {
+            Some(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            None => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": METHOD_NOT_FOUND, "message": format!("Unknown method {method}")},
+            }),
+        };
+        write_message(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uris_are_percent_decoded() {
+        let path = |uri| uri_to_path(uri).map(|x| x.to_string_lossy().into_owned());
+        assert_eq!(path("file:///a%20b/%C3%A9.md").unwrap(), "/a b/é.md");
+        // Percent signs which are not followed by two hex digits are kept.
+        assert_eq!(path("file:///100%/%zz/%+1/%4").unwrap(), "/100%/%zz/%+1/%4");
+        assert_eq!(path("file:///%FF.md"), None);
+        assert_eq!(path("untitled:Untitled-1"), None);
+    }
+
+    #[test]
+    fn messages_are_read_after_their_headers() {
+        let mut input = Vec::new();
+        write_message(&mut input, &json!({"id": 1})).unwrap();
+        input.extend_from_slice(b"content-type: x\r\ncontent-length:  8\r\n\r\n{\"id\":2}");
+        input.extend_from_slice(b"Content-Type: x\r\n\r\n{}");
+        let mut reader = input.as_slice();
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({"id": 1})));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({"id": 2})));
+        assert!(read_message(&mut reader).is_err());
+        assert_eq!(read_message(&mut &b""[..]).unwrap(), None);
+    }
+}
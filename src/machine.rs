@@ -0,0 +1,114 @@
+//! Built-in placeholders for values which differ between machines, like `{HOME}` and
+//! `{HOSTNAME}`.
+//!
+//! In the expected output, the placeholders are replaced with the values on this machine before
+//! the output is matched. In output which is written to the documents, like `???` holes and
+//! recorded output, the values are replaced with the placeholders. The values are only replaced
+//! where they are not part of a longer word, so a user `root` doesn't turn `chroot` into
+//! `ch{USER}`, and the longest values are replaced first, so a working directory in the home
+//! directory becomes `{CWD}` rather than `{HOME}/...`.
+
+use std::borrow::Cow;
+use std::path::Path;
+
+lazy_static::lazy_static! {
+    /// The placeholders which are the same for all sessions.
+    static ref MACHINE_VALUES: Vec<(&'static str, String)> = {
+        let env = |names: &[&str]| names.iter().find_map(|x| std::env::var(x).ok());
+        let temp_dir = std::env::temp_dir().display().to_string();
+        vec![
+            ("{HOME}", env(&["HOME", "USERPROFILE"])),
+            ("{TMPDIR}", Some(temp_dir.trim_end_matches(['/', '\\']).to_string())),
+            ("{USER}", env(&["USER", "USERNAME"])),
+            ("{HOSTNAME}", hostname()),
+        ]
+        .into_iter()
+        .filter_map(|(placeholder, value)| Some((placeholder, value?)))
+        .collect()
+    };
+}
+
+/// The name of this machine.
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is valid for its length.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|x| *x == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// The name of this machine.
+#[cfg(windows)]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// The values of the built-in placeholders for a session.
+#[derive(Debug, Clone)]
+pub(crate) struct MachineValues(Vec<(&'static str, String)>);
+
+impl MachineValues {
+    /// The values for a session which runs in `cwd`, or in the current directory if it is
+    /// `None`. Values shorter than two characters, like a home directory `/`, are left out since
+    /// they would be replaced everywhere.
+    pub(crate) fn new(cwd: Option<&Path>) -> Self {
+        let cwd = match cwd {
+            Some(cwd) => Some(cwd.display().to_string()),
+            None => std::env::current_dir()
+                .ok()
+                .map(|x| x.display().to_string()),
+        };
+        let mut values: Vec<_> = cwd
+            .map(|x| ("{CWD}", x))
+            .into_iter()
+            .chain(MACHINE_VALUES.iter().cloned())
+            .filter(|(_, value)| value.chars().count() >= 2)
+            .collect();
+        values.sort_by_key(|(_, value)| std::cmp::Reverse(value.len()));
+        Self(values)
+    }
+
+    /// Replace the placeholders in an expected line with their values.
+    pub(crate) fn expand<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let mut line = Cow::Borrowed(line);
+        for (placeholder, value) in &self.0 {
+            if line.contains(placeholder) {
+                line = Cow::Owned(line.replace(placeholder, value));
+            }
+        }
+        line
+    }
+
+    /// Replace the values in an actual line with the placeholders.
+    pub(crate) fn restore(&self, line: &str) -> String {
+        self.0
+            .iter()
+            .fold(line.to_string(), |line, (placeholder, value)| {
+                replace_words(&line, value, placeholder)
+            })
+    }
+}
+
+/// Replace the occurrences of `from` in `text` with `to`, except where `from` starts or ends
+/// with a word character which continues a word before or after it.
+fn replace_words(text: &str, from: &str, to: &str) -> String {
+    let is_word = |x: Option<char>| x.is_some_and(|x| x.is_alphanumeric() || x == '_');
+    let (first, last) = (from.chars().next(), from.chars().next_back());
+    let mut result = String::new();
+    let mut end = 0;
+    for (start, _) in text.match_indices(from) {
+        let before = text[..start].chars().next_back();
+        let after = text[start + from.len()..].chars().next();
+        if (is_word(first) && is_word(before)) || (is_word(last) && is_word(after)) {
+            continue;
+        }
+        result += &text[end..start];
+        result += to;
+        end = start + from.len();
+    }
+    result += &text[end..];
+    result
+}
@@ -1,3 +1,1473 @@
-fn main() {
-    println!("Hello, world!");
+use clap::{Args, Parser, Subcommand};
+use pandoc_ast::Pandoc;
+use regex::Regex;
+use repl_check::backend::{DefaultBackend, PooledBackend, ReplBackend};
+use repl_check::cache::{Cache, DEFAULT_CACHE_DIR};
+use repl_check::config::{expand_inputs, Config};
+use repl_check::diff::format_diff;
+use repl_check::doctest::{self, DoctestFormat};
+use repl_check::document::{
+    accept_pending, code_block_lines, pending_path, read_document, write_documents, NewDocument,
+    PENDING_DIR,
+};
+use repl_check::history::{History, HISTORY_FILE_NAME};
+use repl_check::html;
+use repl_check::lsp::{self, Diagnostic};
+use repl_check::progress::stderr_progress;
+use repl_check::reader::Cancel;
+use repl_check::report::{
+    BlockReport, BlockStatus, BlockUpdate, DocumentReport, FailureKind, Report, ScreenError,
+    SessionReport,
+};
+use repl_check::watch::Watcher;
+use repl_check::{
+    apply_updates, check_documents_with_backend, code_block_coverage, failure_kind,
+    has_global_sessions, list_sessions, patch_source, unknown_attributes, validate_documents,
+    CheckResult, Coverage, ExecutionOrder, OnFailure, Options, SessionInfo, UpdatePolicy,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The number of commands listed with `--timings`.
+const SLOWEST_COMMANDS: usize = 10;
+
+/// The exit code when blocks failed because their output didn't match, see [Report::exit_code].
+const EXIT_MISMATCH: u8 = 1;
+
+/// The exit code when a REPL could not be run as expected, like when it could not be started or
+/// a prompt timed out, and of other errors.
+const EXIT_ERROR: u8 = 2;
+
+/// The exit code when the command line, the configuration or a document is invalid.
+const EXIT_INVALID: u8 = 3;
+
+/// An error which makes the program exit with `code`, see [main].
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+struct ExitError {
+    code: u8,
+    error: anyhow::Error,
+}
+
+/// An error about invalid input, which exits with [EXIT_INVALID].
+fn invalid(error: anyhow::Error) -> ExitError {
+    ExitError {
+        code: EXIT_INVALID,
+        error,
+    }
+}
+
+/// Verify that REPL sessions in documents produce the documented output.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Defaults to `check` with the `[inputs]` in the configuration file.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Run the fake REPL of [repl_check::testing::fake_repl] instead, for tests.
+    #[arg(long, hide = true)]
+    fake_repl: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check that all REPL sessions produce the expected output.
+    Check(RunArgs),
+
+    /// Run all REPL sessions and write updated prompts and `???` holes back to the documents, or
+    /// what `--update` allows. Either all documents are updated or none, if any of them can't be
+    /// written.
+    Update(RunArgs),
+
+    /// Check the documents, and check them again whenever they are saved. Only the sessions in
+    /// the changed documents are run, unless sessions continue across documents.
+    Watch(RunArgs),
+
+    /// List the REPL sessions and their blocks without running anything.
+    List(ListArgs),
+
+    /// Replace the documents with their pending updates from `check --pending`, once they have
+    /// been reviewed.
+    Accept(AcceptArgs),
+
+    /// List the code blocks which are not checked since they are not REPL blocks, by language,
+    /// and how many of all code blocks are checked.
+    Coverage(CoverageArgs),
+
+    /// Print the parsed sessions, blocks, commands and expected output of the documents, with
+    /// what every expected line means and where everything is, for editors and other runners.
+    Export(ExportArgs),
+
+    /// Print Markdown with REPL blocks for the doctests in a source file, like a Python module.
+    ImportDoctest(ImportDoctestArgs),
+
+    /// Print the REPL blocks in the documents as doctests, session by session.
+    ExportDoctest(ExportDoctestArgs),
+
+    /// Run a language server on stdin and stdout, which checks documents when they are opened or
+    /// saved in an editor and shows the failing blocks as diagnostics.
+    Lsp(LspArgs),
+}
+
+#[derive(Args, Debug)]
+struct AcceptArgs {
+    /// The documents, directories or glob patterns whose updates to accept. Defaults to the
+    /// `[inputs]` in the configuration file.
+    files: Vec<PathBuf>,
+
+    /// The configuration file, defaults to `repl-check.toml` in the current directory.
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct CoverageArgs {
+    /// The documents, directories or glob patterns to scan. Defaults to the `[inputs]` in the
+    /// configuration file.
+    files: Vec<PathBuf>,
+
+    /// The configuration file, defaults to `repl-check.toml` in the current directory.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Fail like on a mismatch, with exit code 1, if less than this percentage of the code blocks
+    /// are checked.
+    #[arg(long, value_name = "PERCENT")]
+    fail_under: Option<f64>,
+}
+
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// The documents, directories or glob patterns to export. Defaults to the `[inputs]` in the
+    /// configuration file.
+    files: Vec<PathBuf>,
+
+    /// Enable features, blocks with an `if_feature` attribute are not exported unless it is
+    /// enabled.
+    #[arg(long, value_delimiter = ',')]
+    features: Vec<String>,
+
+    /// The configuration file, defaults to `repl-check.toml` in the current directory.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Sessions with the same name in different documents are the same session, like with
+    /// `repl-check check --shared-sessions`.
+    #[arg(long)]
+    shared_sessions: bool,
+
+    /// The format of the output, only `json` for now.
+    #[arg(long, default_value = "json")]
+    format: ExportFormat,
+}
+
+/// The formats of `repl-check export`.
+#[derive(Debug, Clone, Copy)]
+enum ExportFormat {
+    /// The sessions as a JSON array. The lines of the blocks are counted in the document, and
+    /// the lines of the invocations in the code of the block starting at 0.
+    Json,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "json" => Ok(Self::Json),
+            _ => Err("Expected json".to_string()),
+        }
+    }
+}
+
+/// A report which is written after `repl-check check`, see `--report`.
+#[derive(Debug, Clone)]
+enum ReportOutput {
+    /// A static HTML report and a badge in a directory, see [html].
+    Html(PathBuf),
+}
+
+impl std::str::FromStr for ReportOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.split_once('=') {
+            Some(("html", dir)) if !dir.is_empty() => Ok(Self::Html(dir.into())),
+            _ => Err("Expected html=DIR".to_string()),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct ImportDoctestArgs {
+    /// The source file with the doctests.
+    file: PathBuf,
+
+    /// The format of the doctests, `python` or `elixir`.
+    #[arg(long, default_value = "python")]
+    format: DoctestFormat,
+
+    /// The session of the generated REPL blocks.
+    #[arg(long, default_value = "doctest")]
+    session: String,
+}
+
+#[derive(Args, Debug)]
+struct ExportDoctestArgs {
+    /// The documents, directories or glob patterns to export. Defaults to the `[inputs]` in the
+    /// configuration file.
+    files: Vec<PathBuf>,
+
+    /// The configuration file, defaults to `repl-check.toml` in the current directory.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// The format of the doctests, `python` or `elixir`.
+    #[arg(long, default_value = "python")]
+    format: DoctestFormat,
+}
+
+#[derive(Args, Debug)]
+struct LspArgs {
+    /// Enable features, blocks with an `if_feature` attribute are skipped unless it is enabled.
+    #[arg(long, value_delimiter = ',')]
+    features: Vec<String>,
+
+    /// The configuration file, defaults to `repl-check.toml` in the current directory.
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ListArgs {
+    /// The documents, directories or glob patterns to list. Defaults to the `[inputs]` in the
+    /// configuration file.
+    files: Vec<PathBuf>,
+
+    /// Enable features, blocks with an `if_feature` attribute are not listed unless it is enabled.
+    #[arg(long, value_delimiter = ',')]
+    features: Vec<String>,
+
+    /// The configuration file, defaults to `repl-check.toml` in the current directory.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Sessions with the same name in different documents are the same session, like with
+    /// `repl-check check --shared-sessions`.
+    #[arg(long)]
+    shared_sessions: bool,
+
+    /// Print the sessions as JSON, for editors and other tools.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug, Default)]
+struct RunArgs {
+    /// The documents, directories or glob patterns to check. Defaults to the `[inputs]` in the
+    /// configuration file.
+    files: Vec<PathBuf>,
+
+    /// Enable features, blocks with an `if_feature` attribute are skipped unless it is enabled.
+    #[arg(long, value_delimiter = ',')]
+    features: Vec<String>,
+
+    /// The configuration file, defaults to `repl-check.toml` in the current directory.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Don't start any new sessions after this time (e.g. `10m`), they are reported as not run.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    max_total_time: Option<Duration>,
+
+    /// Apply the first suggested fix to mismatching output and write it to the document.
+    #[arg(long)]
+    fix_suggestions: bool,
+
+    /// The timeout when waiting for a prompt (e.g. `30s`), overrides the configuration file.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Fail when a command prints nothing new for this long (e.g. `30s`) while waiting for the
+    /// prompt, even if the timeout is larger. Overrides the configuration file.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    idle_timeout: Option<Duration>,
+
+    /// The number of documents to check in parallel, overrides the configuration file.
+    #[arg(long, short = 'j')]
+    concurrency: Option<usize>,
+
+    /// Write SVG snapshots of the terminal screen of failed sessions to this directory. Requires
+    /// the `vt100` feature.
+    #[arg(long)]
+    screenshot_dir: Option<PathBuf>,
+
+    /// Write a transcript of everything sent to and read from the REPL of every session which is
+    /// run, with timestamps, to this directory. Useful for debugging prompt regexes.
+    #[arg(long, value_name = "DIR")]
+    save_transcripts: Option<PathBuf>,
+
+    /// Don't delete the temporary directories of sessions with `sandbox=true` afterwards, and
+    /// print where they are, for debugging.
+    #[arg(long)]
+    keep_sandboxes: bool,
+
+    /// Sessions with the same name in different documents are the same session, which is kept
+    /// running from one document to the next in the order the documents are given. The same as
+    /// `scope=global` on every session, which can be overridden with `scope=document`.
+    #[arg(long)]
+    shared_sessions: bool,
+
+    /// Stop at the first failing block, instead of running all sessions and reporting all
+    /// failures at the end.
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// What to do when a block fails: `report` it, or `debug` it by showing the error and
+    /// attaching the terminal to the REPL of the session, to inspect its state by hand until
+    /// Ctrl-] is pressed. Then the run continues or is aborted, as you choose. Only REPLs in a
+    /// pseudo terminal can be attached to.
+    #[arg(long, value_name = "ACTION", default_value = "report")]
+    on_failure: OnFailure,
+
+    /// The order in which the blocks are run: `session` runs all blocks of a session before the
+    /// next session, and `document` runs the blocks in the order they appear in the documents,
+    /// switching between the REPLs of the sessions.
+    #[arg(long, value_name = "ORDER", default_value = "session")]
+    order: ExecutionOrder,
+
+    /// Keep a pool of started REPLs, so sessions get a REPL which is already running. REPLs of
+    /// sessions with a `reset_cmd` attribute are reset and reused by later sessions with the same
+    /// command, instead of being started for every session.
+    #[arg(long)]
+    reuse_processes: bool,
+
+    /// Fail on blocks which have not passed within this time (e.g. `30d`), like blocks behind a
+    /// feature which is rarely enabled. When blocks last passed is recorded in
+    /// `.repl-check/history.json`. Cached sessions which are older are run again. Overrides the
+    /// configuration file.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    max_age: Option<Duration>,
+
+    /// Fill in the output of commands which have no expected output, and write it back to the
+    /// documents. A block with only prompts and commands is filled in from scratch.
+    #[arg(long)]
+    record: bool,
+
+    /// What may be changed when the documents are updated: `holes` only fills in `???` holes,
+    /// `prompts` also rewrites prompts to the actual prompts, and `all` also replaces any
+    /// mismatching output with the actual output. Defaults to `prompts`. With `check`, the
+    /// documents are updated like with `update`.
+    #[arg(long, value_name = "POLICY")]
+    update: Option<UpdatePolicy>,
+
+    /// Write the updated documents to `.repl-check/pending/` instead of changing them, and fail
+    /// if there are any. Mismatching output is replaced with the actual output, unless `--update`
+    /// says otherwise. The updates can be reviewed, for example as artifacts in CI, and applied
+    /// with `repl-check accept`.
+    #[arg(long)]
+    pending: bool,
+
+    /// Show every change to a block before the documents are written, and ask whether to accept,
+    /// reject or edit it.
+    #[arg(long)]
+    interactive: bool,
+
+    /// Skip sessions which have passed before without any changes to their blocks, their
+    /// attributes or how their REPLs are started. The version of a REPL is not known before it
+    /// is started, so clear the cache directory after upgrading a REPL.
+    #[arg(long)]
+    cache: bool,
+
+    /// With `--cache`, the directory where sessions which have passed are recorded. Defaults to
+    /// `target/repl-check-cache`.
+    #[arg(long, requires = "cache")]
+    cache_dir: Option<PathBuf>,
+
+    /// Only read and validate the documents, reporting errors like a missing prompt or a bad
+    /// regex without starting any REPLs. Fast enough for a pre-commit hook.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Warn about unknown attributes of REPL blocks instead of failing.
+    #[arg(long)]
+    lenient: bool,
+
+    /// Only run the sessions with these names.
+    #[arg(long = "session", value_name = "NAME", value_delimiter = ',')]
+    sessions: Vec<String>,
+
+    /// Don't run the sessions with these names.
+    #[arg(long = "skip-session", value_name = "NAME", value_delimiter = ',')]
+    skip_sessions: Vec<String>,
+
+    /// Only run the blocks whose code matches this regex. The earlier blocks in their sessions
+    /// are run too, to set up the state, and other sessions are not run.
+    #[arg(long, value_name = "REGEX")]
+    block_matching: Option<Regex>,
+
+    /// With `--block-matching`, don't run the earlier blocks in the sessions either.
+    #[arg(long, requires = "block_matching")]
+    skip_earlier_blocks: bool,
+
+    /// Report how the output of the blocks which passed was matched, like the number of lines
+    /// matched by every `...` hole. Cached sessions are not reported. Also log what is going on
+    /// to stderr: `-v` logs sessions and blocks, `-vv` also commands, prompts and matches, and
+    /// `-vvv` everything. `RUST_LOG` overrides the log level.
+    #[arg(long, short = 'v', action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print nothing but a summary line, for scripts which tell failures apart by the exit code.
+    /// Errors are only printed if the command line, the configuration or a document is invalid.
+    #[arg(long, short = 'q', conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Don't show the progress of the sessions while they run. It is shown with progress bars on
+    /// a terminal, and otherwise with a line when a session starts or finishes or a block is done.
+    #[arg(long)]
+    no_progress: bool,
+
+    /// List the slowest commands across all documents in the report.
+    #[arg(long)]
+    timings: bool,
+
+    /// Write the counts of documents, sessions, blocks, commands and failures in the summary,
+    /// together with the durations, as JSON to this file.
+    #[arg(long, value_name = "FILE")]
+    stats: Option<PathBuf>,
+
+    /// Write a report, like `html=target/repl-report/` for a static HTML report with the result
+    /// of every document, its failures and the transcripts of its sessions, and an SVG badge
+    /// like `docs: 98% verified`, which can be published from CI.
+    #[arg(long, value_name = "FORMAT=DIR")]
+    report: Option<ReportOutput>,
+
+    /// Run a session again from the start up to this many times when a block fails, for
+    /// examples which fail now and then. Blocks can override it with the `retries` attribute.
+    #[arg(long, default_value_t = 0)]
+    retries: usize,
+}
+
+impl RunArgs {
+    /// Load the configuration file.
+    fn config(&self) -> anyhow::Result<Config> {
+        match &self.config {
+            Some(path) => Config::load(path),
+            None => Config::load_default(),
+        }
+    }
+
+    /// Print a message to stderr, unless `--quiet` is given.
+    fn eprint(&self, message: impl std::fmt::Display) {
+        if !self.quiet {
+            eprintln!("{message}");
+        }
+    }
+
+    /// Merge the configuration with the command line arguments.
+    fn options(&self, config: &Config) -> Options {
+        let options = config.options();
+        Options {
+            features: options
+                .features
+                .iter()
+                .chain(self.features.iter())
+                .cloned()
+                .collect(),
+            deadline: self.max_total_time.map(|x| Instant::now() + x),
+            fix_suggestions: self.fix_suggestions,
+            timeout: self.timeout.or(options.timeout),
+            idle_timeout: self.idle_timeout.or(options.idle_timeout),
+            shared_sessions: self.shared_sessions,
+            fail_fast: self.fail_fast,
+            record: self.record,
+            cache: self.cache.then(|| {
+                Cache::new(
+                    self.cache_dir
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_CACHE_DIR.into()),
+                )
+            }),
+            max_age: self.max_age.or(options.max_age),
+            sessions: self.sessions.clone(),
+            skip_sessions: self.skip_sessions.clone(),
+            block_filter: self.block_matching.clone(),
+            skip_earlier_blocks: self.skip_earlier_blocks,
+            lenient: self.lenient,
+            verbose: self.verbose > 0,
+            retries: self.retries,
+            on_failure: self.on_failure,
+            order: self.order,
+            keep_transcripts: self.save_transcripts.is_some() || self.report.is_some(),
+            keep_sandboxes: self.keep_sandboxes,
+            progress: (!self.no_progress && !self.quiet).then(stderr_progress),
+            ..options
+        }
+    }
+}
+
+/// Log to stderr at the level given by `-v` and `-q`, or by `RUST_LOG` if it is set.
+fn init_logging(args: &RunArgs) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => "off",
+        (false, 0) => "warn",
+        (false, 1) => "info",
+        (false, 2) => "debug",
+        (false, _) => "trace",
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(format!("repl_check={level}")));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Write the screen snapshot of a failed session to `<dir>/<document>-<session>.svg`.
+fn write_screenshot(dir: &Path, path: &Path, error: &anyhow::Error) -> anyhow::Result<()> {
+    let Some(ScreenError {
+        session, screen, ..
+    }) = error.downcast_ref()
+    else {
+        return Ok(());
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let svg_path = dir.join(format!("{stem}-{session}.svg"));
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&svg_path, &screen.svg)
+        .map_err(|e| anyhow::anyhow!("Failed to write {}: {e}", svg_path.display()))?;
+    eprintln!("Wrote a screenshot to {}", svg_path.display());
+    Ok(())
+}
+
+/// Write the transcripts of the sessions in a document to files in `dir`.
+fn write_transcripts(dir: &Path, path: &Path, sessions: &[SessionReport]) -> anyhow::Result<()> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    for session in sessions {
+        let Some(transcript) = &session.transcript else {
+            continue;
+        };
+        let transcript_path = dir.join(format!("{stem}-{}.txt", session.name));
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(&transcript_path, transcript)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {e}", transcript_path.display()))?;
+    }
+    Ok(())
+}
+
+/// A document which has been read.
+struct LoadedDocument<'a> {
+    path: &'a Path,
+    format: Option<&'a str>,
+    options: Options,
+    document: Pandoc,
+
+    /// The source text of the document, which updated blocks are patched into.
+    source: Option<String>,
+}
+
+/// Read a document.
+fn load_file<'a>(
+    path: &'a Path,
+    config: &'a Config,
+    options: &Options,
+) -> anyhow::Result<LoadedDocument<'a>> {
+    let format = config
+        .input_settings(path)
+        .and_then(|x| x.format.as_deref());
+    let options = config.document_options(path, options)?;
+    let document = read_document(path, format)?;
+    Ok(LoadedDocument {
+        path,
+        format,
+        options,
+        document,
+        source: std::fs::read_to_string(path).ok(),
+    })
+}
+
+/// Check a group of documents together. Returns a report for every document, and the updates of
+/// its blocks if it should be written back. Errors are printed as they occur.
+fn check_files<B: ReplBackend>(
+    documents: &[&LoadedDocument],
+    args: &RunArgs,
+    write: bool,
+) -> Vec<(DocumentReport, Vec<BlockUpdate>)> {
+    let inputs: Vec<_> = documents
+        .iter()
+        .map(|x| (&x.document, &x.options))
+        .collect();
+    let results = match check_documents_with_backend::<B>(&inputs) {
+        Ok(results) => results,
+        Err(e) => {
+            if let Some(dir) = &args.screenshot_dir {
+                if let Err(e) = write_screenshot(dir, documents[0].path, &e) {
+                    args.eprint(e);
+                }
+            }
+            let paths: Vec<_> = documents
+                .iter()
+                .map(|x| x.path.display().to_string())
+                .collect();
+            let error = format!("In {}: {e}", paths.join(", "));
+            args.eprint(format!("Error: {error}"));
+            // The blocks are reported as not run, so they are not verified in the summary.
+            let sessions = list_sessions(&inputs).unwrap_or_default();
+            return documents
+                .iter()
+                .enumerate()
+                .map(|(i, x)| {
+                    let mut blocks: Vec<BlockReport> = sessions
+                        .iter()
+                        .flat_map(|session| {
+                            session
+                                .blocks
+                                .iter()
+                                .filter(|block| block.document == i)
+                                .map(|block| BlockReport {
+                                    number: block.number,
+                                    session: session.name.clone(),
+                                    code: block.code.clone(),
+                                    status: BlockStatus::NotRun,
+                                    duration: None,
+                                    commands: 0,
+                                    alternative: None,
+                                    diff: None,
+                                })
+                        })
+                        .collect();
+                    blocks.sort_by_key(|x| x.number);
+                    let report = DocumentReport {
+                        path: x.path.to_path_buf(),
+                        error: Some(error.clone()),
+                        failure_kind: Some(failure_kind(&e)),
+                        blocks,
+                        ..DocumentReport::default()
+                    };
+                    (report, Vec::new())
+                })
+                .collect();
+        }
+    };
+    let mut reports = Vec::new();
+    for (loaded, result) in documents.iter().zip(results) {
+        let CheckResult {
+            sessions,
+            updates,
+            failures,
+            blocks,
+            notes,
+            timings,
+        } = result;
+        if let Some(dir) = &args.screenshot_dir {
+            for failure in &failures {
+                if let Err(e) = write_screenshot(dir, loaded.path, &failure.error) {
+                    args.eprint(e);
+                }
+            }
+        }
+        if let Some(dir) = &args.save_transcripts {
+            if let Err(e) = write_transcripts(dir, loaded.path, &sessions) {
+                args.eprint(e);
+            }
+        }
+        for session in &sessions {
+            for dir in &session.kept_sandboxes {
+                args.eprint(format!(
+                    "Kept the sandbox of session {}: {}",
+                    session.name,
+                    dir.display()
+                ));
+            }
+        }
+        let report = DocumentReport {
+            path: loaded.path.to_path_buf(),
+            sessions,
+            error: None,
+            failures: failures.iter().map(ToString::to_string).collect(),
+            failure_kind: failures.iter().map(|x| x.kind).max(),
+            blocks,
+            stale: Vec::new(),
+            notes,
+            timings,
+        };
+        reports.push((report, if write { updates } else { Vec::new() }));
+    }
+    reports
+}
+
+/// Show every update of the blocks in a document as a diff and ask whether to accept, reject or
+/// edit it. Returns the accepted updates.
+fn review_updates(path: &Path, updates: Vec<BlockUpdate>) -> anyhow::Result<Vec<BlockUpdate>> {
+    let color = std::io::stdout().is_terminal();
+    let mut accepted = Vec::new();
+    for mut update in updates {
+        loop {
+            println!(
+                "{}: Code block {} in session {}:",
+                path.display(),
+                update.number,
+                update.session
+            );
+            print!("{}", format_diff(&update.code, &update.updated_code, color));
+            print!("Accept this change? [a]ccept, [r]eject, [e]dit: ");
+            std::io::stdout().flush()?;
+            let mut answer = String::new();
+            if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+                anyhow::bail!("No answer, no documents were changed.");
+            }
+            match answer.trim() {
+                "a" | "accept" => {
+                    accepted.push(update);
+                    break;
+                }
+                "r" | "reject" => break,
+                "e" | "edit" => update.updated_code = edit(&update.updated_code)?,
+                _ => println!("Please answer a, r or e."),
+            }
+        }
+    }
+    Ok(accepted)
+}
+
+/// Edit a text in the editor in `$VISUAL` or `$EDITOR`, or `vi` by default.
+fn edit(text: &str) -> anyhow::Result<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let path = std::env::temp_dir().join(format!("repl-check-{}.txt", std::process::id()));
+    std::fs::write(&path, format!("{text}\n"))?;
+    // The editor may contain arguments, like `code --wait`.
+    let status = std::process::Command::new("sh")
+        .args(["-c", &format!("{editor} \"$1\""), "sh"])
+        .arg(&path)
+        .status();
+    let text = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => anyhow::bail!("The editor `{editor}` failed: {status}"),
+        Err(e) => anyhow::bail!("Failed to run the editor `{editor}`: {e}"),
+    }
+    let text = text?;
+    Ok(text.strip_suffix('\n').unwrap_or(&text).to_string())
+}
+
+/// Record the blocks which passed in the history, and with `max_age` flag the other blocks as
+/// stale if they have not passed within that time.
+fn update_history(report: &mut Report, max_age: Option<Duration>) -> anyhow::Result<()> {
+    let path = Path::new(HISTORY_FILE_NAME);
+    let mut history = History::load(path)?;
+    let now = SystemTime::now();
+    for document in &mut report.documents {
+        for block in &document.blocks {
+            if block.status == BlockStatus::Passed {
+                history.record_passed(&document.path, &block.code, now);
+                continue;
+            }
+            let Some(max_age) = max_age else {
+                continue;
+            };
+            let last_passed = history.last_passed(&document.path, &block.code);
+            if last_passed.is_some_and(|x| now.duration_since(x).unwrap_or_default() <= max_age) {
+                continue;
+            }
+            let last_passed = match last_passed {
+                Some(x) => format!("last passed {}", humantime::format_rfc3339_seconds(x)),
+                None => "never passed".to_string(),
+            };
+            document.stale.push(format!(
+                "Code block {} in session {}: {last_passed}",
+                block.number, block.session
+            ));
+        }
+    }
+    if let Err(e) = history.save(path) {
+        eprintln!("Warning: {e}");
+    }
+    Ok(())
+}
+
+/// The documents to check, given as arguments or as `[inputs]` in the configuration.
+fn input_files(args: &RunArgs, config: &Config) -> anyhow::Result<Vec<PathBuf>> {
+    let files = if args.files.is_empty() {
+        config.input_files()?
+    } else {
+        expand_inputs(&args.files)?
+    };
+    if files.is_empty() {
+        anyhow::bail!(
+            "No documents to check: Give them as arguments or as [inputs] in the config."
+        );
+    }
+    Ok(files)
+}
+
+/// Check documents with the backend `B`, write back the updates and print a report.
+///
+/// If `changed` is given, only those documents are checked, unless there are sessions which
+/// continue across documents. All documents are validated anyway.
+fn run<B: ReplBackend>(
+    files: &[PathBuf],
+    changed: Option<&[PathBuf]>,
+    args: &RunArgs,
+    config: &Config,
+    update: bool,
+) -> anyhow::Result<()> {
+    // Stops the sessions which are running when a document fails with --fail-fast, or when the
+    // run is aborted from the debug shell of --on-failure=debug.
+    let cancel = Cancel::default();
+    let options = Options {
+        update: update.then(|| {
+            let default = match args.pending {
+                true => UpdatePolicy::All,
+                false => UpdatePolicy::default(),
+            };
+            args.update.unwrap_or(default)
+        }),
+        cancel: (args.fail_fast || args.on_failure == OnFailure::Debug).then(|| cancel.clone()),
+        ..args.options(config)
+    };
+    let start = Instant::now();
+    // Validate all documents before running anything, to report all errors at once.
+    let mut documents = Vec::new();
+    let mut invalid = 0;
+    for path in files {
+        match load_file(path, config, &options) {
+            Ok(document) => documents.push(document),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                invalid += 1;
+            }
+        }
+    }
+    let inputs: Vec<_> = documents
+        .iter()
+        .map(|x| (&x.document, &x.options))
+        .collect();
+    for document in documents.iter().filter(|_| args.lenient) {
+        for warning in unknown_attributes(&document.document) {
+            args.eprint(format!(
+                "Warning: In {}: {warning}",
+                document.path.display()
+            ));
+        }
+    }
+    for (document, result) in documents.iter().zip(validate_documents(&inputs)) {
+        if let Err(e) = result {
+            let errors: Vec<String> = e.to_string().lines().map(|x| format!("  {x}")).collect();
+            eprintln!(
+                "Error: In {}:\n{}",
+                document.path.display(),
+                errors.join("\n")
+            );
+            invalid += 1;
+        }
+    }
+    if invalid > 0 {
+        return Err(ExitError {
+            code: EXIT_INVALID,
+            error: anyhow::anyhow!("{invalid} of {} documents have errors.", files.len()),
+        }
+        .into());
+    }
+    if args.dry_run {
+        let sessions = list_sessions(&inputs)?;
+        let blocks: usize = sessions.iter().map(|x| x.blocks.len()).sum();
+        println!(
+            "{} documents with {} sessions and {blocks} blocks are valid.",
+            files.len(),
+            sessions.len()
+        );
+        return Ok(());
+    }
+    let write = update || args.fix_suggestions || args.record;
+    let results = Mutex::new(BTreeMap::new());
+    // If any session continues in other documents, all documents are checked together in order,
+    // otherwise they are checked in parallel.
+    let (shared, separate): (Vec<_>, Vec<_>) = if documents
+        .iter()
+        .any(|x| has_global_sessions(&x.document, &x.options))
+    {
+        (documents.iter().enumerate().collect(), Vec::new())
+    } else {
+        let separate = documents
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| changed.is_none_or(|changed| changed.contains(&x.path.to_path_buf())))
+            .collect();
+        (Vec::new(), separate)
+    };
+    if !shared.is_empty() {
+        let group: Vec<_> = shared.iter().map(|(_, x)| *x).collect();
+        let mut results = results.lock().unwrap();
+        for ((idx, _), result) in shared.iter().zip(check_files::<B>(&group, args, write)) {
+            results.insert(*idx, result);
+        }
+    }
+    let concurrency = args.concurrency.or(config.concurrency).unwrap_or(1).max(1);
+    let next_file = AtomicUsize::new(0);
+    // Set when a document has failed with --fail-fast, so no more documents are checked.
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..concurrency.min(separate.len()) {
+            scope.spawn(|| loop {
+                let i = next_file.fetch_add(1, Ordering::Relaxed);
+                let Some(&(idx, document)) = separate.get(i) else {
+                    break;
+                };
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let (report, updates) = check_files::<B>(&[document], args, write).remove(0);
+                if report.error.is_some() && args.fail_fast {
+                    stop.store(true, Ordering::Relaxed);
+                    cancel.cancel();
+                }
+                results.lock().unwrap().insert(idx, (report, updates));
+            });
+        }
+    });
+    let mut report = Report {
+        duration: start.elapsed(),
+        slowest_commands: if args.timings { SLOWEST_COMMANDS } else { 0 },
+        ..Report::default()
+    };
+    let mut results = results.into_inner().unwrap();
+    if args.interactive {
+        for (idx, (_, updates)) in &mut results {
+            *updates = review_updates(documents[*idx].path, std::mem::take(updates))?;
+        }
+    }
+    // All updated documents are written at once, so a failure doesn't leave some of them updated.
+    // Only the code of the updated blocks is changed in the source if the blocks can be found in
+    // it, otherwise the whole document is written by pandoc.
+    let updated_documents: Vec<_> = results
+        .iter()
+        .filter(|(_, (_, updates))| !updates.is_empty())
+        .map(|(idx, (_, updates))| {
+            let loaded = &documents[*idx];
+            let patched = loaded
+                .source
+                .as_deref()
+                .and_then(|source| patch_source(source, &loaded.document, updates));
+            let document = match patched {
+                Some(source) => NewDocument::Source(source),
+                None => {
+                    NewDocument::Pandoc(loaded.format, apply_updates(&loaded.document, updates))
+                }
+            };
+            let path = match args.pending {
+                true => pending_path(loaded.path),
+                false => loaded.path.to_path_buf(),
+            };
+            (path, document)
+        })
+        .collect();
+    if args.pending {
+        // The pending updates of documents which are up to date now are stale.
+        for (idx, (_, updates)) in &results {
+            if updates.is_empty() {
+                let _ = std::fs::remove_file(pending_path(documents[*idx].path));
+            }
+        }
+        for (path, _) in &updated_documents {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+    }
+    let write_error = write_documents(&updated_documents).err().map(|e| {
+        args.eprint(format!("Error: {e}"));
+        e.to_string()
+    });
+    report.documents = results
+        .into_values()
+        .map(|(mut report, updates)| {
+            if !updates.is_empty() && report.error.is_none() && write_error.is_some() {
+                report.error.clone_from(&write_error);
+                report.failure_kind = Some(FailureKind::Error);
+            }
+            report
+        })
+        .collect();
+    update_history(&mut report, options.max_age)?;
+    match args.quiet {
+        true => println!("{}", report.summary_line()),
+        false => println!("{report}"),
+    }
+    if let Some(path) = &args.stats {
+        let stats = serde_json::to_string_pretty(&report.stats())? + "\n";
+        std::fs::write(path, stats)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {e}", path.display()))?;
+    }
+    if let Some(ReportOutput::Html(dir)) = &args.report {
+        html::write_report(&report, dir)?;
+        args.eprint(format!("Wrote the HTML report to {}", dir.display()));
+    }
+    let error = match report.failures() {
+        0 => None,
+        1 => Some(anyhow::anyhow!("1 document failed.")),
+        n => Some(anyhow::anyhow!("{n} documents failed.")),
+    };
+    if let Some(error) = error {
+        let code = report.exit_code();
+        return Err(ExitError { code, error }.into());
+    }
+    if args.pending && !updated_documents.is_empty() {
+        for (path, _) in &updated_documents {
+            args.eprint(format!("Pending: {}", path.display()));
+        }
+        return Err(ExitError {
+            code: EXIT_MISMATCH,
+            error: anyhow::anyhow!(
+                "{} documents have pending updates in {PENDING_DIR}. Review them and run \
+                 `repl-check accept`.",
+                updated_documents.len()
+            ),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Replace the documents with their pending updates.
+fn accept(args: &AcceptArgs) -> anyhow::Result<()> {
+    let args = RunArgs {
+        files: args.files.clone(),
+        config: args.config.clone(),
+        ..RunArgs::default()
+    };
+    let config = args.config().map_err(invalid)?;
+    let files = input_files(&args, &config).map_err(invalid)?;
+    let accepted = accept_pending(&files)?;
+    for path in &accepted {
+        println!("Accepted the updates of {}", path.display());
+    }
+    if accepted.is_empty() {
+        println!("No pending updates in {PENDING_DIR}.");
+    }
+    Ok(())
+}
+
+/// Check the documents, and check them again when they are changed until the program is killed.
+fn watch(args: &RunArgs, config: &Config) -> anyhow::Result<()> {
+    if args.fix_suggestions
+        || args.record
+        || args.interactive
+        || args.update.is_some()
+        || args.pending
+    {
+        return Err(invalid(anyhow::anyhow!(
+            "Documents can't be written in watch mode, since that would trigger another check."
+        ))
+        .into());
+    }
+    if args.dry_run {
+        return Err(invalid(anyhow::anyhow!("--dry-run can't be used in watch mode.")).into());
+    }
+    type Backend = PooledBackend<DefaultBackend>;
+    let mut files = input_files(args, config).map_err(invalid)?;
+    // Directories are watched for new documents too.
+    let watched: Vec<_> = args
+        .files
+        .iter()
+        .filter(|x| x.is_dir())
+        .chain(&files)
+        .cloned()
+        .collect();
+    let mut watcher = Watcher::new(&watched)?;
+    let mut changed = None;
+    loop {
+        if let Err(e) = run::<Backend>(&files, changed.as_deref(), args, config, false) {
+            eprintln!("Error: {e}");
+        }
+        eprintln!("Watching for changes...");
+        changed = loop {
+            let changed_paths: Vec<_> = watcher
+                .wait()
+                .inspect_err(|_| Backend::clear_pool())?
+                .iter()
+                .filter_map(|x| std::fs::canonicalize(x).ok())
+                .collect();
+            files = input_files(args, config)
+                .inspect_err(|_| Backend::clear_pool())
+                .map_err(invalid)?;
+            let changed_files: Vec<_> = files
+                .iter()
+                .filter(|x| std::fs::canonicalize(x).is_ok_and(|x| changed_paths.contains(&x)))
+                .cloned()
+                .collect();
+            if !changed_files.is_empty() {
+                break Some(changed_files);
+            }
+        };
+    }
+}
+
+/// The line of every block in its document by the index of the document and the number of the
+/// block, if it can be found in the source.
+fn block_lines(
+    documents: &[LoadedDocument],
+    sessions: &[SessionInfo],
+) -> BTreeMap<(usize, usize), usize> {
+    let mut lines = BTreeMap::new();
+    for (idx, document) in documents.iter().enumerate() {
+        let Ok(source) = std::fs::read_to_string(document.path) else {
+            continue;
+        };
+        // The blocks are searched for in the order they appear in the document.
+        let mut blocks: Vec<_> = sessions
+            .iter()
+            .flat_map(|x| &x.blocks)
+            .filter(|x| x.document == idx)
+            .collect();
+        blocks.sort_by_key(|x| x.number);
+        let codes: Vec<&str> = blocks.iter().map(|x| x.code.as_str()).collect();
+        for (block, line) in blocks.iter().zip(code_block_lines(&source, &codes)) {
+            if let Some(line) = line {
+                lines.insert((idx, block.number), line);
+            }
+        }
+    }
+    lines
+}
+
+/// Serve diagnostics for the documents which are opened in an editor. Every document is checked
+/// on its own, and every failing block is shown at its first line.
+fn lsp(args: &LspArgs) -> anyhow::Result<()> {
+    let args = RunArgs {
+        features: args.features.clone(),
+        config: args.config.clone(),
+        no_progress: true,
+        ..RunArgs::default()
+    };
+    let config = args.config().map_err(invalid)?;
+    let options = args.options(&config);
+    lsp::serve(|path| {
+        let loaded = load_file(path, &config, &options)?;
+        let result =
+            check_documents_with_backend::<DefaultBackend>(&[(&loaded.document, &loaded.options)])?
+                .remove(0);
+        let source = loaded.source.as_deref().unwrap_or_default();
+        let codes: Vec<&str> = result.blocks.iter().map(|x| x.code.as_str()).collect();
+        let lines: HashMap<usize, usize> = result
+            .blocks
+            .iter()
+            .zip(code_block_lines(source, &codes))
+            .filter_map(|(block, line)| Some((block.number, line?)))
+            .collect();
+        Ok(result
+            .failures
+            .iter()
+            .map(|failure| Diagnostic {
+                line: lines.get(&failure.block).copied().unwrap_or(1),
+                message: failure.error.to_string(),
+            })
+            .collect())
+    })
+}
+
+/// Print the sessions in the documents, as JSON if `--json` is given.
+fn list(args: &ListArgs) -> anyhow::Result<()> {
+    let json = args.json;
+    let args = RunArgs {
+        files: args.files.clone(),
+        features: args.features.clone(),
+        config: args.config.clone(),
+        shared_sessions: args.shared_sessions,
+        ..RunArgs::default()
+    };
+    let config = args.config().map_err(invalid)?;
+    let options = args.options(&config);
+    let files = input_files(&args, &config).map_err(invalid)?;
+    let documents = files
+        .iter()
+        .map(|path| load_file(path, &config, &options))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(invalid)?;
+    let inputs: Vec<_> = documents
+        .iter()
+        .map(|x| (&x.document, &x.options))
+        .collect();
+    let sessions = list_sessions(&inputs).map_err(invalid)?;
+    let lines = block_lines(&documents, &sessions);
+    if json {
+        let sessions: Vec<_> = sessions
+            .iter()
+            .map(|session| {
+                let blocks: Vec<_> = session
+                    .blocks
+                    .iter()
+                    .map(|block| {
+                        serde_json::json!({
+                            "document": documents[block.document].path,
+                            "number": block.number,
+                            "line": lines.get(&(block.document, block.number)),
+                            "code": block.code,
+                            "commands": block.commands,
+                        })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "name": session.name,
+                    "document": documents[session.document].path,
+                    "command": session.command,
+                    "blocks": blocks,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&sessions)?);
+        return Ok(());
+    }
+    for session in &sessions {
+        println!(
+            "{}: {} (`{}`), {} blocks",
+            documents[session.document].path.display(),
+            session.name,
+            session.command,
+            session.blocks.len()
+        );
+        for block in &session.blocks {
+            let location = match lines.get(&(block.document, block.number)) {
+                Some(line) => format!("{}:{line}", documents[block.document].path.display()),
+                None => documents[block.document].path.display().to_string(),
+            };
+            println!("  block {} at {location}", block.number);
+            for command in &block.commands {
+                for line in command.lines() {
+                    println!("    {line}");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// List the code blocks in the documents which are not checked, by language, and fail if too few
+/// of the code blocks are checked.
+fn coverage(args: &CoverageArgs) -> anyhow::Result<()> {
+    let run_args = RunArgs {
+        files: args.files.clone(),
+        config: args.config.clone(),
+        ..RunArgs::default()
+    };
+    let config = run_args.config().map_err(invalid)?;
+    let options = run_args.options(&config);
+    let files = input_files(&run_args, &config).map_err(invalid)?;
+    let mut total = Coverage::default();
+    // Where the unchecked blocks are, by language.
+    let mut unchecked: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for path in &files {
+        let document = load_file(path, &config, &options).map_err(invalid)?;
+        let coverage = code_block_coverage(&document.document);
+        let codes: Vec<&str> = coverage.unchecked.iter().map(|x| x.1.as_str()).collect();
+        let lines = match &document.source {
+            Some(source) => code_block_lines(source, &codes),
+            None => vec![None; codes.len()],
+        };
+        for ((language, _), line) in coverage.unchecked.iter().zip(lines) {
+            let location = match line {
+                Some(line) => format!("{}:{line}", path.display()),
+                None => path.display().to_string(),
+            };
+            let language = language.as_deref().unwrap_or("no language");
+            unchecked
+                .entry(language.to_string())
+                .or_default()
+                .push(location);
+        }
+        total.blocks += coverage.blocks;
+        total.unchecked.extend(coverage.unchecked);
+    }
+    for (language, locations) in &unchecked {
+        println!("{language}: {} unchecked blocks", locations.len());
+        for location in locations {
+            println!("  {location}");
+        }
+    }
+    let percentage = total.percentage();
+    println!(
+        "{} of {} code blocks are checked ({percentage:.1}%).",
+        total.blocks - total.unchecked.len(),
+        total.blocks
+    );
+    if let Some(min) = args.fail_under.filter(|x| percentage < *x) {
+        return Err(ExitError {
+            code: EXIT_MISMATCH,
+            error: anyhow::anyhow!("Less than {min}% of the code blocks are checked."),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Print the parsed sessions, blocks and commands of the documents in a format for other tools.
+fn export(args: &ExportArgs) -> anyhow::Result<()> {
+    let run_args = RunArgs {
+        files: args.files.clone(),
+        features: args.features.clone(),
+        config: args.config.clone(),
+        shared_sessions: args.shared_sessions,
+        ..RunArgs::default()
+    };
+    let config = run_args.config().map_err(invalid)?;
+    let options = run_args.options(&config);
+    let files = input_files(&run_args, &config).map_err(invalid)?;
+    let documents = files
+        .iter()
+        .map(|path| load_file(path, &config, &options))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(invalid)?;
+    let inputs: Vec<_> = documents
+        .iter()
+        .map(|x| (&x.document, &x.options))
+        .collect();
+    let sessions = list_sessions(&inputs).map_err(invalid)?;
+    let lines = block_lines(&documents, &sessions);
+    match args.format {
+        ExportFormat::Json => {
+            let sessions: Vec<_> = sessions
+                .iter()
+                .map(|session| {
+                    let blocks: Vec<_> = session
+                        .blocks
+                        .iter()
+                        .map(|block| {
+                            let line = lines.get(&(block.document, block.number));
+                            serde_json::json!({
+                                "document": documents[block.document].path,
+                                "number": block.number,
+                                "lines": line.map(|x| *x..x + block.code.lines().count()),
+                                "code": block.code,
+                                "prompt": block.prompt,
+                                "continuation_prompt": block.continuation_prompt,
+                                "initial_output": block.initial_output,
+                                "invocations": block.invocations,
+                            })
+                        })
+                        .collect();
+                    serde_json::json!({
+                        "name": session.name,
+                        "document": documents[session.document].path,
+                        "command": session.command,
+                        "blocks": blocks,
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&sessions)?);
+        }
+    }
+    Ok(())
+}
+
+/// Print the doctests in a source file as Markdown with REPL blocks.
+fn import_doctest(args: &ImportDoctestArgs) -> anyhow::Result<()> {
+    let source = std::fs::read_to_string(&args.file).map_err(|e| {
+        invalid(anyhow::anyhow!(
+            "Failed to read {}: {e}",
+            args.file.display()
+        ))
+    })?;
+    print!("{}", doctest::import(&source, args.format, &args.session));
+    Ok(())
+}
+
+/// Print the REPL blocks in the documents as doctests, with a comment before every session.
+fn export_doctest(args: &ExportDoctestArgs) -> anyhow::Result<()> {
+    let run_args = RunArgs {
+        files: args.files.clone(),
+        config: args.config.clone(),
+        ..RunArgs::default()
+    };
+    let config = run_args.config().map_err(invalid)?;
+    let options = run_args.options(&config);
+    let files = input_files(&run_args, &config).map_err(invalid)?;
+    let documents = files
+        .iter()
+        .map(|path| load_file(path, &config, &options))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(invalid)?;
+    let inputs: Vec<_> = documents
+        .iter()
+        .map(|x| (&x.document, &x.options))
+        .collect();
+    for session in list_sessions(&inputs).map_err(invalid)? {
+        println!("# Session {}", session.name);
+        let doctest = doctest::export(&session.blocks, args.format)
+            .map_err(|e| invalid(anyhow::anyhow!("In session {}: {e}", session.name)))?;
+        print!("{doctest}");
+    }
+    Ok(())
+}
+
+/// Run the command given on the command line. Errors which are not an [ExitError] exit with
+/// [EXIT_ERROR].
+fn run_command(cli: Cli) -> anyhow::Result<()> {
+    if cli.fake_repl {
+        return Ok(repl_check::testing::fake_repl()?);
+    }
+    let command = cli
+        .command
+        .unwrap_or_else(|| Command::Check(RunArgs::default()));
+    if let Command::Check(args) | Command::Update(args) | Command::Watch(args) = &command {
+        init_logging(args);
+    }
+    let (args, update) = match &command {
+        Command::Check(args) => (args, args.update.is_some() || args.pending),
+        Command::Update(args) => (args, true),
+        Command::Watch(args) => return watch(args, &args.config().map_err(invalid)?),
+        Command::List(args) => return list(args),
+        Command::Accept(args) => return accept(args),
+        Command::Coverage(args) => return coverage(args),
+        Command::Export(args) => return export(args),
+        Command::ImportDoctest(args) => return import_doctest(args),
+        Command::ExportDoctest(args) => return export_doctest(args),
+        Command::Lsp(args) => return lsp(args),
+    };
+    let config = args.config().map_err(invalid)?;
+    let files = input_files(args, &config).map_err(invalid)?;
+    if args.reuse_processes {
+        type Backend = PooledBackend<DefaultBackend>;
+        let result = run::<Backend>(&files, None, args, &config, update);
+        Backend::clear_pool();
+        return result;
+    }
+    run::<DefaultBackend>(&files, None, args, &config, update)
+}
+
+/// Exits with 0 if all documents passed, [EXIT_MISMATCH] if blocks failed because their output
+/// didn't match, [EXIT_ERROR] if a REPL could not be run as expected and [EXIT_INVALID] if the
+/// command line, the configuration or a document is invalid.
+fn main() -> ExitCode {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            return match e.use_stderr() {
+                true => ExitCode::from(EXIT_INVALID),
+                false => ExitCode::SUCCESS,
+            };
+        }
+    };
+    let quiet = matches!(&cli.command, Some(Command::Check(x) | Command::Update(x)) if x.quiet);
+    let (code, error) = match run_command(cli) {
+        Ok(()) => return ExitCode::SUCCESS,
+        Err(e) => match e.downcast::<ExitError>() {
+            Ok(ExitError { code, error }) => (code, error),
+            Err(error) => (EXIT_ERROR, error),
+        },
+    };
+    // With --quiet, the summary line tells that documents failed.
+    if !quiet || code == EXIT_INVALID {
+        eprintln!("Error: {error:?}");
+    }
+    ExitCode::from(code)
 }
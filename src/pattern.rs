@@ -1,16 +1,360 @@
 //! Utilities for matching and updating the expected with the actual command output.
 //!
 //! Both the expected and actual outputs are given as slices of lines.
-//! The matching works as follows (everything modulo trailing whitespaces):
+//! The matching works as follows (everything modulo whitespace, case and Unicode normalization
+//! according to [Comparison]):
 //! - All normal lines, that is every line which is not "..." or "???", are matched exactly.
 //! - Lines only consisting of "..." matches any number of arbitrary lines.
 //! - Lines only consisting of "???" matches any number of arbitrary lines and updates the expected
-//!     lines with the actual lines.
+//!   lines with the actual lines.
+//! - Both kinds of holes can be bounded like regex quantifiers: `...{5}` matches exactly five
+//!   lines, `...{0,3}` at most three, and `...{2,}` at least two.
+//! - A backslash before a hole or the start or end of a group, like `\...`, makes it a normal line
+//!   which matches the text after the backslash. The actual output is escaped with [escape_line]
+//!   before it is matched, so it is written back to the documents escaped.
+//! - The lines between a `{unordered}` and a `{/unordered}` line must all match, but in any order.
+//!   The group ends at the end of the expected lines if there is no `{/unordered}` line, and it
+//!   can't contain holes.
+//!
+//! Within a normal line, numbers can be matched approximately with placeholders:
+//! - `{~3.14159 ±1e-4}` matches a number within the tolerance, which may also be written `+-`.
+//! - `{~3.14159}` matches a number which rounds to the written digits, that is within half a unit
+//!   of the last digit.
+//! - `{float}` matches any number.
+//!
+//! Timestamps and durations can be matched with the tokens `{DATE}` like `2024-01-31`, `{TIME}`
+//! like `12:34:56.789`, `{DATETIME}` like `2024-01-31T12:34:56Z` and `{DURATION}` like `1.24s`
+//! or `3m 2s`. More tokens, or other regexes for these, can be set in the `[tokens]` section of
+//! the configuration file, see [Tokens].
+//!
+//! With the `line_annotations` session attribute, an expected line can end with annotations like
+//! `#repl: optional` which change how only that line is matched, see [parse_annotations].
+//!
+//! Runs of actual lines which look like binary data are replaced with a line like
+//! `{binary:512 bytes}` by [collapse_binary], which matches an expected line like that with
+//! roughly the same size.
+//!
+//! With the `separate_stderr` session attribute, lines from stderr are tagged with `stderr: ` or
+//! `err> `, and the actual lines are reordered by [interleave_streams] before they are matched.
 
-use crate::LinesCow;
+use crate::backend::STDERR_PREFIX;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::iter;
+use std::ops::Range;
+use unicode_normalization::UnicodeNormalization;
+
+/// How whitespace is compared in normal lines, set with the `whitespace` session attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Whitespace {
+    /// All whitespace must match exactly, like in ASCII tables.
+    Exact,
+
+    /// Trailing whitespace is ignored.
+    #[default]
+    Trim,
+
+    /// Trailing whitespace is ignored and runs of whitespace are the same as a single space, for
+    /// REPLs which pad columns differently across versions.
+    Collapse,
+}
+
+impl std::str::FromStr for Whitespace {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(Self::Exact),
+            "trim" => Ok(Self::Trim),
+            "collapse" => Ok(Self::Collapse),
+            _ => Err("Expected exact, trim or collapse".to_string()),
+        }
+    }
+}
+
+impl Whitespace {
+    /// Normalize the whitespace in a line.
+    fn normalize(self, line: &str) -> Cow<'_, str> {
+        match self {
+            Self::Exact => Cow::Borrowed(line),
+            Self::Trim => Cow::Borrowed(line.trim_end()),
+            Self::Collapse => {
+                let mut collapsed = String::with_capacity(line.len());
+                for (i, word) in line.split_whitespace().enumerate() {
+                    if i > 0 || line.starts_with(char::is_whitespace) {
+                        collapsed.push(' ');
+                    }
+                    collapsed += word;
+                }
+                Cow::Owned(collapsed)
+            }
+        }
+    }
+}
+
+/// Whether letter case matters, set with the `case` session attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Case {
+    #[default]
+    Sensitive,
+    Insensitive,
+}
+
+impl std::str::FromStr for Case {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sensitive" => Ok(Self::Sensitive),
+            "insensitive" => Ok(Self::Insensitive),
+            _ => Err("Expected sensitive or insensitive".to_string()),
+        }
+    }
+}
+
+/// A Unicode normalization form which lines are normalized to before they are compared, set with
+/// the `unicode_normalize` session attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicodeForm {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+}
+
+impl std::str::FromStr for UnicodeForm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(Self::Nfc),
+            "nfd" => Ok(Self::Nfd),
+            "nfkc" => Ok(Self::Nfkc),
+            "nfkd" => Ok(Self::Nfkd),
+            _ => Err("Expected nfc, nfd, nfkc or nfkd".to_string()),
+        }
+    }
+}
+
+/// The regexes of the built-in tokens.
+const BUILTIN_TOKENS: [(&str, &str); 4] = [
+    (
+        "DATE",
+        r"\d{4}-\d{2}-\d{2}|\d{1,2}/\d{1,2}/\d{2,4}|\d{1,2} [A-Za-z]{3} \d{4}",
+    ),
+    (
+        "TIME",
+        r"\d{1,2}:\d{2}(?::\d{2}(?:[.,]\d+)?)?(?: ?[AaPp][Mm])?(?:Z|[+-]\d{2}:?\d{2})?",
+    ),
+    (
+        "DATETIME",
+        r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}(?::\d{2}(?:[.,]\d+)?)?(?:Z|[+-]\d{2}:?\d{2})?",
+    ),
+    (
+        "DURATION",
+        concat!(
+            r"(?:\d+(?:[.,]\d+)? ?(?:hours?|hrs?|h|minutes?|mins?|m|seconds?|secs?|s|",
+            r"milliseconds?|ms|microseconds?|[uµ]s|nanoseconds?|ns)\b ?)*",
+            r"\d+(?:[.,]\d+)? ?(?:hours?|hrs?|h|minutes?|mins?|m|seconds?|secs?|s|",
+            r"milliseconds?|ms|microseconds?|[uµ]s|nanoseconds?|ns)\b",
+            r"|\d+:\d{2}(?::\d{2})?(?:[.,]\d+)?",
+        ),
+    ),
+];
+
+lazy_static::lazy_static! {
+    /// The built-in tokens by name.
+    static ref BUILTIN_TOKEN_REGEXES: Vec<(&'static str, Regex)> = BUILTIN_TOKENS
+        .iter()
+        .map(|(name, regex)| (*name, anchor_token(regex).unwrap()))
+        .collect();
+}
+
+/// Compile the regex of a token, so that it only matches at the start of the text.
+fn anchor_token(regex: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{regex})"))
+}
+
+/// Named tokens like `{DATE}` in expected lines, which match the text of a regex. There are
+/// built-in tokens for timestamps and durations, and custom ones from the `[tokens]` section of
+/// the configuration file, which take precedence. The names are not case sensitive.
+#[derive(Debug, Clone, Default)]
+pub struct Tokens {
+    /// The custom tokens with their anchored regexes.
+    custom: Vec<(String, Regex)>,
+}
+
+impl Tokens {
+    /// Tokens with custom regexes, which are not anchored, by name.
+    pub fn new(custom: &BTreeMap<String, String>) -> Result<Self, regex::Error> {
+        let custom = custom
+            .iter()
+            .map(|(name, regex)| Ok((name.clone(), anchor_token(regex)?)))
+            .collect::<Result<_, regex::Error>>()?;
+        Ok(Self { custom })
+    }
+
+    /// The anchored regex of a token.
+    fn get(&self, name: &str) -> Option<&Regex> {
+        let custom = self
+            .custom
+            .iter()
+            .map(|(name, regex)| (name.as_str(), regex));
+        let builtin = BUILTIN_TOKEN_REGEXES
+            .iter()
+            .map(|(name, regex)| (*name, regex));
+        custom
+            .chain(builtin)
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, regex)| regex)
+    }
+}
+
+/// Deserialize the `[tokens]` section of the configuration file, a map from names to regexes.
+pub fn deserialize_tokens<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Tokens, D::Error> {
+    Tokens::new(&BTreeMap::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// Only the built-in tokens, for [Comparison::default].
+static NO_CUSTOM_TOKENS: Tokens = Tokens { custom: Vec::new() };
+
+/// How an expected and an actual line are compared.
+#[derive(Debug, Clone, Copy)]
+pub struct Comparison<'a> {
+    pub whitespace: Whitespace,
+    pub case: Case,
+    pub unicode_form: Option<UnicodeForm>,
+
+    /// The tokens like `{DATE}` which may be used in expected lines.
+    pub tokens: &'a Tokens,
+
+    /// Whether annotations like `#repl: optional` at the end of expected lines are parsed, set
+    /// with the `line_annotations` session attribute.
+    pub annotations: bool,
+}
+
+impl Default for Comparison<'_> {
+    fn default() -> Self {
+        Self {
+            whitespace: Whitespace::default(),
+            case: Case::default(),
+            unicode_form: None,
+            tokens: &NO_CUSTOM_TOKENS,
+            annotations: false,
+        }
+    }
+}
+
+impl<'a> Comparison<'a> {
+    /// The text of an expected line without its annotations, and the comparison for it.
+    fn annotated<'b>(self, line: &'b str) -> (&'b str, LineAnnotations, Comparison<'a>) {
+        let (line, annotations) = match self.annotations {
+            true => parse_annotations(line).unwrap_or((line, LineAnnotations::default())),
+            false => (line, LineAnnotations::default()),
+        };
+        let comparison = match annotations.exact_whitespace {
+            true => Self {
+                whitespace: Whitespace::Exact,
+                ..self
+            },
+            false => self,
+        };
+        (line, annotations, comparison)
+    }
+
+    /// Normalize a line, so that two lines match if they are equal after normalization.
+    fn normalize(self, line: &str) -> Cow<'_, str> {
+        let line = self.whitespace.normalize(line);
+        let line = match self.unicode_form {
+            None => line,
+            Some(UnicodeForm::Nfc) => Cow::Owned(line.nfc().collect()),
+            Some(UnicodeForm::Nfd) => Cow::Owned(line.nfd().collect()),
+            Some(UnicodeForm::Nfkc) => Cow::Owned(line.nfkc().collect()),
+            Some(UnicodeForm::Nfkd) => Cow::Owned(line.nfkd().collect()),
+        };
+        match self.case {
+            Case::Sensitive => line,
+            Case::Insensitive => Cow::Owned(line.to_lowercase()),
+        }
+    }
+}
+
+/// The start of the annotations at the end of an expected line.
+const ANNOTATION_PREFIX: &str = "#repl:";
+
+/// How a single expected line is matched, set with annotations at its end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineAnnotations {
+    /// `optional`: The line may be missing from the actual output.
+    pub optional: bool,
+
+    /// `regex`: The line is a regex which must match all of the actual line.
+    pub regex: bool,
+
+    /// `exact-whitespace`: All whitespace in the line must match exactly, like with
+    /// `whitespace=exact`.
+    pub exact_whitespace: bool,
+}
+
+/// Split an expected line like `some output  #repl: optional, regex` into the text and the
+/// comma separated annotations. The annotations are separated from the text by a space, and other
+/// whitespace before them is part of the text, which only matters with `exact-whitespace`. A line
+/// without annotations is returned as it is, and unknown annotations are an error.
+pub fn parse_annotations(line: &str) -> Result<(&str, LineAnnotations), String> {
+    let mut annotations = LineAnnotations::default();
+    let Some(start) = line.rfind(ANNOTATION_PREFIX) else {
+        return Ok((line, annotations));
+    };
+    let text = match &line[..start] {
+        "" => "",
+        text => match text.strip_suffix([' ', '\t']) {
+            Some(text) => text,
+            None => return Ok((line, annotations)),
+        },
+    };
+    for name in line[start + ANNOTATION_PREFIX.len()..].split(',') {
+        match name.trim() {
+            "optional" => annotations.optional = true,
+            "regex" => annotations.regex = true,
+            "exact-whitespace" => annotations.exact_whitespace = true,
+            name => {
+                return Err(format!(
+                    "Unknown line annotation {name}, expected optional, regex or exact-whitespace"
+                ))
+            }
+        }
+    }
+    if annotations.regex {
+        line_regex(text, Case::Sensitive).map_err(|e| format!("Bad regex in line: {e}"))?;
+    }
+    Ok((text, annotations))
+}
 
-#[derive(thiserror::Error, Debug)]
+thread_local! {
+    /// The compiled regexes of [line_regex] by the regex and the case, since an expected line is
+    /// compared with many actual lines while the holes are matched.
+    static LINE_REGEXES: RefCell<HashMap<(String, Case), Result<Regex, regex::Error>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Compile the regex of an expected line with the `regex` annotation, which must match all of an
+/// actual line. Every regex is only compiled once.
+fn line_regex(regex: &str, case: Case) -> Result<Regex, regex::Error> {
+    LINE_REGEXES.with(|regexes| {
+        regexes
+            .borrow_mut()
+            .entry((regex.to_string(), case))
+            .or_insert_with(|| {
+                regex::RegexBuilder::new(&format!("^(?:{regex})$"))
+                    .case_insensitive(case == Case::Insensitive)
+                    .build()
+            })
+            .clone()
+    })
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy)]
 pub struct ParseError<'a> {
     /// The expected line or end of input.
     expected: Option<&'a str>,
@@ -21,21 +365,106 @@ pub struct ParseError<'a> {
 impl<'a> fmt::Display for ParseError<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match (self.expected, self.got) {
-            (Some(expected), Some(got)) => write!(f, "Expected: {expected}\nGot: {got}"),
+            (Some(expected), Some(got)) => {
+                write!(f, "Expected: {expected}\nGot: {}", printable(got))
+            }
             (Some(expected), None) => write!(f, "Expected: {expected}\nGot end of input."),
-            (None, Some(got)) => write!(f, "Expected end of input\nGot: {got}"),
+            (None, Some(got)) => write!(f, "Expected end of input\nGot: {}", printable(got)),
             _ => unreachable!(),
         }
     }
 }
 
-/// The result when parsing. The ok value is a tuple of the remaining lines and an option which is
-/// `None` if nothing should be updated or `Some(lines)` if the input should be updated.
-type ParseResult<'a> = Result<(&'a [&'a str], Option<Vec<&'a str>>), ParseError<'a>>;
+/// A part of the expected lines and the actual lines it matched, as indices into both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchedSegment {
+    /// Expected lines which matched the actual lines, in order or in an unordered group, and which
+    /// are kept.
+    Literal {
+        expected: Range<usize>,
+        actual: Range<usize>,
+    },
+
+    /// A `...` hole at an expected line which matched the actual lines.
+    HoleConsumed {
+        expected: usize,
+        actual: Range<usize>,
+    },
+
+    /// Expected lines, a `???` hole, which are replaced with the actual lines they matched.
+    Updated {
+        expected: Range<usize>,
+        actual: Range<usize>,
+    },
+}
+
+impl MatchedSegment {
+    /// Move the segment by `expected` and `actual` lines, for a segment of a match of lines which
+    /// start there.
+    fn shift(self, expected: usize, actual: usize) -> Self {
+        let shift = |x: Range<usize>, by| x.start + by..x.end + by;
+        match self {
+            Self::Literal {
+                expected: e,
+                actual: a,
+            } => Self::Literal {
+                expected: shift(e, expected),
+                actual: shift(a, actual),
+            },
+            Self::HoleConsumed {
+                expected: e,
+                actual: a,
+            } => Self::HoleConsumed {
+                expected: e + expected,
+                actual: shift(a, actual),
+            },
+            Self::Updated {
+                expected: e,
+                actual: a,
+            } => Self::Updated {
+                expected: shift(e, expected),
+                actual: shift(a, actual),
+            },
+        }
+    }
+
+    /// The expected line of a hole of either kind and the actual lines it matched, or `None` if
+    /// this is not a hole.
+    pub fn hole(&self) -> Option<(usize, Range<usize>)> {
+        match self {
+            Self::Literal { .. } => None,
+            Self::HoleConsumed { expected, actual } => Some((*expected, actual.clone())),
+            Self::Updated { expected, actual } => Some((expected.start, actual.clone())),
+        }
+    }
+}
+
+/// The result when parsing. The ok value is a tuple of the remaining lines and the matched
+/// segments, with indices into the expected and actual lines which were parsed.
+type ParseResult<'a> = Result<(&'a [&'a str], Vec<MatchedSegment>), ParseError<'a>>;
 
-/// Match a list of lines exactly.
-/// Match exactly line by line.
-fn match_lines<'a>(expected: &[&'a str], actual: &'a [&'a str]) -> ParseResult<'a> {
+/// Match exactly line by line. If `anchored` is set, all of `actual` must be matched.
+fn match_lines<'a>(
+    expected: &[&'a str],
+    actual: &'a [&'a str],
+    anchored: bool,
+    comparison: Comparison,
+) -> ParseResult<'a> {
+    if expected.iter().any(|x| comparison.annotated(x).1.optional) {
+        let matched = match_optional_lines(
+            expected,
+            actual,
+            anchored,
+            comparison,
+            (0, 0),
+            &mut HashMap::new(),
+        )?;
+        let segments = vec![MatchedSegment::Literal {
+            expected: 0..expected.len(),
+            actual: 0..matched,
+        }];
+        return Ok((&actual[matched..], segments));
+    }
     let mut i = 0usize;
     while i < expected.len() {
         if i == actual.len() {
@@ -44,7 +473,7 @@ fn match_lines<'a>(expected: &[&'a str], actual: &'a [&'a str]) -> ParseResult<'
                 got: None,
             });
         }
-        if expected[i].trim_end() != actual[i].trim_end() {
+        if !lines_match(expected[i], actual[i], comparison) {
             return Err(ParseError {
                 expected: Some(expected[i]),
                 got: Some(actual[i]),
@@ -52,78 +481,1156 @@ fn match_lines<'a>(expected: &[&'a str], actual: &'a [&'a str]) -> ParseResult<'
         }
         i += 1;
     }
-    Ok((&actual[i..], None))
+    if anchored && i < actual.len() {
+        return Err(ParseError {
+            expected: None,
+            got: Some(actual[i]),
+        });
+    }
+    let segments = match i {
+        0 => Vec::new(),
+        _ => vec![MatchedSegment::Literal {
+            expected: 0..i,
+            actual: 0..i,
+        }],
+    };
+    Ok((&actual[i..], segments))
+}
+
+/// Match line by line like [match_lines] from expected line `i` and actual line `j`, where lines
+/// with the `optional` annotation are matched if the following lines match then and skipped
+/// otherwise. Returns the number of actual lines which were matched. The errors at every pair of
+/// lines are remembered in `failed`, so each pair is only tried once.
+fn match_optional_lines<'a>(
+    expected: &[&'a str],
+    actual: &'a [&'a str],
+    anchored: bool,
+    comparison: Comparison,
+    (i, j): (usize, usize),
+    failed: &mut HashMap<(usize, usize), ParseError<'a>>,
+) -> Result<usize, ParseError<'a>> {
+    let got = actual.get(j).copied();
+    let Some(&line) = expected.get(i) else {
+        return match got {
+            Some(got) if anchored => Err(ParseError {
+                expected: None,
+                got: Some(got),
+            }),
+            _ => Ok(j),
+        };
+    };
+    if let Some(e) = failed.get(&(i, j)) {
+        return Err(*e);
+    }
+    let result = match got {
+        Some(got) if lines_match(line, got, comparison) => match_optional_lines(
+            expected,
+            actual,
+            anchored,
+            comparison,
+            (i + 1, j + 1),
+            failed,
+        ),
+        _ => Err(ParseError {
+            expected: Some(line),
+            got,
+        }),
+    };
+    let result = match result {
+        Err(e) if comparison.annotated(line).1.optional => {
+            match_optional_lines(expected, actual, anchored, comparison, (i + 1, j), failed)
+                .map_err(|_| e)
+        }
+        result => result,
+    };
+    if let Err(e) = result {
+        failed.insert((i, j), e);
+    }
+    result
 }
 
-fn with_holes<'a, const UPDATE: bool>(
-    pattern: &mut impl FnMut(&[&'a str], &'a [&'a str]) -> ParseResult<'a>,
+/// The lines which start and end a group of lines in any order.
+const UNORDERED_START: &str = "{unordered}";
+const UNORDERED_END: &str = "{/unordered}";
+
+/// Match groups of lines in any order, and match the lines between the groups with `pattern`.
+///
+/// If `anchored` is set, all of `actual` must be matched.
+fn with_unordered_groups<'a>(
+    pattern: &mut impl FnMut(&[&'a str], &'a [&'a str], bool) -> ParseResult<'a>,
     expected: &[&'a str],
     actual: &'a [&'a str],
+    anchored: bool,
+    comparison: Comparison,
 ) -> ParseResult<'a> {
-    let hole = if UPDATE { "???" } else { "..." };
-    match expected.iter().position(|line| line.trim() == hole) {
-        None => pattern(expected, actual),
-        Some(hole_idx) => {
-            let before_hole = &expected[..hole_idx];
-            let after_hole = &expected[hole_idx + 1..];
-
-            let (actual, updated_before) = pattern(before_hole, actual)?;
-
-            let mut err = None;
-            for i in 0..=actual.len() {
-                match with_holes::<UPDATE>(pattern, after_hole, &actual[i..]) {
-                    Err(e) => err = Some(e),
-                    Ok((remaining_input, updated_after)) => {
-                        let push_hole_content = |x: &mut Vec<&'a str>| {
-                            if UPDATE {
-                                x.extend_from_slice(&actual[..i])
-                            } else {
-                                x.push(expected[hole_idx])
-                            }
-                        };
-                        let updated = match (updated_before, updated_after) {
-                            (Some(mut x), Some(y)) => {
-                                push_hole_content(&mut x);
-                                x.extend_from_slice(&y);
-                                Some(x)
-                            }
-                            (Some(mut x), None) => {
-                                push_hole_content(&mut x);
-                                x.extend_from_slice(after_hole);
-                                Some(x)
-                            }
-                            (None, Some(y)) => {
-                                let mut x = before_hole.to_vec();
-                                push_hole_content(&mut x);
-                                x.extend_from_slice(&y);
-                                Some(x)
-                            }
-                            (None, None) => None,
-                        };
-                        return Ok((remaining_input, updated));
-                    }
+    let Some(start) = expected.iter().position(|x| x.trim() == UNORDERED_START) else {
+        return pattern(expected, actual, anchored);
+    };
+    let (rest, mut segments) = pattern(&expected[..start], actual, false)?;
+    let group_start = actual.len() - rest.len();
+    let actual = rest;
+    let group_end = expected[start + 1..]
+        .iter()
+        .position(|x| x.trim() == UNORDERED_END)
+        .map_or(expected.len(), |i| start + 1 + i);
+    let group = &expected[start + 1..group_end];
+    // The lines after the group and the `{/unordered}` line.
+    let after_group = expected.get(group_end + 1..).unwrap_or_default();
+
+    let window = &actual[..group.len().min(actual.len())];
+    let assignment = match_unordered(group, window, comparison);
+    if let Some(unmatched) = assignment.iter().position(Option::is_none) {
+        let got = (0..window.len()).find(|i| !assignment.contains(&Some(*i)));
+        return Err(ParseError {
+            expected: Some(group[unmatched]),
+            got: got.map(|i| window[i]),
+        });
+    }
+
+    let (remaining, segments_after) = with_unordered_groups(
+        pattern,
+        after_group,
+        &actual[group.len()..],
+        anchored,
+        comparison,
+    )?;
+    let after_start = expected.len() - after_group.len();
+    segments.push(MatchedSegment::Literal {
+        expected: start..after_start,
+        actual: group_start..group_start + group.len(),
+    });
+    segments.extend(
+        segments_after
+            .into_iter()
+            .map(|x| x.shift(after_start, group_start + group.len())),
+    );
+    Ok((remaining, segments))
+}
+
+/// Assign a distinct actual line to as many expected lines as possible, by augmenting paths.
+/// Returns the index of the actual line for every expected line, or `None` if it is unmatched.
+fn match_unordered(
+    expected: &[&str],
+    actual: &[&str],
+    comparison: Comparison,
+) -> Vec<Option<usize>> {
+    /// Try to assign an actual line to expected line `i`, reassigning other expected lines.
+    fn augment(
+        i: usize,
+        matches: &dyn Fn(usize, usize) -> bool,
+        owner: &mut [Option<usize>],
+        visited: &mut [bool],
+    ) -> bool {
+        for j in 0..owner.len() {
+            if visited[j] || !matches(i, j) {
+                continue;
+            }
+            visited[j] = true;
+            if owner[j].is_none_or(|k| augment(k, matches, owner, visited)) {
+                owner[j] = Some(i);
+                return true;
+            }
+        }
+        false
+    }
+
+    // The expected line which every actual line is assigned to.
+    let mut owner = vec![None; actual.len()];
+    let matches = |i: usize, j: usize| lines_match(expected[i], actual[j], comparison);
+    for i in 0..expected.len() {
+        augment(i, &matches, &mut owner, &mut vec![false; actual.len()]);
+    }
+    let mut assignment = vec![None; expected.len()];
+    for (j, i) in owner.iter().enumerate() {
+        if let Some(i) = i {
+            assignment[*i] = Some(j);
+        }
+    }
+    assignment
+}
+
+/// Parse a hole line of a kind, `...` or `???`, and return the minimum and maximum number of
+/// lines it may match.
+fn parse_hole(line: &str, hole: &str) -> Option<(usize, usize)> {
+    let bounds = line.trim().strip_prefix(hole)?;
+    if bounds.is_empty() {
+        return Some((0, usize::MAX));
+    }
+    let bounds = bounds.strip_prefix('{')?.strip_suffix('}')?;
+    let bound = |x: &str, default| match x.trim() {
+        "" => Some(default),
+        x => x.parse().ok(),
+    };
+    let (min, max) = match bounds.split_once(',') {
+        None => (bounds.trim().parse().ok()?, bounds.trim().parse().ok()?),
+        Some((min, max)) => (bound(min, 0)?, bound(max, usize::MAX)?),
+    };
+    (min <= max).then_some((min, max))
+}
+
+/// Whether a line is a hole of any kind.
+fn is_hole(line: &str) -> bool {
+    parse_hole(line, "...").is_some() || parse_hole(line, "???").is_some()
+}
+
+/// Whether a line is a hole or the start or end of a group.
+fn is_marker(line: &str) -> bool {
+    is_hole(line) || matches!(line.trim(), UNORDERED_START | UNORDERED_END)
+}
+
+/// What an expected line means to the matching, see [annotate_line].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LineAnnotation {
+    /// A normal line which is matched exactly.
+    Text,
+
+    /// A normal line with number placeholders like `{~3.14}` or `{float}`, or tokens like `{DATE}`.
+    Placeholders { count: usize },
+
+    /// A line escaped with a backslash, which matches the text after it.
+    Escaped,
+
+    /// A `...` hole with the minimum and maximum number of lines it matches, or no maximum.
+    Hole { min: usize, max: Option<usize> },
+
+    /// A `???` hole, which is updated with the actual lines.
+    UpdateHole { min: usize, max: Option<usize> },
+
+    /// The `{unordered}` line which starts a group.
+    UnorderedStart,
+
+    /// The `{/unordered}` line which ends a group.
+    UnorderedEnd,
+
+    /// A `{binary:N bytes}` line.
+    Binary { bytes: usize },
+}
+
+/// Find out what an expected line means, for tools which show or run the blocks themselves.
+pub fn annotate_line(line: &str) -> LineAnnotation {
+    let max = |x| (x != usize::MAX).then_some(x);
+    if let Some((min, x)) = parse_hole(line, "...") {
+        return LineAnnotation::Hole { min, max: max(x) };
+    }
+    if let Some((min, x)) = parse_hole(line, "???") {
+        return LineAnnotation::UpdateHole { min, max: max(x) };
+    }
+    if let Some(bytes) = binary_size(line) {
+        return LineAnnotation::Binary { bytes };
+    }
+    let text = line.trim();
+    match text {
+        UNORDERED_START => LineAnnotation::UnorderedStart,
+        UNORDERED_END => LineAnnotation::UnorderedEnd,
+        _ if text.starts_with('\\') && is_marker(text.trim_start_matches('\\')) => {
+            LineAnnotation::Escaped
+        }
+        _ => match tokenize(line, &NO_CUSTOM_TOKENS)
+            .iter()
+            .filter(|x| !matches!(x, Token::Text(_)))
+            .count()
+        {
+            0 => LineAnnotation::Text,
+            count => LineAnnotation::Placeholders { count },
+        },
+    }
+}
+
+/// Escape an actual line which would be a hole or the start or end of a group in the expected
+/// lines, by adding a backslash before it. Lines which are already escaped get another backslash.
+pub fn escape_line(line: &str) -> Cow<'_, str> {
+    let text = line.trim_start();
+    match is_marker(text.trim_start_matches('\\')) {
+        true => Cow::Owned(format!("{}\\{text}", &line[..line.len() - text.len()])),
+        false => Cow::Borrowed(line),
+    }
+}
+
+/// The tags of lines from stderr in the expected lines. The actual lines from stderr get the
+/// first tag, [STDERR_PREFIX], unless they are retagged by [interleave_streams].
+pub const STDERR_TAGS: [&str; 2] = [STDERR_PREFIX, "err> "];
+
+/// Split the stderr tag from a line, or return `None` if it is not from stderr. An empty line
+/// may have the tag without its trailing space.
+fn split_stderr_tag(line: &str) -> Option<(&'static str, &str)> {
+    STDERR_TAGS
+        .into_iter()
+        .find_map(|tag| match line.strip_prefix(tag) {
+            Some(rest) => Some((tag, rest)),
+            None => (line == tag.trim_end()).then_some((tag, "")),
+        })
+}
+
+/// The tag of the lines from stderr in some expected lines, like a whole block, or
+/// [STDERR_PREFIX] if there are none.
+pub fn stderr_tag(expected: &[&str]) -> &'static str {
+    expected
+        .iter()
+        .find_map(|line| split_stderr_tag(line))
+        .map_or(STDERR_PREFIX, |(tag, _)| tag)
+}
+
+/// Reorder actual lines from stdout and stderr, where the lines from stderr start with
+/// [STDERR_PREFIX], to follow the expected lines, since the order in which the lines of the two
+/// streams are read is not reliable.
+///
+/// The lines of each stream keep their order. Every actual line is matched with the next
+/// expected line of the same stream which it matches, and the lines are sorted by the positions
+/// of these expected lines. A line which doesn't match any expected line stays after the line
+/// before it in its stream. The lines from stderr are retagged with `tag`, see [stderr_tag].
+pub fn interleave_streams<'a>(
+    expected: &[&str],
+    actual: Vec<Cow<'a, str>>,
+    tag: &str,
+    comparison: Comparison,
+) -> Vec<Cow<'a, str>> {
+    let expected: Vec<(bool, &str)> = expected
+        .iter()
+        .map(|line| match split_stderr_tag(line) {
+            Some((_, rest)) => (true, rest),
+            None => (false, *line),
+        })
+        .collect();
+    // The index of the next expected line to search from and of the last matched line, for
+    // stdout and stderr.
+    let mut next = [0; 2];
+    let mut last = [0; 2];
+    let mut keyed = Vec::new();
+    for line in actual {
+        let (stderr, text) = match line.strip_prefix(STDERR_PREFIX) {
+            Some(rest) => (true, rest),
+            None => (false, line.as_ref()),
+        };
+        let stream = usize::from(stderr);
+        let found = expected[next[stream]..]
+            .iter()
+            .position(|(x, y)| *x == stderr && !is_marker(y) && lines_match(y, text, comparison));
+        if let Some(i) = found {
+            last[stream] = next[stream] + i;
+            next[stream] = last[stream] + 1;
+        }
+        let line = match stderr && tag != STDERR_PREFIX {
+            true => Cow::Owned(format!("{tag}{text}")),
+            false => line,
+        };
+        keyed.push((last[stream], line));
+    }
+    // The sort is stable, so lines with the same key stay in the order they were read.
+    keyed.sort_by_key(|(key, _)| *key);
+    keyed.into_iter().map(|(_, line)| line).collect()
+}
+
+/// The indices of the holes of both kinds in `expected` which are not in unordered groups.
+fn holes_outside_groups(expected: &[&str]) -> Vec<usize> {
+    let mut in_group = false;
+    let mut holes = Vec::new();
+    for (i, line) in expected.iter().enumerate() {
+        match line.trim() {
+            UNORDERED_START => in_group = true,
+            UNORDERED_END => in_group = false,
+            _ if !in_group && is_hole(line) => holes.push(i),
+            _ => {}
+        }
+    }
+    holes
+}
+
+/// A segment of the expected lines between two holes which has been matched, followed by the
+/// hole after it, in the search of [with_holes].
+struct SegmentMatch<'a> {
+    /// The index of the segment.
+    segment: usize,
+
+    /// Where the segment starts in the actual lines.
+    start: usize,
+
+    /// The result of matching the segment, with indices into the segment and the actual lines
+    /// from `start`.
+    matched: Vec<MatchedSegment>,
+
+    /// Where the hole after the segment starts in the actual lines.
+    hole_start: usize,
+
+    /// The next number of lines to try to match with the hole, and the largest number.
+    next: usize,
+    max: usize,
+
+    /// The error of the last number of lines which has been tried.
+    err: ParseError<'a>,
+}
+
+/// Match lines with holes, `...` and `???`, and match the lines between the holes with `pattern`.
+///
+/// A hole matches as few lines as possible, and the holes before it take precedence. If `anchored` is set, all of `actual` must be matched,
+/// otherwise the match is followed by another hole and may end anywhere.
+///
+/// The holes split the expected lines into segments, which are matched in a depth first search
+/// over the segment and where it starts in the actual lines. The search is iterative, and it
+/// remembers where segments have failed to match, so every segment is matched at most once at
+/// every position and the time is polynomial instead of exponential in the number of holes.
+fn with_holes<'a>(
+    pattern: &mut impl FnMut(&[&'a str], &'a [&'a str], bool) -> ParseResult<'a>,
+    expected: &[&'a str],
+    actual: &'a [&'a str],
+    anchored: bool,
+) -> ParseResult<'a> {
+    let hole_idxs = holes_outside_groups(expected);
+    if hole_idxs.is_empty() {
+        return pattern(expected, actual, anchored);
+    }
+    // Where every segment starts and ends in the expected lines.
+    let segments: Vec<Range<usize>> = iter::once(0)
+        .chain(hole_idxs.iter().map(|i| i + 1))
+        .zip(hole_idxs.iter().copied().chain(iter::once(expected.len())))
+        .map(|(start, end)| start..end)
+        .collect();
+    let last = hole_idxs.len();
+    let n = actual.len();
+    // The error of every segment and position where the segment and the following ones have
+    // failed to match, at `k * (n + 1) + start`.
+    let mut failed: Vec<Option<ParseError<'a>>> = vec![None; (last + 1) * (n + 1)];
+    let mut stack: Vec<SegmentMatch<'a>> = Vec::new();
+    let (mut k, mut start) = (0, 0);
+    loop {
+        // Match segment `k` at `start`, and if it is the last one the search is done.
+        let result = match failed[k * (n + 1) + start] {
+            Some(err) => Err(err),
+            None => pattern(
+                &expected[segments[k].clone()],
+                &actual[start..],
+                anchored && k == last,
+            ),
+        };
+        let mut err = match result {
+            Ok((remaining, matched)) if k == last => {
+                let segments =
+                    join_segments(stack, (start, matched), expected, &hole_idxs, &segments);
+                return Ok((remaining, segments));
+            }
+            Ok((remaining, matched)) => {
+                let hole_start = n - remaining.len();
+                let hole = expected[hole_idxs[k]];
+                let (min, max) = parse_hole(hole, "...")
+                    .or_else(|| parse_hole(hole, "???"))
+                    .unwrap();
+                stack.push(SegmentMatch {
+                    segment: k,
+                    start,
+                    matched,
+                    hole_start,
+                    next: min,
+                    max: max.min(n - hole_start),
+                    err: ParseError {
+                        expected: Some(expected[hole_idxs[k]]),
+                        got: None,
+                    },
+                });
+                None
+            }
+            Err(e) => {
+                failed[k * (n + 1) + start] = Some(e);
+                Some(e)
+            }
+        };
+        // Try the next number of lines for the last hole, and backtrack when there are no more.
+        loop {
+            let Some(top) = stack.last_mut() else {
+                return Err(err.expect("the first segment failed"));
+            };
+            if let Some(e) = err.take() {
+                top.err = e;
+            }
+            if top.next <= top.max {
+                k = top.segment + 1;
+                start = top.hole_start + top.next;
+                top.next += 1;
+                break;
+            }
+            let top = stack.pop().unwrap();
+            failed[top.segment * (n + 1) + top.start] = Some(top.err);
+            err = Some(top.err);
+        }
+    }
+}
+
+/// The matched segments of [with_holes]: those of the segments in `matched` and of the holes after
+/// them, followed by `last`, which is where the last segment starts in the actual lines and what it
+/// matched.
+fn join_segments(
+    matched: Vec<SegmentMatch>,
+    last: (usize, Vec<MatchedSegment>),
+    expected: &[&str],
+    hole_idxs: &[usize],
+    segments: &[Range<usize>],
+) -> Vec<MatchedSegment> {
+    let mut joined = Vec::new();
+    for x in matched {
+        let expected_start = segments[x.segment].start;
+        joined.extend(
+            x.matched
+                .into_iter()
+                .map(|y| y.shift(expected_start, x.start)),
+        );
+        // The lines which matched the hole.
+        let actual = x.hole_start..x.hole_start + x.next - 1;
+        let hole = hole_idxs[x.segment];
+        joined.push(match parse_hole(expected[hole], "???").is_some() {
+            true => MatchedSegment::Updated {
+                expected: hole..hole + 1,
+                actual,
+            },
+            false => MatchedSegment::HoleConsumed {
+                expected: hole,
+                actual,
+            },
+        });
+    }
+    let (start, matched_last) = last;
+    let expected_start = segments[hole_idxs.len()].start;
+    joined.extend(
+        matched_last
+            .into_iter()
+            .map(|y| y.shift(expected_start, start)),
+    );
+    joined
+}
+
+/// The lines before the first hole, which must match the beginning of the actual output exactly.
+/// A `{binary:N bytes}` line ends it too, since it matches several lines before they are
+/// collapsed.
+pub fn literal_prefix<'a>(expected: &'a [&'a str]) -> &'a [&'a str] {
+    let end = expected
+        .iter()
+        .position(|line| {
+            is_hole(line) || line.trim() == UNORDERED_START || binary_size(line).is_some()
+        })
+        .unwrap_or(expected.len());
+    &expected[..end]
+}
+
+/// Whether an expected and an actual line match exactly, modulo the normalization by `comparison`
+/// and the number placeholders in the expected line.
+pub fn lines_match(expected: &str, actual: &str, comparison: Comparison) -> bool {
+    if let (Some(x), Some(y)) = (binary_size(expected), binary_size(actual)) {
+        return x.abs_diff(y) as f64 <= BINARY_SIZE_TOLERANCE * x.max(y) as f64;
+    }
+    let (expected, annotations, comparison) = comparison.annotated(expected);
+    if annotations.regex {
+        // The whitespace in the regex is normalized like in an expected line of text.
+        let regex = comparison.whitespace.normalize(expected);
+        let actual = comparison.normalize(actual);
+        return line_regex(&regex, comparison.case).is_ok_and(|x| x.is_match(&actual));
+    }
+    let (expected, actual) = (comparison.normalize(expected), comparison.normalize(actual));
+    expected == actual
+        || (expected.contains('{') && numbers_match(&expected, &actual, comparison.tokens))
+}
+
+/// How much the sizes of two `{binary:N bytes}` lines may differ relative to the larger one for
+/// them to match, since binary output like compressed data rarely has the same size every time.
+const BINARY_SIZE_TOLERANCE: f64 = 0.1;
+
+/// The size in a `{binary:N bytes}` line.
+fn binary_size(line: &str) -> Option<usize> {
+    let size = line
+        .trim()
+        .strip_prefix("{binary:")?
+        .strip_suffix(" bytes}")?;
+    size.parse().ok()
+}
+
+/// Whether a line looks like binary data rather than text: at least a quarter of its characters,
+/// and at least two, are control characters other than tabs or replacement characters from
+/// invalid UTF-8.
+pub fn is_binary(line: &str) -> bool {
+    let binary = line
+        .chars()
+        .filter(|x| (x.is_control() && *x != '\t') || *x == char::REPLACEMENT_CHARACTER)
+        .count();
+    binary >= 2 && binary * 4 >= line.chars().count()
+}
+
+/// Replace every run of actual lines which look like binary data with a line like
+/// `{binary:512 bytes}`, where the size includes the newlines between the lines, so that the
+/// output can be shown and matched safely.
+pub fn collapse_binary<'a>(actual: Vec<Cow<'a, str>>) -> Vec<Cow<'a, str>> {
+    let mut result = Vec::with_capacity(actual.len());
+    // The size of the run of binary lines so far.
+    let mut run: Option<usize> = None;
+    for line in actual {
+        match (is_binary(&line), run) {
+            (true, Some(size)) => run = Some(size + 1 + line.len()),
+            (true, None) => run = Some(line.len()),
+            (false, _) => {
+                if let Some(size) = run.take() {
+                    result.push(Cow::Owned(format!("{{binary:{size} bytes}}")));
+                }
+                result.push(line);
+            }
+        }
+    }
+    if let Some(size) = run {
+        result.push(Cow::Owned(format!("{{binary:{size} bytes}}")));
+    }
+    result
+}
+
+/// A line with its control characters escaped, except tabs, so that it can be printed without
+/// messing up the terminal.
+pub fn printable(line: &str) -> Cow<'_, str> {
+    match line.chars().any(|x| x.is_control() && x != '\t') {
+        true => Cow::Owned(
+            line.chars()
+                .map(|x| match x.is_control() && x != '\t' {
+                    true => x.escape_debug().to_string(),
+                    false => x.to_string(),
+                })
+                .collect(),
+        ),
+        false => Cow::Borrowed(line),
+    }
+}
+
+/// A part of an expected line.
+#[derive(Debug, Clone, Copy)]
+enum Token<'a> {
+    /// Text which must match exactly.
+    Text(&'a str),
+    /// A number within a tolerance of a value.
+    Number { value: f64, tolerance: f64 },
+    /// Any number.
+    AnyNumber,
+    /// Text which matches the anchored regex of a token in [Tokens].
+    Pattern(&'a Regex),
+}
+
+/// Split an expected line into text, number placeholders and `tokens`. Braces which don't start
+/// a valid placeholder are text.
+fn tokenize<'a>(line: &'a str, tokens: &'a Tokens) -> Vec<Token<'a>> {
+    let mut tokens = Vec::new();
+    let mut text_start = 0;
+    let mut i = 0;
+    while let Some(offset) = line[i..].find('{') {
+        let start = i + offset;
+        let placeholder = line[start..].find('}').and_then(|end| {
+            let inner = &line[start + 1..start + end];
+            let token = tokens
+                .get(inner)
+                .map(Token::Pattern)
+                .or_else(|| parse_placeholder(inner))?;
+            Some((token, end))
+        });
+        match placeholder {
+            Some((token, end)) => {
+                if text_start < start {
+                    tokens.push(Token::Text(&line[text_start..start]));
+                }
+                tokens.push(token);
+                i = start + end + 1;
+                text_start = i;
+            }
+            None => i = start + 1,
+        }
+    }
+    if text_start < line.len() {
+        tokens.push(Token::Text(&line[text_start..]));
+    }
+    tokens
+}
+
+/// Parse the inside of a placeholder, like `~3.14 ±0.01` or `float`.
+fn parse_placeholder(inner: &str) -> Option<Token<'static>> {
+    if inner == "float" {
+        return Some(Token::AnyNumber);
+    }
+    let inner = inner.strip_prefix('~')?;
+    let (value, tolerance) = match inner.split_once('±').or_else(|| inner.split_once("+-")) {
+        Some((value, tolerance)) => (value.trim(), Some(tolerance.trim())),
+        None => (inner.trim(), None),
+    };
+    if scan_number(value) != value.len() || value.is_empty() {
+        return None;
+    }
+    let tolerance = match tolerance {
+        Some(tolerance) => tolerance.parse().ok().filter(|x: &f64| *x >= 0.0)?,
+        None => last_digit_tolerance(value),
+    };
+    Some(Token::Number {
+        value: value.parse().ok()?,
+        tolerance,
+    })
+}
+
+/// Half a unit of the last digit of a number, so `3.14` gives `0.005` and `2e3` gives `500`.
+fn last_digit_tolerance(number: &str) -> f64 {
+    let (mantissa, exponent) = match number.find(['e', 'E']) {
+        Some(i) => (&number[..i], number[i + 1..].parse().unwrap_or(0)),
+        None => (number, 0),
+    };
+    let decimals = mantissa.split_once('.').map_or(0, |(_, x)| x.len() as i32);
+    0.5 * 10f64.powi(exponent - decimals)
+}
+
+/// The length of the number at the start of `text`, or 0 if there is none. A number is an
+/// optional sign, digits with an optional decimal point and an optional exponent.
+fn scan_number(text: &str) -> usize {
+    let bytes = text.as_bytes();
+    let digits = |mut i: usize| {
+        while bytes.get(i).is_some_and(u8::is_ascii_digit) {
+            i += 1;
+        }
+        i
+    };
+    let mut i = usize::from(matches!(bytes.first(), Some(b'+' | b'-')));
+    let integer_end = digits(i);
+    let mut end = integer_end;
+    if bytes.get(end) == Some(&b'.') {
+        end = digits(end + 1);
+    }
+    if end == i || (integer_end == i && end == i + 1) {
+        return 0;
+    }
+    i = end;
+    if matches!(bytes.get(i), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(bytes.get(i + 1), Some(b'+' | b'-')));
+        let exponent_end = digits(i + 1 + sign);
+        if exponent_end > i + 1 + sign {
+            end = exponent_end;
+        }
+    }
+    end
+}
+
+/// Match an actual line against an expected line with number placeholders.
+fn numbers_match(expected: &str, actual: &str, tokens: &Tokens) -> bool {
+    let tokens = tokenize(expected, tokens);
+    if !tokens.iter().any(|x| !matches!(x, Token::Text(_))) {
+        return false;
+    }
+    let mut rest = actual;
+    for token in tokens {
+        match token {
+            Token::Text(text) => match rest.strip_prefix(text) {
+                Some(x) => rest = x,
+                None => return false,
+            },
+            Token::Number { value, tolerance } => {
+                let len = scan_number(rest);
+                match rest[..len].parse::<f64>() {
+                    Ok(x) if x == value || (x - value).abs() <= tolerance => rest = &rest[len..],
+                    _ => return false,
                 }
             }
-            Err(err.unwrap())
+            Token::AnyNumber => match scan_number(rest) {
+                0 => return false,
+                len => rest = &rest[len..],
+            },
+            Token::Pattern(regex) => match regex.find(rest) {
+                Some(x) if !x.is_empty() => rest = &rest[x.end()..],
+                _ => return false,
+            },
         }
     }
+    rest.is_empty()
 }
 
+/// Match the actual lines against the expected lines. Returns the matched segments in the order
+/// of the expected lines, which cover all expected lines and all actual lines.
 pub fn matchit<'a>(
     expected: &[&'a str],
     actual: &'a [&'a str],
-) -> Result<Option<Vec<&'a str>>, ParseError<'a>> {
-    let (remaining_input, updated) = with_holes::<true>(
-        &mut |x, y| with_holes::<false>(&mut match_lines, x, y),
+    comparison: Comparison,
+) -> Result<Vec<MatchedSegment>, ParseError<'a>> {
+    let (_, matched) = match_all(expected, actual, comparison, true)?;
+    Ok(matched)
+}
+
+/// Match the expected lines against a prefix of the actual lines, like [matchit] but any actual
+/// lines after the match are allowed. Returns the matched segments and the number of actual lines
+/// at the end which were not matched.
+pub fn matchit_prefix<'a>(
+    expected: &[&'a str],
+    actual: &'a [&'a str],
+    comparison: Comparison,
+) -> Result<(Vec<MatchedSegment>, usize), ParseError<'a>> {
+    let (remaining, matched) = match_all(expected, actual, comparison, false)?;
+    Ok((matched, remaining.len()))
+}
+
+/// Match with all kinds of patterns. If `anchored` is set, all of `actual` must be matched.
+fn match_all<'a>(
+    expected: &[&'a str],
+    actual: &'a [&'a str],
+    comparison: Comparison,
+    anchored: bool,
+) -> ParseResult<'a> {
+    with_holes(
+        &mut |x, y, anchored| {
+            with_unordered_groups(
+                &mut |x, y, anchored| match_lines(x, y, anchored, comparison),
+                x,
+                y,
+                anchored,
+                comparison,
+            )
+        },
         expected,
         actual,
-    )?;
-    if !remaining_input.is_empty() {
-        return Err(ParseError {
-            expected: None,
-            got: Some(remaining_input[0]),
-        });
+        anchored,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The numbers of lines which the holes of both kinds match, the first match in the order of
+    /// the numbers of the first hole, then the second and so on, or `None` if there is no match.
+    /// Every number of lines is tried for every hole, which takes exponential time but doesn't
+    /// depend on how [with_holes] splits the lines into segments. Only lines of text and holes are
+    /// supported.
+    fn hole_lengths(expected: &[&str], actual: &[&str], anchored: bool) -> Option<Vec<usize>> {
+        let Some((line, after)) = expected.split_first() else {
+            return (!anchored || actual.is_empty()).then(Vec::new);
+        };
+        match parse_hole(line, "...").or_else(|| parse_hole(line, "???")) {
+            Some((min, max)) => (min..=max.min(actual.len())).find_map(|i| {
+                let mut lengths = hole_lengths(after, &actual[i..], anchored)?;
+                lengths.insert(0, i);
+                Some(lengths)
+            }),
+            None => match actual.split_first() {
+                Some((first, rest)) if first == line => hole_lengths(after, rest, anchored),
+                _ => None,
+            },
+        }
     }
 
-    Ok(updated)
+    #[test]
+    fn bounded_holes() {
+        let actual = ["a", "b", "b", "c"];
+        let comparison = Comparison::default();
+        assert!(matchit(&["a", "...{2}", "c"], &actual, comparison).is_ok());
+        assert!(matchit(&["a", "...{3,}", "c"], &actual, comparison).is_err());
+        let matched = matchit(&["???{1,}", "b", "c"], &actual, comparison).unwrap();
+        assert_eq!(
+            matched[0],
+            MatchedSegment::Updated {
+                expected: 0..1,
+                actual: 0..2
+            }
+        );
+    }
+
+    #[test]
+    fn builtin_tokens() {
+        let cases = [
+            ("{DATE}", "2024-01-31", true),
+            ("{DATE}", "1/31/2024", true),
+            ("{DATE}", "31 Jan 2024", true),
+            ("{DATE}", "2024-1-31", false),
+            ("{DATE}", "2024/01/31", false),
+            ("{DATE}", "31 January 2024", false),
+            ("{TIME}", "12:34", true),
+            ("{TIME}", "12:34:56.789", true),
+            ("{TIME}", "9:05 PM", true),
+            ("{TIME}", "12:34:56Z", true),
+            ("{TIME}", "12:34+02:00", true),
+            ("{TIME}", "12:3", false),
+            ("{TIME}", "1234", false),
+            ("{DATETIME}", "2024-01-31T12:34:56Z", true),
+            ("{DATETIME}", "2024-01-31 12:34", true),
+            ("{DATETIME}", "2024-01-31T12:34:56.123+0100", true),
+            ("{DATETIME}", "2024-01-31", false),
+            ("{DATETIME}", "2024-01-31T12", false),
+            ("{DURATION}", "1.5s", true),
+            ("{DURATION}", "2 min", true),
+            ("{DURATION}", "1h 30m", true),
+            ("{DURATION}", "0,5 s", true),
+            ("{DURATION}", "3µs", true),
+            ("{DURATION}", "1:02:03", true),
+            ("{DURATION}", "5 parsecs", false),
+            ("{DURATION}", "1h30", false),
+            ("{DURATION}", "1:2", false),
+            ("took {duration}.", "took 12ms.", true),
+        ];
+        for (expected, actual, matches) in cases {
+            assert_eq!(
+                lines_match(expected, actual, Comparison::default()),
+                matches,
+                "{expected} {actual}"
+            );
+        }
+    }
+
+    #[test]
+    fn custom_tokens_override_builtin_tokens() {
+        let custom = BTreeMap::from([("DATE".to_string(), r"\d+".to_string())]);
+        let tokens = Tokens::new(&custom).unwrap();
+        let comparison = Comparison {
+            tokens: &tokens,
+            ..Comparison::default()
+        };
+        let cases = [
+            ("{date}", "12345", true),
+            ("{DATE}", "2024-01-31", false),
+            ("{TIME}", "12:34", true),
+        ];
+        for (expected, actual, matches) in cases {
+            assert_eq!(
+                lines_match(expected, actual, comparison),
+                matches,
+                "{expected} {actual}"
+            );
+        }
+    }
+
+    #[test]
+    fn holes_of_both_kinds_backtrack_together() {
+        let expected = ["...", "b", "???{0}", "c"];
+        let matched = matchit(&expected, &["b", "x", "b", "c"], Comparison::default()).unwrap();
+        assert_eq!(
+            matched[0],
+            MatchedSegment::HoleConsumed {
+                expected: 0,
+                actual: 0..2
+            }
+        );
+    }
+
+    #[test]
+    fn number_placeholders() {
+        let matches = |expected, actual| lines_match(expected, actual, Comparison::default());
+        // The tolerance is half a unit of the last digit.
+        assert!(matches("pi = {~3.14}", "pi = 3.144"));
+        assert!(matches("pi = {~3.14}", "pi = 3.136"));
+        assert!(!matches("pi = {~3.14}", "pi = 3.146"));
+        assert_eq!(last_digit_tolerance("2e3"), 500.0);
+        assert!(matches("{~10 ±0.5} s", "10.4 s"));
+        assert!(!matches("{~10 ±0.5} s", "10.6 s"));
+        assert!(matches("{~10+-1}", "9"));
+        assert!(!matches("{~10+-1}", "11.5"));
+        assert!(matches("{~2e3}", "2.4e3"));
+        assert!(!matches("{~2e3}", "2600"));
+        assert!(matches("{~1.5e-3}", "0.00152"));
+        assert!(matches("{~-1.5}", "-1.54"));
+        assert!(!matches("{~-1.5}", "1.5"));
+        assert!(matches("x = {float} m", "x = -1.5e10 m"));
+        assert!(!matches("x = {float} m", "x = . m"));
+        // Placeholders which can't be parsed are text.
+        for malformed in ["{~3.1", "{~}", "{~abc}", "{~1 ±-1}", "{~1.2.3}"] {
+            assert!(matches(malformed, malformed), "{malformed}");
+            assert!(!matches(malformed, "1"), "{malformed}");
+        }
+        assert_eq!(scan_number("-1.5e-3x"), 7);
+        assert_eq!(scan_number("1e"), 1);
+        assert_eq!(scan_number(".5"), 2);
+        assert_eq!(scan_number("5."), 2);
+        assert_eq!(scan_number("."), 0);
+        assert_eq!(scan_number("+"), 0);
+    }
+
+    #[test]
+    fn line_annotations() {
+        let annotations = |optional, regex, exact_whitespace| LineAnnotations {
+            optional,
+            regex,
+            exact_whitespace,
+        };
+        assert_eq!(
+            parse_annotations("out  #repl: optional, regex"),
+            Ok(("out ", annotations(true, true, false)))
+        );
+        assert_eq!(
+            parse_annotations("#repl: exact-whitespace"),
+            Ok(("", annotations(false, false, true)))
+        );
+        // The annotations must follow whitespace.
+        assert_eq!(
+            parse_annotations("a#repl: optional"),
+            Ok(("a#repl: optional", LineAnnotations::default()))
+        );
+        assert_eq!(
+            parse_annotations("a #repl: sometimes"),
+            Err(
+                "Unknown line annotation sometimes, expected optional, regex or exact-whitespace"
+                    .to_string()
+            )
+        );
+        assert!(parse_annotations("( #repl: regex")
+            .unwrap_err()
+            .starts_with("Bad regex in line"));
+    }
+
+    #[test]
+    fn annotated_lines() {
+        let comparison = Comparison {
+            annotations: true,
+            ..Comparison::default()
+        };
+        let optional = ["a", "b #repl: optional", "c"];
+        assert!(matchit(&optional, &["a", "c"], comparison).is_ok());
+        assert!(matchit(&optional, &["a", "b", "c"], comparison).is_ok());
+        assert!(matchit(&optional, &["a", "x", "c"], comparison).is_err());
+        let regex = r"\d+ items #repl: regex";
+        assert!(lines_match(regex, "12 items", comparison));
+        assert!(!lines_match(regex, "x items", comparison));
+        assert!(!lines_match(regex, "12 items left", comparison));
+        let exact = "a  #repl: exact-whitespace";
+        assert!(lines_match(exact, "a ", comparison));
+        assert!(!lines_match(exact, "a", comparison));
+        assert!(lines_match("a ", "a", comparison));
+    }
+
+    #[test]
+    fn exact_whitespace() {
+        let line = " a \t b  ";
+        assert_eq!(Whitespace::Exact.normalize(line), line);
+        assert_eq!(Whitespace::Exact.normalize("a\t"), "a\t");
+    }
+
+    #[test]
+    fn trimmed_whitespace() {
+        assert_eq!(Whitespace::Trim.normalize(" a \t b  "), " a \t b");
+        assert_eq!(Whitespace::Trim.normalize("a\t \t"), "a");
+        assert_eq!(Whitespace::Trim.normalize(" \t "), "");
+    }
+
+    #[test]
+    fn collapsed_whitespace() {
+        assert_eq!(Whitespace::Collapse.normalize("a \t b  "), "a b");
+        assert_eq!(Whitespace::Collapse.normalize("\t  a\t\tb\t"), " a b");
+        assert_eq!(Whitespace::Collapse.normalize(" \t "), "");
+    }
+
+    #[test]
+    fn case_and_unicode_normalization() {
+        let comparison = |case, unicode_form| Comparison {
+            case,
+            unicode_form,
+            ..Comparison::default()
+        };
+        let sensitive = comparison(Case::Sensitive, None);
+        let insensitive = comparison(Case::Insensitive, None);
+        assert!(!lines_match("Hello World", "hELLO wORLD", sensitive));
+        assert!(lines_match("Hello World", "hELLO wORLD", insensitive));
+        // A composed and a decomposed é.
+        let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+        assert!(!lines_match(composed, decomposed, sensitive));
+        for form in [UnicodeForm::Nfc, UnicodeForm::Nfd, UnicodeForm::Nfkc] {
+            let normalized = comparison(Case::Sensitive, Some(form));
+            assert!(lines_match(composed, decomposed, normalized), "{form:?}");
+            assert!(lines_match(decomposed, composed, normalized), "{form:?}");
+        }
+        let both = comparison(Case::Insensitive, Some(UnicodeForm::Nfc));
+        assert!(lines_match("CAF\u{c9}", decomposed, both));
+        // The ligature ﬁ is only the same as fi in the compatibility forms.
+        let ligature = |form| lines_match("\u{fb01}le", "file", comparison(Case::Sensitive, form));
+        assert!(!ligature(None));
+        assert!(!ligature(Some(UnicodeForm::Nfc)));
+        assert!(ligature(Some(UnicodeForm::Nfkc)));
+        assert!(ligature(Some(UnicodeForm::Nfkd)));
+    }
+
+    #[test]
+    fn escaped_lines() {
+        assert_eq!(escape_line("..."), "\\...");
+        assert_eq!(escape_line("  ...{2}"), "  \\...{2}");
+        assert_eq!(escape_line("\\???"), "\\\\???");
+        assert_eq!(escape_line("{unordered}"), "\\{unordered}");
+        assert!(matches!(escape_line("...."), Cow::Borrowed("....")));
+        assert!(matches!(escape_line("\\x"), Cow::Borrowed(_)));
+        assert_eq!(annotate_line("\\..."), LineAnnotation::Escaped);
+        assert_eq!(annotate_line("\\\\???"), LineAnnotation::Escaped);
+        assert_eq!(annotate_line("\\x"), LineAnnotation::Text);
+    }
+
+    #[test]
+    fn escaped_output_matches_itself() {
+        let comparison = Comparison::default();
+        let output = ["...", "\\???", "{unordered}", "{/unordered}", "x"];
+        // The actual lines are escaped before they are matched, and written to the documents
+        // like that when they are updated.
+        let actual: Vec<_> = output.iter().map(|x| escape_line(x)).collect();
+        let actual: Vec<&str> = actual.iter().map(AsRef::as_ref).collect();
+        assert_eq!(
+            actual,
+            ["\\...", "\\\\???", "\\{unordered}", "\\{/unordered}", "x"]
+        );
+        assert_eq!(
+            matchit(&actual, &actual, comparison).unwrap(),
+            [MatchedSegment::Literal {
+                expected: 0..5,
+                actual: 0..5
+            }]
+        );
+        // The escaped lines are not holes.
+        let other = ["y", "\\\\???", "\\{unordered}", "\\{/unordered}", "x"];
+        assert!(matchit(&actual, &other, comparison).is_err());
+    }
+
+    #[test]
+    fn unordered_groups() {
+        let comparison = Comparison::default();
+        let group = ["{unordered}", "a", "b", "{/unordered}", "c"];
+        assert_eq!(
+            matchit(&group, &["b", "a", "c"], comparison).unwrap(),
+            [
+                MatchedSegment::Literal {
+                    expected: 0..4,
+                    actual: 0..2
+                },
+                MatchedSegment::Literal {
+                    expected: 4..5,
+                    actual: 2..3
+                },
+            ]
+        );
+        // Every line in the group matches a line of its own.
+        let duplicate = ["{unordered}", "a", "a", "{/unordered}"];
+        assert!(matchit(&duplicate, &["a", "a"], comparison).is_ok());
+        let e = matchit(&duplicate, &["a", "b"], comparison).unwrap_err();
+        assert_eq!((e.expected, e.got), (Some("a"), Some("b")));
+        let e = matchit(&group, &["a", "x", "c"], comparison).unwrap_err();
+        assert_eq!((e.expected, e.got), (Some("b"), Some("x")));
+        // Without `{/unordered}` the group lasts to the end.
+        assert_eq!(
+            matchit(&["{unordered}", "b", "a"], &["a", "b"], comparison).unwrap(),
+            [MatchedSegment::Literal {
+                expected: 0..3,
+                actual: 0..2
+            }]
+        );
+    }
+
+    #[cfg(feature = "proptest")]
+    proptest::proptest! {
+        #[test]
+        fn holes_match_like_an_exhaustive_search(
+            expected in proptest::collection::vec(
+                proptest::sample::select(
+                    &[
+                        "a", "b", "...", "...{1}", "...{0,2}", "???", "???{0}", "???{1,}",
+                        "???{,1}",
+                    ][..],
+                ),
+                0..8,
+            ),
+            actual in proptest::collection::vec(
+                proptest::sample::select(&["a", "b", "c"][..]),
+                0..10,
+            ),
+            anchored: bool,
+        ) {
+            let matched = match_all(&expected, &actual, Comparison::default(), anchored);
+            let lengths = matched.ok().map(|(_, segments)| {
+                segments
+                    .iter()
+                    .filter_map(|x| Some(x.hole()?.1.len()))
+                    .collect::<Vec<_>>()
+            });
+            proptest::prop_assert_eq!(lengths, hole_lengths(&expected, &actual, anchored));
+        }
+    }
 }
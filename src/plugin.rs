@@ -0,0 +1,77 @@
+//! External commands which extend the matching of output, configured in the `[filters]` and
+//! `[matchers]` sections of the configuration file and enabled on blocks with the `filter` and
+//! `matcher` attributes.
+//!
+//! A plugin is run with `sh -c` every time it is used. It gets a JSON object on stdin and must
+//! write a JSON object to stdout:
+//!
+//! - An output filter gets `{"output": "..."}` with the output of a command and responds with
+//!   `{"output": "..."}`, which is matched against the document instead.
+//! - A matcher gets `{"expected": [...], "actual": [...]}` with the expected and actual lines and
+//!   responds with `{"matches": true}` or `{"matches": false, "message": "..."}`. It replaces the
+//!   built-in matching of patterns.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[derive(Serialize)]
+struct FilterRequest<'a> {
+    output: &'a str,
+}
+
+#[derive(Deserialize)]
+struct FilterResponse {
+    output: String,
+}
+
+#[derive(Serialize)]
+struct MatcherRequest<'a> {
+    expected: &'a [&'a str],
+    actual: &'a [&'a str],
+}
+
+#[derive(Deserialize)]
+struct MatcherResponse {
+    matches: bool,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// Run a plugin command with a request and parse its response.
+fn run<T: DeserializeOwned>(command: &str, request: &impl Serialize) -> anyhow::Result<T> {
+    let error = |e: &dyn std::fmt::Display| anyhow::anyhow!("The plugin `{command}` failed: {e}");
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| error(&e))?;
+    let request = serde_json::to_string(request)?;
+    // The plugin may exit without reading its input, so a broken pipe is not an error here.
+    let _ = child.stdin.take().unwrap().write_all(request.as_bytes());
+    let output = child.wait_with_output().map_err(|e| error(&e))?;
+    if !output.status.success() {
+        return Err(error(&output.status));
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| error(&format!("Bad response: {e}")))
+}
+
+/// Filter the output of a command through an output filter.
+pub fn filter(command: &str, output: &str) -> anyhow::Result<String> {
+    let response: FilterResponse = run(command, &FilterRequest { output })?;
+    Ok(response.output)
+}
+
+/// Match output with a custom matcher. Returns an error message if it doesn't match.
+pub fn matches(
+    command: &str,
+    expected: &[&str],
+    actual: &[&str],
+) -> anyhow::Result<Result<(), String>> {
+    let response: MatcherResponse = run(command, &MatcherRequest { expected, actual })?;
+    Ok(match response.matches {
+        true => Ok(()),
+        false => Err(response.message.unwrap_or_default()),
+    })
+}
@@ -0,0 +1,131 @@
+//! Presets with the command and prompts of common REPLs, which are used by sessions with the
+//! `preset` attribute. Attributes on the block override the preset, and more presets can be
+//! defined in the `[presets]` section of the configuration file.
+
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// Default session attributes for a REPL.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preset {
+    /// The command which starts the REPL, the `cmd` attribute.
+    pub cmd: Option<Cow<'static, str>>,
+
+    /// A regex for the prompt, the `prompt` attribute.
+    pub prompt: Option<Cow<'static, str>>,
+
+    /// A regex for the prompt when the REPL waits for more lines of a command, the
+    /// `continuation_prompt` attribute.
+    pub continuation_prompt: Option<Cow<'static, str>>,
+
+    /// A command which makes the REPL exit at the end of the session, the `quit` attribute.
+    pub quit: Option<Cow<'static, str>>,
+
+    /// A command which restores a clean state in the REPL so that it can be reused, the
+    /// `reset_cmd` attribute.
+    pub reset_cmd: Option<Cow<'static, str>>,
+}
+
+impl Preset {
+    /// The value of a session attribute in the preset.
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        match key {
+            "cmd" => self.cmd.as_deref(),
+            "prompt" => self.prompt.as_deref(),
+            "continuation_prompt" => self.continuation_prompt.as_deref(),
+            "quit" => self.quit.as_deref(),
+            "reset_cmd" => self.reset_cmd.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+const fn preset(
+    cmd: &'static str,
+    prompt: &'static str,
+    continuation_prompt: &'static str,
+    quit: &'static str,
+) -> Preset {
+    Preset {
+        cmd: Some(Cow::Borrowed(cmd)),
+        prompt: Some(Cow::Borrowed(prompt)),
+        continuation_prompt: Some(Cow::Borrowed(continuation_prompt)),
+        quit: Some(Cow::Borrowed(quit)),
+        reset_cmd: None,
+    }
+}
+
+/// The built-in presets by name.
+static BUILTIN_PRESETS: &[(&str, Preset)] = &[
+    (
+        "python",
+        preset(
+            "env PYTHON_BASIC_REPL=1 python3 -q",
+            ">>> ",
+            r"\.\.\.(?: |$)",
+            "exit()",
+        ),
+    ),
+    (
+        "ipython",
+        Preset {
+            cmd: Some(Cow::Borrowed(
+                "ipython --no-banner --simple-prompt --colors=NoColor",
+            )),
+            prompt: Some(Cow::Borrowed(r"In \[{n}\]: ")),
+            continuation_prompt: Some(Cow::Borrowed(r" +\.\.\.: ")),
+            quit: Some(Cow::Borrowed("exit")),
+            reset_cmd: Some(Cow::Borrowed("%reset -f")),
+        },
+    ),
+    ("node", preset("node", "> ", r"\.\.\. |\| ", ".exit")),
+    (
+        "ghci",
+        preset(
+            "ghci -ignore-dot-ghci",
+            r"(?:ghci|Prelude)> ",
+            r"(?:ghci|Prelude)\| ",
+            ":quit",
+        ),
+    ),
+    (
+        "irb",
+        preset(
+            "irb --nocolorize --noautocomplete",
+            r"irb\(main\):{n}:0> ",
+            r"irb\(main\):\d+:\d+[*'\x22]? ",
+            "exit",
+        ),
+    ),
+    (
+        "psql",
+        preset("psql", r"\w*=[#>] ", r"\w*[-'\x22(]\s*[#>] ", r"\q"),
+    ),
+    (
+        "sqlite3",
+        preset("sqlite3", "sqlite> ", r" *\.\.\.> ", ".quit"),
+    ),
+    (
+        "bash",
+        preset(
+            "env PS1='$ ' PS2='> ' bash --norc --noprofile",
+            r"[$] ",
+            "> ",
+            "exit",
+        ),
+    ),
+];
+
+/// The built-in preset with a name.
+pub fn builtin_preset(name: &str) -> Option<&'static Preset> {
+    BUILTIN_PRESETS
+        .iter()
+        .find(|(x, _)| *x == name)
+        .map(|(_, preset)| preset)
+}
+
+/// The names of all built-in presets.
+pub fn builtin_preset_names() -> impl Iterator<Item = &'static str> {
+    BUILTIN_PRESETS.iter().map(|(name, _)| *name)
+}
@@ -0,0 +1,133 @@
+//! Progress of the sessions while they run, so that a long run of a large book isn't silent until
+//! the report at the end.
+//!
+//! On a terminal every running session gets a progress bar with the number of blocks done and the
+//! command which is running, otherwise a line is printed when a session starts and finishes and
+//! when a block is done.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::io::IsTerminal;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Receives the progress of the sessions which are run, see [crate::Options::progress].
+pub trait Progress: Send + Sync + std::fmt::Debug {
+    /// A session with `blocks` blocks is started, or started again for a retry.
+    fn start_session(&self, name: &str, blocks: usize) -> Box<dyn SessionProgress>;
+}
+
+/// The progress of one running session.
+pub trait SessionProgress {
+    /// A command is sent to the REPL.
+    fn command(&self, cmd: &str);
+
+    /// A block has been run.
+    fn block_done(&self, passed: bool);
+
+    /// The session has stopped, after all its blocks or after a failure.
+    fn finish(self: Box<Self>, passed: bool);
+}
+
+/// Progress bars if stdout is a terminal, otherwise lines, both on stderr.
+pub fn stderr_progress() -> Arc<dyn Progress> {
+    match std::io::stdout().is_terminal() {
+        true => Arc::new(Bars(MultiProgress::new())),
+        false => Arc::new(Lines),
+    }
+}
+
+/// Progress which isn't reported anywhere.
+pub(crate) struct NoProgress;
+
+impl SessionProgress for NoProgress {
+    fn command(&self, _: &str) {}
+    fn block_done(&self, _: bool) {}
+    fn finish(self: Box<Self>, _: bool) {}
+}
+
+/// A progress bar for every running session.
+#[derive(Debug)]
+struct Bars(MultiProgress);
+
+impl Progress for Bars {
+    fn start_session(&self, name: &str, blocks: usize) -> Box<dyn SessionProgress> {
+        let bar = self.0.add(ProgressBar::new(blocks as u64));
+        bar.set_style(
+            ProgressStyle::with_template("{spinner} {prefix} [{pos}/{len}] {wide_msg}").unwrap(),
+        );
+        bar.set_prefix(name.to_string());
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Box::new(SessionBar {
+            multi: self.0.clone(),
+            bar,
+            name: name.to_string(),
+        })
+    }
+}
+
+struct SessionBar {
+    multi: MultiProgress,
+    bar: ProgressBar,
+    name: String,
+}
+
+impl SessionProgress for SessionBar {
+    fn command(&self, cmd: &str) {
+        self.bar
+            .set_message(cmd.lines().next().unwrap_or_default().to_string());
+    }
+
+    fn block_done(&self, _: bool) {
+        self.bar.inc(1);
+    }
+
+    fn finish(self: Box<Self>, passed: bool) {
+        self.bar.finish_and_clear();
+        self.multi.remove(&self.bar);
+        let status = if passed { "passed" } else { "failed" };
+        let _ = self
+            .multi
+            .println(format!("Session {} {status}", self.name));
+    }
+}
+
+/// A line when a session starts and finishes and when a block is done.
+#[derive(Debug)]
+struct Lines;
+
+impl Progress for Lines {
+    fn start_session(&self, name: &str, blocks: usize) -> Box<dyn SessionProgress> {
+        eprintln!("Session {name} started");
+        Box::new(SessionLines {
+            name: name.to_string(),
+            blocks,
+            done: Default::default(),
+        })
+    }
+}
+
+struct SessionLines {
+    name: String,
+    blocks: usize,
+    done: std::cell::Cell<usize>,
+}
+
+impl SessionProgress for SessionLines {
+    fn command(&self, _: &str) {}
+
+    fn block_done(&self, passed: bool) {
+        self.done.set(self.done.get() + 1);
+        let status = if passed { "passed" } else { "failed" };
+        eprintln!(
+            "Session {}: Block {}/{} {status}",
+            self.name,
+            self.done.get(),
+            self.blocks
+        );
+    }
+
+    fn finish(self: Box<Self>, passed: bool) {
+        let status = if passed { "passed" } else { "failed" };
+        eprintln!("Session {} {status}", self.name);
+    }
+}
@@ -0,0 +1,217 @@
+//! The backend which runs the REPL in a pseudo terminal with portable-pty, or in a pseudo console
+//! (ConPTY) on Windows.
+//!
+//! The pseudo console renders the output of the REPL like a terminal would, with escape codes
+//! for colors and cursor movements and `\r\n` line endings. The escape codes are removed and the
+//! line endings are turned into `\n`, so the output can be compared like on Unix.
+
+#[cfg(unix)]
+use crate::backend::wait_with_usage;
+use crate::backend::{ReplBackend, SpawnOptions, TerminalSettings, QUIT_GRACE};
+use crate::reader::{Encoding, OutputReader, ReadLimits};
+use crate::report::ResourceUsage;
+#[cfg(feature = "vt100")]
+use crate::report::ScreenSnapshot;
+#[cfg(windows)]
+use portable_pty::ChildKiller;
+use portable_pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtySize};
+use regex::Regex;
+use std::io::Write;
+use std::time::Duration;
+
+/// The key which ends a line.
+const ENTER: &str = if cfg!(windows) { "\r" } else { "\n" };
+
+/// A REPL running in a pseudo terminal.
+///
+/// With the `vt100` feature, the terminal is emulated so that a snapshot of the screen can be
+/// shown on failures.
+pub struct PtyBackend {
+    child: Box<dyn Child + Send + Sync>,
+
+    /// The pseudo terminal, which is closed when it is dropped.
+    _master: Box<dyn MasterPty + Send>,
+    writer: Box<dyn Write + Send>,
+    reader: OutputReader,
+
+    /// How the input is encoded, see [SpawnOptions::encoding].
+    encoding: Encoding,
+
+    /// See [SpawnOptions::quit].
+    quit: Option<String>,
+
+    /// Set when the REPL has exited.
+    resource_usage: Option<ResourceUsage>,
+}
+
+/// Wait for the REPL to exit, see [wait_with_usage].
+#[cfg(unix)]
+fn wait_pty_child(child: &mut dyn Child, grace: Duration) -> anyhow::Result<ResourceUsage> {
+    let pid = child
+        .process_id()
+        .ok_or_else(|| anyhow::anyhow!("The REPL has no process id."))?;
+    wait_with_usage(pid as _, grace)
+}
+
+/// Wait for the REPL to exit for `grace`, and then kill it. The resource usage is not measured on
+/// Windows, so the default is returned.
+#[cfg(windows)]
+fn wait_pty_child(child: &mut dyn Child, grace: Duration) -> anyhow::Result<ResourceUsage> {
+    let start = std::time::Instant::now();
+    while child.try_wait()?.is_none() {
+        if start.elapsed() >= grace {
+            // The process may exit right before it is killed.
+            let _ = child.kill();
+            child.wait()?;
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    Ok(ResourceUsage::default())
+}
+
+/// A stream for reading the output from the pseudo terminal.
+#[cfg(unix)]
+fn master_reader(master: &dyn MasterPty) -> anyhow::Result<std::fs::File> {
+    let fd = master
+        .as_raw_fd()
+        .ok_or_else(|| anyhow::anyhow!("The pseudo terminal has no file descriptor."))?;
+    // SAFETY: The file descriptor is valid as long as `master`, and it is duplicated.
+    let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    Ok(fd.into())
+}
+
+/// A stream for reading the output from the pseudo console.
+#[cfg(windows)]
+fn master_reader(master: &dyn MasterPty) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
+    master.try_clone_reader()
+}
+
+/// Write all of `bytes` to the REPL. The pseudo terminal may be non-blocking when its output is
+/// read asynchronously, so writes which would block are tried again.
+fn write_input(writer: &mut dyn Write, mut bytes: &[u8]) -> anyhow::Result<()> {
+    while !bytes.is_empty() {
+        match writer.write(bytes) {
+            Ok(n) => bytes = &bytes[n..],
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+impl ReplBackend for PtyBackend {
+    fn spawn(options: &SpawnOptions) -> anyhow::Result<Self> {
+        let TerminalSettings { cols, rows, .. } = options.terminal;
+        let pair = native_pty_system().openpty(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })?;
+        // The command is built like for the other backends, so containers and ssh work too.
+        let command = options.command()?;
+        let mut builder = CommandBuilder::new(command.get_program());
+        builder.args(command.get_args());
+        for (key, value) in command.get_envs() {
+            match value {
+                Some(value) => builder.env(key, value),
+                None => builder.env_remove(key),
+            }
+        }
+        // portable-pty starts in the home directory unless told otherwise.
+        match command.get_current_dir() {
+            Some(dir) => builder.cwd(dir),
+            None => builder.cwd(std::env::current_dir()?),
+        }
+        let child = pair.slave.spawn_command(builder)?;
+        let limits = ReadLimits {
+            timeout: Duration::from_millis(options.timeout_ms),
+            idle_timeout: options.idle_timeout_ms.map(Duration::from_millis),
+            max_output_bytes: options.max_output_bytes,
+            cancel: options.cancel.cloned(),
+        };
+        let reader =
+            OutputReader::new(master_reader(&*pair.master)?, limits).encoding(options.encoding);
+        #[cfg(windows)]
+        let reader = reader.clean_console();
+        #[cfg(feature = "vt100")]
+        let reader = reader.emulate_terminal(rows, cols);
+        Ok(Self {
+            child,
+            writer: pair.master.take_writer()?,
+            _master: pair.master,
+            reader,
+            encoding: options.encoding,
+            quit: options.quit.map(str::to_string),
+            resource_usage: None,
+        })
+    }
+
+    fn send_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.send_keys(&format!("{line}{ENTER}"))
+    }
+
+    fn send_keys(&mut self, keys: &str) -> anyhow::Result<()> {
+        write_input(&mut *self.writer, &self.encoding.encode(keys))
+    }
+
+    fn read_until_prompt(&mut self, prompt: &Regex) -> anyhow::Result<(String, Option<String>)> {
+        self.reader.read_until_prompt(prompt, None)
+    }
+
+    fn read_until_prompt_streaming(
+        &mut self,
+        prompt: &Regex,
+        on_line: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        self.reader.read_until_prompt(prompt, Some(on_line))
+    }
+
+    fn peek_until_idle(&mut self, idle: Duration) -> anyhow::Result<String> {
+        Ok(self.reader.peek_until_idle(idle))
+    }
+
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        if self.resource_usage.is_none() {
+            // The REPL may have exited already, so the quit command can fail.
+            let grace = match self.quit.clone() {
+                Some(quit) => self.send_line(&quit).map_or(Duration::ZERO, |_| QUIT_GRACE),
+                None => Duration::ZERO,
+            };
+            self.resource_usage = Some(wait_pty_child(&mut *self.child, grace)?);
+        }
+        Ok(())
+    }
+
+    fn kill(&mut self) -> anyhow::Result<()> {
+        if self.resource_usage.is_none() {
+            self.resource_usage = Some(wait_pty_child(&mut *self.child, Duration::ZERO)?);
+        }
+        Ok(())
+    }
+
+    fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.resource_usage.filter(|_| cfg!(unix))
+    }
+
+    #[cfg(feature = "vt100")]
+    fn screen_snapshot(&self) -> Option<ScreenSnapshot> {
+        self.reader.screen().map(crate::screen::snapshot)
+    }
+
+    #[cfg(unix)]
+    fn attach(&mut self) -> anyhow::Result<()> {
+        let Self { writer, reader, .. } = self;
+        crate::debug::attach(|input| write_input(&mut **writer, input), reader)
+    }
+}
+
+impl Drop for PtyBackend {
+    fn drop(&mut self) {
+        let _ = self.kill();
+    }
+}
@@ -0,0 +1,454 @@
+//! Incremental reading of the output of a REPL.
+//!
+//! The output is read by a thread of its own and sent over a channel in chunks as it comes, so a
+//! read loop can stop between the chunks: when the prompt has been read, when too much output has
+//! been read without the prompt, when no output has come for the idle timeout, when the timeout
+//! has passed, or when the read is cancelled from another thread with a [Cancel] handle. The
+//! [ReadLimits] decide when a read loop stops, and [OutputReader] is the read loop for a single
+//! output stream, like that of a pseudo terminal. The output is decoded with an [Encoding].
+
+use crate::backend::{idle_timeout_error, output_limit_error, TimeoutError};
+use regex::Regex;
+use std::borrow::Cow;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The longest time a read loop waits for output before it checks whether it has been cancelled.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+lazy_static::lazy_static! {
+    /// An escape code: a control sequence, an operating system command like setting the window
+    /// title, or a two character sequence.
+    static ref ESCAPE_CODE: Regex =
+        Regex::new(r"\x1b(?:\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(?:\x07|\x1b\\)|[@-Z\\^_])").unwrap();
+}
+
+/// How the output of a REPL is decoded, set with the `encoding` attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// UTF-8, where invalid UTF-8 is an error.
+    Utf8,
+
+    /// ISO 8859-1, where every byte is a character, for REPLs in a latin-1 locale.
+    Latin1,
+
+    /// UTF-8, where invalid sequences are replaced with the replacement character.
+    #[default]
+    Lossy,
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "utf8" | "utf-8" => Ok(Self::Utf8),
+            "latin1" | "latin-1" => Ok(Self::Latin1),
+            "lossy" => Ok(Self::Lossy),
+            _ => Err("Expected utf8, latin1 or lossy".to_string()),
+        }
+    }
+}
+
+impl Encoding {
+    /// The length of the start of `bytes` which can be decoded, without an incomplete UTF-8
+    /// sequence at the end which may be completed by the next chunk of output.
+    pub(crate) fn complete_len(self, bytes: &[u8]) -> usize {
+        let incomplete =
+            match self {
+                Self::Latin1 => 0,
+                Self::Utf8 | Self::Lossy => bytes.utf8_chunks().last().map_or(0, |x| {
+                    match std::str::from_utf8(x.invalid()) {
+                        Err(e) if e.error_len().is_none() => x.invalid().len(),
+                        _ => 0,
+                    }
+                }),
+            };
+        bytes.len() - incomplete
+    }
+
+    /// Decode output which is complete according to [Self::complete_len].
+    pub(crate) fn decode(self, bytes: &[u8]) -> anyhow::Result<Cow<'_, str>> {
+        match self {
+            Self::Utf8 => std::str::from_utf8(bytes).map(Cow::Borrowed).map_err(|e| {
+                anyhow::anyhow!(
+                    "The REPL printed invalid UTF-8: {e}\nSet the encoding attribute to latin1 or \
+                     lossy to read it anyway."
+                )
+            }),
+            Self::Latin1 => Ok(bytes.iter().map(|x| char::from(*x)).collect()),
+            Self::Lossy => Ok(String::from_utf8_lossy(bytes)),
+        }
+    }
+
+    /// Encode input to the REPL. Characters which are not in latin-1 are sent as `?`.
+    pub(crate) fn encode(self, text: &str) -> Cow<'_, [u8]> {
+        match self {
+            Self::Latin1 => text
+                .chars()
+                .map(|x| u8::try_from(x).unwrap_or(b'?'))
+                .collect(),
+            Self::Utf8 | Self::Lossy => Cow::Borrowed(text.as_bytes()),
+        }
+    }
+}
+
+/// A handle to cancel reads, which can be shared between threads. Once it is cancelled, all
+/// reads with it in their [ReadLimits] fail with [CancelledError].
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    /// Cancel all reads with this handle, now and later.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The error when a read has been cancelled with a [Cancel] handle.
+#[derive(Debug, thiserror::Error)]
+#[error("Cancelled while waiting for the prompt `{expected}`, got: {got}")]
+pub struct CancelledError {
+    /// The prompt regex.
+    pub expected: String,
+
+    /// The output which has been read.
+    pub got: String,
+}
+
+/// When a read loop stops without the prompt.
+#[derive(Debug, Clone)]
+pub struct ReadLimits {
+    /// The longest time to wait for the prompt.
+    pub timeout: Duration,
+
+    /// The longest time to wait for more output.
+    pub idle_timeout: Option<Duration>,
+
+    /// The most output to read without the prompt.
+    pub max_output_bytes: usize,
+
+    pub cancel: Option<Cancel>,
+}
+
+impl ReadLimits {
+    /// Whether reads have been cancelled with [Self::cancel].
+    pub fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(Cancel::is_cancelled)
+    }
+
+    /// How long a read loop which started at `start` and last got output at `last_output` should
+    /// wait for more output, or the error if it should stop. `got` is the output which has been
+    /// read without the prompt.
+    pub fn next_wait(
+        &self,
+        start: Instant,
+        last_output: Instant,
+        prompt: &Regex,
+        got: &str,
+    ) -> anyhow::Result<Duration> {
+        if got.len() > self.max_output_bytes {
+            return Err(output_limit_error(self.max_output_bytes, got));
+        }
+        if self.cancelled() {
+            return Err(CancelledError {
+                expected: prompt.to_string(),
+                got: got.to_string(),
+            }
+            .into());
+        }
+        let remaining = self.timeout.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return Err(TimeoutError {
+                expected: prompt.to_string(),
+                got: got.to_string(),
+                timeout: self.timeout,
+            }
+            .into());
+        }
+        let mut wait = remaining;
+        if let Some(idle) = self.idle_timeout {
+            let idle_remaining = idle.saturating_sub(last_output.elapsed());
+            if idle_remaining.is_zero() {
+                return Err(idle_timeout_error(idle, prompt, got));
+            }
+            wait = wait.min(idle_remaining);
+        }
+        if self.cancel.is_some() {
+            wait = wait.min(CANCEL_POLL_INTERVAL);
+        }
+        Ok(wait)
+    }
+}
+
+/// An output stream of a REPL. On Unix it must be a file descriptor, so that it can be read
+/// asynchronously with the `async` feature, see [crate::runtime].
+#[cfg(unix)]
+pub trait Stream: Read + std::os::fd::AsFd + Send + 'static {}
+
+#[cfg(unix)]
+impl<T: Read + std::os::fd::AsFd + Send + 'static> Stream for T {}
+
+/// An output stream of a REPL.
+#[cfg(not(unix))]
+pub trait Stream: Read + Send + 'static {}
+
+#[cfg(not(unix))]
+impl<T: Read + Send + 'static> Stream for T {}
+
+/// Read a stream and send the chunks to `sender`, tagged with `tag`, followed by an empty chunk
+/// at end of file. The stream is read in a thread of its own, or by a task on a shared runtime
+/// with the `async` feature on Unix.
+pub(crate) fn spawn_reader<T: Copy + Send + 'static>(
+    stream: impl Stream,
+    tag: T,
+    sender: Sender<(T, Vec<u8>)>,
+) {
+    #[cfg(all(feature = "async", unix))]
+    let Err(stream) = crate::runtime::spawn_async_reader(stream, tag, &sender) else {
+        return;
+    };
+    let mut stream = stream;
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    let _ = sender.send((tag, Vec::new()));
+                    break;
+                }
+                Ok(n) => {
+                    if sender.send((tag, buf[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Reads the output of a REPL from a single stream, up to the prompt.
+pub struct OutputReader {
+    /// Receives chunks of output. An empty chunk means end of file.
+    output: Receiver<((), Vec<u8>)>,
+    eof: bool,
+
+    /// Output which has been read but not decoded yet, since it ends with an incomplete UTF-8
+    /// sequence, or with an incomplete escape code or `\r` with [Self::clean_console].
+    raw: Vec<u8>,
+
+    encoding: Encoding,
+
+    /// The error if output couldn't be decoded, which is returned by the next read. The output is
+    /// decoded lossily then.
+    decode_error: Option<anyhow::Error>,
+
+    /// Decoded output which is read but not yet returned.
+    buffer: String,
+
+    limits: ReadLimits,
+
+    /// Whether escape codes are removed and `\r\n` is turned into `\n`.
+    clean_console: bool,
+
+    #[cfg(feature = "vt100")]
+    screen: Option<Box<vt100::Parser>>,
+}
+
+impl OutputReader {
+    /// Start reading a stream.
+    pub fn new(stream: impl Stream, limits: ReadLimits) -> Self {
+        let (sender, output) = channel();
+        spawn_reader(stream, (), sender);
+        Self {
+            output,
+            eof: false,
+            raw: Vec::new(),
+            encoding: Encoding::default(),
+            decode_error: None,
+            buffer: String::new(),
+            limits,
+            clean_console: false,
+            #[cfg(feature = "vt100")]
+            screen: None,
+        }
+    }
+
+    /// Remove escape codes and turn `\r\n` into `\n`, for the output of a Windows pseudo console
+    /// which renders the output like a terminal would.
+    pub fn clean_console(mut self) -> Self {
+        self.clean_console = true;
+        self
+    }
+
+    /// Decode the output with `encoding` instead of [Encoding::Lossy].
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Feed the output to an emulated terminal of this size, see [Self::screen].
+    #[cfg(feature = "vt100")]
+    pub fn emulate_terminal(mut self, rows: u16, cols: u16) -> Self {
+        self.screen = Some(Box::new(vt100::Parser::new(rows, cols, 0)));
+        self
+    }
+
+    /// The screen of the emulated terminal, if any.
+    #[cfg(feature = "vt100")]
+    pub fn screen(&self) -> Option<&vt100::Screen> {
+        self.screen.as_ref().map(|x| x.screen())
+    }
+
+    /// Move the output in [Self::raw] to [Self::buffer], up to an incomplete UTF-8 sequence, or
+    /// an incomplete escape code or `\r` with [Self::clean_console], at the end.
+    fn decode(&mut self) {
+        let mut end = self.raw.len();
+        if !self.eof {
+            end = self.encoding.complete_len(&self.raw);
+            if self.clean_console {
+                if let Some(escape) = self.raw[..end].iter().rposition(|x| *x == b'\x1b') {
+                    let code = String::from_utf8_lossy(&self.raw[escape..end]);
+                    if ESCAPE_CODE.find(&code).is_none_or(|x| x.start() > 0) {
+                        end = escape;
+                    }
+                }
+                if self.raw[..end].ends_with(b"\r") {
+                    end -= 1;
+                }
+            }
+        }
+        let text = match self.encoding.decode(&self.raw[..end]) {
+            Ok(text) => text,
+            Err(e) => {
+                self.decode_error.get_or_insert(e);
+                String::from_utf8_lossy(&self.raw[..end])
+            }
+        };
+        if self.clean_console {
+            let cleaned = ESCAPE_CODE.replace_all(&text, "").replace("\r\n", "\n");
+            self.buffer += &cleaned;
+        } else {
+            self.buffer += &text;
+        }
+        self.raw.drain(..end);
+    }
+
+    /// Receive the next chunk of output and add it to the buffer, or return the error if no
+    /// output came within `timeout`.
+    fn receive(&mut self, timeout: Duration) -> Result<(), RecvTimeoutError> {
+        match self.output.recv_timeout(timeout) {
+            Ok((_, chunk)) if chunk.is_empty() => self.eof = true,
+            Ok((_, chunk)) => {
+                #[cfg(feature = "vt100")]
+                if let Some(screen) = &mut self.screen {
+                    screen.process(&chunk);
+                }
+                self.raw.extend(chunk);
+            }
+            Err(RecvTimeoutError::Timeout) => return Err(RecvTimeoutError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => self.eof = true,
+        }
+        self.decode();
+        Ok(())
+    }
+
+    /// Read output until the prompt or end of file, and call `on_line` with every complete line
+    /// as it is read if it is given, see [crate::backend::ReplBackend::read_until_prompt] and
+    /// [crate::backend::ReplBackend::read_until_prompt_streaming]. Without `on_line`, the prompt
+    /// is searched for in all output, since a prompt which spans lines may start before the last
+    /// complete line.
+    pub fn read_until_prompt(
+        &mut self,
+        prompt: &Regex,
+        mut on_line: Option<&mut dyn FnMut(&str) -> anyhow::Result<()>>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        let start = Instant::now();
+        let mut last_output = start;
+        // The length of the complete lines in the buffer which have been given to `on_line`.
+        let mut streamed = 0;
+        loop {
+            if let Some(e) = self.decode_error.take() {
+                return Err(e);
+            }
+            let search_start = if on_line.is_some() { streamed } else { 0 };
+            let prompt_match = prompt
+                .find_at(&self.buffer, search_start)
+                .map(|m| (m.start(), m.end()));
+            if let Some(on_line) = on_line.as_mut() {
+                let end = prompt_match.map_or(self.buffer.len(), |(start, _)| start);
+                while let Some(len) = self.buffer[streamed..end].find('\n') {
+                    let line = &self.buffer[streamed..streamed + len];
+                    on_line(line.strip_suffix('\r').unwrap_or(line))?;
+                    streamed += len + 1;
+                }
+            }
+            if let Some((start, end)) = prompt_match {
+                let actual_prompt = self.buffer[start..end].to_string();
+                let output = self.buffer[..start].to_string();
+                self.buffer.drain(..end);
+                return Ok((output, Some(actual_prompt)));
+            }
+            if self.eof {
+                if let Some(on_line) = on_line {
+                    self.buffer[streamed..].lines().try_for_each(on_line)?;
+                }
+                return Ok((std::mem::take(&mut self.buffer), None));
+            }
+            let wait = self
+                .limits
+                .next_wait(start, last_output, prompt, &self.buffer)?;
+            if self.receive(wait).is_ok() {
+                last_output = Instant::now();
+            }
+        }
+    }
+
+    /// Whether the end of the output has been reached.
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Return the output which has been read but not returned and the next chunk of output if it
+    /// comes within `timeout`, as it is, for passing it through to a terminal.
+    pub fn pass_through(&mut self, timeout: Duration) -> Vec<u8> {
+        let mut output = std::mem::take(&mut self.buffer).into_bytes();
+        output.append(&mut self.raw);
+        if self.eof {
+            return output;
+        }
+        match self.output.recv_timeout(timeout) {
+            Ok((_, chunk)) if chunk.is_empty() => self.eof = true,
+            Ok((_, chunk)) => {
+                #[cfg(feature = "vt100")]
+                if let Some(screen) = &mut self.screen {
+                    screen.process(&chunk);
+                }
+                output.extend(chunk);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => self.eof = true,
+        }
+        output
+    }
+
+    /// Wait until nothing has been read for `idle`, and return all output which has been read but
+    /// not returned, without consuming it.
+    pub fn peek_until_idle(&mut self, idle: Duration) -> String {
+        let start = Instant::now();
+        while !self.eof && start.elapsed() < self.limits.timeout && !self.limits.cancelled() {
+            if self.receive(idle).is_err() {
+                break;
+            }
+        }
+        self.buffer.clone()
+    }
+}
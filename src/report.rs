@@ -0,0 +1,529 @@
+//! Reports of the results of checking documents.
+
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The result of running a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStatus {
+    /// All blocks in the session matched.
+    Passed,
+
+    /// A block in the session failed.
+    Failed,
+
+    /// The session was not run since the time budget was exceeded.
+    NotRun,
+
+    /// The session was not run since it has passed before without any changes.
+    Cached,
+
+    /// The `setup_cmd` or the `teardown_cmd` of the session failed.
+    HookFailed,
+}
+
+impl fmt::Display for SessionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionStatus::Passed => write!(f, "passed"),
+            SessionStatus::Failed => write!(f, "failed"),
+            SessionStatus::NotRun => write!(f, "not run"),
+            SessionStatus::Cached => write!(f, "cached"),
+            SessionStatus::HookFailed => write!(f, "hook failed"),
+        }
+    }
+}
+
+/// Resources used by the REPL processes of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// User and system CPU time.
+    pub cpu_time: Duration,
+
+    /// Peak resident set size in bytes.
+    pub max_rss: u64,
+}
+
+impl ResourceUsage {
+    /// Combine the usage of two processes which were run one after another.
+    pub fn combine(self, other: ResourceUsage) -> ResourceUsage {
+        ResourceUsage {
+            cpu_time: self.cpu_time + other.cpu_time,
+            max_rss: self.max_rss.max(other.max_rss),
+        }
+    }
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.2} s CPU, {:.1} MiB peak RSS",
+            self.cpu_time.as_secs_f64(),
+            self.max_rss as f64 / (1024.0 * 1024.0)
+        )
+    }
+}
+
+/// A snapshot of the emulated terminal screen of a REPL.
+#[derive(Debug, Clone)]
+pub struct ScreenSnapshot {
+    /// The text on the screen.
+    pub text: String,
+
+    /// The screen rendered as an SVG image, with colors.
+    pub svg: String,
+}
+
+/// An error in a session together with the terminal screen at the moment it happened.
+#[derive(Debug, thiserror::Error)]
+#[error("{error}\nTerminal screen:\n{}", screen.text)]
+pub struct ScreenError {
+    /// The name of the session.
+    pub session: String,
+    pub error: anyhow::Error,
+    pub screen: ScreenSnapshot,
+}
+
+/// The result of running a REPL block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// The output of all commands in the block matched.
+    Passed,
+
+    /// The block failed, see [BlockFailure].
+    Failed,
+
+    /// The block was not run, since it is disabled, its session was not run or an earlier block
+    /// in the session failed.
+    NotRun,
+
+    /// The block was not run since its session is cached, but it passed the last time it was run.
+    Cached,
+}
+
+/// The result of a REPL block in a document.
+#[derive(Debug, Clone)]
+pub struct BlockReport {
+    /// The number of the code block in the document, starting at 1.
+    pub number: usize,
+
+    /// The name of the session.
+    pub session: String,
+
+    /// The code of the block in the document, before it is updated.
+    pub code: String,
+    pub status: BlockStatus,
+
+    /// How long it took to run the block, `None` if it was not run.
+    pub duration: Option<Duration>,
+
+    /// The number of commands which were run in the block.
+    pub commands: usize,
+
+    /// The number of the code block with the `alt_of` attribute whose expected output matched,
+    /// if the block's own didn't.
+    pub alternative: Option<usize>,
+
+    /// A diff from the expected to the actual output, like from [crate::diff::format_diff], if
+    /// the block failed because the output of a command didn't match.
+    pub diff: Option<String>,
+}
+
+/// How long a command took until its output was read.
+#[derive(Debug, Clone)]
+pub struct CommandTiming {
+    /// The number of the code block in the document, starting at 1.
+    pub block: usize,
+
+    /// The name of the session.
+    pub session: String,
+    pub cmd: String,
+    pub duration: Duration,
+}
+
+/// A change to the code of a REPL block, like filled in `???` holes or renumbered prompts, which
+/// is written back to the document when updating.
+#[derive(Debug, Clone)]
+pub struct BlockUpdate {
+    /// The number of the code block in the document, starting at 1.
+    pub number: usize,
+
+    /// The name of the session.
+    pub session: String,
+
+    /// The code of the block in the document.
+    pub code: String,
+
+    /// The updated code.
+    pub updated_code: String,
+}
+
+/// Why a block or a document failed, see [Report::exit_code].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FailureKind {
+    /// The output didn't match the expected output, the REPL didn't exit as expected or a
+    /// command took longer than its `max_duration`.
+    Mismatch,
+
+    /// The REPL could not be run as expected, like when it could not be started, a prompt
+    /// timed out or a hook failed.
+    Error,
+}
+
+/// A block which failed, the following blocks in its session were not run.
+#[derive(Debug, thiserror::Error)]
+#[error("Code block {block} in session {session}: {error}")]
+pub struct BlockFailure {
+    /// The name of the session.
+    pub session: String,
+
+    /// The number of the code block in the document, starting at 1.
+    pub block: usize,
+    pub error: anyhow::Error,
+    pub kind: FailureKind,
+}
+
+/// The result of a session in a document.
+#[derive(Debug, Clone)]
+pub struct SessionReport {
+    pub name: String,
+    pub status: SessionStatus,
+
+    /// The resources used by the session, if the backend can measure it.
+    pub resource_usage: Option<ResourceUsage>,
+
+    /// The number of REPL blocks in the session.
+    pub blocks: usize,
+
+    /// How many times the session was run again after a block failed.
+    pub retries: usize,
+
+    /// Everything sent to and read from the REPL, with [crate::Options::keep_transcripts].
+    pub transcript: Option<String>,
+
+    /// The error if the `setup_cmd` or the `teardown_cmd` of the session failed.
+    pub hook_error: Option<String>,
+
+    /// The sandboxes of the session which were kept with [crate::Options::keep_sandboxes].
+    pub kept_sandboxes: Vec<PathBuf>,
+}
+
+/// The result of checking a document.
+#[derive(Debug, Default)]
+pub struct DocumentReport {
+    pub path: PathBuf,
+
+    /// The reports of all sessions, empty if the document failed.
+    pub sessions: Vec<SessionReport>,
+
+    /// The error if the document failed as a whole.
+    pub error: Option<String>,
+
+    /// The errors of all blocks which failed.
+    pub failures: Vec<String>,
+
+    /// The most severe kind of the failures and of [Self::error], or `None` if there are none.
+    pub failure_kind: Option<FailureKind>,
+
+    /// A report for every REPL block in the document.
+    pub blocks: Vec<BlockReport>,
+
+    /// Blocks which have not passed within the maximum age.
+    pub stale: Vec<String>,
+
+    /// Notes about how the output of the blocks which passed was matched, in verbose mode.
+    pub notes: Vec<String>,
+
+    /// How long every command in the blocks which passed took.
+    pub timings: Vec<CommandTiming>,
+}
+
+impl DocumentReport {
+    /// Whether the document failed, because of an error, a failing block or hook, or stale
+    /// blocks.
+    pub fn failed(&self) -> bool {
+        self.error.is_some()
+            || !self.failures.is_empty()
+            || !self.stale.is_empty()
+            || self.sessions.iter().any(|x| x.hook_error.is_some())
+    }
+}
+
+/// Counts and durations of a run, for the summary of a [Report] and as JSON for dashboards.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    pub files: usize,
+    pub sessions: usize,
+    pub sessions_passed: usize,
+    pub sessions_cached: usize,
+    pub sessions_failed: usize,
+    pub sessions_hook_failed: usize,
+    pub sessions_not_run: usize,
+    pub blocks: usize,
+    pub blocks_passed: usize,
+    pub blocks_cached: usize,
+    pub blocks_failed: usize,
+
+    /// Blocks which were not run, since they are disabled, their sessions were not run or an
+    /// earlier block failed.
+    pub blocks_skipped: usize,
+    pub commands: usize,
+
+    /// The number of documents which failed.
+    pub failures: usize,
+
+    /// The total time of the run in seconds.
+    pub duration_secs: f64,
+
+    /// The average time of the blocks which were run in seconds, or zero if none was run.
+    pub average_block_secs: f64,
+
+    /// The average time of the commands which were run in seconds, or zero if none was run.
+    pub average_command_secs: f64,
+}
+
+/// The results of all sessions in a number of documents.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub documents: Vec<DocumentReport>,
+
+    /// The total time it took to check all documents.
+    pub duration: Duration,
+
+    /// The number of the slowest commands to list, none if it is zero.
+    pub slowest_commands: usize,
+}
+
+impl Report {
+    /// Count the sessions with a given status.
+    pub fn count(&self, status: SessionStatus) -> usize {
+        self.sessions().filter(|x| x.status == status).count()
+    }
+
+    /// The number of documents which failed.
+    pub fn failures(&self) -> usize {
+        self.documents.iter().filter(|x| x.failed()).count()
+    }
+
+    /// The exit code of the program after the run: 0 if all documents passed, 1 if blocks only
+    /// failed because of mismatches or are stale, and 2 if a REPL could not be run as expected,
+    /// see [FailureKind].
+    pub fn exit_code(&self) -> u8 {
+        let kind = self
+            .documents
+            .iter()
+            .filter_map(|x| {
+                let hooks = x.sessions.iter().any(|x| x.hook_error.is_some());
+                let stale = (!x.stale.is_empty()).then_some(FailureKind::Mismatch);
+                x.failure_kind
+                    .max(stale)
+                    .max(hooks.then_some(FailureKind::Error))
+            })
+            .max();
+        match kind {
+            None => 0,
+            Some(FailureKind::Mismatch) => 1,
+            Some(FailureKind::Error) => 2,
+        }
+    }
+
+    /// The summary in a single line, like `files: 2 (1 failed), blocks: 5 passed, 1 cached,
+    /// 1 failed, 0 skipped, time: 3.14 s`.
+    pub fn summary_line(&self) -> String {
+        let stats = self.stats();
+        format!(
+            "files: {} ({} failed), blocks: {} passed, {} cached, {} failed, {} skipped, time: \
+             {:.2} s",
+            stats.files,
+            stats.failures,
+            stats.blocks_passed,
+            stats.blocks_cached,
+            stats.blocks_failed,
+            stats.blocks_skipped,
+            stats.duration_secs
+        )
+    }
+
+    /// All sessions in all documents.
+    fn sessions(&self) -> impl Iterator<Item = &SessionReport> {
+        self.documents.iter().flat_map(|x| &x.sessions)
+    }
+
+    /// The counts and durations of the run.
+    pub fn stats(&self) -> Stats {
+        let blocks: Vec<&BlockReport> = self.documents.iter().flat_map(|x| &x.blocks).collect();
+        let count_blocks =
+            |status: BlockStatus| blocks.iter().filter(|x| x.status == status).count();
+        let run: Vec<&BlockReport> = blocks
+            .iter()
+            .filter(|x| x.duration.is_some())
+            .copied()
+            .collect();
+        let block_time: Duration = run.iter().filter_map(|x| x.duration).sum();
+        let commands = run.iter().map(|x| x.commands).sum();
+        let average = |total: Duration, count: usize| match count {
+            0 => 0.0,
+            n => total.as_secs_f64() / n as f64,
+        };
+        Stats {
+            files: self.documents.len(),
+            sessions: self.sessions().count(),
+            sessions_passed: self.count(SessionStatus::Passed),
+            sessions_cached: self.count(SessionStatus::Cached),
+            sessions_failed: self.count(SessionStatus::Failed),
+            sessions_hook_failed: self.count(SessionStatus::HookFailed),
+            sessions_not_run: self.count(SessionStatus::NotRun),
+            blocks: blocks.len(),
+            blocks_passed: count_blocks(BlockStatus::Passed),
+            blocks_cached: count_blocks(BlockStatus::Cached),
+            blocks_failed: count_blocks(BlockStatus::Failed),
+            blocks_skipped: count_blocks(BlockStatus::NotRun),
+            commands,
+            failures: self.failures(),
+            duration_secs: self.duration.as_secs_f64(),
+            average_block_secs: average(block_time, run.len()),
+            average_command_secs: average(block_time, commands),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for DocumentReport {
+            path,
+            sessions,
+            error,
+            ..
+        } in self.documents.iter()
+        {
+            if error.is_some() {
+                writeln!(f, "{}: failed", path.display())?;
+            }
+            for session in sessions {
+                write!(
+                    f,
+                    "{}: {}: {}",
+                    path.display(),
+                    session.name,
+                    session.status
+                )?;
+                match session.retries {
+                    0 => {}
+                    1 => write!(f, " after 1 retry")?,
+                    n => write!(f, " after {n} retries")?,
+                }
+                if let Some(usage) = session.resource_usage {
+                    write!(f, " ({usage})")?;
+                }
+                writeln!(f)?;
+            }
+        }
+        if self.documents.iter().any(|x| !x.notes.is_empty()) {
+            writeln!(f)?;
+            writeln!(f, "Notes:")?;
+            for document in self.documents.iter() {
+                for note in &document.notes {
+                    writeln!(f, "{}: {note}", document.path.display())?;
+                }
+            }
+        }
+        if self.slowest_commands > 0 {
+            let mut timings: Vec<_> = self
+                .documents
+                .iter()
+                .flat_map(|x| x.timings.iter().map(move |y| (&x.path, y)))
+                .collect();
+            timings.sort_by_key(|(_, x)| std::cmp::Reverse(x.duration));
+            writeln!(f)?;
+            writeln!(f, "Slowest commands:")?;
+            for (path, timing) in timings.into_iter().take(self.slowest_commands) {
+                writeln!(
+                    f,
+                    "  {:>7.3} s  {}: Code block {} in session {}: {}",
+                    timing.duration.as_secs_f64(),
+                    path.display(),
+                    timing.block,
+                    timing.session,
+                    timing.cmd
+                )?;
+            }
+        }
+        if self.documents.iter().any(|x| !x.failures.is_empty()) {
+            writeln!(f)?;
+            writeln!(f, "Failures:")?;
+            for document in self.documents.iter() {
+                for failure in &document.failures {
+                    writeln!(f)?;
+                    writeln!(f, "{}: {failure}", document.path.display())?;
+                }
+            }
+        }
+        if self.sessions().any(|x| x.hook_error.is_some()) {
+            writeln!(f)?;
+            writeln!(f, "Hook failures:")?;
+            for document in self.documents.iter() {
+                for session in &document.sessions {
+                    if let Some(error) = &session.hook_error {
+                        writeln!(f)?;
+                        writeln!(f, "{}: {}: {error}", document.path.display(), session.name)?;
+                    }
+                }
+            }
+        }
+        if self.documents.iter().any(|x| !x.stale.is_empty()) {
+            writeln!(f)?;
+            writeln!(f, "Stale blocks:")?;
+            for document in self.documents.iter() {
+                for stale in &document.stale {
+                    writeln!(f, "{}: {stale}", document.path.display())?;
+                }
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "Summary:")?;
+        let stats = self.stats();
+        let rows = [
+            ("files", stats.files.to_string()),
+            (
+                "sessions",
+                format!(
+                    "{} passed, {} cached, {} failed, {} hooks failed, {} not run",
+                    stats.sessions_passed,
+                    stats.sessions_cached,
+                    stats.sessions_failed,
+                    stats.sessions_hook_failed,
+                    stats.sessions_not_run
+                ),
+            ),
+            (
+                "blocks",
+                format!(
+                    "{} passed, {} cached, {} failed, {} skipped",
+                    stats.blocks_passed,
+                    stats.blocks_cached,
+                    stats.blocks_failed,
+                    stats.blocks_skipped
+                ),
+            ),
+            ("commands", stats.commands.to_string()),
+            ("failures", stats.failures.to_string()),
+            (
+                "time",
+                format!(
+                    "{:.2} s, {:.3} s per block, {:.3} s per command",
+                    stats.duration_secs, stats.average_block_secs, stats.average_command_secs
+                ),
+            ),
+        ];
+        for (i, (key, value)) in rows.iter().enumerate() {
+            write!(f, "  {key:<10}{value}")?;
+            if i + 1 < rows.len() {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+//! Asynchronous reading of output with tokio, with the `async` feature, for checking many
+//! documents with mostly idle REPLs at once.
+//!
+//! The output of all REPLs is read by tasks on a small shared runtime instead of by a thread for
+//! every stream (on Unix), also by the command line. The sessions themselves are still run
+//! synchronously. For library users with a tokio runtime, [check_documents_async] checks
+//! documents with a bound on how many are checked at the same time.
+
+use crate::backend::ReplBackend;
+use crate::reader::Stream;
+use crate::{check_document_with_backend, CheckResult, Options};
+use pandoc_ast::Pandoc;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
+#[cfg(unix)]
+use {
+    std::fs::File,
+    std::io::Read,
+    std::os::fd::{AsFd, AsRawFd},
+    tokio::io::unix::AsyncFd,
+    tokio::io::Interest,
+};
+
+lazy_static::lazy_static! {
+    /// The runtime which reads the output of the REPLs.
+    static ref RUNTIME: Runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("repl-check-io")
+        .enable_io()
+        .build()
+        .expect("Failed to start the tokio runtime");
+}
+
+/// Read a stream in a task on the shared runtime and send the chunks to `sender` like
+/// [crate::reader::spawn_reader]. The stream is given back if it can't be read asynchronously.
+#[cfg(unix)]
+pub(crate) fn spawn_async_reader<S: Stream, T: Copy + Send + 'static>(
+    stream: S,
+    tag: T,
+    sender: &Sender<(T, Vec<u8>)>,
+) -> Result<(), S> {
+    let Ok(fd) = stream.as_fd().try_clone_to_owned() else {
+        return Err(stream);
+    };
+    let _runtime = RUNTIME.enter();
+    let Ok(file) = AsyncFd::with_interest(File::from(fd), Interest::READABLE) else {
+        return Err(stream);
+    };
+    let raw_fd = file.get_ref().as_raw_fd();
+    // SAFETY: The file descriptor is valid as long as `file`. The flag is set on the open file
+    // description which `stream` shares, so `stream` is not used after this.
+    let nonblocking = unsafe {
+        let flags = libc::fcntl(raw_fd, libc::F_GETFL);
+        flags >= 0 && libc::fcntl(raw_fd, libc::F_SETFL, flags | libc::O_NONBLOCK) >= 0
+    };
+    if !nonblocking {
+        return Err(stream);
+    }
+    drop(stream);
+    let sender = sender.clone();
+    RUNTIME.spawn(async move {
+        let mut buf = [0u8; 4096];
+        loop {
+            let Ok(mut guard) = file.readable().await else {
+                break;
+            };
+            let result = guard.try_io(|x| {
+                let mut file: &File = x.get_ref();
+                file.read(&mut buf)
+            });
+            match result {
+                // Nothing to read after all.
+                Err(_) => continue,
+                Ok(Ok(0) | Err(_)) => break,
+                Ok(Ok(n)) => {
+                    if sender.send((tag, buf[..n].to_vec())).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = sender.send((tag, Vec::new()));
+    });
+    Ok(())
+}
+
+/// Check documents with at most `concurrency` documents at the same time, like
+/// [check_document_with_backend] for every document. It is not used by the command line, which
+/// checks documents on threads of its own with `--concurrency`.
+///
+/// Every document is checked synchronously on a thread of the blocking pool of the runtime it is
+/// called from, which it occupies until all its sessions are done, so this only saves the
+/// threads which would read the output of the REPLs.
+pub async fn check_documents_async<B: ReplBackend + 'static>(
+    documents: Vec<(Pandoc, Options)>,
+    concurrency: usize,
+) -> Vec<anyhow::Result<CheckResult>> {
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let tasks: Vec<_> = documents
+        .into_iter()
+        .map(|(document, options)| {
+            let permits = permits.clone();
+            tokio::spawn(async move {
+                let _permit = permits.acquire_owned().await?;
+                tokio::task::spawn_blocking(move || {
+                    check_document_with_backend::<B>(&document, &options)
+                })
+                .await?
+            })
+        })
+        .collect();
+    let mut results = Vec::new();
+    for task in tasks {
+        results.push(task.await.map_err(anyhow::Error::from).and_then(|x| x));
+    }
+    results
+}
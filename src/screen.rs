@@ -0,0 +1,138 @@
+//! Snapshots of an emulated terminal screen as text and SVG.
+
+use crate::report::ScreenSnapshot;
+use std::fmt::Write;
+
+/// The width of a cell in the SVG.
+const CELL_WIDTH: f64 = 8.4;
+/// The height of a cell in the SVG.
+const CELL_HEIGHT: f64 = 17.0;
+const FONT_SIZE: f64 = 14.0;
+const DEFAULT_FG: &str = "#d4d4d4";
+const DEFAULT_BG: &str = "#1e1e1e";
+
+/// The 16 standard terminal colors.
+const PALETTE: [&str; 16] = [
+    "#000000", "#cd3131", "#0dbc79", "#e5e510", "#2472c8", "#bc3fbc", "#11a8cd", "#e5e5e5",
+    "#666666", "#f14c4c", "#23d18b", "#f5f543", "#3b8eea", "#d670d6", "#29b8db", "#ffffff",
+];
+
+/// Take a snapshot of a screen.
+pub fn snapshot(screen: &vt100::Screen) -> ScreenSnapshot {
+    let mut lines: Vec<String> = screen
+        .contents()
+        .lines()
+        .map(|x| x.trim_end().to_string())
+        .collect();
+    while lines.last().is_some_and(|x| x.is_empty()) {
+        lines.pop();
+    }
+    ScreenSnapshot {
+        text: lines.join("\n"),
+        svg: render_svg(screen),
+    }
+}
+
+/// Convert a terminal color to a CSS color, or `None` for the default color.
+fn css_color(color: vt100::Color) -> Option<String> {
+    match color {
+        vt100::Color::Default => None,
+        vt100::Color::Idx(i @ 0..=15) => Some(PALETTE[i as usize].to_string()),
+        vt100::Color::Idx(i @ 16..=231) => {
+            let i = i - 16;
+            let level = |x: u8| if x == 0 { 0 } else { 55 + 40 * x };
+            Some(format!(
+                "#{:02x}{:02x}{:02x}",
+                level(i / 36),
+                level(i / 6 % 6),
+                level(i % 6)
+            ))
+        }
+        vt100::Color::Idx(i) => {
+            let level = 8 + 10 * (i - 232);
+            Some(format!("#{level:02x}{level:02x}{level:02x}"))
+        }
+        vt100::Color::Rgb(r, g, b) => Some(format!("#{r:02x}{g:02x}{b:02x}")),
+    }
+}
+
+/// Escape text for XML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Render a screen as an SVG image.
+fn render_svg(screen: &vt100::Screen) -> String {
+    let (rows, cols) = screen.size();
+    let width = cols as f64 * CELL_WIDTH;
+    let height = rows as f64 * CELL_HEIGHT;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+         font-family=\"monospace\" font-size=\"{FONT_SIZE}\">\n\
+         <rect width=\"100%\" height=\"100%\" fill=\"{DEFAULT_BG}\"/>\n"
+    );
+    for row in 0..rows {
+        let y = row as f64 * CELL_HEIGHT;
+        for col in 0..cols {
+            let Some(cell) = screen.cell(row, col) else {
+                continue;
+            };
+            if cell.is_wide_continuation() {
+                continue;
+            }
+            let (mut fg, mut bg) = (css_color(cell.fgcolor()), css_color(cell.bgcolor()));
+            if cell.inverse() {
+                (fg, bg) = (
+                    Some(bg.unwrap_or(DEFAULT_BG.to_string())),
+                    Some(fg.unwrap_or(DEFAULT_FG.to_string())),
+                );
+            }
+            let x = col as f64 * CELL_WIDTH;
+            let cell_width = if cell.is_wide() { 2.0 } else { 1.0 } * CELL_WIDTH;
+            if let Some(bg) = bg {
+                writeln!(
+                    svg,
+                    "<rect x=\"{x}\" y=\"{y}\" width=\"{cell_width}\" height=\"{CELL_HEIGHT}\" \
+                     fill=\"{bg}\"/>"
+                )
+                .unwrap();
+            }
+            if !cell.has_contents() || cell.contents().trim().is_empty() {
+                continue;
+            }
+            write!(
+                svg,
+                "<text x=\"{x}\" y=\"{}\" fill=\"{}\"",
+                y + FONT_SIZE,
+                fg.as_deref().unwrap_or(DEFAULT_FG)
+            )
+            .unwrap();
+            if cell.bold() {
+                svg += " font-weight=\"bold\"";
+            }
+            if cell.italic() {
+                svg += " font-style=\"italic\"";
+            }
+            if cell.underline() {
+                svg += " text-decoration=\"underline\"";
+            }
+            writeln!(svg, ">{}</text>", escape(cell.contents())).unwrap();
+        }
+    }
+    if !screen.hide_cursor() {
+        let (row, col) = screen.cursor_position();
+        writeln!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{CELL_WIDTH}\" height=\"{CELL_HEIGHT}\" \
+             fill=\"{DEFAULT_FG}\" fill-opacity=\"0.5\"/>",
+            col as f64 * CELL_WIDTH,
+            row as f64 * CELL_HEIGHT
+        )
+        .unwrap();
+    }
+    svg += "</svg>\n";
+    svg
+}
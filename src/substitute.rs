@@ -0,0 +1,137 @@
+//! Sed-style substitutions like `s/0x[0-9a-f]+/0xADDR/g`, which rewrite the actual output before
+//! it is matched and written to the documents.
+//!
+//! The delimiter is the character after the `s`, and it can be escaped with a backslash. The
+//! pattern is a [regex]. In the replacement, `&` is the whole match, `\1` to `\9` are the capture
+//! groups and `\n` is a newline. The flags are `g` to replace all matches instead of only the
+//! first, and `i` to ignore case. Several substitutions can be separated by `;`.
+
+use anyhow::Context;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+
+/// A parsed substitution.
+#[derive(Debug, Clone)]
+pub struct Substitution {
+    /// The expression as it was written.
+    source: String,
+    regex: Regex,
+    /// The replacement in the syntax of [Regex::replace].
+    replacement: String,
+    global: bool,
+}
+
+impl Substitution {
+    /// Apply the substitution to a text.
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.global {
+            true => self.regex.replace_all(text, &self.replacement),
+            false => self.regex.replace(text, &self.replacement),
+        }
+    }
+
+    /// Apply a list of substitutions in order.
+    pub fn apply_all(substitutions: &[Substitution], text: String) -> String {
+        substitutions
+            .iter()
+            .fold(text, |text, x| x.apply(&text).into_owned())
+    }
+
+    /// The expression as it was written.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+/// Parse substitutions separated by `;`.
+pub fn parse_substitutions(script: &str) -> anyhow::Result<Vec<Substitution>> {
+    let mut substitutions = Vec::new();
+    let mut rest = script.trim_start();
+    while !rest.is_empty() {
+        let (substitution, remaining) =
+            parse_one(rest).with_context(|| format!("Bad substitution: {}", rest.trim_end()))?;
+        substitutions.push(substitution);
+        rest = remaining.trim_start();
+        rest = rest.strip_prefix(';').unwrap_or(rest).trim_start();
+    }
+    Ok(substitutions)
+}
+
+/// Parse one substitution at the start of `text` and return the remaining text.
+fn parse_one(text: &str) -> anyhow::Result<(Substitution, &str)> {
+    let mut chars = text.strip_prefix('s').context("Expected `s`")?.chars();
+    let delimiter = chars
+        .next()
+        .filter(|x| !x.is_alphanumeric() && !x.is_whitespace() && *x != '\\')
+        .context("Expected a delimiter after `s`")?;
+
+    // Read until an unescaped delimiter, and return the parts as (escaped, char).
+    let read_part = |chars: &mut std::str::Chars| -> anyhow::Result<Vec<(bool, char)>> {
+        let mut part = Vec::new();
+        loop {
+            match chars.next() {
+                None => anyhow::bail!("Missing delimiter `{delimiter}`"),
+                Some(x) if x == delimiter => return Ok(part),
+                Some('\\') => part.push((true, chars.next().unwrap_or('\\'))),
+                Some(x) => part.push((false, x)),
+            }
+        }
+    };
+
+    let mut pattern = String::new();
+    for (escaped, x) in read_part(&mut chars)? {
+        match (escaped, x) {
+            (true, x) if x == delimiter => pattern += &regex::escape(&x.to_string()),
+            (true, x) => {
+                pattern.push('\\');
+                pattern.push(x);
+            }
+            (false, x) => pattern.push(x),
+        }
+    }
+    let mut replacement = String::new();
+    for (escaped, x) in read_part(&mut chars)? {
+        match (escaped, x) {
+            (true, x @ '0'..='9') => replacement += &format!("${{{x}}}"),
+            (true, 'n') => replacement.push('\n'),
+            (false, '&') => replacement += "${0}",
+            (_, '$') => replacement += "$$",
+            (_, x) => replacement.push(x),
+        }
+    }
+
+    let rest = chars.as_str();
+    let flags_end = rest
+        .find(|x: char| x == ';' || x.is_whitespace())
+        .unwrap_or(rest.len());
+    let mut global = false;
+    for flag in rest[..flags_end].chars() {
+        match flag {
+            'g' => global = true,
+            'i' => pattern.insert_str(0, "(?i)"),
+            _ => anyhow::bail!("Unknown flag `{flag}`"),
+        }
+    }
+    let source = text[..text.len() - rest.len() + flags_end].to_string();
+    let substitution = Substitution {
+        source,
+        regex: Regex::new(&pattern)?,
+        replacement,
+        global,
+    };
+    Ok((substitution, &rest[flags_end..]))
+}
+
+/// Deserialize a list of substitutions, where every string may contain several.
+pub fn deserialize_substitutions<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Substitution>, D::Error> {
+    let mut substitutions = Vec::new();
+    for script in Vec::<String>::deserialize(deserializer)? {
+        substitutions.extend(
+            parse_substitutions(&script).map_err(|e| serde::de::Error::custom(format!("{e:#}")))?,
+        );
+    }
+    Ok(substitutions)
+}
@@ -0,0 +1,85 @@
+//! Heuristics which suggest how to fix the expected output of a command when it doesn't match.
+
+use crate::pattern::{self, Comparison};
+use std::fmt;
+
+/// A suggested fix for mismatching output.
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    /// A description of the fix.
+    pub description: String,
+
+    /// The expected lines after applying the fix.
+    pub fixed: Vec<String>,
+}
+
+impl fmt::Display for Suggestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+/// Suggest fixes which make `expected` match `actual`, the most specific suggestions first.
+///
+/// `first_line` is the (1-based) line number of the first expected line in the block and is used
+/// in the descriptions. The lines are compared according to `comparison`.
+pub fn suggest(
+    expected: &[&str],
+    actual: &[&str],
+    first_line: usize,
+    comparison: Comparison,
+) -> Vec<Suggestion> {
+    let mut suggestions = Vec::new();
+    let normalize = |x: &str| x.split_whitespace().collect::<Vec<_>>().join(" ");
+    if expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .all(|(x, y)| normalize(x) == normalize(y))
+    {
+        suggestions.push(Suggestion {
+            description: "The output matches if whitespace is ignored.".to_string(),
+            fixed: actual.iter().map(|x| x.to_string()).collect(),
+        });
+    }
+
+    if let Some(i) = (0..=expected.len()).find(|&i| {
+        let mut fixed = expected.to_vec();
+        fixed.insert(i, "...");
+        pattern::matchit(&fixed, actual, comparison).is_ok()
+    }) {
+        let mut fixed: Vec<String> = expected.iter().map(|x| x.to_string()).collect();
+        fixed.insert(i, "...".to_string());
+        suggestions.push(Suggestion {
+            description: format!(
+                "A `...` hole before line {} would absorb the extra output.",
+                first_line + i
+            ),
+            fixed,
+        });
+    }
+
+    // Try to remove as few consecutive expected lines as possible.
+    'outer: for len in 1..=expected.len() {
+        for i in 0..=expected.len() - len {
+            let fixed: Vec<&str> = expected[..i]
+                .iter()
+                .chain(&expected[i + len..])
+                .copied()
+                .collect();
+            if pattern::matchit(&fixed, actual, comparison).is_ok() {
+                let lines = if len == 1 {
+                    format!("Line {} is", first_line + i)
+                } else {
+                    format!("Lines {}-{} are", first_line + i, first_line + i + len - 1)
+                };
+                suggestions.push(Suggestion {
+                    description: format!("{lines} no longer printed, remove them."),
+                    fixed: fixed.into_iter().map(|x| x.to_string()).collect(),
+                });
+                break 'outer;
+            }
+        }
+    }
+    suggestions
+}
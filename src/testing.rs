@@ -0,0 +1,210 @@
+//! Helpers for testing matching and backends without real REPLs like Python or GHCi.
+//!
+//! [fake_repl] is a tiny REPL which is built into the binary and started with
+//! `repl-check --fake-repl`, and [SyntheticSession] builds a document with a session in code and
+//! checks it, without pandoc. With the `proptest` feature, [strategies] generates expected and
+//! actual lines which match or don't match.
+
+use crate::pattern::{self, Comparison};
+use crate::{check_document, CheckResult, Options};
+use pandoc_ast::Pandoc;
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// The prompt of [fake_repl].
+pub const FAKE_PROMPT: &str = "fake> ";
+
+/// Run the fake REPL on stdin and stdout until `exit` or the end of the input. It prints
+/// [FAKE_PROMPT] before every command and understands these commands:
+/// - `echo TEXT` prints the text.
+/// - `err TEXT` prints the text to stderr.
+/// - `seq N` prints the numbers from 1 to N, a line each.
+/// - `sleep MS` waits for the milliseconds.
+/// - `exit` exits.
+///
+/// Empty lines print nothing and other commands print an error.
+pub fn fake_repl() -> std::io::Result<()> {
+    let mut stdin = std::io::stdin().lock();
+    let mut stdout = std::io::stdout().lock();
+    loop {
+        write!(stdout, "{FAKE_PROMPT}")?;
+        stdout.flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (cmd, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match cmd {
+            "" => (),
+            "echo" => writeln!(stdout, "{arg}")?,
+            "err" => eprintln!("{arg}"),
+            "seq" => {
+                for i in 1..=arg.parse::<u64>().unwrap_or(0) {
+                    writeln!(stdout, "{i}")?;
+                }
+            }
+            "sleep" => std::thread::sleep(Duration::from_millis(arg.parse().unwrap_or(0))),
+            "exit" => return Ok(()),
+            _ => writeln!(stdout, "unknown command: {cmd}")?,
+        }
+    }
+}
+
+/// A session which is built in code and checked as a document with a REPL block for every
+/// [Self::block].
+#[derive(Debug, Clone)]
+pub struct SyntheticSession {
+    /// The shell command which starts the REPL.
+    cmd: String,
+
+    /// The prompt as the REPL prints it.
+    prompt: String,
+
+    /// More session attributes, like `timeout`.
+    attrs: Vec<(String, String)>,
+
+    /// The text of every block.
+    blocks: Vec<String>,
+}
+
+impl SyntheticSession {
+    /// A session with a REPL which is started with the shell command `cmd` and prints `prompt`.
+    pub fn new(cmd: &str, prompt: &str) -> Self {
+        Self {
+            cmd: cmd.to_string(),
+            prompt: prompt.to_string(),
+            attrs: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    /// A session with [fake_repl] of the `repl-check` binary at `exe`, like
+    /// `env!("CARGO_BIN_EXE_repl-check")` in the integration tests of this crate.
+    pub fn fake(exe: &Path) -> Self {
+        Self::new(&format!("'{}' --fake-repl", exe.display()), FAKE_PROMPT)
+    }
+
+    /// Set a session attribute, like `timeout` or `mode`.
+    pub fn attr(mut self, key: &str, value: &str) -> Self {
+        self.attrs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Add a block with commands and the expected lines of their output.
+    pub fn block(mut self, commands: &[(&str, &[&str])]) -> Self {
+        let mut lines = Vec::new();
+        for (cmd, output) in commands {
+            lines.push(format!("{}{cmd}", self.prompt));
+            lines.extend(output.iter().map(|x| x.to_string()));
+        }
+        self.blocks.push(lines.join("\n"));
+        self
+    }
+
+    /// The session attributes of the first block.
+    fn session_attrs(&self) -> Vec<(String, String)> {
+        let prompt = regex::escape(&self.prompt);
+        [
+            ("cmd".to_string(), self.cmd.clone()),
+            ("prompt".to_string(), prompt),
+        ]
+        .into_iter()
+        .chain(self.attrs.iter().cloned())
+        .collect()
+    }
+
+    /// The session as Markdown, like it would be written in a document.
+    pub fn markdown(&self) -> String {
+        let attrs: String = self
+            .session_attrs()
+            .iter()
+            .map(|(key, value)| format!(" {key}=\"{}\"", value.replace('"', "\\\"")))
+            .collect();
+        let mut markdown = String::new();
+        for (i, text) in self.blocks.iter().enumerate() {
+            let attrs = if i == 0 { attrs.as_str() } else { "" };
+            markdown += &format!("```{{.repl-synthetic{attrs}}}\n{text}\n```\n\n");
+        }
+        markdown
+    }
+
+    /// The session as a document, like pandoc would parse [Self::markdown].
+    pub fn document(&self) -> Pandoc {
+        let blocks: Vec<Value> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let attrs = if i == 0 {
+                    self.session_attrs()
+                } else {
+                    Vec::new()
+                };
+                json!({"t": "CodeBlock", "c": [["", ["repl-synthetic"], attrs], text]})
+            })
+            .collect();
+        let document = json!({"pandoc-api-version": [1, 23, 1], "meta": {}, "blocks": blocks});
+        serde_json::from_value(document).expect("The document is valid.")
+    }
+
+    /// Run the session and check it.
+    pub fn check(&self, options: &Options) -> anyhow::Result<CheckResult> {
+        check_document(&self.document(), options)
+    }
+}
+
+/// Whether the actual lines match the expected lines, with holes, placeholders and the default
+/// comparison, like in a REPL block without session attributes.
+pub fn output_matches(expected: &[&str], actual: &[&str]) -> bool {
+    let actual: Vec<_> = actual.iter().map(|x| pattern::escape_line(x)).collect();
+    let actual: Vec<&str> = actual.iter().map(AsRef::as_ref).collect();
+    pattern::matchit(expected, &actual, Comparison::default()).is_ok()
+}
+
+/// Generators of expected and actual lines for proptest.
+#[cfg(feature = "proptest")]
+pub mod strategies {
+    use proptest::prelude::*;
+
+    /// A line which is matched literally: it has no braces or backslashes and it can't be a hole
+    /// since it doesn't start with a dot, a question mark or whitespace.
+    pub fn plain_line() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9,:;=+*/()-][a-zA-Z0-9 .,:;=?+*/()-]{0,40}"
+    }
+
+    /// Expected and actual lines which match, where some runs of the actual lines are `...`
+    /// holes in the expected lines.
+    pub fn matching_lines() -> impl Strategy<Value = (Vec<String>, Vec<String>)> {
+        prop::collection::vec((plain_line(), any::<bool>()), 0..20).prop_map(|lines| {
+            let mut expected: Vec<String> = Vec::new();
+            let mut actual = Vec::new();
+            for (line, hidden) in lines {
+                if !hidden {
+                    expected.push(line.clone());
+                } else if expected.last().map(String::as_str) != Some("...") {
+                    expected.push("...".to_string());
+                }
+                actual.push(line);
+            }
+            (expected, actual)
+        })
+    }
+
+    /// Expected and actual lines without holes which don't match since one of the actual lines
+    /// is changed.
+    pub fn mismatching_lines() -> impl Strategy<Value = (Vec<String>, Vec<String>)> {
+        prop::collection::vec(plain_line(), 1..20)
+            .prop_flat_map(|lines| {
+                let len = lines.len();
+                (Just(lines), 0..len)
+            })
+            .prop_map(|(expected, changed)| {
+                let mut actual = expected.clone();
+                actual[changed] += "x";
+                (expected, actual)
+            })
+    }
+}
@@ -0,0 +1,172 @@
+//! Transcripts of everything sent to and read from the REPLs, with timestamps, for debugging
+//! prompt regexes and echoed commands. Parts of them are shown with the failures of blocks, and
+//! the whole transcripts are kept with [crate::Options::keep_transcripts].
+
+use crate::backend::ReplBackend;
+use crate::report::{ResourceUsage, ScreenSnapshot};
+use regex::Regex;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// The most entries of a transcript shown with a failure.
+const EXCERPT_ENTRIES: usize = 20;
+
+/// What an entry of a [Transcript] is.
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Sent,
+    Read,
+    Restart,
+}
+
+/// Everything sent to and read from a REPL.
+#[derive(Debug)]
+pub struct Transcript {
+    start: Instant,
+    entries: Vec<(Instant, Direction, String)>,
+}
+
+impl Transcript {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            entries: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, direction: Direction, text: String) {
+        self.entries.push((Instant::now(), direction, text));
+    }
+
+    /// The number of entries, for an [Self::excerpt] of the entries after this point.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The entries from `start` on, one per line with the time since the start of the
+    /// transcript. The text is quoted with escapes, so it shows exactly what was sent and read.
+    pub fn render(&self, start: usize) -> String {
+        let mut text = String::new();
+        for (time, direction, entry) in &self.entries[start..] {
+            let time = time.duration_since(self.start).as_secs_f64();
+            let _ = match direction {
+                Direction::Sent => writeln!(text, "{time:9.3}s sent {entry:?}"),
+                Direction::Read => writeln!(text, "{time:9.3}s read {entry:?}"),
+                Direction::Restart => writeln!(text, "{time:9.3}s restart"),
+            };
+        }
+        text
+    }
+
+    /// The last [EXCERPT_ENTRIES] entries from `start` on, see [Self::render].
+    pub fn excerpt(&self, start: usize) -> String {
+        let skipped = self.len().saturating_sub(start + EXCERPT_ENTRIES);
+        let text = self.render(start + skipped);
+        let text = text.trim_end();
+        match skipped {
+            0 => text.to_string(),
+            _ => format!("({skipped} earlier entries)\n{text}"),
+        }
+    }
+}
+
+/// A backend which records a [Transcript] of another one.
+pub struct TranscriptBackend<B> {
+    backend: B,
+    transcript: Transcript,
+}
+
+impl<B> TranscriptBackend<B> {
+    pub fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+
+    /// Go on with a process which has been spawned to restart the REPL, keeping the transcript.
+    pub fn restart(&mut self, process: Self) {
+        self.transcript.push(Direction::Restart, String::new());
+        self.transcript.entries.extend(process.transcript.entries);
+        self.backend = process.backend;
+    }
+}
+
+impl<B: ReplBackend> TranscriptBackend<B> {
+    /// Record the output and the prompt returned by a read.
+    fn record_read(
+        &mut self,
+        result: anyhow::Result<(String, Option<String>)>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        if let Ok((output, prompt)) = &result {
+            let text = output.clone() + prompt.as_deref().unwrap_or_default();
+            self.transcript.push(Direction::Read, text);
+        }
+        result
+    }
+}
+
+impl<B: ReplBackend> ReplBackend for TranscriptBackend<B> {
+    fn spawn(options: &crate::backend::SpawnOptions) -> anyhow::Result<Self> {
+        Ok(Self {
+            backend: B::spawn(options)?,
+            transcript: Transcript::new(),
+        })
+    }
+
+    fn send_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.transcript.push(Direction::Sent, format!("{line}\n"));
+        self.backend.send_line(line)
+    }
+
+    fn send_keys(&mut self, keys: &str) -> anyhow::Result<()> {
+        self.transcript.push(Direction::Sent, keys.to_string());
+        self.backend.send_keys(keys)
+    }
+
+    fn read_until_prompt(&mut self, prompt: &Regex) -> anyhow::Result<(String, Option<String>)> {
+        let result = self.backend.read_until_prompt(prompt);
+        self.record_read(result)
+    }
+
+    fn read_until_prompt_streaming(
+        &mut self,
+        prompt: &Regex,
+        on_line: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<(String, Option<String>)> {
+        // The lines which have been read are recorded if reading stops with an error.
+        let mut lines = String::new();
+        let result = self
+            .backend
+            .read_until_prompt_streaming(prompt, &mut |line| {
+                lines += line;
+                lines.push('\n');
+                on_line(line)
+            });
+        if result.is_err() && !lines.is_empty() {
+            self.transcript.push(Direction::Read, lines);
+        }
+        self.record_read(result)
+    }
+
+    fn peek_until_idle(&mut self, idle: Duration) -> anyhow::Result<String> {
+        self.backend.peek_until_idle(idle)
+    }
+
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.backend.shutdown()
+    }
+
+    fn kill(&mut self) -> anyhow::Result<()> {
+        self.backend.kill()
+    }
+
+    fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.backend.resource_usage()
+    }
+
+    fn screen_snapshot(&self) -> Option<ScreenSnapshot> {
+        self.backend.screen_snapshot()
+    }
+
+    fn attach(&mut self) -> anyhow::Result<()> {
+        self.backend.attach()
+    }
+}
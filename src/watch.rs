@@ -0,0 +1,223 @@
+//! Watching files and directories for changes, for re-running documents when they are saved.
+//!
+//! On Linux the kernel is notified with inotify, elsewhere the modification times are polled.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// After a change, wait this long for more changes so that a save which writes several files, or
+/// writes a file in several steps, triggers only one run.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// All directories in a directory, recursively, including the directory itself.
+fn walk_dirs(dir: &Path, dirs: &mut Vec<PathBuf>) {
+    dirs.push(dir.to_path_buf());
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|x| x.is_dir()) {
+            walk_dirs(&entry.path(), dirs);
+        }
+    }
+}
+
+/// The directories to watch for a set of files and directories: the directories themselves and
+/// all their subdirectories, and the directories containing the files.
+fn watched_dirs(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            walk_dirs(path, &mut dirs);
+        } else {
+            let dir = path.parent().filter(|x| !x.as_os_str().is_empty());
+            dirs.push(dir.unwrap_or(".".as_ref()).to_path_buf());
+        }
+    }
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::{walk_dirs, watched_dirs, DEBOUNCE};
+    use std::collections::HashMap;
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::path::{Path, PathBuf};
+
+    /// Watches directories for files which are written or moved into them.
+    pub struct Watcher {
+        fd: OwnedFd,
+
+        /// The watched directories by their watch descriptors.
+        dirs: HashMap<libc::c_int, PathBuf>,
+    }
+
+    impl Watcher {
+        /// Watch a set of files and directories. Directories are watched recursively.
+        pub fn new(paths: &[PathBuf]) -> anyhow::Result<Self> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                anyhow::bail!("inotify_init1 failed: {}", std::io::Error::last_os_error());
+            }
+            let mut watcher = Watcher {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                dirs: HashMap::new(),
+            };
+            for dir in watched_dirs(paths) {
+                watcher.add_dir(&dir)?;
+            }
+            Ok(watcher)
+        }
+
+        fn add_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+            let path = CString::new(dir.as_os_str().as_bytes())?;
+            let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), mask) };
+            if wd < 0 {
+                anyhow::bail!(
+                    "Failed to watch {}: {}",
+                    dir.display(),
+                    std::io::Error::last_os_error()
+                );
+            }
+            self.dirs.insert(wd, dir.to_path_buf());
+            Ok(())
+        }
+
+        /// Wait until `timeout_ms` has passed (or forever if it is -1) for events, and read them.
+        /// Returns whether there were any events.
+        fn read_events(
+            &mut self,
+            timeout_ms: libc::c_int,
+            changed: &mut Vec<PathBuf>,
+        ) -> anyhow::Result<bool> {
+            let mut poll_fd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } <= 0 {
+                return Ok(false);
+            }
+            // Large enough for at least one event with the longest file name, and aligned.
+            let mut buffer = vec![0u64; 1024];
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len() * 8,
+                )
+            };
+            if len < 0 {
+                anyhow::bail!(
+                    "Failed to read inotify events: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+            let bytes = buffer.as_ptr().cast::<u8>();
+            let mut pos = 0;
+            let header_len = std::mem::size_of::<libc::inotify_event>();
+            while pos + header_len <= len as usize {
+                let event = unsafe { &*bytes.add(pos).cast::<libc::inotify_event>() };
+                let name_ptr = unsafe { bytes.add(pos + header_len) };
+                pos += header_len + event.len as usize;
+                if event.len == 0 {
+                    continue;
+                }
+                let name = unsafe { CStr::from_ptr(name_ptr.cast()) };
+                let Some(dir) = self.dirs.get(&event.wd) else {
+                    continue;
+                };
+                let path = dir.join(std::ffi::OsStr::from_bytes(name.to_bytes()));
+                if event.mask & libc::IN_ISDIR != 0 {
+                    // Watch new directories too.
+                    let mut dirs = Vec::new();
+                    walk_dirs(&path, &mut dirs);
+                    for dir in dirs {
+                        self.add_dir(&dir)?;
+                    }
+                } else if event.mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 {
+                    changed.push(path);
+                }
+            }
+            Ok(true)
+        }
+
+        /// Wait for files to change and return their paths.
+        pub fn wait(&mut self) -> anyhow::Result<Vec<PathBuf>> {
+            let mut changed = Vec::new();
+            while changed.is_empty() {
+                self.read_events(-1, &mut changed)?;
+                while self.read_events(DEBOUNCE.as_millis() as libc::c_int, &mut changed)? {}
+            }
+            changed.sort();
+            changed.dedup();
+            Ok(changed)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::{watched_dirs, DEBOUNCE};
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    /// How often the modification times are checked.
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    /// Watches directories for files which are changed, by polling their modification times.
+    pub struct Watcher {
+        paths: Vec<PathBuf>,
+
+        /// The modification time of every file in the watched directories.
+        times: BTreeMap<PathBuf, SystemTime>,
+    }
+
+    impl Watcher {
+        /// Watch a set of files and directories. Directories are watched recursively.
+        pub fn new(paths: &[PathBuf]) -> anyhow::Result<Self> {
+            let paths = paths.to_vec();
+            let times = Self::modification_times(&paths);
+            Ok(Watcher { paths, times })
+        }
+
+        fn modification_times(paths: &[PathBuf]) -> BTreeMap<PathBuf, SystemTime> {
+            let mut times = BTreeMap::new();
+            for dir in watched_dirs(paths) {
+                for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+                    if let Ok(time) = entry.metadata().and_then(|x| x.modified()) {
+                        times.insert(entry.path(), time);
+                    }
+                }
+            }
+            times
+        }
+
+        /// Wait for files to change and return their paths.
+        pub fn wait(&mut self) -> anyhow::Result<Vec<PathBuf>> {
+            loop {
+                std::thread::sleep(POLL_INTERVAL);
+                let times = Self::modification_times(&self.paths);
+                let changed: Vec<_> = times
+                    .iter()
+                    .filter(|(path, time)| self.times.get(*path) != Some(time))
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                self.times = times;
+                if !changed.is_empty() {
+                    std::thread::sleep(DEBOUNCE);
+                    self.times = Self::modification_times(&self.paths);
+                    return Ok(changed);
+                }
+            }
+        }
+    }
+}
+
+pub use imp::Watcher;
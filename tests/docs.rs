@@ -0,0 +1,7 @@
+//! Check the REPL sessions in the documents in `tests/docs` as tests, one test for every block.
+//!
+//! Run with `cargo test --features harness --test docs`.
+
+fn main() {
+    repl_check::harness(&["tests/docs"]);
+}
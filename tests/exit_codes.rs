@@ -0,0 +1,86 @@
+//! The exit codes of the command line for passing documents, mismatches, errors and invalid input.
+//!
+//! The documents are read with pandoc, which must be installed.
+
+use std::process::Command;
+
+/// The attributes of a block of a shell session.
+const SHELL: &str = "cmd=\"env PS1='$ ' sh\" prompt=\"[$] \"";
+
+/// Write a Markdown document with a REPL block with `attrs` and `code` and a Python block which
+/// is not checked to a new directory, and run `repl-check` there with `args` followed by the
+/// document. Returns the exit code.
+fn exit_code(name: &str, attrs: &str, code: &str, args: &[&str]) -> i32 {
+    let dir = std::env::temp_dir().join(format!(
+        "repl-check-exit-codes-{name}-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let document = dir.join("doc.md");
+    std::fs::write(
+        &document,
+        format!("```{{.repl-a {attrs}}}\n{code}\n```\n\n```python\nprint(1)\n```\n"),
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_repl-check"))
+        .current_dir(&dir)
+        .args(args)
+        .arg(&document)
+        .output();
+    let _ = std::fs::remove_dir_all(&dir);
+    output.unwrap().status.code().unwrap()
+}
+
+#[test]
+fn passing_documents_exit_with_0() {
+    assert_eq!(
+        exit_code("pass", SHELL, "$ echo hello\nhello", &["check"]),
+        0
+    );
+}
+
+#[test]
+fn mismatches_exit_with_1() {
+    assert_eq!(
+        exit_code("mismatch", SHELL, "$ echo hello\nbye", &["check"]),
+        1
+    );
+}
+
+#[test]
+fn errors_exit_with_2() {
+    let attrs = "cmd=repl-check-no-such-program prompt=\"[$] \"";
+    assert_eq!(
+        exit_code("error", attrs, "$ echo hello\nhello", &["check"]),
+        2
+    );
+}
+
+#[test]
+fn invalid_input_exits_with_3() {
+    let code = "$ echo hello\nhello";
+    let attrs = format!("{SHELL} colour=red");
+    assert_eq!(exit_code("attribute", &attrs, code, &["check"]), 3);
+    assert_eq!(
+        exit_code("option", SHELL, code, &["check", "--no-such-option"]),
+        3
+    );
+    let config = ["export", "--config", "no-such-config.toml"];
+    assert_eq!(exit_code("config", SHELL, code, &config), 3);
+}
+
+#[test]
+fn coverage_below_fail_under_exits_with_1() {
+    // One of the two code blocks is checked.
+    let code = "$ echo hello\nhello";
+    let coverage = |percent| {
+        exit_code(
+            "coverage",
+            SHELL,
+            code,
+            &["coverage", "--fail-under", percent],
+        )
+    };
+    assert_eq!(coverage("40"), 0);
+    assert_eq!(coverage("60"), 1);
+}
@@ -0,0 +1,42 @@
+//! Properties of the matching of expected and actual lines, and sessions with the fake REPL.
+//!
+//! Run with `cargo test --features proptest --test properties`.
+
+use proptest::prelude::*;
+use repl_check::report::FailureKind;
+use repl_check::testing::{output_matches, strategies, SyntheticSession};
+use repl_check::Options;
+use std::path::Path;
+
+/// Borrow the lines.
+fn strs(lines: &[String]) -> Vec<&str> {
+    lines.iter().map(String::as_str).collect()
+}
+
+proptest! {
+    #[test]
+    fn lines_match_themselves(lines in prop::collection::vec(strategies::plain_line(), 0..20)) {
+        prop_assert!(output_matches(&strs(&lines), &strs(&lines)));
+    }
+
+    #[test]
+    fn holes_match_hidden_lines((expected, actual) in strategies::matching_lines()) {
+        prop_assert!(output_matches(&strs(&expected), &strs(&actual)));
+    }
+
+    #[test]
+    fn changed_lines_mismatch((expected, actual) in strategies::mismatching_lines()) {
+        prop_assert!(!output_matches(&strs(&expected), &strs(&actual)));
+    }
+}
+
+#[test]
+fn fake_repl_session() {
+    let session = SyntheticSession::fake(Path::new(env!("CARGO_BIN_EXE_repl-check")))
+        .block(&[("echo hello", &["hello"][..]), ("seq 3", &["1", "..."])])
+        .block(&[("echo bye", &["hi"])]);
+    let result = session.check(&Options::default()).unwrap();
+    assert_eq!(result.failures.len(), 1);
+    assert_eq!(result.failures[0].block, 2);
+    assert_eq!(result.failures[0].kind, FailureKind::Mismatch);
+}